@@ -1,5 +1,6 @@
 from flask import Flask, request, jsonify, render_template
-from datetime import datetime
+from datetime import datetime, timedelta, timezone
+import json
 import sqlite3
 import os
 
@@ -7,19 +8,53 @@ app = Flask(__name__)
 
 DATABASE_FILE = 'weather_data.db'
 
+# Largest reply body that leaves room for the headers in the station's 1024-byte response buffer
+REPLY_BUDGET = 768
+
 def init_db():
     """Initialize SQLite database with weather_readings table."""
     conn = sqlite3.connect(DATABASE_FILE)
     cursor = conn.cursor()
     
+    # Any quantity may be missing, depending on the sensors a station has; the full
+    # reading is kept as JSON for the ones without a column
     cursor.execute('''
         CREATE TABLE IF NOT EXISTS weather_readings (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
-            temperature REAL NOT NULL,
-            humidity REAL NOT NULL,
+            temperature REAL,
+            humidity REAL,
+            pressure REAL,
+            reading TEXT,
             timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
         )
     ''')
+    columns = {row[1]: row for row in cursor.execute('PRAGMA table_info(weather_readings)')}
+    if 'reading' not in columns:
+        # Databases from before every quantity was optional required temperature and humidity
+        cursor.execute('ALTER TABLE weather_readings RENAME TO weather_readings_old')
+        cursor.execute('''
+            CREATE TABLE weather_readings (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                temperature REAL,
+                humidity REAL,
+                pressure REAL,
+                reading TEXT,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            )
+        ''')
+        cursor.execute('''
+            INSERT INTO weather_readings (id, temperature, humidity, timestamp)
+            SELECT id, temperature, humidity, timestamp FROM weather_readings_old
+        ''')
+        cursor.execute('DROP TABLE weather_readings_old')
+    # Calibration commands waiting for a station, kept until it acknowledges their id
+    cursor.execute('''
+        CREATE TABLE IF NOT EXISTS calibration_queue (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            station TEXT NOT NULL,
+            command TEXT NOT NULL
+        )
+    ''')
     
     conn.commit()
     conn.close()
@@ -36,7 +71,11 @@ def get_latest_reading():
     conn = get_db_connection()
     cursor = conn.cursor()
     
-    cursor.execute('SELECT temperature, humidity, timestamp FROM weather_readings ORDER BY id DESC LIMIT 1')
+    cursor.execute('''
+        SELECT temperature, humidity, timestamp FROM weather_readings
+        WHERE temperature IS NOT NULL OR humidity IS NOT NULL
+        ORDER BY timestamp DESC, id DESC LIMIT 1
+    ''')
     row = cursor.fetchone()
     conn.close()
     
@@ -55,38 +94,146 @@ def home():
     last_update = None
     
     if reading:
-        weather_data = {'temp': reading['temp'], 'hum': reading['hum']}
+        weather_data = {key: reading[key] for key in ('temp', 'hum') if reading[key] is not None}
         # Format timestamp as HH:MM:SS
         dt = datetime.strptime(reading['timestamp'], '%Y-%m-%d %H:%M:%S')
         last_update = dt.strftime("%H:%M:%S")
     
     return render_template('index.html', weather_data=weather_data, last_update=last_update)
 
+def station_time(reading):
+    """When the station took the reading, if its clock was set."""
+    unix_ms = reading.get('unix_ms')
+    if unix_ms is None:
+        return None
+    return datetime.fromtimestamp(unix_ms / 1000, timezone.utc)
+
+def pending_calibration(cursor, station):
+    """Commands queued for a station, oldest first, as (id, command)."""
+    cursor.execute('SELECT id, command FROM calibration_queue WHERE station = ? ORDER BY id', (station,))
+    return [(row['id'], row['command']) for row in cursor.fetchall()]
+
+def station_reply(status="success"):
+    """Reply to an upload, handing over calibration commands queued for the station.
+
+    The station names itself with the "station" query parameter and reports the id of the
+    last command it applied in "cal_ack"; those are dropped from the queue, and the rest
+    are sent, as many as fit in the station's response buffer, with the id of the last
+    one in "calibration_id". Commands stay queued until acknowledged, so a lost reply is
+    simply sent again."""
+    reply = {"status": status}
+    station = request.args.get('station')
+    if not station:
+        return jsonify(reply)
+
+    conn = get_db_connection()
+    cursor = conn.cursor()
+    cal_ack = request.args.get('cal_ack', type=int)
+    if cal_ack:
+        cursor.execute('DELETE FROM calibration_queue WHERE station = ? AND id <= ?', (station, cal_ack))
+        conn.commit()
+    pending = pending_calibration(cursor, station)
+    conn.close()
+
+    commands, last_id = [], None
+    for command_id, command in pending:
+        if len(json.dumps({"calibration": commands + [command], "calibration_id": command_id, "status": status})) > REPLY_BUDGET:
+            break
+        commands.append(command)
+        last_id = command_id
+    if commands:
+        # jsonify sorts the keys, so the list comes before its id and a reply cut short loses the id
+        reply = {"calibration": commands, "calibration_id": last_id, "status": status}
+        print(f"🔧 Sending calibration to {station}: {commands}")
+    return jsonify(reply)
+
+@app.route('/calibration', methods=['POST'])
+def calibration():
+    """Queue calibration commands for one station, named by its MAC address, e.g.
+    {"station": "240AC4123456", "commands": ["dht temp -0.8 1.02", "sht hum 2.5"]} or
+    {"station": "240AC4123456", "commands": ["clear"]}."""
+    station = request.json.get('station')
+    commands = request.json.get('commands', [])
+    if not isinstance(station, str) or not station:
+        return jsonify({"status": "error", "message": "station is required"}), 400
+    # Each one must fit in a reply on its own, or it would hold up the queue
+    if not all(isinstance(c, str) and '"' not in c and len(c) <= 100 for c in commands):
+        return jsonify({"status": "error", "message": "commands must be strings of at most 100 characters"}), 400
+
+    conn = get_db_connection()
+    cursor = conn.cursor()
+    for command in commands:
+        cursor.execute('INSERT INTO calibration_queue (station, command) VALUES (?, ?)', (station, command))
+    conn.commit()
+    pending = [command for _, command in pending_calibration(cursor, station)]
+    conn.close()
+    return jsonify({"status": "queued", "station": station, "pending": pending})
+
 @app.route('/data', methods=['GET', 'POST'])
 def data():
     if request.method == 'POST':
         # Handle data sent from ESP device
-        data_json = request.json
+        data_json = request.get_json(silent=True)
+        if not isinstance(data_json, dict):
+            return jsonify({"status": "error", "message": "Expected a JSON object"}), 400
+        if data_json.get('status') == 'sensor_failed':
+            # The station could not get a valid reading; log it rather than store fake values
+            timestamp = datetime.now().strftime("%H:%M:%S")
+            print(f"⚠️ Sensor failure reported: {data_json.get('error')} at {timestamp}")
+            return station_reply()
+
         temp = data_json.get('temp')
         hum = data_json.get('hum')
-        
-        if temp is not None and hum is not None:
-            conn = get_db_connection()
-            cursor = conn.cursor()
-            
-            cursor.execute('''
-                INSERT INTO weather_readings (temperature, humidity)
-                VALUES (?, ?)
-            ''', (temp, hum))
-            
-            conn.commit()
-            conn.close()
-            
-            timestamp = datetime.now().strftime("%H:%M:%S")
-            print(f"📊 Data received: temp={temp}°C, humidity={hum}% at {timestamp}")
-            return jsonify({"status": "success"})
-        else:
-            return jsonify({"status": "error", "message": "Missing temperature or humidity data"}), 400
+        pres = data_json.get('pres')
+
+        # Values the station graded as bad are outside the sensor's range; keep them out of the history
+        quality = data_json.get('quality', {})
+        if quality.get('temp') == 'bad':
+            print(f"⚠️ Implausible temperature discarded: {temp}°C")
+            temp = None
+        if quality.get('hum') == 'bad':
+            print(f"⚠️ Implausible humidity discarded: {hum}%")
+            hum = None
+        if quality.get('pres') == 'bad':
+            print(f"⚠️ Implausible pressure discarded: {pres} hPa")
+            pres = None
+        if 'suspect' in quality.values():
+            print(f"⚠️ Suspect reading: quality={quality}")
+
+        if data_json.get('rain') is not None:
+            print(f"🌧️ Rain: {data_json.get('rain')} mm, rate {data_json.get('rain_rate')} mm/h")
+        if data_json.get('wind') is not None:
+            print(f"💨 Wind: {data_json.get('wind')} m/s, gust {data_json.get('gust')} m/s from {data_json.get('wind_card', '?')}")
+        if data_json.get('co2') is not None:
+            print(f"🫧 CO2: {data_json.get('co2')} ppm")
+        if data_json.get('pm25') is not None:
+            print(f"🌫️ PM1.0 {data_json.get('pm1')}, PM2.5 {data_json.get('pm25')}, PM10 {data_json.get('pm10')} µg/m³ (AQI {data_json.get('aqi')})")
+        if data_json.get('lat') is not None:
+            print(f"📍 Location: {data_json.get('lat')}, {data_json.get('lon')} alt {data_json.get('alt')} m ({data_json.get('fix')}, {data_json.get('sats')} sats) at {data_json.get('gps_time', '?')}")
+        if data_json.get('batt_v') is not None:
+            print(f"🔋 Battery: {data_json.get('batt_v')}V ({data_json.get('batt_pct')}%)")
+
+        # External 1-Wire probes, keyed by ROM code
+        for rom, probe_temp in data_json.get('probes', {}).items():
+            print(f"🌡️ Probe {rom}: {probe_temp}°C")
+
+        # Stations report whatever their sensors measured, so every quantity is optional
+        conn = get_db_connection()
+        cursor = conn.cursor()
+
+        # The station's own time when it has one, in the same format and UTC as CURRENT_TIMESTAMP
+        taken = station_time(data_json)
+        cursor.execute('''
+            INSERT INTO weather_readings (temperature, humidity, pressure, reading, timestamp)
+            VALUES (?, ?, ?, ?, COALESCE(?, CURRENT_TIMESTAMP))
+        ''', (temp, hum, pres, json.dumps(data_json), taken.strftime('%Y-%m-%d %H:%M:%S') if taken else None))
+
+        conn.commit()
+        conn.close()
+
+        timestamp = datetime.now().strftime("%H:%M:%S")
+        print(f"📊 Data received: temp={temp}°C, humidity={hum}%, pressure={pres} hPa at {timestamp}")
+        return station_reply()
     else:
         # Return the latest data
         reading = get_latest_reading()
@@ -94,13 +241,68 @@ def data():
             return jsonify({'temp': reading['temp'], 'hum': reading['hum']})
         return jsonify({})
 
+@app.route('/data/batch', methods=['POST'])
+def data_batch():
+    """Readings the station kept while it was offline, oldest first.
+
+    Each sample carries the station's RTC time "ts" in milliseconds, and the
+    batch the RTC time "now" it was sent at, so a sample was taken now - ts
+    milliseconds ago; readings taken once the station's clock was set carry
+    their own time, which is used instead. The RTC time starts over when the
+    station loses power, so both also carry the "boot" they count from: a
+    sample from an earlier one without its own time cannot be dated, and is
+    rejected. The reply acknowledges the last sequence number received, stored
+    or rejected; the station keeps the samples until then."""
+    batch = request.json
+    boot = batch.get('boot')
+    now_ms = batch.get('now', 0)
+    received = datetime.now(timezone.utc)
+    samples = batch.get('samples', [])
+    if batch.get('dropped'):
+        print(f"⚠️ Station dropped {batch.get('dropped')} readings while offline")
+
+    conn = get_db_connection()
+    cursor = conn.cursor()
+    rejected = 0
+    for sample in samples:
+        reading = sample.get('reading', {})
+        taken = station_time(reading)
+        if taken is None:
+            ts = sample.get('ts')
+            if boot is None or sample.get('boot') != boot or ts is None or ts > now_ms:
+                print(f"⚠️ Rejected backlogged reading #{sample.get('seq')}: taken before a power loss, time unknown")
+                rejected += 1
+                continue
+            taken = received - timedelta(milliseconds=now_ms - ts)
+        # As for /data: every quantity is optional, and ones graded bad are left out
+        quality = reading.get('quality', {})
+        temp = reading.get('temp') if quality.get('temp') != 'bad' else None
+        hum = reading.get('hum') if quality.get('hum') != 'bad' else None
+        pres = reading.get('pres') if quality.get('pres') != 'bad' else None
+        # Same format and UTC as CURRENT_TIMESTAMP
+        cursor.execute('''
+            INSERT INTO weather_readings (temperature, humidity, pressure, reading, timestamp)
+            VALUES (?, ?, ?, ?, ?)
+        ''', (temp, hum, pres, json.dumps(reading), taken.strftime('%Y-%m-%d %H:%M:%S')))
+        print(f"📦 Backlogged reading #{sample.get('seq')}: temp={temp}°C, humidity={hum}%, pressure={pres} hPa taken {taken.strftime('%H:%M:%S')} UTC")
+    conn.commit()
+    conn.close()
+
+    if not samples:
+        return jsonify({"status": "error", "message": "Empty batch"}), 400
+    return jsonify({"status": "success", "ack": samples[-1].get('seq'), "rejected": rejected})
+
 @app.route('/history', methods=['GET'])
 def history():
     """Return all historical weather data as JSON."""
     conn = get_db_connection()
     cursor = conn.cursor()
     
-    cursor.execute('SELECT temperature, humidity, timestamp FROM weather_readings ORDER BY id ASC')
+    cursor.execute('''
+        SELECT temperature, humidity, timestamp FROM weather_readings
+        WHERE temperature IS NOT NULL OR humidity IS NOT NULL
+        ORDER BY timestamp ASC, id ASC
+    ''')
     rows = cursor.fetchall()
     conn.close()
     
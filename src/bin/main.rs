@@ -13,11 +13,13 @@ use core::net::Ipv4Addr;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use embassy_executor::Spawner;
-use embassy_net::{Runner, StackResources, tcp::TcpSocket};
-use embassy_time::{Duration, Timer};
+use embassy_net::{ConfigV4, Ipv4Cidr, Runner, StackResources, StaticConfigV4, dns::DnsQueryType, tcp::TcpSocket, udp::{PacketMetadata, UdpSocket}};
+use embassy_time::{Duration, Instant, Timer};
 use esp_alloc as _;
 use esp_backtrace as _;
-use esp_hal::rtc_cntl::sleep::TimerWakeupSource;
+use esp_hal::rtc_cntl::sleep::{Ext0WakeupSource, TimerWakeupSource, WakeupLevel};
+use esp_hal::rtc_cntl::wakeup_cause;
+use esp_hal::system::SleepSource;
 use esp_hal::{clock::CpuClock, delay::Delay, gpio::{Level, Output, OutputConfig}, ram, rng::Rng, timer::timg::TimerGroup, rtc_cntl::Rtc};
 use esp_println::println;
 use esp_radio::{Controller, wifi::{ClientConfig, ModeConfig, ScanConfig, WifiController, WifiDevice, WifiEvent, WifiStaState}};
@@ -44,6 +46,120 @@ const PASSWORD: &str = match option_env!("PASSWORD") {
     Some(s) => s,
     None => "",
 };
+// "DHT22" selects the DHT22/AM2302 driver, anything else the DHT11 one.
+const DHT_MODEL: &str = match option_env!("DHT_MODEL") {
+    Some(s) => s,
+    None => "DHT11",
+};
+// Good DHT samples to take the median of, and failed reads tolerated per wake.
+const DHT_SAMPLES: u8 = 3;
+const DHT_RETRIES: u8 = 3;
+// Resolution DS18B20 probes on the 1-Wire bus are set to, 9 to 12 bits.
+const DS18B20_RESOLUTION: u8 = 12;
+// "SHT3X" or "SHT4X" when a Sensirion humidity sensor is fitted at 0x44.
+const SHT_MODEL: Option<&str> = option_env!("SHT_MODEL");
+// "SCD40" or "SCD41" when a Sensirion CO2 sensor is fitted on the I2C bus.
+const SCD4X_MODEL: Option<&str> = option_env!("SCD4X_MODEL");
+// Automatic self-calibration needs the sensor to see outdoor air (~400 ppm)
+// at least weekly; set SCD4X_ASC=off where it never does (indoors,
+// greenhouses). The setting is stored in the sensor.
+const SCD4X_ASC: bool = !matches!(option_env!("SCD4X_ASC"), Some("off"));
+// Battery sense divider in front of the ADC pin (GPIO35, see `main`). Equal
+// resistors keep a full 4.2V cell at 2.1V, inside the ADC's linear range.
+const BATTERY_DIVIDER: Divider = Divider { top_ohms: 100_000, bottom_ohms: 100_000 };
+// ADC conversions averaged per battery reading.
+const BATTERY_SAMPLES: usize = 16;
+// Set RAIN_GAUGE to anything when a tipping-bucket gauge is wired to GPIO34
+// (reed switch to GND, 10k pull-up to 3.3V). Without one the pin floats, so
+// it must not be used as a wake-up source.
+const RAIN_GAUGE: bool = option_env!("RAIN_GAUGE").is_some();
+const RAIN_MICROMETRES_PER_TIP: u32 = rain::MICROMETRES_PER_TIP;
+// Time between uploads in seconds, set with SLEEP_INTERVAL_S; rain gauge
+// tips in between only wake the station briefly. SCHEDULE adapts it to the
+// battery and the weather, between a fifth and twelve times as long.
+const SLEEP_INTERVAL_MS: u64 = match option_env!("SLEEP_INTERVAL_S") {
+    Some(s) => match u64::from_str_radix(s, 10) {
+        Ok(seconds) if seconds > 0 => seconds * 1_000,
+        _ => panic!("SLEEP_INTERVAL_S must be a whole number of seconds"),
+    },
+    None => 300_000,
+};
+const SCHEDULE: Policy = Policy::new(SLEEP_INTERVAL_MS);
+// Set REPORT_ON_CHANGE to anything to leave WiFi off on wakes where no value
+// has moved by its delta in REPORT_RULES since the last upload, until the
+// quantity's heartbeat is due. Alerts are always reported.
+const REPORT_ON_CHANGE: bool = option_env!("REPORT_ON_CHANGE").is_some();
+// Deltas in the units of Measurement, set with REPORT_DELTA_TEMP (tenths of a
+// degree), REPORT_DELTA_HUM (tenths of a percent), REPORT_DELTA_PRES (pascals),
+// REPORT_DELTA_CO2 (ppm), REPORT_DELTA_PM25 (µg/m³), REPORT_DELTA_WIND (tenths
+// of a m/s), REPORT_DELTA_BATT (percent) and REPORT_DELTA_RAIN (hundredths of a
+// millimetre), and heartbeats in seconds set with REPORT_HEARTBEAT_TEMP_S and so
+// on, with the same suffixes. Unset ones keep ReportRules::DEFAULT.
+const REPORT_RULES: ReportRules = {
+    let default = ReportRules::DEFAULT;
+    ReportRules {
+        temperature: report_threshold(default.temperature, option_env!("REPORT_DELTA_TEMP"), option_env!("REPORT_HEARTBEAT_TEMP_S")),
+        humidity: report_threshold(default.humidity, option_env!("REPORT_DELTA_HUM"), option_env!("REPORT_HEARTBEAT_HUM_S")),
+        pressure: report_threshold(default.pressure, option_env!("REPORT_DELTA_PRES"), option_env!("REPORT_HEARTBEAT_PRES_S")),
+        co2: report_threshold(default.co2, option_env!("REPORT_DELTA_CO2"), option_env!("REPORT_HEARTBEAT_CO2_S")),
+        pm2_5: report_threshold(default.pm2_5, option_env!("REPORT_DELTA_PM25"), option_env!("REPORT_HEARTBEAT_PM25_S")),
+        wind_speed: report_threshold(default.wind_speed, option_env!("REPORT_DELTA_WIND"), option_env!("REPORT_HEARTBEAT_WIND_S")),
+        battery: report_threshold(default.battery, option_env!("REPORT_DELTA_BATT"), option_env!("REPORT_HEARTBEAT_BATT_S")),
+        rain: report_threshold(default.rain, option_env!("REPORT_DELTA_RAIN"), option_env!("REPORT_HEARTBEAT_RAIN_S")),
+    }
+};
+// How long a DHCP lease is reused across wakes, in seconds, set with
+// DHCP_LEASE_S. The server's lease time is not passed up by the network
+// stack, so this must not be longer than the one the router hands out.
+const DHCP_LEASE_MS: u64 = match option_env!("DHCP_LEASE_S") {
+    Some(s) => match u64::from_str_radix(s, 10) {
+        Ok(seconds) => seconds * 1_000,
+        Err(_) => panic!("DHCP_LEASE_S must be a whole number of seconds"),
+    },
+    None => 3_600_000,
+};
+// Set WIND_SENSORS to anything when an anemometer (reed switch to GND on
+// GPIO27) and a wind vane (GPIO36, 10k pull-up to 3.3V) are fitted.
+const WIND_SENSORS: bool = option_env!("WIND_SENSORS").is_some();
+const ANEMOMETER: Anemometer = Anemometer::SPARKFUN;
+const VANE_TABLE: VaneTable = wind::SPARKFUN_VANE;
+const VANE_PULLUP_OHMS: u32 = 10_000;
+const VANE_SUPPLY_MV: u16 = 3_300;
+// Anemometer pulses are counted over WIND_INTERVALS intervals; the gust is
+// the best GUST_INTERVALS in a row (3s, as the WMO defines it).
+const WIND_INTERVAL_MS: u32 = 1_000;
+const WIND_INTERVALS: usize = 10;
+const GUST_INTERVALS: usize = 3;
+// Set PMS_SENSOR to anything when a Plantower PMS5003/PMS7003 is wired to
+// UART2 (sensor TX to GPIO16, sensor RX to GPIO17). It is woken at the start
+// of each wake and put back to sleep once read.
+const PMS_SENSOR: bool = option_env!("PMS_SENSOR").is_some();
+// How long to wait for a frame after requesting one.
+const PMS_READ_TIMEOUT_MS: u64 = 2_000;
+// Set GPS_SENSOR to anything when an NMEA GPS module is wired to UART1
+// (module TX to GPIO25, module RX to GPIO26) on a portable station. Each
+// wake waits at most GPS_FIX_TIMEOUT_MS for a fix.
+const GPS_SENSOR: bool = option_env!("GPS_SENSOR").is_some();
+const GPS_FIX_TIMEOUT_MS: u64 = 20_000;
+// Readings that could not be uploaded are kept for later; each batch upload
+// carries as many of them as fit in this many bytes of JSON.
+const BATCH_BUFFER: usize = 2048;
+// Start of the `calib` data partition in partitions.csv, holding the
+// per-sensor calibration table.
+const CALIBRATION_OFFSET: u32 = 0x31_0000;
+// The `log` data partition in partitions.csv; readings the RTC backlog has
+// no room for are moved there, about 70 per 4 KiB sector.
+const LOG_OFFSET: u32 = 0x31_1000;
+const LOG_SIZE: u32 = 0x4_0000;
+// Time server, by name or address, set with NTP_SERVER. The clock is only
+// synced again once its estimated error exceeds CLOCK_MAX_ERROR_MS; when
+// SNTP does not get through, the Date header of the upload's response does.
+const NTP_SERVER: &str = match option_env!("NTP_SERVER") {
+    Some(s) => s,
+    None => "pool.ntp.org",
+};
+const CLOCK_MAX_ERROR_MS: u64 = 1_000;
+const SNTP_TIMEOUT_MS: u64 = 2_000;
 const SERVER_IP: &str = match option_env!("SERVER_IP") {
     Some(s) => s,
     None => "172.20.10.2",
@@ -55,110 +171,586 @@ static STOP_WIFI: AtomicBool = AtomicBool::new(false);
 // Watchdog progress counter - if this doesn't increment, device is hung
 static LAST_PROGRESS: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
 
-use esp_hal::gpio::{DriveMode, Flex, InputConfig};
-use esp_hal::time::Instant;
+use esp_hal::{Async, Blocking};
+use esp_hal::i2c::master::{Config as I2cConfig, I2c};
+use core::cell::RefCell;
+use embedded_hal_bus::i2c::RefCellDevice;
+use esp_hal::gpio::{DriveMode, Flex, Input, InputConfig, Pull};
+use esp_hal::pcnt::{channel::{CtrlMode, EdgeMode}, unit::Unit, Pcnt};
+use esp_hal::peripherals::GPIO34;
+use esp_hal::rmt::{Channel, PulseCode, Rmt, Rx, RxChannelConfig, RxChannelCreator};
+use esp_hal::time::Rate;
+use esp_hal::uart::{Config as UartConfig, Uart};
+use esp_storage::FlashStorage;
+use esp_hal::analog::adc::{Adc, AdcChannel, AdcConfig, AdcPin, Attenuation};
+use esp_hal::efuse::{self, Efuse};
+use esp_hal::peripherals::ADC1;
+use station_core::backlog::{self, Backlog, Sample, BACKLOG_WORDS, SAMPLE_WORDS};
+use station_core::battery::{self, AdcCalibration, Battery, Divider};
+use station_core::bme280::{self, Bme280, Chip};
+use station_core::calibration::{self, Applied, CalibrationTable, Command, TableFull, APPLIED_WORDS};
+use station_core::clock::{Reference, Timestamp, WallClock, CLOCK_WORDS};
+use station_core::dht::{self, Model, Pulse, Reading};
+use station_core::ds18b20::{self, Resolution};
+use station_core::fixed::{Scaled, Tenths};
+use station_core::flash_log::{FlashLog, Position};
+use station_core::http_date;
+use station_core::network::{AccessPoint, Lease, LinkCache, LINK_WORDS};
+use station_core::nmea::{self, Fix, LineReader, Tracker};
+use station_core::onewire::{self, OneWire, OneWireBus, Rom};
+use station_core::plausibility::{ChangeRules, History, Limits, Quality, HISTORY_WORDS};
+use station_core::pms::{self, FrameParser, Particulates};
+use station_core::rain::{self, RainGauge, GAUGE_WORDS};
+use station_core::report::{LastReport, ReportRules, Threshold, REPORT_WORDS};
+use station_core::sampling::{Sampler, SamplingPolicy, Step};
+use station_core::scd4x::{self, Scd4x};
+use station_core::schedule::{self, Conditions, Policy, Trend, TREND_WORDS};
+use station_core::sensor::{self, EnvironmentalSensor, Fault, Measurement, ProbeReading, SensorError, MAX_PROBES};
+use station_core::sht::{self, Sht, Variant};
+use station_core::sntp::{self, Backoff};
+use station_core::wind::{self, Anemometer, VaneTable, Wind};
+
+// Plausibility history from earlier wakes. RTC fast memory survives deep
+// sleep; `History::from_words` discards it after a power cycle.
+#[ram(unstable(rtc_fast, persistent))]
+static mut PLAUSIBILITY_HISTORY: [u32; HISTORY_WORDS] = [0; HISTORY_WORDS];
+
+// Rain gauge tips not yet uploaded, and when the next upload is due on the
+// RTC timer, so a tip wake-up can go back to sleep for the remainder.
+#[ram(unstable(rtc_fast, persistent))]
+static mut RAIN_GAUGE_STATE: [u32; GAUGE_WORDS] = [0; GAUGE_WORDS];
+#[ram(unstable(rtc_fast, persistent))]
+static mut NEXT_UPLOAD_MS: u64 = 0;
+
+// The previous upload's reading, to tell how fast the weather is changing.
+#[ram(unstable(rtc_fast, persistent))]
+static mut TREND: [u32; TREND_WORDS] = [0; TREND_WORDS];
+
+// The values in the last upload the server accepted, for REPORT_ON_CHANGE.
+#[ram(unstable(rtc_fast, persistent))]
+static mut LAST_REPORT: [u32; REPORT_WORDS] = [0; REPORT_WORDS];
+
+// The access point joined last and the DHCP lease it gave, to skip the scan
+// and DHCP on the next wake.
+#[ram(unstable(rtc_fast, persistent))]
+static mut LINK: [u32; LINK_WORDS] = [0; LINK_WORDS];
+
+// The number of the last calibration command from the server that was
+// applied and saved, reported with each upload until the server drops it.
+#[ram(unstable(rtc_fast, persistent))]
+static mut CALIBRATION_APPLIED: [u32; APPLIED_WORDS] = [0; APPLIED_WORDS];
+
+// The wall clock, as the RTC time of the last sync and the Unix time it
+// corresponds to, and the RTC drift measured between syncs.
+#[ram(unstable(rtc_fast, persistent))]
+static mut CLOCK: [u32; CLOCK_WORDS] = [0; CLOCK_WORDS];
+
+// When to try SNTP again after it failed, so a network that blocks it does
+// not cost the reply timeout on every wake.
+#[ram(unstable(rtc_fast, persistent))]
+static mut SNTP_BACKOFF: [u32; sntp::BACKOFF_WORDS] = [0; sntp::BACKOFF_WORDS];
+
+// Readings waiting for the network to come back. Deep sleep powers down RTC
+// slow memory on the ESP32, so this stays in fast memory with the rest.
+#[ram(unstable(rtc_fast, persistent))]
+static mut BACKLOG: [u32; BACKLOG_WORDS] = [0; BACKLOG_WORDS];
+
+/// The I2C bus, shared by every sensor on it.
+type SharedI2c = RefCellDevice<'static, I2c<'static, Blocking>>;
+
+// One RMT memory block on the ESP32; a DHT reply needs about 43 codes.
+const RMT_CODES: usize = 64;
+// With the 80 MHz APB clock divided by 80, one RMT tick is one microsecond.
+const RMT_CLK_DIVIDER: u8 = 80;
+// A line that stays put for 1ms means the sensor has finished its reply.
+const RMT_IDLE_TICKS: u16 = 1000;
+
+/// DHT reader that lets the RMT peripheral time the sensor's reply, so the
+/// bits survive interrupt load from the WiFi stack and the executor keeps
+/// running while the frame comes in.
+struct RmtDht {
+    pin: Flex<'static>,
+    rx: Channel<'static, Async, Rx>,
+    model: Model,
+}
+
+impl RmtDht {
+    async fn read(&mut self) -> Result<Reading, SensorError> {
+        let mut codes = [PulseCode::default(); RMT_CODES];
+        self.pin.set_low();
+        Timer::after(Duration::from_micros(self.model.start_pulse_us() as u64)).await;
+        // `receive` arms the channel immediately, so it is already listening
+        // when the line is released and the sensor answers.
+        let transfer = self.rx.receive(&mut codes);
+        self.pin.set_high();
+        let count = match embassy_time::with_timeout(Duration::from_millis(10), transfer).await {
+            Ok(Ok(count)) => count,
+            Ok(Err(_)) => return Err(SensorError::PinError),
+            Err(_) => return Err(SensorError::NoResponse),
+        };
 
-#[derive(Debug)]
-pub enum SensorError {
-    ChecksumMismatch,
-    Timeout,
-    PinError,
+        let mut pulses = [Pulse::default(); RMT_CODES * 2];
+        let mut len = 0;
+        'codes: for code in codes.iter().take(count) {
+            for (level, micros) in [(code.level1(), code.length1()), (code.level2(), code.length2())] {
+                if micros == 0 {
+                    break 'codes;
+                }
+                pulses[len] = Pulse { high: level == Level::High, micros };
+                len += 1;
+            }
+        }
+        dht::decode_pulses(&pulses[..len]).map(|data| self.model.decode(&data))
+    }
+}
+
+/// The DHT, sampled according to `policy` each time it is polled.
+struct DhtSensor {
+    reader: RmtDht,
+    policy: SamplingPolicy,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Reading {
-    pub humidity: u8,
-    pub temperature: i8,
+impl EnvironmentalSensor for DhtSensor {
+    fn name(&self) -> &'static str {
+        "dht"
+    }
+
+    fn limits(&self) -> Limits {
+        match self.reader.model {
+            Model::Dht11 => Limits::DHT11,
+            Model::Dht22 => Limits::DHT22,
+        }
+    }
+
+    async fn measure(&mut self) -> Result<Measurement, SensorError> {
+        let warm_up_ms = self.policy.warm_up_remaining_ms(embassy_time::Instant::now().as_millis());
+        Timer::after(Duration::from_millis(warm_up_ms as u64)).await;
+        let mut sampler = Sampler::new(self.policy);
+        loop {
+            match sampler.record(self.reader.read().await) {
+                Step::ReadAfter(ms) => Timer::after(Duration::from_millis(ms as u64)).await,
+                Step::Done => break,
+            }
+        }
+        let m = sampler.finish()?;
+        println!("{} Sensor - Temperature: {} °C, humidity: {} %", DHT_MODEL, Tenths(m.temperature as i32), Tenths(m.humidity as i32));
+        Ok(m.into())
+    }
 }
 
-pub struct DHT11 {
-    pub delay: Delay,
+/// BME280/BMP280 on the I2C bus, read in forced mode so it sleeps between polls.
+struct Bme280Sensor {
+    driver: Bme280<SharedI2c>,
+    delay: Delay,
 }
 
-const _ERROR_CHECKSUM: u8 = 254; // Error code indicating checksum mismatch.
-const ERROR_TIMEOUT: u8 = 253; // Error code indicating a timeout occurred during reading.
-const TIMEOUT_DURATION: u64 = 1000; // Duration (in milliseconds) to wait before timing out.
-impl DHT11 {
-    pub fn new(delay: Delay) -> Self {
-        Self { delay }
+impl EnvironmentalSensor for Bme280Sensor {
+    fn name(&self) -> &'static str {
+        "bme280"
     }
 
-    pub fn read(&mut self, pin: &mut Flex) -> Result<Reading, SensorError> {
-        let data = self.read_raw(pin)?;
-        let rh = data[0];
-        let temp_signed = data[2];
-        let temp = {
-            let (signed, magnitude) = convert_signed(temp_signed);
-            let temp_sign = if signed { -1 } else { 1 };
-            temp_sign * magnitude as i8
-        };
+    fn limits(&self) -> Limits {
+        Limits::BME280
+    }
 
-        Ok(Reading {
-            temperature: temp,
-            humidity: rh,
-        })
+    async fn measure(&mut self) -> Result<Measurement, SensorError> {
+        let m = self.driver.measure(&mut self.delay)?;
+        println!("{:?} Sensor - Temperature: {} °C, pressure: {} Pa", self.driver.chip(), Tenths(m.temperature as i32), m.pressure.unwrap_or(0));
+        let mut measurement = Measurement::from(m);
+        if self.driver.chip() == Chip::Bmp280 {
+            measurement.humidity = None;
+        }
+        Ok(measurement)
     }
+}
 
-    fn read_raw(&mut self, pin: &mut Flex) -> Result<[u8; 5], SensorError> {
-        pin.set_output_enable(true);
-        pin.set_low();
-        self.delay.delay_millis(20); 
-        pin.set_high();
-        self.delay.delay_micros(40);
-        pin.set_input_enable(true);
+/// SHT3x/SHT4x on the I2C bus. A near-saturated reading runs the heater after
+/// the measurement so condensation is gone by the next wake.
+struct ShtSensor {
+    driver: Sht<SharedI2c>,
+    delay: Delay,
+}
 
-        let now = Instant::now();
+impl EnvironmentalSensor for ShtSensor {
+    fn name(&self) -> &'static str {
+        "sht"
+    }
 
-        while pin.is_high() {
-            if now.elapsed().as_millis() > TIMEOUT_DURATION {
-                // println!("wait for low timeout.");
-                return Err(SensorError::Timeout);
+    fn limits(&self) -> Limits {
+        Limits::SHT
+    }
+
+    async fn measure(&mut self) -> Result<Measurement, SensorError> {
+        let m = self.driver.measure(&mut self.delay)?;
+        println!("{:?} Sensor - Temperature: {} °C, humidity: {} %", self.driver.variant(), Tenths(m.temperature as i32), Tenths(m.humidity as i32));
+        if m.humidity >= sht::CONDENSATION_HUMIDITY {
+            println!("Humidity near saturation, heating sensor to clear condensation");
+            if let Err(error) = self.driver.heat(&mut self.delay) {
+                println!("Heater failed: {:?}", error);
             }
         }
- 
-        if pin.is_low() {
-            self.delay.delay_micros(80);
-            if pin.is_low() {
-                return Err(SensorError::Timeout);
+        Ok(m.into())
+    }
+}
+
+/// SCD40/SCD41 CO2 sensor on the I2C bus, compensated with the pressure from
+/// a sensor polled before it.
+struct Scd4xSensor {
+    driver: Scd4x<SharedI2c>,
+    delay: Delay,
+    pressure: Option<u32>,
+}
+
+impl EnvironmentalSensor for Scd4xSensor {
+    fn name(&self) -> &'static str {
+        "scd4x"
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::SCD4X
+    }
+
+    fn prepare(&mut self, earlier: &Measurement) {
+        self.pressure = earlier.pressure.filter(|_| earlier.quality.pressure != Some(Quality::Bad));
+    }
+
+    async fn measure(&mut self) -> Result<Measurement, SensorError> {
+        if let Some(pressure) = self.pressure {
+            self.driver.set_ambient_pressure(pressure, &mut self.delay)?;
+        }
+        let m = self.driver.measure(&mut self.delay)?;
+        println!("{:?} Sensor - CO2: {} ppm, temperature: {} °C, humidity: {} %", self.driver.variant(), m.co2, Tenths(m.temperature as i32), Tenths(m.humidity as i32));
+        Ok(m.into())
+    }
+}
+
+/// 1-Wire master that keeps interrupts off for each time slot. A slot is only
+/// a few tens of microseconds, but WiFi interrupts landing in the middle of
+/// one would corrupt the bit.
+struct CriticalOneWire(OneWire<Flex<'static>, Delay>);
+
+impl OneWireBus for CriticalOneWire {
+    fn reset(&mut self) -> Result<bool, SensorError> {
+        critical_section::with(|_| self.0.reset())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), SensorError> {
+        critical_section::with(|_| self.0.write_bit(bit))
+    }
+
+    fn read_bit(&mut self) -> Result<bool, SensorError> {
+        critical_section::with(|_| self.0.read_bit())
+    }
+}
+
+/// DS18B20 probes found on the 1-Wire bus at start-up, converted together and
+/// reported one by one under their ROM codes.
+struct Ds18b20Sensor {
+    bus: CriticalOneWire,
+    probes: heapless::Vec<Rom, MAX_PROBES>,
+    resolution: Resolution,
+}
+
+impl EnvironmentalSensor for Ds18b20Sensor {
+    fn name(&self) -> &'static str {
+        "ds18b20"
+    }
+
+    async fn measure(&mut self) -> Result<Measurement, SensorError> {
+        ds18b20::start_conversion(&mut self.bus)?;
+        Timer::after(Duration::from_millis(self.resolution.conversion_ms() as u64)).await;
+        let mut measurement = Measurement::default();
+        let mut last_error = SensorError::NoResponse;
+        for rom in &self.probes {
+            match ds18b20::read_temperature(&mut self.bus, rom, self.resolution) {
+                Ok(temperature) => {
+                    println!("DS18B20 {} - Temperature: {} °C", rom, Tenths(temperature as i32));
+                    measurement.probes.push(ProbeReading { rom: *rom, temperature }).ok();
+                }
+                Err(error) => {
+                    println!("DS18B20 {} failed: {:?}", rom, error);
+                    measurement.faults.push(Fault { sensor: "ds18b20", error }).ok();
+                    last_error = error;
+                }
             }
         }
-        self.delay.delay_micros(80);
-        let mut buf = [0; 5];
-        for idx in 0..5 {
-            buf[idx] = self.read_byte(pin);
-            if buf[idx] == ERROR_TIMEOUT {
-                return Err(SensorError::Timeout);
+        if measurement.probes.is_empty() {
+            return Err(last_error);
+        }
+        Ok(measurement)
+    }
+}
+
+/// Every sensor driver this firmware knows about. Adding a sensor type means
+/// adding a variant here; which ones a station has is decided at start-up.
+enum StationSensor {
+    Dht(DhtSensor),
+    Bme280(Bme280Sensor),
+    Sht(ShtSensor),
+    Ds18b20(Ds18b20Sensor),
+    Scd4x(Scd4xSensor),
+}
+
+impl EnvironmentalSensor for StationSensor {
+    fn name(&self) -> &'static str {
+        match self {
+            StationSensor::Dht(sensor) => sensor.name(),
+            StationSensor::Bme280(sensor) => sensor.name(),
+            StationSensor::Sht(sensor) => sensor.name(),
+            StationSensor::Ds18b20(sensor) => sensor.name(),
+            StationSensor::Scd4x(sensor) => sensor.name(),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        match self {
+            StationSensor::Dht(sensor) => sensor.limits(),
+            StationSensor::Bme280(sensor) => sensor.limits(),
+            StationSensor::Sht(sensor) => sensor.limits(),
+            StationSensor::Ds18b20(sensor) => sensor.limits(),
+            StationSensor::Scd4x(sensor) => sensor.limits(),
+        }
+    }
+
+    fn prepare(&mut self, earlier: &Measurement) {
+        match self {
+            StationSensor::Dht(sensor) => sensor.prepare(earlier),
+            StationSensor::Bme280(sensor) => sensor.prepare(earlier),
+            StationSensor::Sht(sensor) => sensor.prepare(earlier),
+            StationSensor::Ds18b20(sensor) => sensor.prepare(earlier),
+            StationSensor::Scd4x(sensor) => sensor.prepare(earlier),
+        }
+    }
+
+    async fn measure(&mut self) -> Result<Measurement, SensorError> {
+        match self {
+            StationSensor::Dht(sensor) => sensor.measure().await,
+            StationSensor::Bme280(sensor) => sensor.measure().await,
+            StationSensor::Sht(sensor) => sensor.measure().await,
+            StationSensor::Ds18b20(sensor) => sensor.measure().await,
+            StationSensor::Scd4x(sensor) => sensor.measure().await,
+        }
+    }
+}
+
+const MAX_SENSORS: usize = 8;
+/// The sensors fitted to this station, polled in order; earlier entries win
+/// when two sensors report the same quantity.
+type SensorRegistry = heapless::Vec<StationSensor, MAX_SENSORS>;
+
+/// Averages `BATTERY_SAMPLES` conversions of the battery sense pin and
+/// converts them with the reference voltage factory-calibrated in eFuse.
+fn read_battery<P: AdcChannel>(adc: &mut Adc<'_, ADC1<'_>, Blocking>, pin: &mut AdcPin<P, ADC1<'_>>) -> Battery {
+    Battery::from_millivolts(BATTERY_DIVIDER.battery_mv(read_pin_mv(adc, pin)))
+}
+
+/// Voltage at an ADC1 pin set up with 11dB attenuation, averaged over
+/// `BATTERY_SAMPLES` conversions.
+fn read_pin_mv<P: AdcChannel>(adc: &mut Adc<'_, ADC1<'_>, Blocking>, pin: &mut AdcPin<P, ADC1<'_>>) -> u16 {
+    let vref = battery::decode_vref_efuse(Efuse::read_field_le::<u8>(efuse::ADC_VREF)).unwrap_or(battery::DEFAULT_VREF_MV);
+    let calibration = AdcCalibration::from_vref(vref, battery::Attenuation::Db11);
+    let mut samples = [0u16; BATTERY_SAMPLES];
+    for sample in samples.iter_mut() {
+        *sample = loop {
+            if let Ok(raw) = adc.read_oneshot(pin) {
+                break raw;
+            }
+        };
+    }
+    calibration.millivolts(battery::trimmed_mean(&mut samples))
+}
+
+/// Counts anemometer pulses over `WIND_INTERVALS` intervals. The PCNT unit
+/// counts in hardware, so the executor is free in the meantime.
+async fn sample_wind(unit: &Unit<'static, 0>) -> [u16; WIND_INTERVALS] {
+    let mut counts = [0u16; WIND_INTERVALS];
+    unit.clear();
+    unit.resume();
+    let mut last = unit.value();
+    for count in counts.iter_mut() {
+        Timer::after(Duration::from_millis(WIND_INTERVAL_MS as u64)).await;
+        let value = unit.value();
+        *count = value.wrapping_sub(last) as u16;
+        last = value;
+    }
+    unit.pause();
+    counts
+}
+
+/// Sends a command to the particulate sensor and waits until it is out on
+/// the wire, so a sleep command is not cut off by deep sleep.
+async fn pms_command(uart: &mut Uart<'static, Async>, command: &[u8; 7]) -> Result<(), SensorError> {
+    use embedded_io_async::Write;
+    uart.write_all(command).await.map_err(|_| SensorError::BusError)?;
+    uart.flush().await.map_err(|_| SensorError::BusError)
+}
+
+/// Requests a frame from the particulate sensor in passive mode and returns
+/// the atmospheric concentrations. Frames with a bad checksum are skipped
+/// until `PMS_READ_TIMEOUT_MS` runs out.
+async fn read_particulates(uart: &mut Uart<'static, Async>) -> Result<Particulates, SensorError> {
+    // Drop anything sent before passive mode took effect
+    let mut buf = [0u8; pms::FRAME_LEN];
+    while uart.read_buffered(&mut buf).unwrap_or(0) > 0 {}
+
+    pms_command(uart, &pms::READ_PASSIVE).await?;
+    let mut parser = FrameParser::new();
+    let mut last_error = SensorError::NoResponse;
+    let read = async {
+        loop {
+            let count = uart.read_async(&mut buf).await.map_err(|_| SensorError::BusError)?;
+            for &byte in &buf[..count] {
+                match parser.push(byte) {
+                    Some(Ok(frame)) => return Ok(frame.atmospheric),
+                    Some(Err(error)) => last_error = error,
+                    None => {}
+                }
             }
         }
-        let sum = buf[0]
-            .wrapping_add(buf[1])
-            .wrapping_add(buf[2])
-            .wrapping_add(buf[3]);
+    };
+    match embassy_time::with_timeout(Duration::from_millis(PMS_READ_TIMEOUT_MS), read).await {
+        Ok(result) => result,
+        Err(_) => Err(last_error),
+    }
+}
 
-        if buf[4] == (sum & 0xFF) {
-            return Ok(buf); // Success
-        } else {
-            return Err(SensorError::ChecksumMismatch);
+/// Reads NMEA sentences until the GPS module reports a fix with a date, or
+/// `GPS_FIX_TIMEOUT_MS` runs out. Returns the best fix seen, or `NoResponse`
+/// if not a single valid sentence arrived.
+async fn read_gps_fix(uart: &mut Uart<'static, Async>) -> Result<Option<Fix>, SensorError> {
+    // Sentences queued up while other sensors were read are stale
+    let mut buf = [0u8; 64];
+    while uart.read_buffered(&mut buf).unwrap_or(0) > 0 {}
+
+    let mut reader = LineReader::new();
+    let mut tracker = Tracker::new();
+    let mut heard = false;
+    let read = async {
+        loop {
+            let count = uart.read_async(&mut buf).await.map_err(|_| SensorError::BusError)?;
+            for &byte in &buf[..count] {
+                let Some(line) = reader.push(byte) else { continue };
+                match nmea::parse(line) {
+                    Ok(sentence) => {
+                        heard = true;
+                        tracker.update(&sentence);
+                    }
+                    Err(nmea::NmeaError::Unsupported) => heard = true,
+                    Err(_) => {}
+                }
+                if tracker.fix().is_some_and(|fix| fix.date.is_some()) {
+                    return Ok(());
+                }
+            }
         }
+    };
+    if let Ok(Err(error)) = embassy_time::with_timeout(Duration::from_millis(GPS_FIX_TIMEOUT_MS), read).await {
+        return Err(error);
     }
-    fn read_byte(&mut self, pin: &mut Flex) -> u8 {
-        let mut buf = 0u8;
-        for idx in 0..8u8 {
-            while pin.is_low() {}
-            self.delay.delay_micros(30); 
-            if pin.is_high() {
-                buf |= 1 << (7 - idx);
+    if !heard {
+        return Err(SensorError::NoResponse);
+    }
+    Ok(tracker.fix())
+}
+
+fn load_rain_gauge() -> RainGauge {
+    RainGauge::from_words(unsafe { &*(&raw const RAIN_GAUGE_STATE) })
+}
+
+fn store_rain_gauge(gauge: &RainGauge) {
+    unsafe { (&raw mut RAIN_GAUGE_STATE).write(gauge.to_words()) };
+}
+
+fn load_backlog() -> Backlog {
+    // Names the power-on if RTC memory lost the backlog, so samples from
+    // before a power loss can be told apart from the ones after it
+    let boot = Rng::new().random();
+    Backlog::from_words(unsafe { &*(&raw const BACKLOG) }, boot)
+}
+
+fn store_backlog(backlog: &Backlog) {
+    unsafe { (&raw mut BACKLOG).write(backlog.to_words()) };
+}
+
+fn load_link() -> LinkCache {
+    LinkCache::from_words(unsafe { &*(&raw const LINK) })
+}
+
+fn remember_access_point(access_point: Option<AccessPoint>) {
+    let link = LinkCache { access_point, ..load_link() };
+    unsafe { (&raw mut LINK).write(link.to_words()) };
+}
+
+fn remember_lease(lease: Option<Lease>) {
+    let link = LinkCache { lease, ..load_link() };
+    unsafe { (&raw mut LINK).write(link.to_words()) };
+}
+
+fn load_clock() -> WallClock {
+    WallClock::from_words(unsafe { &*(&raw const CLOCK) })
+}
+
+fn store_clock(clock: &WallClock) {
+    unsafe { (&raw mut CLOCK).write(clock.to_words()) };
+}
+
+/// Logs how long into the wake `event` happened, to compare reconnect paths.
+fn log_timing(event: &str) {
+    println!("[TIMING] {}: {} ms after wake", event, Instant::now().as_millis());
+}
+
+/// Deep sleeps until the next upload is due, or until the rain gauge tips.
+fn sleep_until_next_upload(rtc: &mut Rtc<'_>, mut rain_pin: GPIO34<'static>) -> ! {
+    let delay = Delay::new();
+    let now = rtc.time_since_boot().as_millis();
+    let remaining = unsafe { (&raw const NEXT_UPLOAD_MS).read() }.saturating_sub(now).min(SCHEDULE.max_interval_ms);
+    let timer = TimerWakeupSource::new(core::time::Duration::from_millis(remaining));
+
+    // Arming the gauge while its switch is still closed would wake the
+    // station again straight away, so wait for the bucket to settle first
+    let mut released = false;
+    if RAIN_GAUGE {
+        let input = Input::new(rain_pin.reborrow(), InputConfig::default());
+        for _ in 0..100 {
+            if input.is_high() {
+                released = true;
+                break;
             }
-            while pin.is_high() {}
+            delay.delay_millis(10);
         }
-        buf
+    }
+
+    log_timing("Awake");
+    println!("Entering deep sleep for {} ms...", remaining);
+    delay.delay_millis(100); // Give time to println flush to UART
+    // The sleep_deep call should not return - it will reset the device
+    if released {
+        let rain_wakeup = Ext0WakeupSource::new(rain_pin, WakeupLevel::Low);
+        rtc.sleep_deep(&[&timer, &rain_wakeup]);
+    } else {
+        rtc.sleep_deep(&[&timer]);
     }
 }
 
-fn convert_signed(signed: u8) -> (bool, u8) {
-    let sign = signed & 0x80 != 0;
-    let magnitude = signed & 0x7F;
-    (sign, magnitude)
+/// `default` with the delta from a REPORT_DELTA_* variable and the heartbeat
+/// from a REPORT_HEARTBEAT_*_S one, where set.
+const fn report_threshold(default: Threshold, delta: Option<&str>, heartbeat_s: Option<&str>) -> Threshold {
+    Threshold {
+        delta: match delta {
+            Some(s) => match u32::from_str_radix(s, 10) {
+                Ok(delta) => delta,
+                Err(_) => panic!("REPORT_DELTA_* must be a whole number"),
+            },
+            None => default.delta,
+        },
+        heartbeat_ms: match heartbeat_s {
+            Some(s) => match u64::from_str_radix(s, 10) {
+                Ok(seconds) if seconds > 0 => seconds * 1_000,
+                _ => panic!("REPORT_HEARTBEAT_*_S must be a whole number of seconds"),
+            },
+            None => default.heartbeat_ms,
+        },
+    }
 }
 
 // Helper function to parse IP address string (e.g., "192.168.1.1" -> Ipv4Addr)
@@ -192,9 +784,18 @@ fn parse_ipv4(ip_str: &str) -> Ipv4Addr {
 async fn main(spawner: Spawner) -> ! {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
-    let mut temperature = 8;
-    let mut humidity = 8;
-    let mut dht11_pin = Flex::new(peripherals.GPIO2);
+    let mut rtc = Rtc::new(peripherals.LPWR);
+    let mut rain_gauge = load_rain_gauge();
+
+    // A rain gauge tip only needs counting; skip sensors and WiFi entirely
+    if RAIN_GAUGE && wakeup_cause() == SleepSource::Ext0 {
+        let counted = rain_gauge.record_tip(rtc.time_since_boot().as_millis());
+        store_rain_gauge(&rain_gauge);
+        println!("Rain gauge tip {}", if counted { "counted" } else { "ignored as bounce" });
+        sleep_until_next_upload(&mut rtc, peripherals.GPIO34);
+    }
+
+    let mut dht_pin = Flex::new(peripherals.GPIO2);
     let timg0 = TimerGroup::new(peripherals.TIMG0);
 
 
@@ -208,20 +809,256 @@ async fn main(spawner: Spawner) -> ! {
 
 
 
+    // Start the particulate sensor's fan first so it spins up while the
+    // other sensors are read
+    let mut pms_uart = None;
+    if PMS_SENSOR {
+        let uart = Uart::new(peripherals.UART2, UartConfig::default().with_baudrate(9_600))
+            .unwrap()
+            .with_rx(peripherals.GPIO16)
+            .with_tx(peripherals.GPIO17)
+            .into_async();
+        pms_uart = Some(uart);
+    }
+    let pms_woken_at = embassy_time::Instant::now();
+    if let Some(uart) = pms_uart.as_mut() {
+        if let Err(error) = pms_command(uart, &pms::WAKE).await {
+            println!("Could not wake PMS: {:?}", error);
+        }
+        pms_command(uart, &pms::PASSIVE_MODE).await.ok();
+    }
+
     let delay = Delay::new();
-    let mut dht11 = DHT11::new(delay);
     let out_config = OutputConfig::default().with_drive_mode(DriveMode::OpenDrain);
-    dht11_pin.apply_output_config(&out_config);
+    dht_pin.apply_output_config(&out_config);
     let input_config = InputConfig::default();
-    dht11_pin.apply_input_config(&input_config);
+    dht_pin.apply_input_config(&input_config);
+    dht_pin.set_high(); // Release the bus before driving it
+    dht_pin.set_output_enable(true);
+    dht_pin.set_input_enable(true);
+
+    let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(80)).unwrap().into_async();
+    let rx_config = RxChannelConfig::default()
+        .with_clk_divider(RMT_CLK_DIVIDER)
+        .with_idle_threshold(RMT_IDLE_TICKS);
+    let rx = rmt.channel0.configure_rx(dht_pin.peripheral_input(), rx_config).unwrap();
+    let model = if DHT_MODEL == "DHT22" { Model::Dht22 } else { Model::Dht11 };
+    let policy = SamplingPolicy {
+        samples: DHT_SAMPLES,
+        retries: DHT_RETRIES,
+        ..SamplingPolicy::for_model(model)
+    };
+    let mut sensors = SensorRegistry::new();
+
+    let i2c = I2c::new(peripherals.I2C0, I2cConfig::default())
+        .unwrap()
+        .with_sda(peripherals.GPIO21)
+        .with_scl(peripherals.GPIO22);
+    let i2c_bus = &*mk_static!(RefCell<I2c<'static, Blocking>>, RefCell::new(i2c));
+
+    // A Sensirion sensor is more accurate than the DHT, so it goes first and
+    // its values win
+    let sht_variant = match SHT_MODEL {
+        Some("SHT3X") => Some(Variant::Sht3x),
+        Some("SHT4X") => Some(Variant::Sht4x),
+        _ => None,
+    };
+    if let Some(variant) = sht_variant {
+        let driver = Sht::new(RefCellDevice::new(i2c_bus), sht::DEFAULT_ADDRESS, variant);
+        sensors.push(StationSensor::Sht(ShtSensor { driver, delay: Delay::new() })).ok();
+    }
 
-    match dht11.read(&mut dht11_pin) {
-        Ok(m) => {
-            temperature = m.temperature;
-            humidity = m.humidity;
-            println!("DHT 11 Sensor - Temperature: {} °C, humidity: {} %", m.temperature, m.humidity);
-        },
-        Err(error) => println!("An error occurred while trying to read sensor: {:?}", error),
+    sensors.push(StationSensor::Dht(DhtSensor { reader: RmtDht { pin: dht_pin, rx, model }, policy })).ok();
+
+    // The BME280 is optional; probe for it on the default ESP32 I2C pins
+    let mut bme_delay = Delay::new();
+    match Bme280::new(RefCellDevice::new(i2c_bus), bme280::PRIMARY_ADDRESS, &mut bme_delay) {
+        Ok(driver) => {
+            println!("Found {:?} at {:#x}", driver.chip(), bme280::PRIMARY_ADDRESS);
+            sensors.push(StationSensor::Bme280(Bme280Sensor { driver, delay: bme_delay })).ok();
+        }
+        Err(error) => println!("No BME280 detected: {:?}", error),
+    }
+
+    // The CO2 sensor comes after the BME280 so it can be compensated with
+    // its pressure; its own temperature and humidity only fill gaps since
+    // the sensor warms itself while measuring
+    let scd4x_variant = match SCD4X_MODEL {
+        Some("SCD40") => Some(scd4x::Variant::Scd40),
+        Some("SCD41") => Some(scd4x::Variant::Scd41),
+        _ => None,
+    };
+    if let Some(variant) = scd4x_variant {
+        let mut driver = Scd4x::new(RefCellDevice::new(i2c_bus), variant);
+        let mut scd_delay = Delay::new();
+        match driver.set_automatic_self_calibration(SCD4X_ASC, &mut scd_delay) {
+            Ok(true) => println!("SCD4x self-calibration {} and saved", if SCD4X_ASC { "enabled" } else { "disabled" }),
+            Ok(false) => {}
+            Err(error) => println!("Could not configure SCD4x self-calibration: {:?}", error),
+        }
+        sensors.push(StationSensor::Scd4x(Scd4xSensor { driver, delay: scd_delay, pressure: None })).ok();
+    }
+
+    // External DS18B20 probes share one open-drain 1-Wire line on GPIO4,
+    // pulled up to 3.3V with 4.7k
+    let mut onewire_pin = Flex::new(peripherals.GPIO4);
+    onewire_pin.apply_output_config(&out_config);
+    onewire_pin.apply_input_config(&input_config);
+    onewire_pin.set_high();
+    onewire_pin.set_output_enable(true);
+    onewire_pin.set_input_enable(true);
+    let mut onewire_bus = CriticalOneWire(OneWire::new(onewire_pin, Delay::new()));
+    let resolution = Resolution::from_bits(DS18B20_RESOLUTION).unwrap_or(Resolution::Bits12);
+    match onewire::search_all::<_, MAX_PROBES>(&mut onewire_bus) {
+        Ok(roms) => {
+            let mut probes = heapless::Vec::new();
+            for rom in roms.into_iter().filter(|rom| rom.family() == ds18b20::FAMILY_CODE) {
+                println!("Found DS18B20 {}", rom);
+                if let Err(error) = ds18b20::set_resolution(&mut onewire_bus, &rom, resolution) {
+                    println!("Could not set resolution of {}: {:?}", rom, error);
+                }
+                probes.push(rom).ok();
+            }
+            if !probes.is_empty() {
+                sensors.push(StationSensor::Ds18b20(Ds18b20Sensor { bus: onewire_bus, probes, resolution })).ok();
+            }
+        }
+        Err(error) => println!("1-Wire search failed: {:?}", error),
+    }
+
+    // Corrections for this station's sensors, set from the server
+    let mut flash = FlashStorage::new();
+    let mut calibration_table = CalibrationTable::load(&mut flash, CALIBRATION_OFFSET).unwrap_or_default();
+    println!("Calibration: {} corrections", calibration_table.entries().len());
+    let mut flash_log = match FlashLog::<SAMPLE_WORDS>::mount(&mut flash, LOG_OFFSET, LOG_SIZE) {
+        Ok(log) => Some(log),
+        Err(error) => {
+            println!("✗ Could not read flash log: {:?}", error);
+            None
+        }
+    };
+
+    let mut measurement = sensor::poll_all(&mut sensors, &calibration_table).await;
+    for fault in &measurement.faults {
+        println!("✗ Sensor {} failed: {:?}", fault.sensor, fault.error);
+    }
+
+    // Battery voltage is read before WiFi starts drawing current, which
+    // would pull it down. Any ADC1 pin (GPIO32-39) works; ADC2 is taken by WiFi.
+    let mut adc_config = AdcConfig::new();
+    let mut battery_pin = adc_config.enable_pin(peripherals.GPIO35, Attenuation::_11dB);
+    let mut vane_pin = adc_config.enable_pin(peripherals.GPIO36, Attenuation::_11dB);
+    let mut adc = Adc::new(peripherals.ADC1, adc_config);
+    let battery = read_battery(&mut adc, &mut battery_pin);
+    println!("Battery: {} mV ({}%)", battery.millivolts, battery.percent);
+    measurement.battery = Some(battery);
+
+    if WIND_SENSORS {
+        // Count falling edges of the anemometer's reed switch; the glitch
+        // filter drops the shortest contact bounces
+        let pcnt = Pcnt::new(peripherals.PCNT);
+        let anemometer_pin = Input::new(peripherals.GPIO27, InputConfig::default().with_pull(Pull::Up));
+        let unit = pcnt.unit0;
+        unit.set_filter(Some(1023)).ok();
+        let channel = &unit.channel0;
+        channel.set_edge_signal(anemometer_pin.peripheral_input());
+        channel.set_ctrl_mode(CtrlMode::Keep, CtrlMode::Keep);
+        channel.set_input_mode(EdgeMode::Increment, EdgeMode::Hold);
+        let counts = sample_wind(&unit).await;
+        let mut wind = Wind::from_counts(&counts, WIND_INTERVAL_MS, GUST_INTERVALS, &ANEMOMETER);
+
+        let vane_mv = read_pin_mv(&mut adc, &mut vane_pin);
+        wind.direction = wind::vane_resistance(vane_mv, VANE_SUPPLY_MV, VANE_PULLUP_OHMS)
+            .and_then(|ohms| wind::direction(ohms, &VANE_TABLE));
+        match wind.direction {
+            Some(direction) => println!("Wind: {} m/s, gust {} m/s from {}", Tenths(wind.speed as i32), Tenths(wind.gust as i32), direction.cardinal()),
+            None => println!("Wind: {} m/s, gust {} m/s, vane unreadable at {} mV", Tenths(wind.speed as i32), Tenths(wind.gust as i32), vane_mv),
+        }
+        measurement.wind = Some(wind);
+    }
+
+    // The particulate sensor keeps warming up while the GPS looks for a fix
+    if GPS_SENSOR {
+        let mut gps_uart = Uart::new(peripherals.UART1, UartConfig::default().with_baudrate(9_600))
+            .unwrap()
+            .with_rx(peripherals.GPIO25)
+            .with_tx(peripherals.GPIO26)
+            .into_async();
+        match read_gps_fix(&mut gps_uart).await {
+            Ok(Some(fix)) => {
+                println!("GPS fix ({}, {} satellites): {}, {}", fix.quality.code(), fix.satellites, Scaled { value: fix.position.latitude as i64, places: 7 }, Scaled { value: fix.position.longitude as i64, places: 7 });
+                measurement.location = Some(fix);
+            }
+            Ok(None) => println!("GPS: no fix within {} ms", GPS_FIX_TIMEOUT_MS),
+            Err(error) => {
+                println!("✗ Sensor gps failed: {:?}", error);
+                measurement.faults.push(Fault { sensor: "gps", error }).ok();
+            }
+        }
+    }
+
+    if let Some(uart) = pms_uart.as_mut() {
+        let warm_up = Duration::from_millis(pms::WAKE_UP_MS as u64);
+        let elapsed = pms_woken_at.elapsed();
+        if elapsed < warm_up {
+            Timer::after(warm_up - elapsed).await;
+        }
+        match read_particulates(uart).await {
+            Ok(pm) => {
+                println!("PMS - PM1.0: {} µg/m³, PM2.5: {} µg/m³, PM10: {} µg/m³, AQI {}", pm.pm1_0, pm.pm2_5, pm.pm10, pm.aqi());
+                measurement.particulates = Some(pm);
+            }
+            Err(error) => {
+                println!("✗ Sensor pms failed: {:?}", error);
+                measurement.faults.push(Fault { sensor: "pms", error }).ok();
+            }
+        }
+        // The fan draws around 60mA, so it only runs while the station is awake
+        if let Err(error) = pms_command(uart, &pms::SLEEP).await {
+            println!("Could not put PMS to sleep: {:?}", error);
+        }
+    }
+
+    // The RTC timer keeps counting through deep sleep, so it times the gap
+    // between wakes for the rate-of-change check, and dates backlogged readings
+    let taken_ms = rtc.time_since_boot().as_millis();
+    let mut clock = load_clock();
+    measurement.timestamp = clock.now(taken_ms);
+    let mut history = History::from_words(unsafe { &*(&raw const PLAUSIBILITY_HISTORY) });
+    history.assess(&mut measurement, taken_ms, &ChangeRules::DEFAULT);
+    unsafe { (&raw mut PLAUSIBILITY_HISTORY).write(history.to_words()) };
+    println!("Quality: {:?}", measurement.quality);
+
+    let rainfall = rain_gauge.report(rtc.time_since_boot().as_millis(), RAIN_MICROMETRES_PER_TIP);
+    if RAIN_GAUGE {
+        println!("Rain: {} tips since last upload", rainfall.tips);
+        measurement.rain = Some(rainfall);
+    }
+
+    let mut trend = Trend::from_words(unsafe { &*(&raw const TREND) });
+    let change = trend.update(&measurement, taken_ms, &SCHEDULE);
+    unsafe { (&raw mut TREND).write(trend.to_words()) };
+    let conditions = Conditions {
+        battery_percent: measurement.battery.map(|battery| battery.percent),
+        change,
+        alert: SCHEDULE.alerts.triggered(&measurement),
+    };
+    let decision = schedule::next_interval(&SCHEDULE, &conditions);
+    println!("Next upload in {} s ({:?})", decision.interval_ms / 1_000, decision.reason);
+
+    let mut last_report = LastReport::from_words(unsafe { &*(&raw const LAST_REPORT) });
+    if REPORT_ON_CHANGE && !conditions.alert {
+        match last_report.due(&measurement, taken_ms, &REPORT_RULES) {
+            Some(trigger) => println!("Reporting: {:?}", trigger),
+            None => {
+                // Rain tips stay in the gauge and the backlog in RTC memory
+                // until the next upload, so nothing is lost by skipping WiFi
+                println!("No change worth reporting, WiFi stays off");
+                let next_upload = rtc.time_since_boot().as_millis() + decision.interval_ms;
+                unsafe { (&raw mut NEXT_UPLOAD_MS).write(next_upload) };
+                sleep_until_next_upload(&mut rtc, peripherals.GPIO34);
+            }
+        }
     }
     delay.delay_millis(500);
 
@@ -237,41 +1074,101 @@ async fn main(spawner: Spawner) -> ! {
     println!("[MAIN] Watchdog spawned");
 
     println!("[MAIN] Starting WiFi initialization...");
-    let wifi = Wifi::new(peripherals.WIFI, spawner).await;
+    log_timing("Starting WiFi");
+    let mut wifi = Wifi::new(peripherals.WIFI, spawner, &load_link(), rtc.time_since_boot().as_millis()).await;
     println!("[MAIN] WiFi initialized successfully");
 
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
 
-    // Give the network stack time to stabilize before asking for DHCP
-    println!("Giving network stack time to initialize...");
-    Timer::after(Duration::from_millis(2000)).await;
+    let has_ip = wifi.wait_for_ip(&rtc).await;
+    if !has_ip {
+        println!("⚠ WARNING: No IP address obtained! Device won't be able to send data.");
+    }
 
-    println!("Waiting to get IP address (DHCP)...");
-    let mut ip_timeout = 0;
-    let mut has_ip = false;
-    loop {
-        if let Some(config) = wifi.stack.config_v4() {
-            println!("✓ Got IP: {}", config.address);
-            has_ip = true;
-            break;
+    let now_ms = rtc.time_since_boot().as_millis();
+    if has_ip && clock.needs_sync(now_ms, CLOCK_MAX_ERROR_MS) {
+        let mut backoff = Backoff::from_words(unsafe { &*(&raw const SNTP_BACKOFF) });
+        if !backoff.may_try(now_ms) {
+            println!("SNTP failed recently, leaving the clock to the upload's Date header");
+        } else if sync_clock(wifi.stack, &rtc, &mut clock).await {
+            backoff.succeeded();
+            store_clock(&clock);
+            measurement.timestamp = clock.now(taken_ms);
+        } else {
+            backoff.failed(rtc.time_since_boot().as_millis());
+        }
+        unsafe { (&raw mut SNTP_BACKOFF).write(backoff.to_words()) };
+    } else if let (Some(now), Some(error)) = (clock.now(now_ms), clock.error_ms(now_ms)) {
+        println!("Clock: {} (±{} ms, no sync needed)", Timestamp(now), error);
+    }
+
+    // Older readings go first so the server receives them in order: those
+    // in flash were spilled from the RTC backlog, so they come before it
+    let mut backlog = load_backlog();
+    let mut flushed = true;
+    if let Some(log) = flash_log.as_mut().filter(|_| has_ip) {
+        flushed = flush_flash_log(wifi.stack, &mut rx_buffer, &mut tx_buffer, &mut flash, log, backlog.boot(), rtc.time_since_boot().as_millis()).await;
+    }
+    if has_ip && flushed && !backlog.is_empty() {
+        println!("Uploading {} backlogged readings...", backlog.len());
+        flush_backlog(wifi.stack, &mut rx_buffer, &mut tx_buffer, &mut backlog, rtc.time_since_boot().as_millis()).await;
+        store_backlog(&backlog);
+    }
+
+    println!("Attempting to send weather data...");
+    let previous_calibration = calibration_table.clone();
+    let applied = Applied::from_words(unsafe { &*(&raw const CALIBRATION_APPLIED) });
+    let mut calibration_id = applied.0;
+    let mut accepted = send_weather_data(wifi.stack, &mut rx_buffer, &mut tx_buffer, &measurement, &mut calibration_table, &mut calibration_id, &rtc, &mut clock).await;
+    if !accepted && wifi.reused_lease {
+        // The router may have handed the address to someone else; a fresh
+        // lease settles it, and the next wake starts from that one
+        println!("✗ Upload failed on the cached lease, falling back to DHCP");
+        remember_lease(None);
+        wifi.reused_lease = false;
+        wifi.stack.set_config_v4(ConfigV4::Dhcp(Default::default()));
+        if wifi.wait_for_ip(&rtc).await {
+            accepted = send_weather_data(wifi.stack, &mut rx_buffer, &mut tx_buffer, &measurement, &mut calibration_table, &mut calibration_id, &rtc, &mut clock).await;
         }
-        Timer::after(Duration::from_millis(500)).await;
-        ip_timeout += 1;
-        if ip_timeout % 4 == 0 {
-            println!("Still waiting for IP... ({} seconds elapsed)", ip_timeout / 2);
+    }
+    log_timing("Upload finished");
+    store_clock(&clock);
+    if !accepted {
+        if let Some(log) = flash_log.as_mut().filter(|_| backlog.is_full()) {
+            match backlog.spill(&mut flash, log) {
+                Ok(moved) => println!("Moved {} backlogged readings to the flash log", moved),
+                Err(error) => println!("✗ Could not write flash log: {:?}", error),
+            }
         }
-        if ip_timeout > 120 {
-            println!("✗ DHCP timeout after 60 seconds! Proceeding without IP...");
-            break;
+        if let Some(sequence) = backlog.record(&measurement, taken_ms) {
+            println!("Reading kept as #{} for a later upload ({} waiting)", sequence, backlog.len());
         }
+        store_backlog(&backlog);
     }
-    
-    if !has_ip {
-        println!("⚠ WARNING: No IP address obtained! Device won't be able to send data.");
+    let mut saved = true;
+    if calibration_table != previous_calibration {
+        // Only rewritten when the server changed something, to spare the flash
+        match calibration_table.store(&mut flash, CALIBRATION_OFFSET) {
+            Ok(()) => println!("Calibration saved ({} corrections)", calibration_table.entries().len()),
+            Err(error) => {
+                println!("✗ Could not save calibration: {:?}", error);
+                saved = false;
+            }
+        }
+    }
+    if saved && calibration_id != applied.0 {
+        // Reported with the next upload; until then the server keeps the
+        // commands, and pushes them again if this is lost
+        unsafe { (&raw mut CALIBRATION_APPLIED).write(Applied(calibration_id).to_words()) };
+    }
+    if accepted {
+        // Keep tips the server has not seen for the next upload
+        rain_gauge.acknowledge(&rainfall);
+        store_rain_gauge(&rain_gauge);
+        last_report.sent(&measurement, taken_ms);
+        unsafe { (&raw mut LAST_REPORT).write(last_report.to_words()) };
     }
-    println!("Attempting to send weather data...");
-    send_weather_data(wifi.stack, &mut rx_buffer, &mut tx_buffer, temperature, humidity).await;
     
     // Signal WiFi connection task to stop before deep sleep
     println!("[MAIN] Signaling WiFi connection task to stop...");
@@ -290,26 +1187,9 @@ async fn main(spawner: Spawner) -> ! {
     println!("[MAIN] Waiting for WiFi and LED tasks to shut down gracefully...");
     delay.delay_millis(1500); // Give tasks time to notice stop signals and exit
     
-    // Initialize RTC and deep sleep
-    println!("Initializing RTC...");
-    let mut rtc = Rtc::new(peripherals.LPWR);
-    delay.delay_millis(50); // Give time to println
-    
-    println!("Creating wakeup source (5 seconds)...");
-    let wakeup_source = TimerWakeupSource::new(core::time::Duration::from_secs(5));
-    delay.delay_millis(50); // Give time to println
-    
-    println!("Entering deep sleep now...");
-    delay.delay_millis(100); // Give time to println flush to UART
-    // The sleep_deep call should not return - it will reset the device
-    rtc.sleep_deep(&[&wakeup_source]);
-    
-    // Fallback - should never reach here (but kept for safety)
-    #[allow(unreachable_code)]
-    {
-        println!("ERROR: Returned from sleep_deep()!");
-        loop {}
-    }
+    let next_upload = rtc.time_since_boot().as_millis() + decision.interval_ms;
+    unsafe { (&raw mut NEXT_UPLOAD_MS).write(next_upload) };
+    sleep_until_next_upload(&mut rtc, peripherals.GPIO34);
 }
 
 #[embassy_executor::task]
@@ -363,10 +1243,12 @@ async fn wifi_watchdog() {
 
 struct Wifi {
     stack: embassy_net::Stack<'static>,
+    // The address comes from a lease cached in RTC memory rather than DHCP
+    reused_lease: bool,
 }
 
 impl Wifi {
-    pub async fn new(peripherals: esp_hal::peripherals::WIFI<'static>, spawner: Spawner) -> Self {
+    pub async fn new(peripherals: esp_hal::peripherals::WIFI<'static>, spawner: Spawner, link: &LinkCache, now_ms: u64) -> Self {
         println!("[WiFi::new] Step 1: Initializing esp_radio...");
         let esp_radio_ctrl = &*mk_static!(Controller<'static>, esp_radio::init().unwrap());
         println!("[WiFi::new] Step 2: Creating WiFi interface...");
@@ -374,7 +1256,18 @@ impl Wifi {
         esp_radio::wifi::new(esp_radio_ctrl, peripherals, Default::default()).unwrap();
         println!("[WiFi::new] Step 3: Setting up network config...");
 
-        let config = embassy_net::Config::dhcpv4(Default::default());
+        let lease = link.lease(now_ms);
+        let config = match lease {
+            Some(lease) => {
+                println!("Reusing lease for {} ({} s left)", lease.address, (lease.expires_ms - now_ms) / 1_000);
+                embassy_net::Config::ipv4_static(StaticConfigV4 {
+                    address: Ipv4Cidr::new(lease.address, lease.prefix_len),
+                    gateway: lease.gateway,
+                    dns_servers: lease.dns_servers.iter().flatten().copied().collect(),
+                })
+            }
+            None => embassy_net::Config::dhcpv4(Default::default()),
+        };
         let interface = interfaces.sta;
 
         let rng = Rng::new();
@@ -384,12 +1277,13 @@ impl Wifi {
         let (stack, runner ) = embassy_net::new(
             interface,
             config,
-            mk_static!(StackResources<3>, StackResources::<3>::new()),
+            // DHCP, DNS, the HTTP client and SNTP
+            mk_static!(StackResources<4>, StackResources::<4>::new()),
             seed,
         );
 
         println!("[WiFi::new] Step 5: Spawning connection and net tasks...");
-        spawner.spawn(connection(controller)).ok();
+        spawner.spawn(connection(controller, link.access_point)).ok();
         spawner.spawn(net_task(runner)).ok();
         println!("[WiFi::new] Step 6: Tasks spawned");
 
@@ -398,11 +1292,12 @@ impl Wifi {
         loop {
             if stack.is_link_up() {
                 println!("Wifi link is up!");
+                log_timing("Link up");
                 break;
             }
-            Timer::after(Duration::from_millis(500)).await;
+            Timer::after(Duration::from_millis(100)).await;
             link_timeout += 1;
-            if link_timeout > 60 {
+            if link_timeout > 300 {
                 println!("⚠ WiFi link timeout after 30 seconds, proceeding anyway...");
                 break;
             }
@@ -412,24 +1307,298 @@ impl Wifi {
 
         Self {
             stack,
+            reused_lease: lease.is_some(),
         }
     }
 
+    /// Waits for an address; a new DHCP lease is remembered for the next
+    /// wakes.
+    async fn wait_for_ip(&self, rtc: &Rtc<'_>) -> bool {
+        if self.reused_lease {
+            return self.stack.config_v4().is_some();
+        }
+
+        // Give the network stack time to stabilize before asking for DHCP
+        println!("Giving network stack time to initialize...");
+        Timer::after(Duration::from_millis(2000)).await;
+
+        println!("Waiting to get IP address (DHCP)...");
+        let mut ip_timeout = 0;
+        loop {
+            if let Some(config) = self.stack.config_v4() {
+                println!("✓ Got IP: {}", config.address);
+                log_timing("DHCP lease");
+                let mut dns_servers = [None; 2];
+                for (slot, server) in dns_servers.iter_mut().zip(&config.dns_servers) {
+                    *slot = Some(*server);
+                }
+                remember_lease(Some(Lease {
+                    address: config.address.address(),
+                    prefix_len: config.address.prefix_len(),
+                    gateway: config.gateway,
+                    dns_servers,
+                    expires_ms: rtc.time_since_boot().as_millis() + DHCP_LEASE_MS,
+                }));
+                return true;
+            }
+            Timer::after(Duration::from_millis(500)).await;
+            ip_timeout += 1;
+            if ip_timeout % 4 == 0 {
+                println!("Still waiting for IP... ({} seconds elapsed)", ip_timeout / 2);
+            }
+            if ip_timeout > 120 {
+                println!("✗ DHCP timeout after 60 seconds! Proceeding without IP...");
+                return false;
+            }
+        }
+    }
 }
 
+/// Sets `clock` from one SNTP exchange with `NTP_SERVER`; returns whether
+/// it succeeded.
+async fn sync_clock(stack: embassy_net::Stack<'static>, rtc: &Rtc<'_>, clock: &mut WallClock) -> bool {
+    let server = match stack.dns_query(NTP_SERVER, DnsQueryType::A).await {
+        Ok(addresses) if !addresses.is_empty() => addresses[0],
+        Ok(_) | Err(_) => {
+            println!("✗ Could not resolve time server {}", NTP_SERVER);
+            return false;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; sntp::PACKET_LEN + 20];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; sntp::PACKET_LEN];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(0) {
+        println!("✗ SNTP bind error: {:?}", e);
+        return false;
+    }
+
+    let rng = Rng::new();
+    let nonce = (rng.random() as u64) << 32 | rng.random() as u64;
+    let sent_ms = rtc.time_since_boot().as_millis();
+    if let Err(e) = socket.send_to(&sntp::request(nonce), (server, sntp::PORT)).await {
+        println!("✗ SNTP send error: {:?}", e);
+        return false;
+    }
+    let mut reply = [0; sntp::PACKET_LEN];
+    let received = embassy_time::with_timeout(Duration::from_millis(SNTP_TIMEOUT_MS), socket.recv_from(&mut reply)).await;
+    let received_ms = rtc.time_since_boot().as_millis();
+    let len = match received {
+        Ok(Ok((len, _))) => len,
+        Ok(Err(e)) => {
+            println!("✗ SNTP receive error: {:?}", e);
+            return false;
+        }
+        Err(_) => {
+            println!("✗ No reply from time server {}", server);
+            return false;
+        }
+    };
+    match sntp::parse(&reply[..len], nonce, sent_ms, received_ms) {
+        Ok(reference) => {
+            adjust_clock(clock, reference, "SNTP");
+            true
+        }
+        Err(error) => {
+            println!("✗ Bad SNTP reply: {:?}", error);
+            false
+        }
+    }
+}
+
+fn adjust_clock(clock: &mut WallClock, reference: Reference, source: &str) {
+    match clock.sync(reference) {
+        Some(offset) => println!("✓ Clock synced from {}: {} ms off, RTC drift {} ppm", source, offset, clock.drift_ppm()),
+        None => println!("✓ Clock set from {}", source),
+    }
+    println!("Clock: {} (±{} ms)", Timestamp(reference.unix_ms), reference.error_ms);
+}
+
+/// Posts the measurement; returns whether the server accepted it. The
+/// upload names the station by its MAC address and reports `calibration_id`,
+/// the last calibration command applied; commands in the reply are applied
+/// to `calibration_table` and `calibration_id` moves on to the last of them.
+/// The response's Date header sets `clock` if it knows the time better.
 async fn send_weather_data(
     stack: embassy_net::Stack<'static>,
     rx_buffer: &mut [u8],
     tx_buffer: &mut [u8],
-    temperature: i8,
-    humidity: u8,
+    measurement: &Measurement,
+    calibration_table: &mut CalibrationTable,
+    calibration_id: &mut u32,
+    rtc: &Rtc<'_>,
+    clock: &mut WallClock,
+) -> bool {
+    // Create JSON data
+    let mut json_buffer = [0; sensor::MAX_JSON_LEN];
+    let Some(json_len) = write_json(&mut json_buffer, measurement) else {
+        // Only a sensor name longer than MAX_SENSOR_NAME gets here; the
+        // reading is kept in the backlog, which does not carry the faults
+        println!("✗ Measurement does not fit in {} bytes of JSON, not sent", json_buffer.len());
+        return false;
+    };
+
+    let mut path = [0; 64];
+    let path_len = {
+        use core::fmt::Write;
+        let [m0, m1, m2, m3, m4, m5] = Efuse::mac_address();
+        let mut writer = ArrayWriter::new(&mut path);
+        write!(
+            writer,
+            "/data?station={:02X}{:02X}{:02X}{:02X}{:02X}{:02X}&cal_ack={}",
+            m0, m1, m2, m3, m4, m5, calibration_id
+        )
+        .unwrap();
+        writer.len()
+    };
+    let path = core::str::from_utf8(&path[..path_len]).unwrap();
+
+    let mut buf = [0; 1024];
+    // Timed from before the connection, which only widens the window the
+    // server's Date falls in
+    let sent_ms = rtc.time_since_boot().as_millis();
+    let Some(len) = http_post(stack, rx_buffer, tx_buffer, path, &json_buffer[..json_len], &mut buf).await else {
+        return false;
+    };
+    let received_ms = rtc.time_since_boot().as_millis();
+    let response = core::str::from_utf8(&buf[..len]).unwrap_or("");
+    if len == buf.len() {
+        // Commands cut off here are not acknowledged, so the server sends them again
+        println!("✗ Response filled the {} byte buffer and may be cut short", buf.len());
+    }
+    if let Some(reference) = http_date::reference(response, sent_ms, received_ms) {
+        // After a good SNTP sync the clock is far better than a date to the second
+        if clock.error_ms(received_ms).is_none_or(|error| reference.error_ms < error) {
+            adjust_clock(clock, reference, "HTTP Date");
+        }
+    }
+    for line in calibration::pushed_commands(response) {
+        match Command::parse(line).map(|command| calibration_table.execute(&command)) {
+            Some(Ok(_)) => println!("Calibration: {}", line),
+            Some(Err(TableFull)) => println!("✗ Calibration table full, ignoring: {}", line),
+            None => println!("✗ Invalid calibration command: {}", line),
+        }
+    }
+    if let Some(id) = calibration::pushed_through(response) {
+        *calibration_id = id;
+    }
+    true
+}
+
+/// Uploads the backlog in batches, oldest first, and removes the readings
+/// the server acknowledges. Stops at the first batch that does not go
+/// through; the rest stays for the next wake.
+async fn flush_backlog(
+    stack: embassy_net::Stack<'static>,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+    backlog: &mut Backlog,
+    now_ms: u64,
 ) {
+    let mut batch = [0; BATCH_BUFFER];
+    while !backlog.is_empty() {
+        let Some((len, last)) = backlog.write_batch(&mut batch, now_ms) else {
+            println!("✗ Backlogged reading does not fit in a batch");
+            break;
+        };
+        let mut buf = [0; 512];
+        let Some(response_len) = http_post(stack, rx_buffer, tx_buffer, "/data/batch", &batch[..len], &mut buf).await else {
+            break;
+        };
+        match backlog::acknowledged(core::str::from_utf8(&buf[..response_len]).unwrap_or(""), last) {
+            Some(ack) => {
+                backlog.acknowledge(ack);
+                println!("✓ Backlog acknowledged up to #{} ({} left)", ack, backlog.len());
+            }
+            _ => {
+                println!("✗ Batch upload was not acknowledged");
+                break;
+            }
+        }
+    }
+}
+
+/// Uploads the readings in the flash log in batches, oldest first, and marks
+/// them consumed once the server acknowledges them. `boot` and `now_ms` are
+/// the current power-on and RTC time; readings in the log may predate a
+/// power loss. Returns whether the log was emptied.
+async fn flush_flash_log(
+    stack: embassy_net::Stack<'static>,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+    flash: &mut FlashStorage,
+    log: &mut FlashLog<SAMPLE_WORDS>,
+    boot: u32,
+    now_ms: u64,
+) -> bool {
+    let mut batch = [0; BATCH_BUFFER];
+    let mut words = [0; SAMPLE_WORDS];
+    loop {
+        let mut samples: heapless::Vec<(Position, Sample), { backlog::CAPACITY }> = heapless::Vec::new();
+        let mut after = None;
+        while !samples.is_full() {
+            match log.read(flash, after, &mut words) {
+                Ok(Some(position)) => {
+                    after = Some(position);
+                    if let Some(sample) = Sample::from_words(&words) {
+                        samples.push((position, sample)).ok();
+                    }
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    println!("✗ Could not read flash log: {:?}", error);
+                    return false;
+                }
+            }
+        }
+        if samples.is_empty() {
+            return true;
+        }
+        // Drops are reported with the RTC backlog's batches
+        let Some((len, last)) = backlog::write_batch(samples.iter().map(|(_, sample)| sample), 0, &mut batch, boot, now_ms) else {
+            println!("✗ Logged reading does not fit in a batch");
+            return false;
+        };
+        println!("Uploading readings from the flash log...");
+        let mut buf = [0; 512];
+        let Some(response_len) = http_post(stack, rx_buffer, tx_buffer, "/data/batch", &batch[..len], &mut buf).await else {
+            return false;
+        };
+        // Sequence numbers start over after a power loss, so one may appear
+        // twice in the log; matching the first at worst sends some again
+        let through = backlog::acknowledged(core::str::from_utf8(&buf[..response_len]).unwrap_or(""), last)
+            .and_then(|ack| samples.iter().find(|(_, sample)| sample.sequence == ack).map(|&(position, _)| (position, ack)));
+        let Some((position, ack)) = through else {
+            println!("✗ Batch upload was not acknowledged");
+            return false;
+        };
+        if let Err(error) = log.consume_through(flash, position) {
+            println!("✗ Could not update flash log: {:?}", error);
+            return false;
+        }
+        println!("✓ Flash log acknowledged up to #{}", ack);
+    }
+}
+
+/// Posts `body` as JSON to `path` on the server and reads the whole
+/// response into `response`. Returns the response length if the server
+/// answered with 200 OK.
+async fn http_post(
+    stack: embassy_net::Stack<'static>,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+    path: &str,
+    body: &[u8],
+    response: &mut [u8],
+) -> Option<usize> {
     // Check if we have an IP before attempting to send
     if let Some(config) = stack.config_v4() {
         println!("Network ready with IP: {}", config.address);
     } else {
         println!("✗ No IP address available - skipping data send");
-        return;
+        return None;
     }
     
     let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
@@ -438,7 +1607,7 @@ async fn send_weather_data(
     // Parse server IP from environment variable
     let server_ip = parse_ipv4(SERVER_IP);
     let remote_endpoint = (server_ip, 5000);
-    println!("Connecting to server at {:?}...", remote_endpoint);
+    println!("Connecting to server at {:?} for {}...", remote_endpoint, path);
     
     match embassy_time::with_timeout(
         embassy_time::Duration::from_secs(5),
@@ -447,47 +1616,55 @@ async fn send_weather_data(
         Ok(Ok(_)) => println!("connected!"),
         Ok(Err(e)) => {
             println!("connect error: {:?}", e);
-            return;
+            return None;
         }
         Err(_) => {
             println!("connection timeout!");
-            return;
+            return None;
         }
     }
     
-    // Create JSON data
-    let mut json_buffer = [0; 64];
-    let json_len = write_json(&mut json_buffer, temperature, humidity);
-    
     use embedded_io_async::Write;
-    let request = post_request_bytes(b"/data", b"weather-station.local", &json_buffer[..json_len]);
+    let mut header = [0; 256];
+    let header_len = post_request_header(&mut header, path, "weather-station.local", body.len());
     
-    if let Err(e) = socket.write_all(&request).await {
+    if let Err(e) = socket.write_all(&header[..header_len]).await {
         println!("write error: {:?}", e);
-        return;
+        return None;
+    }
+    if let Err(e) = socket.write_all(body).await {
+        println!("write error: {:?}", e);
+        return None;
     }
     
-    // Read response
-    let mut buf = [0; 1024];
-    match socket.read(&mut buf).await {
-        Ok(0) => println!("read EOF"),
-        Ok(n) => println!("Response: {}", core::str::from_utf8(&buf[..n]).unwrap()),
-        Err(e) => println!("read error: {:?}", e),
+    // Read the whole response; the server closes the connection after it
+    let mut len = 0;
+    while len < response.len() {
+        match socket.read(&mut response[len..]).await {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) => {
+                println!("read error: {:?}", e);
+                break;
+            }
+        }
     }
+    let text = core::str::from_utf8(&response[..len]).unwrap_or("");
+    println!("Response: {}", text);
+    let accepted = text.starts_with("HTTP/1.0 200") || text.starts_with("HTTP/1.1 200");
     
     // Explicitly close the socket before buffers are reused
     socket.close();
+    accepted.then_some(len)
 }
 
-// Helper function to write JSON data
-fn write_json(buffer: &mut [u8], temperature: i8, humidity: u8) -> usize {
-    use core::fmt::Write;
+// Helper function to write JSON data; `None` if it does not fit
+fn write_json(buffer: &mut [u8], measurement: &Measurement) -> Option<usize> {
     let mut writer = ArrayWriter::new(buffer);
     
-    // Format as simple JSON
-    write!(writer, "{{\"temp\":{:.1},\"hum\":{:.1}}}", temperature, humidity).unwrap();
+    measurement.write_json(&mut writer).ok()?;
     
-    writer.len()
+    Some(writer.len())
 }
 
 // Simple writer for arrays
@@ -519,80 +1696,38 @@ impl<'a> core::fmt::Write for ArrayWriter<'a> {
 }
 
 
-fn post_request_bytes(path: &[u8], host: &[u8], data: &[u8]) -> [u8; 256] {
-    let mut buffer = [0; 256]; // Larger buffer for POST data
-    
-    // Copy the request components into the buffer
-    let post_line = b"POST ";
-    let http_version = b" HTTP/1.0\r\n";
-    let host_header = b"Host: ";
-    let content_type = b"Content-Type: application/json\r\n";
-    let content_length = b"Content-Length: ";
-    let mut binding = itoa::Buffer::new();
-    let length_str = binding.format(data.len()); 
-    
-    let mut pos = 0;
-    
-    // Copy POST line
-    buffer[pos..pos+post_line.len()].copy_from_slice(post_line);
-    pos += post_line.len();
-    
-    // Copy path
-    buffer[pos..pos+path.len()].copy_from_slice(path);
-    pos += path.len();
-    
-    // Copy HTTP version
-    buffer[pos..pos+http_version.len()].copy_from_slice(http_version);
-    pos += http_version.len();
-    
-    // Copy Host header
-    buffer[pos..pos+host_header.len()].copy_from_slice(host_header);
-    pos += host_header.len();
-    
-    // Copy host
-    buffer[pos..pos+host.len()].copy_from_slice(host);
-    pos += host.len();
-    
-    // Copy CRLF
-    buffer[pos..pos+2].copy_from_slice(b"\r\n");
-    pos += 2;
-    
-    // Copy Content-Type
-    buffer[pos..pos+content_type.len()].copy_from_slice(content_type);
-    pos += content_type.len();
-    
-    // Copy Content-Length
-    buffer[pos..pos+content_length.len()].copy_from_slice(content_length);
-    pos += content_length.len();
-    
-    // Copy length as string
-    buffer[pos..pos+length_str.len()].copy_from_slice(length_str.as_bytes());
-    pos += length_str.len();
-    
-    // Copy CRLF twice (end of headers)
-    buffer[pos..pos+4].copy_from_slice(b"\r\n\r\n");
-    pos += 4;
-    
-    // Copy data
-    buffer[pos..pos+data.len()].copy_from_slice(data);
-    
-    buffer
+/// Writes the request line and headers of a JSON POST into `buffer`;
+/// returns their length.
+fn post_request_header(buffer: &mut [u8], path: &str, host: &str, content_length: usize) -> usize {
+    use core::fmt::Write;
+    let mut writer = ArrayWriter::new(buffer);
+    write!(
+        writer,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        path, host, content_length
+    )
+    .unwrap();
+    writer.len()
 }
 
 
 
 
 #[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
+async fn connection(mut controller: WifiController<'static>, mut cached: Option<AccessPoint>) {
     println!("start connection task");
     LAST_PROGRESS.store(1, Ordering::Relaxed);
     println!("Device capabilities: {:?}", controller.capabilities());
     LAST_PROGRESS.store(2, Ordering::Relaxed);
     
-    // Give WiFi hardware time to stabilize after deep sleep reset
-    println!("Waiting for WiFi hardware to stabilize...");
-    Timer::after(Duration::from_secs(2)).await;
-    println!("WiFi hardware ready");
+    // Give WiFi hardware time to stabilize after deep sleep reset; the
+    // cached access point is tried straight away, and only costs the wait
+    // if it fails
+    if cached.is_none() {
+        println!("Waiting for WiFi hardware to stabilize...");
+        Timer::after(Duration::from_secs(2)).await;
+        println!("WiFi hardware ready");
+    }
     LAST_PROGRESS.store(3, Ordering::Relaxed);
     
     loop {
@@ -610,29 +1745,41 @@ async fn connection(mut controller: WifiController<'static>) {
             Timer::after(Duration::from_millis(5000)).await;
         }
         LAST_PROGRESS.store(12, Ordering::Relaxed);
+        // The access point this attempt joins, remembered once it works
+        let mut joining = None;
+        let mut from_cache = false;
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = ModeConfig::Client(
-                ClientConfig::default()
-                    .with_ssid(SSID.into())
-                    .with_password(PASSWORD.into()),
-            );
-            controller.set_config(&client_config).unwrap();
+            let fast = cached.take();
+            controller.set_config(&client_config(fast)).unwrap();
             println!("Starting wifi");
             LAST_PROGRESS.store(13, Ordering::Relaxed);
             controller.start_async().await.unwrap();
             println!("Wifi started!");
             LAST_PROGRESS.store(14, Ordering::Relaxed);
 
-            println!("Scan");
-            let scan_config = ScanConfig::default().with_max(10);
-            LAST_PROGRESS.store(15, Ordering::Relaxed);
-            let result = controller
-                .scan_with_config_async(scan_config)
-                .await
-                .unwrap();
-            LAST_PROGRESS.store(16, Ordering::Relaxed);
-            for ap in result {
-                println!("{:?}", ap);
+            if let Some(ap) = fast {
+                println!("Joining cached access point {:02x?} on channel {}", ap.bssid, ap.channel);
+                joining = Some(ap);
+                from_cache = true;
+            } else {
+                println!("Scan");
+                let scan_config = ScanConfig::default().with_ssid(SSID).with_max(10);
+                LAST_PROGRESS.store(15, Ordering::Relaxed);
+                let result = controller
+                    .scan_with_config_async(scan_config)
+                    .await
+                    .unwrap();
+                LAST_PROGRESS.store(16, Ordering::Relaxed);
+                log_timing("Scan finished");
+                for ap in &result {
+                    println!("{:?}", ap);
+                }
+                // Pin the strongest access point, so the next wake can join it without scanning
+                if let Some(best) = result.iter().max_by_key(|ap| ap.signal_strength) {
+                    let ap = AccessPoint { bssid: best.bssid, channel: best.channel };
+                    controller.set_config(&client_config(Some(ap))).unwrap();
+                    joining = Some(ap);
+                }
             }
         }
 
@@ -640,29 +1787,56 @@ async fn connection(mut controller: WifiController<'static>) {
         LAST_PROGRESS.store(20, Ordering::Relaxed);
         
         // Simple attempt with timeout - watchdog will catch if this hangs
-        match embassy_time::with_timeout(
+        let connected = match embassy_time::with_timeout(
             Duration::from_secs(5),
             controller.connect_async()
         ).await {
             Ok(Ok(_)) => {
                 println!("✓ Wifi connected!");
                 LAST_PROGRESS.store(21, Ordering::Relaxed);
-                Timer::after(Duration::from_millis(2000)).await;
+                log_timing("Associated");
+                if joining.is_some() {
+                    remember_access_point(joining);
+                }
+                true
             }
             Ok(Err(e)) => {
                 println!("✗ Failed to connect: {e:?}");
                 LAST_PROGRESS.store(22, Ordering::Relaxed);
-                Timer::after(Duration::from_millis(2000)).await;
+                false
             }
             Err(_) => {
                 println!("✗ Connection timeout!");
                 LAST_PROGRESS.store(23, Ordering::Relaxed);
-                Timer::after(Duration::from_millis(2000)).await;
+                false
+            }
+        };
+        if !connected && from_cache {
+            // The access point moved channel or is gone; restarting the
+            // radio brings the next attempt back to a full scan
+            println!("Cached access point failed, falling back to a full scan");
+            remember_access_point(None);
+            if let Err(e) = controller.stop_async().await {
+                println!("✗ Failed to stop wifi: {e:?}");
             }
+            continue;
         }
+        Timer::after(Duration::from_millis(2000)).await;
     }
 }
 
+/// Client configuration for the network, pinned to one access point and
+/// channel when it is known.
+fn client_config(access_point: Option<AccessPoint>) -> ModeConfig {
+    let config = ClientConfig::default()
+        .with_ssid(SSID.into())
+        .with_password(PASSWORD.into());
+    ModeConfig::Client(match access_point {
+        Some(ap) => config.with_bssid(ap.bssid).with_channel(ap.channel),
+        None => config,
+    })
+}
+
 #[embassy_executor::task]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     // Give network stack time to stabilize after deep sleep reset
@@ -0,0 +1,27 @@
+//! Feeds arbitrary bytes through the line reader and parser. Run with
+//! `cargo +nightly fuzz run nmea` from `station-core`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use station_core::nmea::{self, LineReader, Tracker};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = nmea::parse(data);
+
+    let mut reader = LineReader::new();
+    let mut tracker = Tracker::new();
+    for &byte in data {
+        if let Some(line) = reader.push(byte) {
+            if let Ok(sentence) = nmea::parse(line) {
+                assert!(nmea::verify(line).is_ok());
+                tracker.update(&sentence);
+            }
+        }
+    }
+    if let Some(fix) = tracker.fix() {
+        assert!(fix.position.latitude.unsigned_abs() <= 900_000_000);
+        assert!(fix.position.longitude.unsigned_abs() <= 1_800_000_000);
+        let _ = fix.unix_time();
+    }
+});
@@ -0,0 +1,667 @@
+//! Readings kept in RTC memory while they cannot be uploaded.
+//!
+//! When DHCP times out or the server is unreachable, the reading of that wake
+//! is recorded here instead of being lost. Each sample gets a sequence number
+//! and the RTC time it was taken; the RTC timer keeps running through deep
+//! sleep, so the server can place a sample in time from its age at upload.
+//! A power loss starts the RTC timer over, so each sample also carries an id
+//! of the power-on it was taken in, and only the ages of samples from the
+//! current one mean anything. Samples taken once the station's clock was set
+//! also keep their timestamp.
+//! Once the station is back online the oldest samples are sent in batches,
+//! and a sample is only removed after the server acknowledged its sequence
+//! number.
+//!
+//! Only the quantities that fit a fixed-size slot are kept: temperature,
+//! humidity, pressure and their grades, CO2, battery, wind and particulates.
+//! Probes, location and faults go out with live uploads only, and rain is
+//! left out because the [`RainGauge`](crate::rain::RainGauge) holds on to
+//! unacknowledged tips itself. When the buffer is full the oldest sample is
+//! dropped, and the number of dropped samples is reported with the next
+//! batch.
+//!
+//! Samples that would be dropped can instead be spilled to a
+//! [`FlashLog`](crate::flash_log::FlashLog) in the same slot format, which
+//! holds days' worth of them; it is drained before the backlog since its
+//! samples are older.
+
+use core::fmt::{self, Write};
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Deque;
+
+use crate::battery::Battery;
+use crate::flash_log::FlashLog;
+use crate::persist;
+use crate::plausibility::Quality;
+use crate::pms::Particulates;
+use crate::sensor::Measurement;
+use crate::wind::{Direction, Wind};
+
+/// Number of samples kept; at one wake every five minutes, over two and a
+/// half hours of outage.
+pub const CAPACITY: usize = 32;
+
+const HEADER_WORDS: usize = 5;
+
+/// Number of words one [`Sample`] occupies, in RTC memory or a flash log.
+pub const SAMPLE_WORDS: usize = 14;
+
+/// Number of words [`Backlog`] occupies in RTC memory.
+pub const BACKLOG_WORDS: usize = HEADER_WORDS + CAPACITY * SAMPLE_WORDS;
+
+const HEADER_MAGIC: u32 = 0x424B_4C32;
+const SAMPLE_MAGIC: u32 = 0x534D_5033;
+
+const HAS_TEMPERATURE: u32 = 1 << 0;
+const HAS_HUMIDITY: u32 = 1 << 1;
+const HAS_PRESSURE: u32 = 1 << 2;
+const HAS_CO2: u32 = 1 << 3;
+const HAS_BATTERY: u32 = 1 << 4;
+const HAS_WIND: u32 = 1 << 5;
+const HAS_DIRECTION: u32 = 1 << 6;
+const HAS_PARTICULATES: u32 = 1 << 7;
+// Two bits of quality per quantity from this bit on, then the vane direction.
+const QUALITY_SHIFT: u32 = 8;
+const DIRECTION_SHIFT: u32 = 16;
+const HAS_TIMESTAMP: u32 = 1 << 24;
+
+/// A reading waiting to be uploaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub sequence: u32,
+    /// RTC time the reading was taken, in milliseconds since power-on.
+    pub taken_ms: u64,
+    /// The power-on `taken_ms` counts from, see [`Backlog::boot`].
+    pub boot: u32,
+    /// The reading, reduced to the quantities a sample keeps.
+    pub measurement: Measurement,
+}
+
+impl Sample {
+    pub fn to_words(&self) -> [u32; SAMPLE_WORDS] {
+        let m = &self.measurement;
+        let mut flags = 0;
+        let mut flag = |present: bool, bit: u32| {
+            if present {
+                flags |= bit;
+            }
+        };
+        flag(m.temperature.is_some(), HAS_TEMPERATURE);
+        flag(m.humidity.is_some(), HAS_HUMIDITY);
+        flag(m.pressure.is_some(), HAS_PRESSURE);
+        flag(m.co2.is_some(), HAS_CO2);
+        flag(m.battery.is_some(), HAS_BATTERY);
+        flag(m.wind.is_some(), HAS_WIND);
+        flag(
+            m.wind.is_some_and(|wind| wind.direction.is_some()),
+            HAS_DIRECTION,
+        );
+        flag(m.particulates.is_some(), HAS_PARTICULATES);
+        flag(m.timestamp.is_some(), HAS_TIMESTAMP);
+        let grades = [
+            m.quality.temperature,
+            m.quality.humidity,
+            m.quality.pressure,
+        ];
+        for (idx, quality) in grades.into_iter().enumerate() {
+            flags |= quality_bits(quality) << (QUALITY_SHIFT + 2 * idx as u32);
+        }
+        let wind = m.wind.unwrap_or(Wind {
+            speed: 0,
+            gust: 0,
+            direction: None,
+        });
+        if let Some(direction) = wind.direction {
+            flags |= (direction.0 as u32) << DIRECTION_SHIFT;
+        }
+        let pm = m.particulates.unwrap_or_default();
+        let [taken_low, taken_high] = persist::split_u64(self.taken_ms);
+        let [time_low, time_high] = persist::split_u64(m.timestamp.unwrap_or(0));
+        let mut words = [
+            self.sequence,
+            taken_low,
+            taken_high,
+            flags,
+            pair(m.temperature.unwrap_or(0) as u16, m.humidity.unwrap_or(0)),
+            m.pressure.unwrap_or(0),
+            pair(
+                m.co2.unwrap_or(0),
+                m.battery.map_or(0, |battery| battery.millivolts),
+            ),
+            pair(wind.speed, wind.gust),
+            pair(pm.pm1_0, pm.pm2_5),
+            pm.pm10 as u32,
+            time_low,
+            time_high,
+            self.boot,
+            0,
+        ];
+        persist::seal(&mut words, SAMPLE_MAGIC);
+        words
+    }
+
+    /// Restores a sample saved by [`Sample::to_words`], or `None` if the
+    /// words fail their check.
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        if !persist::is_sealed(words, SAMPLE_MAGIC) {
+            return None;
+        }
+        let flags = words[3];
+        let has = |bit: u32| flags & bit != 0;
+        let low = |word: u32| word as u16;
+        let high = |word: u32| (word >> 16) as u16;
+        let grade = |idx: u32| quality_from_bits(flags >> (QUALITY_SHIFT + 2 * idx));
+
+        let mut measurement = Measurement {
+            temperature: has(HAS_TEMPERATURE).then_some(low(words[4]) as i16),
+            humidity: has(HAS_HUMIDITY).then_some(high(words[4])),
+            pressure: has(HAS_PRESSURE).then_some(words[5]),
+            co2: has(HAS_CO2).then_some(low(words[6])),
+            battery: has(HAS_BATTERY).then(|| Battery::from_millivolts(high(words[6]))),
+            wind: has(HAS_WIND).then_some(Wind {
+                speed: low(words[7]),
+                gust: high(words[7]),
+                direction: has(HAS_DIRECTION)
+                    .then_some(Direction((flags >> DIRECTION_SHIFT) as u8)),
+            }),
+            particulates: has(HAS_PARTICULATES).then_some(Particulates {
+                pm1_0: low(words[8]),
+                pm2_5: high(words[8]),
+                pm10: low(words[9]),
+            }),
+            timestamp: has(HAS_TIMESTAMP).then(|| persist::join_u64(&words[10..12])),
+            ..Default::default()
+        };
+        measurement.quality.temperature = grade(0);
+        measurement.quality.humidity = grade(1);
+        measurement.quality.pressure = grade(2);
+        Some(Self {
+            sequence: words[0],
+            taken_ms: persist::join_u64(&words[1..3]),
+            boot: words[12],
+            measurement,
+        })
+    }
+}
+
+fn pair(low: u16, high: u16) -> u32 {
+    low as u32 | (high as u32) << 16
+}
+
+fn quality_bits(quality: Option<Quality>) -> u32 {
+    match quality {
+        None => 0,
+        Some(Quality::Good) => 1,
+        Some(Quality::Suspect) => 2,
+        Some(Quality::Bad) => 3,
+    }
+}
+
+fn quality_from_bits(bits: u32) -> Option<Quality> {
+    match bits & 0b11 {
+        1 => Some(Quality::Good),
+        2 => Some(Quality::Suspect),
+        3 => Some(Quality::Bad),
+        _ => None,
+    }
+}
+
+/// Ring buffer of samples not yet acknowledged by the server, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct Backlog {
+    samples: Deque<Sample, CAPACITY>,
+    next_sequence: u32,
+    dropped: u32,
+    boot: u32,
+}
+
+impl Backlog {
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Whether the next [`Backlog::record`] drops the oldest sample.
+    pub fn is_full(&self) -> bool {
+        self.samples.is_full()
+    }
+
+    /// Samples lost to a full buffer since the last acknowledged batch.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Identifies the current power-on. RTC memory does not survive a power
+    /// loss, so a new one is given to [`Backlog::from_words`] each time the
+    /// backlog starts over.
+    pub fn boot(&self) -> u32 {
+        self.boot
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter()
+    }
+
+    /// Keeps the quantities of `measurement` a sample can hold, taken at
+    /// `taken_ms` on the RTC timer, dropping the oldest sample if the buffer
+    /// is full. Returns the sequence number given to the sample, or `None`
+    /// if no sensor delivered a value, which is not worth keeping.
+    pub fn record(&mut self, measurement: &Measurement, taken_ms: u64) -> Option<u32> {
+        let stored = Measurement {
+            temperature: measurement.temperature,
+            humidity: measurement.humidity,
+            pressure: measurement.pressure,
+            co2: measurement.co2,
+            quality: measurement.quality,
+            battery: measurement.battery,
+            wind: measurement.wind,
+            particulates: measurement.particulates,
+            timestamp: measurement.timestamp,
+            ..Default::default()
+        };
+        if stored.is_empty() && stored.wind.is_none() && stored.particulates.is_none() {
+            return None;
+        }
+        if self.samples.is_full() {
+            self.samples.pop_front();
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        let sample = Sample {
+            sequence,
+            taken_ms,
+            boot: self.boot,
+            measurement: stored,
+        };
+        // Cannot fail, a slot was freed above.
+        let _ = self.samples.push_back(sample);
+        Some(sequence)
+    }
+
+    /// Removes every sample up to and including sequence number `through`,
+    /// which the server has stored.
+    pub fn acknowledge(&mut self, through: u32) {
+        let mut removed = false;
+        while self
+            .samples
+            .front()
+            .is_some_and(|sample| sample.sequence <= through)
+        {
+            self.samples.pop_front();
+            removed = true;
+        }
+        // The drop count went out with the batch that was just stored.
+        if removed {
+            self.dropped = 0;
+        }
+    }
+
+    /// Moves every sample to `log`, oldest first, to make room for new
+    /// ones. Samples the log had to give up for them count as dropped.
+    /// Returns the number of samples moved; on a flash error the ones not
+    /// yet written stay here.
+    pub fn spill<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        log: &mut FlashLog<SAMPLE_WORDS>,
+    ) -> Result<usize, F::Error> {
+        let mut moved = 0;
+        while let Some(sample) = self.samples.front() {
+            let lost = log.append(flash, &sample.to_words())?;
+            self.dropped = self.dropped.saturating_add(lost as u32);
+            self.samples.pop_front();
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Serializes as many of the oldest samples as fit into `buffer` as one
+    /// batch upload, see [`write_batch`].
+    pub fn write_batch(&self, buffer: &mut [u8], now_ms: u64) -> Option<(usize, u32)> {
+        write_batch(&self.samples, self.dropped, buffer, self.boot, now_ms)
+    }
+
+    pub fn to_words(&self) -> [u32; BACKLOG_WORDS] {
+        let mut words = [0; BACKLOG_WORDS];
+        let (header, slots) = words.split_at_mut(HEADER_WORDS);
+        header[..4].copy_from_slice(&[
+            self.samples.len() as u32,
+            self.next_sequence,
+            self.dropped,
+            self.boot,
+        ]);
+        persist::seal(header, HEADER_MAGIC);
+        for (slot, sample) in slots.chunks_exact_mut(SAMPLE_WORDS).zip(&self.samples) {
+            slot.copy_from_slice(&sample.to_words());
+        }
+        words
+    }
+
+    /// Restores a backlog saved by [`Backlog::to_words`]. Without a valid
+    /// header it starts out empty, for the power-on identified by `boot`; a
+    /// sample whose slot was torn by a reset during the write is lost, but
+    /// the others are kept.
+    pub fn from_words(words: &[u32; BACKLOG_WORDS], boot: u32) -> Self {
+        let (header, slots) = words.split_at(HEADER_WORDS);
+        if !persist::is_sealed(header, HEADER_MAGIC) {
+            return Self {
+                boot,
+                ..Self::default()
+            };
+        }
+        let mut backlog = Self {
+            samples: Deque::new(),
+            next_sequence: header[1],
+            dropped: header[2],
+            boot: header[3],
+        };
+        let len = (header[0] as usize).min(CAPACITY);
+        for slot in slots.chunks_exact(SAMPLE_WORDS).take(len) {
+            if let Some(sample) = Sample::from_words(slot) {
+                let _ = backlog.samples.push_back(sample);
+            }
+        }
+        backlog
+    }
+}
+
+// `Deque` does not implement `PartialEq`.
+impl PartialEq for Backlog {
+    fn eq(&self, other: &Self) -> bool {
+        self.next_sequence == other.next_sequence
+            && self.dropped == other.dropped
+            && self.boot == other.boot
+            && self.samples.iter().eq(other.samples.iter())
+    }
+}
+
+impl Eq for Backlog {}
+
+/// Serializes as many of `samples` as fit into `buffer` as one batch
+/// upload, along with the number of samples `dropped` before them. `now_ms`
+/// is the current RTC time of power-on `boot`, so the server can turn the
+/// `ts` of each sample from that power-on into an age; the others can only
+/// be placed by their `time`. Returns the length written and the sequence
+/// number of the last sample included, or `None` if there are no samples or
+/// not even one fits.
+///
+/// ```json
+/// {"boot":7,"now":615000,"dropped":0,"samples":[{"seq":4,"boot":7,"ts":15000,"reading":{...}}]}
+/// ```
+pub fn write_batch<'a>(
+    samples: impl IntoIterator<Item = &'a Sample>,
+    dropped: u32,
+    buffer: &mut [u8],
+    boot: u32,
+    now_ms: u64,
+) -> Option<(usize, u32)> {
+    let mut w = SliceWriter { buffer, len: 0 };
+    write!(
+        w,
+        "{{\"boot\":{},\"now\":{},\"dropped\":{},\"samples\":[",
+        boot, now_ms, dropped
+    )
+    .ok()?;
+    let mut last = None;
+    for sample in samples {
+        let start = w.len;
+        let written = (|| {
+            if last.is_some() {
+                w.write_str(",")?;
+            }
+            write!(
+                w,
+                "{{\"seq\":{},\"boot\":{},\"ts\":{},\"reading\":",
+                sample.sequence, sample.boot, sample.taken_ms
+            )?;
+            sample.measurement.write_json(&mut w)?;
+            // Room for the closing brackets of the batch.
+            w.write_str("}")?;
+            if w.buffer.len() - w.len < 2 {
+                return Err(fmt::Error);
+            }
+            Ok(())
+        })();
+        if written.is_err() {
+            w.len = start;
+            break;
+        }
+        last = Some(sample.sequence);
+    }
+    let last = last?;
+    w.write_str("]}").ok()?;
+    Some((w.len, last))
+}
+
+/// Reads the sequence number the server acknowledged from its reply to a
+/// batch upload, e.g. `{"status":"success","ack":17}`. `last` is the newest
+/// sequence number in the batch; an acknowledgement beyond it would drop
+/// readings the server never saw, so it counts as none.
+pub fn acknowledged(reply: &str, last: u32) -> Option<u32> {
+    const KEY: &str = "\"ack\"";
+    let rest = reply[reply.find(KEY)? + KEY.len()..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok().filter(|&ack| ack <= last)
+}
+
+/// `fmt::Write` into a byte slice, failing instead of truncating.
+struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Flash;
+    use crate::onewire::Rom;
+    use crate::sensor::ProbeReading;
+
+    fn reading(temperature: i16) -> Measurement {
+        let mut m = Measurement {
+            temperature: Some(temperature),
+            humidity: Some(550),
+            ..Default::default()
+        };
+        m.quality.temperature = Some(Quality::Good);
+        m.quality.humidity = Some(Quality::Suspect);
+        m
+    }
+
+    fn full_reading() -> Measurement {
+        let mut m = reading(-125);
+        m.pressure = Some(101_325);
+        m.co2 = Some(612);
+        m.quality.pressure = Some(Quality::Bad);
+        m.battery = Some(Battery::from_millivolts(3_900));
+        m.wind = Some(Wind {
+            speed: 34,
+            gust: 71,
+            direction: Some(Direction(6)),
+        });
+        m.particulates = Some(Particulates {
+            pm1_0: 3,
+            pm2_5: 8,
+            pm10: 12,
+        });
+        m.timestamp = Some(1_740_787_200_000);
+        m
+    }
+
+    fn batch(backlog: &Backlog, size: usize) -> Option<(String, u32)> {
+        let mut buffer = vec![0; size];
+        let (len, last) = backlog.write_batch(&mut buffer, 600_000)?;
+        Some((String::from_utf8(buffer[..len].to_vec()).unwrap(), last))
+    }
+
+    #[test]
+    fn samples_survive_rtc_memory() {
+        let mut backlog = Backlog::from_words(&[0; BACKLOG_WORDS], 1);
+        backlog.record(&full_reading(), 5_000);
+        backlog.record(&reading(200), 305_000);
+        // The power-on id is only used when there is nothing to restore.
+        let restored = Backlog::from_words(&backlog.to_words(), 2);
+        assert_eq!(restored, backlog);
+        assert_eq!(restored.boot(), 1);
+        let first = restored.samples().next().unwrap();
+        assert_eq!(first.measurement, full_reading());
+        assert_eq!((first.sequence, first.taken_ms, first.boot), (0, 5_000, 1));
+    }
+
+    #[test]
+    fn keeps_only_what_a_slot_holds() {
+        let mut m = reading(200);
+        m.probes
+            .push(ProbeReading {
+                rom: Rom([0x28, 1, 2, 3, 4, 5, 6, 7]),
+                temperature: 150,
+            })
+            .unwrap();
+        let mut backlog = Backlog::default();
+        backlog.record(&m, 0);
+        assert_eq!(backlog.samples().next().unwrap().measurement, reading(200));
+        // Nothing measured, nothing to keep.
+        assert_eq!(backlog.record(&Measurement::default(), 0), None);
+        assert_eq!(backlog.len(), 1);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut backlog = Backlog::default();
+        for idx in 0..CAPACITY as i16 + 3 {
+            backlog.record(&reading(idx), idx as u64);
+        }
+        assert_eq!(backlog.len(), CAPACITY);
+        assert_eq!(backlog.dropped(), 3);
+        assert_eq!(backlog.samples().next().unwrap().sequence, 3);
+    }
+
+    #[test]
+    fn removes_only_acknowledged_samples() {
+        let mut backlog = Backlog::default();
+        for idx in 0..5 {
+            backlog.record(&reading(idx), idx as u64);
+        }
+        backlog.acknowledge(2);
+        let left: Vec<u32> = backlog.samples().map(|s| s.sequence).collect();
+        assert_eq!(left, [3, 4]);
+        // Sequence numbers keep counting after an acknowledgement.
+        assert_eq!(backlog.record(&reading(0), 9), Some(5));
+    }
+
+    #[test]
+    fn writes_batch() {
+        let mut backlog = Backlog::from_words(&[0; BACKLOG_WORDS], 7);
+        backlog.record(&reading(215), 300_000);
+        let (json, last) = batch(&backlog, 512).unwrap();
+        assert_eq!(last, 0);
+        assert!(json.starts_with(
+            "{\"boot\":7,\"now\":600000,\"dropped\":0,\"samples\":[{\"seq\":0,\"boot\":7,\"ts\":300000,\"reading\":{\"status\":\"ok\",\"temp\":21.5,"
+        ));
+        assert!(json.ends_with("}}]}"));
+    }
+
+    #[test]
+    fn batch_tells_samples_from_an_earlier_power_on_apart() {
+        let mut earlier = Backlog::from_words(&[0; BACKLOG_WORDS], 3);
+        earlier.record(&reading(200), 900_000);
+        let mut buffer = [0; 512];
+        let (len, _) = write_batch(earlier.samples(), 0, &mut buffer, 4, 5_000).unwrap();
+        let json = core::str::from_utf8(&buffer[..len]).unwrap();
+        assert!(json.starts_with(
+            "{\"boot\":4,\"now\":5000,\"dropped\":0,\"samples\":[{\"seq\":0,\"boot\":3,\"ts\":900000,"
+        ));
+    }
+
+    #[test]
+    fn batch_stops_at_the_last_sample_that_fits() {
+        let mut backlog = Backlog::default();
+        for idx in 0..10 {
+            backlog.record(&reading(idx), idx as u64);
+        }
+        // Room for the envelope but not a single sample.
+        assert_eq!(batch(&backlog, 40), None);
+        let (json, last) = batch(&backlog, 600).unwrap();
+        assert!(last > 0 && last < 9);
+        assert!(json.ends_with("}]}"));
+        assert_eq!(json.matches("\"seq\"").count(), last as usize + 1);
+    }
+
+    #[test]
+    fn torn_slot_loses_only_that_sample() {
+        let mut backlog = Backlog::default();
+        for idx in 0..3 {
+            backlog.record(&reading(idx), idx as u64);
+        }
+        let mut words = backlog.to_words();
+        words[HEADER_WORDS + SAMPLE_WORDS + 4] ^= 1;
+        let restored = Backlog::from_words(&words, 0);
+        let left: Vec<u32> = restored.samples().map(|s| s.sequence).collect();
+        assert_eq!(left, [0, 2]);
+        assert_eq!(
+            Backlog::from_words(&[0; BACKLOG_WORDS], 0),
+            Backlog::default()
+        );
+    }
+
+    #[test]
+    fn spills_to_flash_log() {
+        let mut flash = Flash::new(2);
+        let mut log = FlashLog::mount(&mut flash, 0, 2 * 4096).unwrap();
+        let mut backlog = Backlog::default();
+        for idx in 0..CAPACITY as i16 {
+            backlog.record(&full_reading(), idx as u64 * 1_000);
+        }
+        assert!(backlog.is_full());
+        assert_eq!(backlog.spill(&mut flash, &mut log).unwrap(), CAPACITY);
+        assert!(backlog.is_empty());
+        // Sequence numbers carry on where the spilled samples left off.
+        assert_eq!(backlog.record(&reading(0), 0), Some(CAPACITY as u32));
+
+        let log = FlashLog::<SAMPLE_WORDS>::mount(&mut flash, 0, 2 * 4096).unwrap();
+        let mut words = [0; SAMPLE_WORDS];
+        let position = log.read(&mut flash, None, &mut words).unwrap().unwrap();
+        let sample = Sample::from_words(&words).unwrap();
+        assert_eq!((sample.sequence, sample.measurement), (0, full_reading()));
+        let position = log.read(&mut flash, Some(position), &mut words).unwrap();
+        assert!(position.is_some());
+        assert_eq!(Sample::from_words(&words).unwrap().taken_ms, 1_000);
+    }
+
+    #[test]
+    fn reads_acknowledgement() {
+        assert_eq!(
+            acknowledged("{\"status\":\"success\",\"ack\":17}", 17),
+            Some(17)
+        );
+        assert_eq!(
+            acknowledged("{\n  \"ack\": 4,\n  \"status\": \"success\"\n}", 9),
+            Some(4)
+        );
+        assert_eq!(acknowledged("{\"status\":\"success\"}", 17), None);
+        assert_eq!(acknowledged("{\"ack\":null}", 17), None);
+        assert_eq!(acknowledged("{\"ack\":18}", 17), None);
+    }
+}
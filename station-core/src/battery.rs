@@ -0,0 +1,220 @@
+//! Battery voltage from the ESP32's ADC.
+//!
+//! The battery is measured through a resistor divider. Raw ADC codes are
+//! averaged, converted to millivolts with the chip's factory reference
+//! voltage, scaled back up by the divider ratio and finally mapped to a
+//! state of charge with a Li-ion discharge table.
+
+/// Reference voltage assumed when none was burned into eFuse.
+pub const DEFAULT_VREF_MV: u16 = 1100;
+
+/// ADC input attenuation; higher attenuation widens the input range.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Attenuation {
+    Db0,
+    Db2_5,
+    Db6,
+    Db11,
+}
+
+// Linear characterization of ADC1 against its reference voltage, from
+// ESP-IDF's `esp_adc_cal` for the ESP32: mV = (A * raw + 2^15) / 2^16 + B
+// with A = vref * scale / 4096. At 11 dB the curve bends above about
+// 2450 mV, so dividers should keep the pin below that.
+const ATTEN_SCALE: [u32; 4] = [57_431, 76_236, 105_481, 196_602];
+const ATTEN_OFFSET: [u32; 4] = [75, 78, 107, 142];
+const COEFF_A_SCALE: u32 = 65_536;
+
+/// Decodes the ESP32's 5-bit `ADC_VREF` eFuse field: a sign-magnitude offset
+/// from 1100 mV in 7 mV steps. Returns `None` if the field was never burned.
+pub fn decode_vref_efuse(bits: u8) -> Option<u16> {
+    let bits = bits & 0x1F;
+    if bits == 0 {
+        return None;
+    }
+    let magnitude = (bits & 0x0F) as u16 * 7;
+    Some(if bits & 0x10 != 0 {
+        DEFAULT_VREF_MV - magnitude
+    } else {
+        DEFAULT_VREF_MV + magnitude
+    })
+}
+
+/// Conversion from 12-bit ADC1 codes to millivolts at the pin.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AdcCalibration {
+    coeff_a: u32,
+    coeff_b: u32,
+}
+
+impl AdcCalibration {
+    pub fn from_vref(vref_mv: u16, attenuation: Attenuation) -> Self {
+        let idx = attenuation as usize;
+        Self {
+            coeff_a: vref_mv as u32 * ATTEN_SCALE[idx] / 4096,
+            coeff_b: ATTEN_OFFSET[idx],
+        }
+    }
+
+    pub fn millivolts(&self, raw: u16) -> u16 {
+        ((self.coeff_a * raw as u32 + COEFF_A_SCALE / 2) / COEFF_A_SCALE + self.coeff_b) as u16
+    }
+}
+
+/// Mean of `samples` without the lowest and highest quarter, which rejects
+/// the spikes the ESP32 ADC is prone to. Sorts `samples` in place.
+pub fn trimmed_mean(samples: &mut [u16]) -> u16 {
+    if samples.is_empty() {
+        return 0;
+    }
+    samples.sort_unstable();
+    let trim = samples.len() / 4;
+    let kept = &samples[trim..samples.len() - trim];
+    let sum: u32 = kept.iter().map(|&s| s as u32).sum();
+    ((sum + kept.len() as u32 / 2) / kept.len() as u32) as u16
+}
+
+/// Resistor divider between the battery and the ADC pin.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Divider {
+    /// Resistor from the battery to the pin.
+    pub top_ohms: u32,
+    /// Resistor from the pin to ground.
+    pub bottom_ohms: u32,
+}
+
+impl Divider {
+    /// Battery voltage giving `pin_mv` at the pin.
+    pub fn battery_mv(&self, pin_mv: u16) -> u16 {
+        let total = self.top_ohms as u64 + self.bottom_ohms as u64;
+        let bottom = self.bottom_ohms.max(1) as u64;
+        (pin_mv as u64 * total / bottom).min(u16::MAX as u64) as u16
+    }
+}
+
+// Open-circuit voltage of a single Li-ion/LiPo cell against state of
+// charge, at light load and room temperature.
+const LI_ION_CURVE: [(u16, u8); 21] = [
+    (3270, 0),
+    (3610, 5),
+    (3690, 10),
+    (3710, 15),
+    (3730, 20),
+    (3750, 25),
+    (3770, 30),
+    (3790, 35),
+    (3800, 40),
+    (3820, 45),
+    (3840, 50),
+    (3850, 55),
+    (3870, 60),
+    (3910, 65),
+    (3950, 70),
+    (3980, 75),
+    (4020, 80),
+    (4080, 85),
+    (4110, 90),
+    (4150, 95),
+    (4200, 100),
+];
+
+/// Estimated state of charge of a single Li-ion cell, interpolating
+/// linearly between points of the discharge curve.
+pub fn li_ion_percent(millivolts: u16) -> u8 {
+    let (first_mv, _) = LI_ION_CURVE[0];
+    if millivolts <= first_mv {
+        return 0;
+    }
+    for pair in LI_ION_CURVE.windows(2) {
+        let [(low_mv, low_pct), (high_mv, high_pct)] = [pair[0], pair[1]];
+        if millivolts <= high_mv {
+            let span = (high_pct - low_pct) as u32 * (millivolts - low_mv) as u32;
+            let width = (high_mv - low_mv) as u32;
+            return low_pct + ((span + width / 2) / width) as u8;
+        }
+    }
+    100
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Battery {
+    pub millivolts: u16,
+    /// Estimated state of charge, 0-100.
+    pub percent: u8,
+}
+
+impl Battery {
+    pub fn from_millivolts(millivolts: u16) -> Self {
+        Self {
+            millivolts,
+            percent: li_ion_percent(millivolts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_vref_efuse() {
+        assert_eq!(decode_vref_efuse(0), None);
+        assert_eq!(decode_vref_efuse(0b0_0011), Some(1121));
+        assert_eq!(decode_vref_efuse(0b1_0011), Some(1079));
+        assert_eq!(decode_vref_efuse(0b0_1111), Some(1205));
+    }
+
+    #[test]
+    fn calibration_matches_esp_idf_characterization() {
+        // Default reference at 11 dB: A = 1100 * 196602 / 4096 = 52798.
+        let cal = AdcCalibration::from_vref(1100, Attenuation::Db11);
+        assert_eq!(cal.millivolts(0), 142);
+        assert_eq!(cal.millivolts(2048), 1792);
+        assert_eq!(cal.millivolts(4095), 3441);
+        // A chip with a higher reference reads higher for the same code.
+        let high = AdcCalibration::from_vref(1150, Attenuation::Db11);
+        assert!(high.millivolts(2048) > cal.millivolts(2048));
+    }
+
+    #[test]
+    fn trimmed_mean_rejects_spikes() {
+        let mut samples = [2000, 2002, 1998, 4095, 2001, 0, 1999, 2000];
+        assert_eq!(trimmed_mean(&mut samples), 2000);
+        assert_eq!(trimmed_mean(&mut [1234]), 1234);
+        assert_eq!(trimmed_mean(&mut []), 0);
+    }
+
+    #[test]
+    fn divider_scales_pin_voltage() {
+        let half = Divider {
+            top_ohms: 100_000,
+            bottom_ohms: 100_000,
+        };
+        assert_eq!(half.battery_mv(2100), 4200);
+        let third = Divider {
+            top_ohms: 200_000,
+            bottom_ohms: 100_000,
+        };
+        assert_eq!(third.battery_mv(1300), 3900);
+    }
+
+    #[test]
+    fn li_ion_percentage_follows_discharge_curve() {
+        assert_eq!(li_ion_percent(4250), 100);
+        assert_eq!(li_ion_percent(4200), 100);
+        assert_eq!(li_ion_percent(3840), 50);
+        assert_eq!(li_ion_percent(3830), 48);
+        assert_eq!(li_ion_percent(3270), 0);
+        assert_eq!(li_ion_percent(3000), 0);
+    }
+
+    #[test]
+    fn li_ion_percentage_is_monotonic() {
+        let mut last = 0;
+        for mv in 3000..4300 {
+            let pct = li_ion_percent(mv);
+            assert!(pct >= last, "{mv}");
+            last = pct;
+        }
+    }
+}
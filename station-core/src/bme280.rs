@@ -0,0 +1,423 @@
+//! Bosch BME280 / BMP280 pressure, temperature and humidity sensor over I2C.
+//!
+//! The sensor is run in forced mode: each [`Bme280::measure`] triggers a single
+//! conversion, after which the chip drops back to sleep on its own and draws
+//! well under a microamp while the ESP32 is in deep sleep. Raw readings are
+//! turned into physical units with the integer compensation formulas from the
+//! datasheet (section 4.2.3), using the trimming coefficients burned into each
+//! chip.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::dht::Reading;
+use crate::sensor::SensorError;
+
+/// Address with SDO tied to ground.
+pub const PRIMARY_ADDRESS: u8 = 0x76;
+/// Address with SDO tied to VDDIO.
+pub const SECONDARY_ADDRESS: u8 = 0x77;
+
+const BME280_CHIP_ID: u8 = 0x60;
+const BMP280_CHIP_IDS: [u8; 3] = [0x56, 0x57, 0x58];
+
+const REG_CALIB_TP: u8 = 0x88;
+const REG_CALIB_H1: u8 = 0xA1;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_CALIB_H2: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+
+const RESET_COMMAND: u8 = 0xB6;
+const STATUS_MEASURING: u8 = 1 << 3;
+const STATUS_IM_UPDATE: u8 = 1 << 0;
+// Oversampling x1 for every channel and forced mode; a conversion then takes
+// under 10ms.
+const OVERSAMPLING_X1: u8 = 0b001;
+const MODE_FORCED: u8 = 0b01;
+const POLL_INTERVAL_MS: u32 = 2;
+const MAX_POLLS: u32 = 25;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Chip {
+    Bme280,
+    /// Pressure and temperature only.
+    Bmp280,
+}
+
+/// Per-chip trimming coefficients, named as in the datasheet.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Calibration {
+    pub dig_t1: u16,
+    pub dig_t2: i16,
+    pub dig_t3: i16,
+    pub dig_p1: u16,
+    pub dig_p2: i16,
+    pub dig_p3: i16,
+    pub dig_p4: i16,
+    pub dig_p5: i16,
+    pub dig_p6: i16,
+    pub dig_p7: i16,
+    pub dig_p8: i16,
+    pub dig_p9: i16,
+    pub dig_h1: u8,
+    pub dig_h2: i16,
+    pub dig_h3: u8,
+    pub dig_h4: i16,
+    pub dig_h5: i16,
+    pub dig_h6: i8,
+}
+
+impl Calibration {
+    /// Parses the 0x88..=0x9F temperature/pressure block and the 0xE1..=0xE7
+    /// humidity block (plus `dig_h1` from 0xA1).
+    pub fn from_registers(tp: &[u8; 24], h1: u8, h: &[u8; 7]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]);
+        Self {
+            dig_t1: u16_at(0),
+            dig_t2: i16_at(2),
+            dig_t3: i16_at(4),
+            dig_p1: u16_at(6),
+            dig_p2: i16_at(8),
+            dig_p3: i16_at(10),
+            dig_p4: i16_at(12),
+            dig_p5: i16_at(14),
+            dig_p6: i16_at(16),
+            dig_p7: i16_at(18),
+            dig_p8: i16_at(20),
+            dig_p9: i16_at(22),
+            dig_h1: h1,
+            dig_h2: i16::from_le_bytes([h[0], h[1]]),
+            dig_h3: h[2],
+            // H4 and H5 are 12-bit values sharing the nibbles of 0xE5.
+            dig_h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16,
+            dig_h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            dig_h6: h[6] as i8,
+        }
+    }
+
+    /// Returns the temperature in hundredths of a degree Celsius together with
+    /// `t_fine`, the intermediate the other two formulas depend on.
+    pub fn compensate_temperature(&self, adc_t: i32) -> (i32, i32) {
+        let t1 = self.dig_t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.dig_t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.dig_t3 as i32) >> 14;
+        let t_fine = var1 + var2;
+        ((t_fine * 5 + 128) >> 8, t_fine)
+    }
+
+    /// Returns the pressure in pascals as Q24.8 fixed point, or `None` when the
+    /// coefficients would divide by zero (an unprogrammed chip).
+    pub fn compensate_pressure(&self, adc_p: i32, t_fine: i32) -> Option<u32> {
+        let mut var1 = t_fine as i64 - 128_000;
+        let mut var2 = var1 * var1 * self.dig_p6 as i64;
+        var2 += (var1 * self.dig_p5 as i64) << 17;
+        var2 += (self.dig_p4 as i64) << 35;
+        var1 = ((var1 * var1 * self.dig_p3 as i64) >> 8) + ((var1 * self.dig_p2 as i64) << 12);
+        var1 = (((1i64 << 47) + var1) * self.dig_p1 as i64) >> 33;
+        if var1 == 0 {
+            return None;
+        }
+        let mut p = 1_048_576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        let var1 = (self.dig_p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        let var2 = (self.dig_p8 as i64 * p) >> 19;
+        p = ((p + var1 + var2) >> 8) + ((self.dig_p7 as i64) << 4);
+        Some(p as u32)
+    }
+
+    /// Returns relative humidity in percent as Q22.10 fixed point.
+    pub fn compensate_humidity(&self, adc_h: i32, t_fine: i32) -> u32 {
+        let mut v = t_fine - 76_800;
+        v = (((adc_h << 14) - ((self.dig_h4 as i32) << 20) - (self.dig_h5 as i32 * v) + 16_384)
+            >> 15)
+            * (((((((v * self.dig_h6 as i32) >> 10)
+                * (((v * self.dig_h3 as i32) >> 11) + 32_768))
+                >> 10)
+                + 2_097_152)
+                * self.dig_h2 as i32
+                + 8192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * self.dig_h1 as i32) >> 4;
+        (v.clamp(0, 419_430_400) >> 12) as u32
+    }
+
+    /// Converts one raw data burst (0xF7..=0xFE) into a [`Reading`].
+    pub fn compensate(&self, data: &[u8; 8], chip: Chip) -> Result<Reading, SensorError> {
+        let adc_p = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | (data[2] as i32 >> 4);
+        let adc_t = ((data[3] as i32) << 12) | ((data[4] as i32) << 4) | (data[5] as i32 >> 4);
+        let adc_h = ((data[6] as i32) << 8) | data[7] as i32;
+
+        let (centi_celsius, t_fine) = self.compensate_temperature(adc_t);
+        let pressure = self
+            .compensate_pressure(adc_p, t_fine)
+            .ok_or(SensorError::UnknownDevice)?;
+        let humidity = match chip {
+            Chip::Bme280 => (self.compensate_humidity(adc_h, t_fine) * 10 + 512) >> 10,
+            Chip::Bmp280 => 0,
+        };
+        Ok(Reading {
+            temperature: round_div(centi_celsius, 10) as i16,
+            humidity: humidity as u16,
+            pressure: Some((pressure + 128) >> 8),
+        })
+    }
+}
+
+fn round_div(value: i32, divisor: i32) -> i32 {
+    if value >= 0 {
+        (value + divisor / 2) / divisor
+    } else {
+        (value - divisor / 2) / divisor
+    }
+}
+
+pub struct Bme280<I> {
+    i2c: I,
+    address: u8,
+    chip: Chip,
+    calibration: Calibration,
+}
+
+impl<I: I2c> Bme280<I> {
+    /// Identifies the chip at `address`, resets it and reads its calibration.
+    pub fn new<D: DelayNs>(i2c: I, address: u8, delay: &mut D) -> Result<Self, SensorError> {
+        let mut sensor = Self {
+            i2c,
+            address,
+            chip: Chip::Bme280,
+            calibration: Calibration::default(),
+        };
+        let mut id = [0u8];
+        sensor.read_registers(REG_CHIP_ID, &mut id)?;
+        sensor.chip = match id[0] {
+            BME280_CHIP_ID => Chip::Bme280,
+            id if BMP280_CHIP_IDS.contains(&id) => Chip::Bmp280,
+            _ => return Err(SensorError::UnknownDevice),
+        };
+
+        sensor.write_register(REG_RESET, RESET_COMMAND)?;
+        delay.delay_ms(2);
+        // The calibration is copied from NVM after reset; wait for it.
+        sensor.wait_status_clear(STATUS_IM_UPDATE, delay)?;
+
+        let mut tp = [0u8; 24];
+        sensor.read_registers(REG_CALIB_TP, &mut tp)?;
+        let mut h1 = [0u8];
+        let mut h = [0u8; 7];
+        if sensor.chip == Chip::Bme280 {
+            sensor.read_registers(REG_CALIB_H1, &mut h1)?;
+            sensor.read_registers(REG_CALIB_H2, &mut h)?;
+        }
+        sensor.calibration = Calibration::from_registers(&tp, h1[0], &h);
+        Ok(sensor)
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// Runs one forced-mode conversion. On a BMP280 the humidity is zero.
+    pub fn measure<D: DelayNs>(&mut self, delay: &mut D) -> Result<Reading, SensorError> {
+        if self.chip == Chip::Bme280 {
+            // ctrl_hum only takes effect after the following ctrl_meas write.
+            self.write_register(REG_CTRL_HUM, OVERSAMPLING_X1)?;
+        }
+        let ctrl_meas = (OVERSAMPLING_X1 << 5) | (OVERSAMPLING_X1 << 2) | MODE_FORCED;
+        self.write_register(REG_CTRL_MEAS, ctrl_meas)?;
+        delay.delay_ms(POLL_INTERVAL_MS);
+        self.wait_status_clear(STATUS_MEASURING, delay)?;
+
+        let mut data = [0u8; 8];
+        self.read_registers(REG_DATA, &mut data)?;
+        self.calibration.compensate(&data, self.chip)
+    }
+
+    fn wait_status_clear<D: DelayNs>(
+        &mut self,
+        mask: u8,
+        delay: &mut D,
+    ) -> Result<(), SensorError> {
+        for _ in 0..MAX_POLLS {
+            let mut status = [0u8];
+            self.read_registers(REG_STATUS, &mut status)?;
+            if status[0] & mask == 0 {
+                return Ok(());
+            }
+            delay.delay_ms(POLL_INTERVAL_MS);
+        }
+        Err(SensorError::NoResponse)
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), SensorError> {
+        self.i2c
+            .write_read(self.address, &[register], buf)
+            .map_err(|_| SensorError::BusError)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), SensorError> {
+        self.i2c
+            .write(self.address, &[register, value])
+            .map_err(|_| SensorError::BusError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{NoDelay, Registers};
+
+    /// Coefficients from the BMP280 datasheet's worked example (section 8.2).
+    fn datasheet_calibration() -> Calibration {
+        Calibration {
+            dig_t1: 27504,
+            dig_t2: 26435,
+            dig_t3: -1000,
+            dig_p1: 36477,
+            dig_p2: -10685,
+            dig_p3: 3024,
+            dig_p4: 2855,
+            dig_p5: 140,
+            dig_p6: -7,
+            dig_p7: 15500,
+            dig_p8: -14600,
+            dig_p9: 6000,
+            // Humidity trimming read from a production BME280.
+            dig_h1: 75,
+            dig_h2: 362,
+            dig_h3: 0,
+            dig_h4: 313,
+            dig_h5: 50,
+            dig_h6: 30,
+        }
+    }
+
+    #[test]
+    fn temperature_matches_datasheet_example() {
+        let (centi, t_fine) = datasheet_calibration().compensate_temperature(519_888);
+        assert_eq!(centi, 2508);
+        assert_eq!(t_fine, 128_422);
+    }
+
+    #[test]
+    fn pressure_matches_datasheet_example() {
+        let q24_8 = datasheet_calibration()
+            .compensate_pressure(415_148, 128_422)
+            .unwrap();
+        // Datasheet: 100653.27 Pa.
+        assert_eq!(q24_8 / 256, 100_653);
+    }
+
+    #[test]
+    fn humidity_matches_floating_point_formula() {
+        let cal = datasheet_calibration();
+        let t_fine = 128_422;
+        for adc_h in [20_000, 28_000, 32_000, 40_000] {
+            let fixed = cal.compensate_humidity(adc_h, t_fine) as f64 / 1024.0;
+            // Double-precision formula from the datasheet, section 8.1.
+            let var = t_fine as f64 - 76_800.0;
+            let mut h = (adc_h as f64
+                - (cal.dig_h4 as f64 * 64.0 + cal.dig_h5 as f64 / 16_384.0 * var))
+                * (cal.dig_h2 as f64 / 65_536.0
+                    * (1.0
+                        + cal.dig_h6 as f64 / 67_108_864.0
+                            * var
+                            * (1.0 + cal.dig_h3 as f64 / 67_108_864.0 * var)));
+            h *= 1.0 - cal.dig_h1 as f64 * h / 524_288.0;
+            let float = h.clamp(0.0, 100.0);
+            assert!(
+                (fixed - float).abs() < 0.1,
+                "adc_h {adc_h}: {fixed} vs {float}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_packed_humidity_coefficients() {
+        let h = [0x6A, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1E];
+        let cal = Calibration::from_registers(&[0; 24], 75, &h);
+        assert_eq!(cal.dig_h2, 362);
+        assert_eq!(cal.dig_h4, (0x13 << 4) | 0x9);
+        assert_eq!(cal.dig_h5, (0x03 << 4) | 0x2);
+        assert_eq!(cal.dig_h6, 30);
+    }
+
+    fn datasheet_registers(chip_id: u8) -> Registers {
+        let cal = datasheet_calibration();
+        let regs = Registers::new(PRIMARY_ADDRESS);
+        regs.set(REG_CHIP_ID, &[chip_id]);
+        let words = [
+            cal.dig_t1 as i32,
+            cal.dig_t2 as i32,
+            cal.dig_t3 as i32,
+            cal.dig_p1 as i32,
+            cal.dig_p2 as i32,
+            cal.dig_p3 as i32,
+            cal.dig_p4 as i32,
+            cal.dig_p5 as i32,
+            cal.dig_p6 as i32,
+            cal.dig_p7 as i32,
+            cal.dig_p8 as i32,
+            cal.dig_p9 as i32,
+        ];
+        for (idx, word) in words.iter().enumerate() {
+            regs.set(REG_CALIB_TP + idx as u8 * 2, &(*word as u16).to_le_bytes());
+        }
+        regs.set(REG_CALIB_H1, &[75]);
+        regs.set(REG_CALIB_H2, &[0x6A, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1E]);
+        // adc_P = 415148, adc_T = 519888, adc_H = 28000.
+        regs.set(REG_DATA, &[0x65, 0x5A, 0xC0, 0x7E, 0xED, 0x00, 0x6D, 0x60]);
+        regs
+    }
+
+    #[test]
+    fn measures_in_forced_mode() {
+        let regs = datasheet_registers(BME280_CHIP_ID);
+        let mut sensor = Bme280::new(regs.clone(), PRIMARY_ADDRESS, &mut NoDelay).unwrap();
+        assert_eq!(sensor.chip(), Chip::Bme280);
+        let reading = sensor.measure(&mut NoDelay).unwrap();
+        assert_eq!(reading.temperature, 251);
+        assert_eq!(reading.pressure, Some(100_653));
+        assert!(reading.humidity > 0 && reading.humidity <= 1000);
+        assert_eq!(regs.get(REG_CTRL_HUM), OVERSAMPLING_X1);
+        assert_eq!(regs.get(REG_CTRL_MEAS) & 0b11, MODE_FORCED);
+    }
+
+    #[test]
+    fn bmp280_has_no_humidity() {
+        let regs = datasheet_registers(0x58);
+        let mut sensor = Bme280::new(regs, PRIMARY_ADDRESS, &mut NoDelay).unwrap();
+        assert_eq!(sensor.chip(), Chip::Bmp280);
+        assert_eq!(sensor.measure(&mut NoDelay).unwrap().humidity, 0);
+    }
+
+    #[test]
+    fn rejects_unknown_chip_id() {
+        let regs = datasheet_registers(0x61);
+        assert!(matches!(
+            Bme280::new(regs, PRIMARY_ADDRESS, &mut NoDelay),
+            Err(SensorError::UnknownDevice)
+        ));
+    }
+
+    #[test]
+    fn missing_device_is_a_bus_error() {
+        let regs = datasheet_registers(BME280_CHIP_ID);
+        assert!(matches!(
+            Bme280::new(regs, SECONDARY_ADDRESS, &mut NoDelay),
+            Err(SensorError::BusError)
+        ));
+    }
+
+    #[test]
+    fn stuck_conversion_times_out() {
+        let regs = datasheet_registers(BME280_CHIP_ID);
+        let mut sensor = Bme280::new(regs.clone(), PRIMARY_ADDRESS, &mut NoDelay).unwrap();
+        regs.set(REG_STATUS, &[STATUS_MEASURING]);
+        assert_eq!(sensor.measure(&mut NoDelay), Err(SensorError::NoResponse));
+    }
+}
@@ -0,0 +1,628 @@
+//! Per-sensor calibration corrections.
+//!
+//! No two cheap sensors read quite alike, so each station carries a table of
+//! linear corrections (`value * gain + offset`) keyed by sensor and quantity.
+//! Corrections are applied to each sensor's result before it is checked and
+//! merged, so the upload only ever contains corrected values.
+//!
+//! The table lives in its own flash region and can be changed at runtime
+//! with text commands, for example pushed by the server in its reply to an
+//! upload:
+//!
+//! ```text
+//! dht temp -0.8 1.02      temperature * 1.02 - 0.8 °C
+//! 28FF4C6A01160367 temp 0.3
+//! sht hum 2.5
+//! clear
+//! ```
+//!
+//! The server numbers the commands it queues for a station and keeps them
+//! until the station reports, with a later upload, the number of the last
+//! one it applied and saved. A reply that was lost or cut short is simply
+//! pushed again; replaying commands in order ends with the same table.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::fixed::parse_decimal;
+use crate::onewire::Rom;
+use crate::persist;
+use crate::sensor::Measurement;
+
+/// Corrections a table holds.
+pub const MAX_ENTRIES: usize = 16;
+
+/// `gain` of a correction that leaves values unchanged.
+pub const UNITY_GAIN: i32 = 10_000;
+
+const MAGIC: u32 = 0xCA11_B8A7;
+const ENTRY_WORDS: usize = 5;
+/// Entry count, the entries and the check word.
+pub const TABLE_WORDS: usize = 1 + MAX_ENTRIES * ENTRY_WORDS + 1;
+const TABLE_BYTES: usize = TABLE_WORDS * 4;
+
+/// Number of words [`Applied`] occupies in RTC memory.
+pub const APPLIED_WORDS: usize = 2;
+
+const APPLIED_MAGIC: u32 = 0xCA11_AC4D;
+
+/// A sensor type by name (as in [`EnvironmentalSensor::name`]), or a 1-Wire
+/// probe by ROM code.
+///
+/// [`EnvironmentalSensor::name`]: crate::sensor::EnvironmentalSensor::name
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SensorId(pub [u8; 8]);
+
+impl SensorId {
+    /// Names longer than eight bytes are cut short.
+    pub fn named(name: &str) -> Self {
+        let mut id = [0u8; 8];
+        for (slot, byte) in id.iter_mut().zip(name.bytes()) {
+            *slot = byte;
+        }
+        SensorId(id)
+    }
+
+    /// Parses a ROM code as printed by [`Rom`]'s `Display` (16 hex digits),
+    /// or else takes the text as a sensor name.
+    pub fn parse(text: &str) -> Option<Self> {
+        if text.is_empty() || text.len() > 16 {
+            return None;
+        }
+        if text.len() == 16 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let mut rom = [0u8; 8];
+            for (idx, byte) in rom.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&text[idx * 2..idx * 2 + 2], 16).ok()?;
+            }
+            return Some(SensorId(rom));
+        }
+        (text.len() <= 8).then(|| SensorId::named(text))
+    }
+}
+
+impl From<Rom> for SensorId {
+    fn from(rom: Rom) -> Self {
+        SensorId(rom.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantity {
+    /// Tenths of a degree Celsius.
+    Temperature,
+    /// Tenths of a percent.
+    Humidity,
+    /// Pascals.
+    Pressure,
+    /// Parts per million.
+    Co2,
+}
+
+impl Quantity {
+    const ALL: [Quantity; 4] = [
+        Quantity::Temperature,
+        Quantity::Humidity,
+        Quantity::Pressure,
+        Quantity::Co2,
+    ];
+
+    /// Name used in commands, matching the upload's keys.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temp",
+            Quantity::Humidity => "hum",
+            Quantity::Pressure => "pres",
+            Quantity::Co2 => "co2",
+        }
+    }
+
+    /// Decimal places of the unit commands use (°C, %, hPa, ppm) that are
+    /// kept in the stored value.
+    fn command_places(&self) -> u32 {
+        match self {
+            Quantity::Temperature | Quantity::Humidity => 1,
+            Quantity::Pressure => 2,
+            Quantity::Co2 => 0,
+        }
+    }
+}
+
+/// `value * gain / 10000 + offset`, with the offset in the quantity's
+/// stored unit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Correction {
+    pub offset: i32,
+    /// In units of 1/10000; [`UNITY_GAIN`] is 1.
+    pub gain: i32,
+}
+
+impl Correction {
+    pub const IDENTITY: Correction = Correction {
+        offset: 0,
+        gain: UNITY_GAIN,
+    };
+
+    pub fn apply(&self, value: i64) -> i64 {
+        let scaled = value * self.gain as i64;
+        (scaled + UNITY_GAIN as i64 / 2).div_euclid(UNITY_GAIN as i64) + self.offset as i64
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub sensor: SensorId,
+    pub quantity: Quantity,
+    pub correction: Correction,
+}
+
+/// No room for another correction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TableFull;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CalibrationTable {
+    entries: heapless::Vec<Entry, MAX_ENTRIES>,
+}
+
+impl CalibrationTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn get(&self, sensor: SensorId, quantity: Quantity) -> Correction {
+        self.entries
+            .iter()
+            .find(|e| e.sensor == sensor && e.quantity == quantity)
+            .map_or(Correction::IDENTITY, |e| e.correction)
+    }
+
+    /// Sets the correction for one sensor and quantity; the identity removes
+    /// it. Returns whether the table changed.
+    pub fn set(
+        &mut self,
+        sensor: SensorId,
+        quantity: Quantity,
+        correction: Correction,
+    ) -> Result<bool, TableFull> {
+        let existing = self
+            .entries
+            .iter()
+            .position(|e| e.sensor == sensor && e.quantity == quantity);
+        match existing {
+            Some(idx) if correction == Correction::IDENTITY => {
+                self.entries.remove(idx);
+                Ok(true)
+            }
+            Some(idx) => {
+                let changed = self.entries[idx].correction != correction;
+                self.entries[idx].correction = correction;
+                Ok(changed)
+            }
+            None if correction == Correction::IDENTITY => Ok(false),
+            None => {
+                self.entries
+                    .push(Entry {
+                        sensor,
+                        quantity,
+                        correction,
+                    })
+                    .map_err(|_| TableFull)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Applies a command; returns whether the table changed.
+    pub fn execute(&mut self, command: &Command) -> Result<bool, TableFull> {
+        match command {
+            Command::Set {
+                sensor,
+                quantity,
+                correction,
+            } => self.set(*sensor, *quantity, *correction),
+            Command::Clear => {
+                let changed = !self.entries.is_empty();
+                self.entries.clear();
+                Ok(changed)
+            }
+        }
+    }
+
+    /// Corrects what the sensor called `sensor` measured. Probes are looked
+    /// up by their ROM code.
+    pub fn apply(&self, sensor: &str, measurement: &mut Measurement) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let id = SensorId::named(sensor);
+        let correct = |quantity, value: i64| self.get(id, quantity).apply(value);
+        if let Some(t) = measurement.temperature.as_mut() {
+            *t = correct(Quantity::Temperature, *t as i64).clamp(i16::MIN as i64, i16::MAX as i64)
+                as i16;
+        }
+        if let Some(h) = measurement.humidity.as_mut() {
+            *h = correct(Quantity::Humidity, *h as i64).clamp(0, 1000) as u16;
+        }
+        if let Some(p) = measurement.pressure.as_mut() {
+            *p = correct(Quantity::Pressure, *p as i64).clamp(0, u32::MAX as i64) as u32;
+        }
+        if let Some(c) = measurement.co2.as_mut() {
+            *c = correct(Quantity::Co2, *c as i64).clamp(0, u16::MAX as i64) as u16;
+        }
+        for probe in measurement.probes.iter_mut() {
+            let correction = self.get(probe.rom.into(), Quantity::Temperature);
+            probe.temperature = correction
+                .apply(probe.temperature as i64)
+                .clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        }
+    }
+
+    pub fn to_words(&self) -> [u32; TABLE_WORDS] {
+        let mut words = [0u32; TABLE_WORDS];
+        words[0] = self.entries.len() as u32;
+        for (entry, chunk) in self
+            .entries
+            .iter()
+            .zip(words[1..].chunks_exact_mut(ENTRY_WORDS))
+        {
+            let id = entry.sensor.0;
+            chunk[0] = u32::from_le_bytes([id[0], id[1], id[2], id[3]]);
+            chunk[1] = u32::from_le_bytes([id[4], id[5], id[6], id[7]]);
+            chunk[2] = entry.quantity as u32;
+            chunk[3] = entry.correction.offset as u32;
+            chunk[4] = entry.correction.gain as u32;
+        }
+        persist::seal(&mut words, MAGIC);
+        words
+    }
+
+    /// Restores a table saved with [`to_words`](Self::to_words); anything
+    /// else (erased flash, a torn write) gives an empty table.
+    pub fn from_words(words: &[u32; TABLE_WORDS]) -> Self {
+        let mut table = Self::new();
+        if !persist::is_sealed(words, MAGIC) || words[0] as usize > MAX_ENTRIES {
+            return table;
+        }
+        for chunk in words[1..].chunks_exact(ENTRY_WORDS).take(words[0] as usize) {
+            let Some(&quantity) = Quantity::ALL.get(chunk[2] as usize) else {
+                return Self::new();
+            };
+            let [a, b, c, d] = chunk[0].to_le_bytes();
+            let [e, f, g, h] = chunk[1].to_le_bytes();
+            let _ = table.entries.push(Entry {
+                sensor: SensorId([a, b, c, d, e, f, g, h]),
+                quantity,
+                correction: Correction {
+                    offset: chunk[3] as i32,
+                    gain: chunk[4] as i32,
+                },
+            });
+        }
+        table
+    }
+
+    /// Reads the table from flash at `offset`.
+    pub fn load<F: ReadNorFlash>(flash: &mut F, offset: u32) -> Result<Self, F::Error> {
+        let mut bytes = [0u8; TABLE_BYTES];
+        flash.read(offset, &mut bytes)?;
+        let mut words = [0u32; TABLE_WORDS];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok(Self::from_words(&words))
+    }
+
+    /// Erases the sector(s) at `offset` and writes the table there. A reset
+    /// in between loses the table rather than corrupting it.
+    pub fn store<F: NorFlash>(&self, flash: &mut F, offset: u32) -> Result<(), F::Error> {
+        let mut bytes = [0u8; TABLE_BYTES];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(self.to_words()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let erase_len = TABLE_BYTES.div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE;
+        flash.erase(offset, offset + erase_len as u32)?;
+        flash.write(offset, &bytes)
+    }
+}
+
+/// A change to the table, parsed from a line of text.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `<sensor> <quantity> <offset> [<gain>]`, with the offset in °C, %,
+    /// hPa or ppm and the gain a plain factor (1 if left out).
+    Set {
+        sensor: SensorId,
+        quantity: Quantity,
+        correction: Correction,
+    },
+    /// `clear`: removes every correction.
+    Clear,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Option<Command> {
+        let mut words = line.split_ascii_whitespace();
+        let first = words.next()?;
+        if first == "clear" {
+            return words.next().is_none().then_some(Command::Clear);
+        }
+        let sensor = SensorId::parse(first)?;
+        let quantity = words.next()?;
+        let quantity = *Quantity::ALL.iter().find(|q| q.code() == quantity)?;
+        let offset = parse_decimal(words.next()?, quantity.command_places())?;
+        let gain = match words.next() {
+            Some(gain) => parse_decimal(gain, 4)?,
+            None => UNITY_GAIN as i64,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+        Some(Command::Set {
+            sensor,
+            quantity,
+            correction: Correction {
+                offset: i32::try_from(offset).ok()?,
+                gain: i32::try_from(gain).ok()?,
+            },
+        })
+    }
+}
+
+/// The number of the last pushed command the station applied and saved,
+/// reported back with each upload so the server can drop it from its queue.
+/// Zero before the first.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Applied(pub u32);
+
+impl Applied {
+    pub fn to_words(&self) -> [u32; APPLIED_WORDS] {
+        let mut words = [self.0, 0];
+        persist::seal(&mut words, APPLIED_MAGIC);
+        words
+    }
+
+    /// Restores the number saved by [`Applied::to_words`]. Memory without a
+    /// valid check word gives zero, which has the server push its whole
+    /// queue again.
+    pub fn from_words(words: &[u32; APPLIED_WORDS]) -> Self {
+        if !persist::is_sealed(words, APPLIED_MAGIC) {
+            return Self::default();
+        }
+        Self(words[0])
+    }
+}
+
+/// The number of the last command in the `"calibration"` array of a reply,
+/// from its `"calibration_id"`, e.g.
+/// `{"calibration":["dht temp -0.8"],"calibration_id":12}`.
+pub fn pushed_through(reply: &str) -> Option<u32> {
+    const KEY: &str = "\"calibration_id\"";
+    let rest = reply[reply.find(KEY)? + KEY.len()..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Commands in the `"calibration"` array of a JSON reply from the server,
+/// e.g. `{"status":"success","calibration":["dht temp -0.8"]}`.
+pub fn pushed_commands(reply: &str) -> impl Iterator<Item = &str> {
+    const KEY: &str = "\"calibration\"";
+    let list = reply
+        .find(KEY)
+        .and_then(|start| reply[start + KEY.len()..].trim_start().strip_prefix(':'))
+        .and_then(|rest| rest.trim_start().strip_prefix('['))
+        .and_then(|rest| rest.find(']').map(|end| &rest[..end]))
+        .unwrap_or("");
+    // Between the quotes, every second piece is a string's contents.
+    list.split('"').skip(1).step_by(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Flash;
+    use crate::sensor::ProbeReading;
+
+    fn set(line: &str) -> Command {
+        Command::parse(line).unwrap()
+    }
+
+    #[test]
+    fn parses_commands_in_display_units() {
+        assert_eq!(
+            set("dht temp -0.8 1.02"),
+            Command::Set {
+                sensor: SensorId::named("dht"),
+                quantity: Quantity::Temperature,
+                correction: Correction {
+                    offset: -8,
+                    gain: 10_200
+                },
+            }
+        );
+        let Command::Set { correction, .. } = set("bme280 pres 1.5") else {
+            panic!("not a set command");
+        };
+        assert_eq!(
+            correction,
+            Correction {
+                offset: 150,
+                gain: UNITY_GAIN
+            }
+        );
+        assert_eq!(set("clear"), Command::Clear);
+    }
+
+    #[test]
+    fn probes_are_addressed_by_rom() {
+        let rom = Rom([0x28, 0xFF, 0x4C, 0x6A, 0x01, 0x16, 0x03, 0x67]);
+        let Command::Set { sensor, .. } = set(&format!("{} temp 0.3", rom)) else {
+            panic!("not a set command");
+        };
+        assert_eq!(sensor, SensorId::from(rom));
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        for line in [
+            "",
+            "dht",
+            "dht wind 1",
+            "dht temp",
+            "dht temp x",
+            "dht temp 1 1 1",
+            "averyverylongname temp 1",
+            "clear all",
+        ] {
+            assert_eq!(Command::parse(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn corrects_each_quantity_of_the_named_sensor() {
+        let mut table = CalibrationTable::new();
+        table.execute(&set("dht temp -0.8 1.02")).unwrap();
+        table.execute(&set("dht hum 5")).unwrap();
+        table.execute(&set("sht hum -3")).unwrap();
+        let mut measurement = Measurement {
+            temperature: Some(250),
+            humidity: Some(980),
+            ..Default::default()
+        };
+        table.apply("dht", &mut measurement);
+        // 25.0 * 1.02 - 0.8 = 24.7; humidity is capped at 100%.
+        assert_eq!(measurement.temperature, Some(247));
+        assert_eq!(measurement.humidity, Some(1000));
+    }
+
+    #[test]
+    fn corrects_probes_individually() {
+        let rom = Rom([0x28, 1, 2, 3, 4, 5, 6, 7]);
+        let other = Rom([0x28, 7, 6, 5, 4, 3, 2, 1]);
+        let mut table = CalibrationTable::new();
+        table
+            .set(
+                rom.into(),
+                Quantity::Temperature,
+                Correction {
+                    offset: 3,
+                    gain: UNITY_GAIN,
+                },
+            )
+            .unwrap();
+        let mut measurement = Measurement::default();
+        for rom in [rom, other] {
+            measurement
+                .probes
+                .push(ProbeReading {
+                    rom,
+                    temperature: -52,
+                })
+                .unwrap();
+        }
+        table.apply("ds18b20", &mut measurement);
+        assert_eq!(measurement.probes[0].temperature, -49);
+        assert_eq!(measurement.probes[1].temperature, -52);
+    }
+
+    #[test]
+    fn reports_whether_table_changed() {
+        let mut table = CalibrationTable::new();
+        assert_eq!(table.execute(&set("dht temp 1")), Ok(true));
+        assert_eq!(table.execute(&set("dht temp 1")), Ok(false));
+        assert_eq!(table.execute(&set("dht temp 0 1")), Ok(true));
+        assert!(table.entries().is_empty());
+        assert_eq!(table.execute(&Command::Clear), Ok(false));
+    }
+
+    #[test]
+    fn refuses_entries_beyond_capacity() {
+        let mut table = CalibrationTable::new();
+        for idx in 0..MAX_ENTRIES {
+            let name = format!("s{}", idx);
+            assert_eq!(table.execute(&set(&format!("{} temp 1", name))), Ok(true));
+        }
+        assert_eq!(table.execute(&set("extra temp 1")), Err(TableFull));
+    }
+
+    #[test]
+    fn round_trips_through_flash() {
+        let mut flash = Flash::new(2);
+        let mut table = CalibrationTable::new();
+        table.execute(&set("dht temp -0.8 1.02")).unwrap();
+        table.execute(&set("28FF4C6A01160367 temp 0.3")).unwrap();
+        table.store(&mut flash, 4096).unwrap();
+        assert_eq!(CalibrationTable::load(&mut flash, 4096).unwrap(), table);
+        // Overwriting works since the sector is erased first.
+        table.execute(&Command::Clear).unwrap();
+        table.store(&mut flash, 4096).unwrap();
+        assert!(
+            CalibrationTable::load(&mut flash, 4096)
+                .unwrap()
+                .entries()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn erased_or_damaged_flash_gives_empty_table() {
+        let mut flash = Flash::new(1);
+        assert_eq!(
+            CalibrationTable::load(&mut flash, 0).unwrap(),
+            CalibrationTable::new()
+        );
+        let mut table = CalibrationTable::new();
+        table.execute(&set("dht temp 1")).unwrap();
+        table.store(&mut flash, 0).unwrap();
+        flash.data[10] ^= 0x04;
+        assert!(
+            CalibrationTable::load(&mut flash, 0)
+                .unwrap()
+                .entries()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn extracts_commands_pushed_in_reply() {
+        let reply = r#"{"calibration":["dht temp -0.8","clear"],"status":"success"}"#;
+        assert_eq!(
+            pushed_commands(reply).collect::<Vec<_>>(),
+            ["dht temp -0.8", "clear"]
+        );
+        let pretty = "{\n  \"calibration\": [\n    \"sht hum 2\"\n  ]\n}";
+        assert_eq!(pushed_commands(pretty).collect::<Vec<_>>(), ["sht hum 2"]);
+        assert_eq!(pushed_commands(r#"{"status":"success"}"#).count(), 0);
+    }
+
+    #[test]
+    fn reads_the_number_of_the_last_pushed_command() {
+        let reply = r#"{"calibration":["dht temp -0.8"],"calibration_id":12,"status":"success"}"#;
+        assert_eq!(
+            pushed_commands(reply).collect::<Vec<_>>(),
+            ["dht temp -0.8"]
+        );
+        assert_eq!(pushed_through(reply), Some(12));
+        assert_eq!(pushed_through(r#"{"calibration_id": 7}"#), Some(7));
+        assert_eq!(pushed_through(r#"{"status":"success"}"#), None);
+        // Cut off before the number.
+        assert_eq!(pushed_through(r#"{"calibration_id":"#), None);
+    }
+
+    #[test]
+    fn applied_survives_rtc_memory() {
+        assert_eq!(Applied::from_words(&Applied(42).to_words()), Applied(42));
+        let mut words = Applied(42).to_words();
+        words[0] = 43;
+        assert_eq!(Applied::from_words(&words), Applied::default());
+    }
+}
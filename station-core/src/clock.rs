@@ -0,0 +1,332 @@
+//! Wall clock time kept across deep sleep.
+//!
+//! The RTC timer keeps counting through deep sleep but knows nothing of the
+//! date, and its slow clock can be off by a fraction of a percent. A
+//! [`WallClock`] pairs one RTC reading with the Unix time it corresponds
+//! to, taken from a time server, and extrapolates from there. Each sync
+//! after a long enough gap also measures how fast the RTC runs, so later
+//! extrapolations are corrected for its drift, and how well that
+//! correction held up, so [`WallClock::error_ms`] can tell when the clock
+//! needs syncing again.
+
+use core::fmt;
+
+use crate::persist;
+
+/// Number of words [`WallClock`] occupies in RTC memory.
+pub const CLOCK_WORDS: usize = 10;
+
+const CLOCK_MAGIC: u32 = 0x434C_4F4B;
+
+/// Assumed error of the RTC rate before it has been measured. Calibrated
+/// against the main crystal at boot, the ESP32's RC slow clock stays
+/// within this across temperature.
+const UNMEASURED_PPM: u32 = 2_000;
+/// Smallest error assumed after measuring, for changes in temperature the
+/// last measurement has not seen.
+const MEASURED_FLOOR_PPM: u32 = 20;
+/// A rate further off than this is a bad sync rather than drift.
+const MAX_DRIFT_PPM: i64 = 50_000;
+/// Syncs closer together than this are not used to measure the rate, since
+/// their own error would dominate.
+const MIN_DRIFT_SPAN_MS: u64 = 10 * 60_000;
+
+const DAY_MS: u64 = 86_400_000;
+
+/// The Unix time corresponding to an RTC reading, as measured from a time
+/// source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub rtc_ms: u64,
+    pub unix_ms: u64,
+    /// How far `unix_ms` may be off, usually half the round trip.
+    pub error_ms: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct WallClock {
+    /// The last sync; `None` until the first.
+    reference: Option<Reference>,
+    /// How much faster real time passes than the RTC counts, in parts per
+    /// million.
+    drift_ppm: i32,
+    /// How far the rate may still be off.
+    rate_error_ppm: u32,
+}
+
+impl WallClock {
+    /// Unix time in milliseconds at RTC time `rtc_ms`, once synced.
+    pub fn now(&self, rtc_ms: u64) -> Option<u64> {
+        let reference = self.reference?;
+        let elapsed = rtc_ms as i64 - reference.rtc_ms as i64;
+        let corrected = elapsed + elapsed * self.drift_ppm as i64 / 1_000_000;
+        u64::try_from(reference.unix_ms as i64 + corrected).ok()
+    }
+
+    /// How far [`WallClock::now`] may be off at `rtc_ms`.
+    pub fn error_ms(&self, rtc_ms: u64) -> Option<u64> {
+        let reference = self.reference?;
+        let elapsed = rtc_ms.abs_diff(reference.rtc_ms);
+        Some(reference.error_ms + elapsed * self.rate_error_ppm() as u64 / 1_000_000)
+    }
+
+    /// Whether the clock is unset or may be off by more than `max_error_ms`.
+    pub fn needs_sync(&self, rtc_ms: u64, max_error_ms: u64) -> bool {
+        self.error_ms(rtc_ms)
+            .is_none_or(|error| error > max_error_ms)
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    pub fn drift_ppm(&self) -> i32 {
+        self.drift_ppm
+    }
+
+    /// Sets the clock from `reference` and returns how far off it was, in
+    /// milliseconds, if it was set before. When the previous sync is long
+    /// enough ago, the RTC rate is measured between the two, unless the
+    /// syncs are too coarse to improve on the rate already known.
+    pub fn sync(&mut self, reference: Reference) -> Option<i64> {
+        let Some(previous) = self.reference else {
+            self.reference = Some(reference);
+            return None;
+        };
+        let predicted = self.now(reference.rtc_ms)?;
+        let offset = reference.unix_ms as i64 - predicted as i64;
+
+        if reference.rtc_ms >= previous.rtc_ms + MIN_DRIFT_SPAN_MS {
+            let elapsed = (reference.rtc_ms - previous.rtc_ms) as i64;
+            let real = reference.unix_ms as i64 - previous.unix_ms as i64;
+            let drift = (real - elapsed) * 1_000_000 / elapsed;
+            // What the two syncs' own errors allow for the new rate.
+            let measurement = (reference.error_ms + previous.error_ms) * 1_000_000 / elapsed as u64;
+            if drift.abs() <= MAX_DRIFT_PPM && measurement < self.rate_error_ppm() as u64 {
+                // How wrong the last correction turned out.
+                let residual = offset.unsigned_abs() * 1_000_000 / elapsed as u64;
+                self.drift_ppm = drift as i32;
+                self.rate_error_ppm = (residual + measurement)
+                    .max(MEASURED_FLOOR_PPM as u64)
+                    .min(UNMEASURED_PPM as u64) as u32;
+            }
+        }
+        self.reference = Some(reference);
+        Some(offset)
+    }
+
+    fn rate_error_ppm(&self) -> u32 {
+        match self.rate_error_ppm {
+            0 => UNMEASURED_PPM,
+            measured => measured,
+        }
+    }
+
+    pub fn to_words(&self) -> [u32; CLOCK_WORDS] {
+        let mut words = [0; CLOCK_WORDS];
+        if let Some(reference) = self.reference {
+            words[0] = 1;
+            words[1..3].copy_from_slice(&persist::split_u64(reference.rtc_ms));
+            words[3..5].copy_from_slice(&persist::split_u64(reference.unix_ms));
+            words[5..7].copy_from_slice(&persist::split_u64(reference.error_ms));
+        }
+        words[7] = self.drift_ppm as u32;
+        words[8] = self.rate_error_ppm;
+        persist::seal(&mut words, CLOCK_MAGIC);
+        words
+    }
+
+    /// Restores a clock saved by [`WallClock::to_words`]. Memory without a
+    /// valid check word gives an unset clock.
+    pub fn from_words(words: &[u32; CLOCK_WORDS]) -> Self {
+        if !persist::is_sealed(words, CLOCK_MAGIC) {
+            return Self::default();
+        }
+        Self {
+            reference: (words[0] != 0).then(|| Reference {
+                rtc_ms: persist::join_u64(&words[1..3]),
+                unix_ms: persist::join_u64(&words[3..5]),
+                error_ms: persist::join_u64(&words[5..7]),
+            }),
+            drift_ppm: words[7] as i32,
+            rate_error_ppm: words[8],
+        }
+    }
+}
+
+/// Unix time in milliseconds, displayed in ISO 8601 in UTC, e.g.
+/// `2025-03-01T14:05:09.250Z`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timestamp(pub u64);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = civil_from_days((self.0 / DAY_MS) as i64);
+        let ms = self.0 % DAY_MS;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1_000 % 60,
+            ms % 1_000
+        )
+    }
+}
+
+/// Days from 1970-01-01 to the given date in the proleptic Gregorian
+/// calendar, the inverse of [`civil_from_days`].
+pub(crate) fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (month as i64 + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` after 1970-01-01 in the proleptic Gregorian calendar, as
+/// year, month and day (Howard Hinnant's algorithm).
+pub(crate) fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000;
+    const HOUR: u64 = 60 * MINUTE;
+    // 2025-03-01T00:00:00Z
+    const MARCH_2025: u64 = 1_740_787_200_000;
+
+    fn reference(rtc_ms: u64, unix_ms: u64, error_ms: u64) -> Reference {
+        Reference {
+            rtc_ms,
+            unix_ms,
+            error_ms,
+        }
+    }
+
+    #[test]
+    fn unset_until_first_sync() {
+        let mut clock = WallClock::default();
+        assert_eq!(clock.now(1_000), None);
+        assert!(clock.needs_sync(1_000, 1_000));
+        assert_eq!(clock.sync(reference(1_000, MARCH_2025, 20)), None);
+        assert_eq!(clock.now(1_000), Some(MARCH_2025));
+        assert_eq!(clock.now(61_000), Some(MARCH_2025 + MINUTE));
+        assert!(!clock.needs_sync(61_000, 1_000));
+    }
+
+    #[test]
+    fn measures_and_corrects_drift() {
+        // The RTC runs 0.1% slow: it counts 999 ms for each real second.
+        let rtc = |real_ms: u64| real_ms * 999 / 1_000;
+        let mut clock = WallClock::default();
+        clock.sync(reference(rtc(0), MARCH_2025, 10));
+        // Uncorrected, the clock is 3.6 s behind after an hour.
+        let offset = clock.sync(reference(rtc(HOUR), MARCH_2025 + HOUR, 10));
+        assert_eq!(offset, Some(3_600));
+        assert_eq!(clock.drift_ppm(), 1_001);
+        // The next hour is extrapolated to within a few milliseconds.
+        let now = clock.now(rtc(2 * HOUR)).unwrap();
+        assert!(now.abs_diff(MARCH_2025 + 2 * HOUR) <= 5, "{}", now);
+    }
+
+    #[test]
+    fn close_syncs_do_not_measure_drift() {
+        let mut clock = WallClock::default();
+        clock.sync(reference(0, MARCH_2025, 100));
+        assert_eq!(
+            clock.sync(reference(MINUTE, MARCH_2025 + MINUTE + 200, 100)),
+            Some(200)
+        );
+        assert_eq!(clock.drift_ppm(), 0);
+        // Implausible rates are a bad sync, not drift.
+        clock.sync(reference(HOUR, MARCH_2025 + 2 * HOUR, 100));
+        assert_eq!(clock.drift_ppm(), 0);
+    }
+
+    #[test]
+    fn resyncs_when_error_grows() {
+        let mut clock = WallClock::default();
+        clock.sync(reference(0, MARCH_2025, 50));
+        // Unmeasured, the rate may be 2000 ppm off: 7.2 s an hour.
+        assert_eq!(clock.error_ms(HOUR), Some(7_250));
+        assert!(!clock.needs_sync(10 * MINUTE, 2_000));
+        assert!(clock.needs_sync(20 * MINUTE, 2_000));
+        // Once measured well, an hour drifts by well under a second.
+        clock.sync(reference(HOUR, MARCH_2025 + HOUR + 100, 50));
+        assert!(clock.error_ms(2 * HOUR).unwrap() < 500);
+        assert!(!clock.needs_sync(2 * HOUR, 2_000));
+    }
+
+    #[test]
+    fn coarse_syncs_keep_a_better_rate() {
+        let mut clock = WallClock::default();
+        clock.sync(reference(0, MARCH_2025, 20));
+        clock.sync(reference(HOUR, MARCH_2025 + HOUR + 360, 20));
+        assert_eq!(clock.drift_ppm(), 100);
+        // Half a second either way over 15 minutes says less about the rate
+        // than the last measurement did.
+        let quarter = HOUR + 15 * MINUTE;
+        clock.sync(reference(quarter, MARCH_2025 + quarter + 500, 500));
+        assert_eq!(clock.drift_ppm(), 100);
+    }
+
+    #[test]
+    fn survives_rtc_memory() {
+        let mut clock = WallClock::default();
+        assert_eq!(WallClock::from_words(&clock.to_words()), clock);
+        clock.sync(reference(5_000, MARCH_2025, 12));
+        clock.sync(reference(HOUR, MARCH_2025 + HOUR - 2_000, 12));
+        assert_eq!(WallClock::from_words(&clock.to_words()), clock);
+        let mut words = clock.to_words();
+        words[3] ^= 1;
+        assert_eq!(WallClock::from_words(&words), WallClock::default());
+    }
+
+    #[test]
+    fn formats_iso_8601() {
+        assert_eq!(Timestamp(0).to_string(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            Timestamp(MARCH_2025 + 14 * HOUR + 5 * MINUTE + 9_250).to_string(),
+            "2025-03-01T14:05:09.250Z"
+        );
+        // A leap day.
+        assert_eq!(
+            Timestamp(1_709_164_800_000).to_string(),
+            "2024-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn converts_dates_both_ways() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2025, 3, 1), (MARCH_2025 / DAY_MS) as i64);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in (-800_000..800_000).step_by(13) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Quantities derived from temperature and relative humidity.
+//!
+//! Computing these on the station means every consumer of the upload sees
+//! the same numbers. The formulas work in `f32` and results are rounded back
+//! to the fixed-point units used elsewhere.
+
+use libm::{atanf, expf, logf, roundf, sqrtf};
+
+use crate::dht::Reading;
+
+// Magnus coefficients from Alduchov & Eskridge (1996), valid from -40 to
+// 50 °C over water.
+const MAGNUS_A: f32 = 17.625;
+const MAGNUS_B: f32 = 243.04;
+const MAGNUS_C: f32 = 6.1094;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Derived {
+    /// Dew point in tenths of a degree Celsius.
+    pub dew_point: i16,
+    /// NWS heat index in tenths of a degree Celsius.
+    pub heat_index: i16,
+    /// Canadian humidex, in tenths (its unit is nominally degrees Celsius).
+    pub humidex: i16,
+    /// Psychrometric wet-bulb temperature in tenths of a degree Celsius.
+    pub wet_bulb: i16,
+    /// Absolute humidity in tenths of a gram per cubic metre.
+    pub absolute_humidity: u16,
+}
+
+impl Derived {
+    /// Derives every quantity from one reading. Returns `None` at 0% relative
+    /// humidity, where the dew point is undefined.
+    pub fn from_reading(reading: &Reading) -> Option<Self> {
+        if reading.humidity == 0 {
+            return None;
+        }
+        let t = reading.temperature_celsius();
+        let rh = reading.humidity_percent().min(100.0);
+        let dew = dew_point(t, rh);
+        Some(Self {
+            dew_point: tenths(dew),
+            heat_index: tenths(heat_index(t, rh)),
+            humidex: tenths(humidex(t, dew)),
+            wet_bulb: tenths(wet_bulb(t, rh)),
+            absolute_humidity: tenths(absolute_humidity(t, rh)).max(0) as u16,
+        })
+    }
+}
+
+fn tenths(value: f32) -> i16 {
+    roundf(value * 10.0) as i16
+}
+
+/// Saturation vapour pressure over water in hPa (Magnus).
+pub fn saturation_vapor_pressure(t: f32) -> f32 {
+    MAGNUS_C * expf(MAGNUS_A * t / (t + MAGNUS_B))
+}
+
+/// Dew point in °C from temperature in °C and relative humidity in percent.
+pub fn dew_point(t: f32, rh: f32) -> f32 {
+    let gamma = logf(rh / 100.0) + MAGNUS_A * t / (MAGNUS_B + t);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+/// Heat index in °C following the NWS algorithm: Steadman's simple formula
+/// below 80 °F, otherwise the Rothfusz regression with the low- and
+/// high-humidity adjustments.
+pub fn heat_index(t: f32, rh: f32) -> f32 {
+    let f = t * 9.0 / 5.0 + 32.0;
+    let simple = 0.5 * (f + 61.0 + (f - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + f) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_2 * f + 10.143_331 * rh
+            - 0.224_755_4 * f * rh
+            - 0.006_837_83 * f * f
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * f * f * rh
+            + 0.000_852_82 * f * rh * rh
+            - 0.000_001_99 * f * f * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&f) {
+            hi -= (13.0 - rh) / 4.0 * sqrtf((17.0 - (f - 95.0).abs()) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&f) {
+            hi += (rh - 85.0) / 10.0 * ((87.0 - f) / 5.0);
+        }
+        hi
+    };
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Humidex from temperature and dew point, both in °C, as defined by
+/// Environment Canada.
+pub fn humidex(t: f32, dew_point: f32) -> f32 {
+    let e = 6.11 * expf(5417.753 * (1.0 / 273.16 - 1.0 / (273.15 + dew_point)));
+    t + 0.5555 * (e - 10.0)
+}
+
+/// Wet-bulb temperature in °C at sea-level pressure using Stull's (2011)
+/// empirical fit, good to within 0.3 °C for 5-99% humidity and -20 to 50 °C.
+pub fn wet_bulb(t: f32, rh: f32) -> f32 {
+    t * atanf(0.151_977 * sqrtf(rh + 8.313_659)) + atanf(t + rh) - atanf(rh - 1.676_331)
+        + 0.003_918_38 * rh * sqrtf(rh) * atanf(0.023_101 * rh)
+        - 4.686_035
+}
+
+/// Absolute humidity in g/m³ from temperature in °C and relative humidity in
+/// percent.
+pub fn absolute_humidity(t: f32, rh: f32) -> f32 {
+    // Vapour density from the ideal gas law: e / (R_v * T), with e in hPa.
+    saturation_vapor_pressure(t) * rh * 2.1674 / (273.15 + t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fahrenheit(celsius: f32) -> f32 {
+        celsius * 9.0 / 5.0 + 32.0
+    }
+
+    fn celsius(fahrenheit: f32) -> f32 {
+        (fahrenheit - 32.0) * 5.0 / 9.0
+    }
+
+    #[test]
+    fn dew_point_matches_reference_values() {
+        // (T °C, RH %, dew point °C)
+        let table = [
+            (25.0, 60.0, 16.7),
+            (20.0, 50.0, 9.3),
+            (30.0, 80.0, 26.2),
+            (0.0, 80.0, -3.0),
+            (-10.0, 90.0, -11.3),
+        ];
+        for (t, rh, expected) in table {
+            let got = dew_point(t, rh);
+            assert!((got - expected).abs() < 0.1, "{t} {rh}: {got}");
+        }
+        assert!((dew_point(15.0, 100.0) - 15.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn heat_index_matches_nws_table() {
+        // (T °F, RH %, heat index °F) from the NWS heat index chart.
+        let table = [
+            (80.0, 40.0, 80.0),
+            (90.0, 70.0, 106.0),
+            (96.0, 65.0, 121.0),
+            (100.0, 40.0, 109.0),
+            (100.0, 50.0, 118.0),
+            (84.0, 90.0, 98.0),
+        ];
+        for (t, rh, expected) in table {
+            let got = fahrenheit(heat_index(celsius(t), rh));
+            assert!((got - expected).abs() < 1.0, "{t} {rh}: {got}");
+        }
+    }
+
+    #[test]
+    fn heat_index_applies_humidity_adjustments() {
+        // Dry air at 95 °F: the regression is reduced by (13 - RH) / 4.
+        let dry = fahrenheit(heat_index(celsius(95.0), 5.0));
+        assert!((dry - 88.2).abs() < 0.1, "{dry}");
+        // Humid air at 82 °F: raised by (RH - 85) / 10 * (87 - T) / 5.
+        let humid = fahrenheit(heat_index(celsius(82.0), 95.0));
+        assert!((humid - 94.0).abs() < 0.1, "{humid}");
+    }
+
+    #[test]
+    fn heat_index_in_cool_air_is_close_to_temperature() {
+        let hi = heat_index(15.0, 50.0);
+        assert!((hi - 15.0).abs() < 1.5, "{hi}");
+    }
+
+    #[test]
+    fn humidex_matches_environment_canada_table() {
+        // (T °C, RH %, humidex)
+        let table = [
+            (25.0, 60.0, 30.0),
+            (30.0, 40.0, 34.0),
+            (30.0, 70.0, 41.0),
+            (35.0, 50.0, 45.0),
+            (40.0, 30.0, 47.0),
+        ];
+        for (t, rh, expected) in table {
+            let got = humidex(t, dew_point(t, rh));
+            assert!((got - expected).abs() < 0.5, "{t} {rh}: {got}");
+        }
+    }
+
+    #[test]
+    fn wet_bulb_matches_stull() {
+        // The worked example in Stull (2011), and a psychrometric table value.
+        assert!((wet_bulb(20.0, 50.0) - 13.7).abs() < 0.1);
+        assert!((wet_bulb(30.0, 60.0) - 24.0).abs() < 0.3);
+    }
+
+    #[test]
+    fn absolute_humidity_matches_saturation_table() {
+        // Saturated air: 4.85 g/m³ at 0 °C, 17.3 at 20 °C, 30.4 at 30 °C.
+        let table = [(0.0, 4.85), (20.0, 17.3), (30.0, 30.4)];
+        for (t, expected) in table {
+            let got = absolute_humidity(t, 100.0);
+            assert!((got - expected).abs() < 0.15, "{t}: {got}");
+        }
+    }
+
+    #[test]
+    fn derives_fixed_point_values_from_reading() {
+        let reading = Reading {
+            temperature: 250,
+            humidity: 600,
+            pressure: None,
+        };
+        let derived = Derived::from_reading(&reading).unwrap();
+        assert_eq!(derived.dew_point, 167);
+        assert_eq!(derived.humidex, 301);
+        assert_eq!(derived.absolute_humidity, 138);
+    }
+
+    #[test]
+    fn dry_air_has_no_dew_point() {
+        let reading = Reading {
+            temperature: 250,
+            humidity: 0,
+            pressure: None,
+        };
+        assert_eq!(Derived::from_reading(&reading), None);
+    }
+}
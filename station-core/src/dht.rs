@@ -0,0 +1,469 @@
+//! DHT11 and DHT22/AM2302 temperature and humidity sensor drivers.
+//!
+//! Both sensors talk over a single open-drain data line: the host pulls it low
+//! to request a measurement, then the sensor answers with an 80us low / 80us
+//! high preamble followed by 40 bits, where the length of each high pulse
+//! encodes the bit value. They only differ in the length of the start pulse
+//! and in how the four data bytes are laid out.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use crate::sensor::SensorError;
+
+/// Monotonic microsecond time source used to bound waits on the data line.
+pub trait Clock {
+    fn now_micros(&self) -> u64;
+}
+
+/// A decoded measurement in fixed point, so DHT11 and DHT22 readings share a
+/// type without losing the DHT22's decimal place.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reading {
+    /// Relative humidity in tenths of a percent.
+    pub humidity: u16,
+    /// Temperature in tenths of a degree Celsius.
+    pub temperature: i16,
+    /// Barometric pressure in pascals, from sensors that measure it.
+    pub pressure: Option<u32>,
+}
+
+impl Reading {
+    pub fn humidity_percent(&self) -> f32 {
+        self.humidity as f32 / 10.0
+    }
+
+    pub fn temperature_celsius(&self) -> f32 {
+        self.temperature as f32 / 10.0
+    }
+}
+
+pub struct DHT11<D, C> {
+    pub delay: D,
+    pub clock: C,
+}
+
+/// The DHT22 uses the same wire protocol as the DHT11 with a shorter start
+/// pulse and 16-bit data words.
+pub struct DHT22<D, C>(DHT11<D, C>);
+
+/// Sensor family, for readers that drive the line themselves and only need the
+/// start pulse length and frame layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Model {
+    Dht11,
+    Dht22,
+}
+
+impl Model {
+    /// How long the host must hold the line low to request a measurement.
+    pub fn start_pulse_us(self) -> u32 {
+        match self {
+            Model::Dht11 => DHT11_START_US,
+            Model::Dht22 => DHT22_START_US,
+        }
+    }
+
+    pub fn decode(self, data: &[u8; 5]) -> Reading {
+        match self {
+            Model::Dht11 => decode_dht11(data),
+            Model::Dht22 => decode_dht22(data),
+        }
+    }
+}
+
+/// One level of a captured waveform and how long it lasted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Pulse {
+    pub high: bool,
+    pub micros: u16,
+}
+
+const DHT11_START_US: u32 = 20_000; // Datasheet asks for at least 18ms.
+const DHT22_START_US: u32 = 1_100; // Datasheet asks for 1-10ms.
+// Accepted length of the sensor's 80us response pulses in a captured trace.
+const PREAMBLE_MIN_US: u16 = 50;
+const PREAMBLE_MAX_US: u16 = 120;
+// High pulses longer than this are ones (nominally 70us), shorter are zeros
+// (nominally 26-28us).
+const ONE_THRESHOLD_US: u16 = 48;
+// Upper bounds for each wait on the line, in microseconds. They sit well above
+// the datasheet's nominal timings (20-200us to respond, 80us response pulses,
+// 50us bit gaps, 70us for a one) so only a misbehaving sensor trips them.
+const RESPONSE_TIMEOUT_US: u64 = 250;
+const PREAMBLE_TIMEOUT_US: u64 = 150;
+const BIT_TIMEOUT_US: u64 = 100;
+
+impl<D: DelayNs, C: Clock> DHT11<D, C> {
+    pub fn new(delay: D, clock: C) -> Self {
+        Self { delay, clock }
+    }
+
+    /// Triggers a measurement on `pin` and decodes the reply.
+    ///
+    /// `pin` must be configured as open-drain with its input buffer enabled,
+    /// so the line can be read back while it is released.
+    pub fn read<P: InputPin + OutputPin>(&mut self, pin: &mut P) -> Result<Reading, SensorError> {
+        self.read_raw(pin, DHT11_START_US)
+            .map(|data| Model::Dht11.decode(&data))
+    }
+
+    fn read_raw<P: InputPin + OutputPin>(
+        &mut self,
+        pin: &mut P,
+        start_us: u32,
+    ) -> Result<[u8; 5], SensorError> {
+        // An idle bus is pulled up; a low line here means a short or a sensor
+        // still busy with a previous frame.
+        if !is_high(pin)? {
+            return Err(SensorError::StuckLow);
+        }
+        pin.set_low().map_err(|_| SensorError::PinError)?;
+        self.delay.delay_us(start_us);
+        pin.set_high().map_err(|_| SensorError::PinError)?;
+        self.delay.delay_us(40);
+
+        self.wait_while(pin, true, RESPONSE_TIMEOUT_US, SensorError::NoResponse)?;
+        self.wait_while(pin, false, PREAMBLE_TIMEOUT_US, SensorError::StuckLow)?;
+        self.wait_while(pin, true, PREAMBLE_TIMEOUT_US, SensorError::StuckHigh)?;
+
+        let mut buf = [0; 5];
+        for (idx, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read_byte(pin, idx as u8 * 8)?;
+        }
+        verify_checksum(buf)
+    }
+
+    /// Reads one byte MSB first; `first_bit` is only used to report where a
+    /// timeout happened.
+    fn read_byte<P: InputPin>(&mut self, pin: &mut P, first_bit: u8) -> Result<u8, SensorError> {
+        let mut buf = 0u8;
+        for idx in 0..8u8 {
+            let timeout = SensorError::BitTimeout {
+                bit: first_bit + idx,
+            };
+            self.wait_while(pin, false, BIT_TIMEOUT_US, timeout)?;
+            self.delay.delay_us(30);
+            if is_high(pin)? {
+                buf |= 1 << (7 - idx);
+            }
+            self.wait_while(pin, true, BIT_TIMEOUT_US, timeout)?;
+        }
+        Ok(buf)
+    }
+
+    /// Busy-waits while the line reads `level`, failing with `error` once
+    /// `timeout_us` has passed.
+    fn wait_while<P: InputPin>(
+        &mut self,
+        pin: &mut P,
+        level: bool,
+        timeout_us: u64,
+        error: SensorError,
+    ) -> Result<(), SensorError> {
+        let start = self.clock.now_micros();
+        while is_high(pin)? == level {
+            if self.clock.now_micros().wrapping_sub(start) > timeout_us {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: DelayNs, C: Clock> DHT22<D, C> {
+    pub fn new(delay: D, clock: C) -> Self {
+        Self(DHT11::new(delay, clock))
+    }
+
+    /// Triggers a measurement on `pin` and decodes the reply, with the same pin
+    /// requirements as [`DHT11::read`].
+    pub fn read<P: InputPin + OutputPin>(&mut self, pin: &mut P) -> Result<Reading, SensorError> {
+        self.0
+            .read_raw(pin, DHT22_START_US)
+            .map(|data| Model::Dht22.decode(&data))
+    }
+}
+
+/// DHT11 frame: integral and decimal humidity bytes, then integral and decimal
+/// temperature bytes with the sign in the top bit of the integral byte.
+pub fn decode_dht11(data: &[u8; 5]) -> Reading {
+    let (negative, magnitude) = convert_signed(data[2]);
+    let temperature = magnitude as i16 * 10 + (data[3] & 0x7F).min(9) as i16;
+    Reading {
+        humidity: data[0] as u16 * 10 + data[1].min(9) as u16,
+        temperature: if negative { -temperature } else { temperature },
+        pressure: None,
+    }
+}
+
+/// DHT22 frame: big-endian humidity in tenths of a percent, then big-endian
+/// sign-magnitude temperature in tenths of a degree.
+pub fn decode_dht22(data: &[u8; 5]) -> Reading {
+    let (negative, magnitude) = convert_signed(data[2]);
+    let temperature = i16::from_be_bytes([magnitude, data[3]]);
+    Reading {
+        humidity: u16::from_be_bytes([data[0], data[1]]),
+        temperature: if negative { -temperature } else { temperature },
+        pressure: None,
+    }
+}
+
+/// Decodes a captured waveform into a checksummed frame.
+///
+/// `pulses` is the line as seen from the moment the host releases it (any
+/// leading low from the start pulse is skipped): the release, the sensor's
+/// 80us low / 80us high response and then a low gap and a high pulse per bit.
+/// Only the high pulse lengths carry data, so the decoder tolerates jitter on
+/// everything else.
+pub fn decode_pulses(pulses: &[Pulse]) -> Result<[u8; 5], SensorError> {
+    let start = pulses
+        .iter()
+        .position(|p| p.high)
+        .ok_or(SensorError::NoResponse)?;
+    let pulses = &pulses[start..];
+    let in_preamble = |p: &Pulse| (PREAMBLE_MIN_US..=PREAMBLE_MAX_US).contains(&p.micros);
+    let data_start = pulses
+        .windows(2)
+        .position(|w| !w[0].high && in_preamble(&w[0]) && w[1].high && in_preamble(&w[1]))
+        .ok_or(SensorError::NoResponse)?
+        + 2;
+
+    let mut bits = pulses[data_start..].chunks(2);
+    let mut buf = [0u8; 5];
+    for bit in 0..40u8 {
+        let high = match bits.next() {
+            Some([low, high]) if !low.high && high.high => high,
+            _ => return Err(SensorError::BitTimeout { bit }),
+        };
+        if high.micros as u64 > BIT_TIMEOUT_US {
+            return Err(SensorError::BitTimeout { bit });
+        }
+        if high.micros > ONE_THRESHOLD_US {
+            buf[bit as usize / 8] |= 1 << (7 - bit % 8);
+        }
+    }
+    verify_checksum(buf)
+}
+
+fn verify_checksum(buf: [u8; 5]) -> Result<[u8; 5], SensorError> {
+    let sum = buf[0]
+        .wrapping_add(buf[1])
+        .wrapping_add(buf[2])
+        .wrapping_add(buf[3]);
+
+    if buf[4] == sum {
+        Ok(buf)
+    } else {
+        Err(SensorError::ChecksumMismatch)
+    }
+}
+
+fn is_high<P: InputPin>(pin: &mut P) -> Result<bool, SensorError> {
+    pin.is_high().map_err(|_| SensorError::PinError)
+}
+
+pub fn convert_signed(signed: u8) -> (bool, u8) {
+    let sign = signed & 0x80 != 0;
+    let magnitude = signed & 0x7F;
+    (sign, magnitude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Bus, dht_frame};
+
+    fn read(bus: &Bus) -> Result<Reading, SensorError> {
+        let mut dht = DHT11::new(bus.delay(), bus.clock());
+        dht.read(&mut bus.pin())
+    }
+
+    fn read_dht22(bus: &Bus) -> Result<Reading, SensorError> {
+        let mut dht = DHT22::new(bus.delay(), bus.clock());
+        dht.read(&mut bus.pin())
+    }
+
+    #[test]
+    fn decodes_positive_reading() {
+        let bus = Bus::new(dht_frame([55, 0, 23, 0]));
+        assert_eq!(
+            read(&bus),
+            Ok(Reading {
+                humidity: 550,
+                temperature: 230,
+                pressure: None,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_negative_temperature() {
+        let bus = Bus::new(dht_frame([40, 0, 0x80 | 7, 0]));
+        assert_eq!(read(&bus).map(|r| r.temperature), Ok(-70));
+    }
+
+    #[test]
+    fn dht11_keeps_decimal_bytes() {
+        let reading = decode_dht11(&[55, 3, 0x80 | 2, 6, 0]);
+        assert_eq!(reading.humidity, 553);
+        assert_eq!(reading.temperature, -26);
+    }
+
+    #[test]
+    fn dht22_datasheet_examples() {
+        // AM2302 datasheet: 0x028C = 65.2 %RH, 0x015F = 35.1 C, 0x8065 = -10.1 C.
+        let reading = decode_dht22(&[0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+        assert_eq!(reading.humidity, 652);
+        assert_eq!(reading.temperature, 351);
+        assert_eq!(
+            decode_dht22(&[0x02, 0x8C, 0x80, 0x65, 0x73]).temperature,
+            -101
+        );
+    }
+
+    #[test]
+    fn dht22_reads_through_pin() {
+        let bus = Bus::new(dht_frame([0x02, 0x8C, 0x80, 0x65]));
+        let reading = read_dht22(&bus).unwrap();
+        assert_eq!(reading.humidity_percent(), 65.2);
+        assert_eq!(reading.temperature_celsius(), -10.1);
+        assert!(bus.host_low_us() < 10_000);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut frame = dht_frame([55, 0, 23, 0]);
+        // Flip the last bit of the checksum byte.
+        let last_high = frame.len() - 2;
+        frame[last_high].1 = if frame[last_high].1 > 50 { 26 } else { 70 };
+        let bus = Bus::new(frame);
+        assert_eq!(read(&bus), Err(SensorError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn reports_no_response_when_line_stays_high() {
+        let bus = Bus::new(Vec::new());
+        assert_eq!(read(&bus), Err(SensorError::NoResponse));
+    }
+
+    #[test]
+    fn reports_stuck_low() {
+        let bus = Bus::new(vec![(true, 30), (false, 1_000_000)]);
+        assert_eq!(read(&bus), Err(SensorError::StuckLow));
+    }
+
+    #[test]
+    fn reports_stuck_high_after_preamble() {
+        let bus = Bus::new(vec![(true, 30), (false, 80), (true, 1_000_000)]);
+        assert_eq!(read(&bus), Err(SensorError::StuckHigh));
+    }
+
+    #[test]
+    fn reports_bit_timeout_on_truncated_frame() {
+        let mut frame = dht_frame([55, 0, 23, 0]);
+        // Preamble is three segments, each bit two more; keep 12 bits and
+        // then hold the line low as if the sensor had browned out.
+        frame.truncate(3 + 12 * 2);
+        frame.push((false, 1_000_000));
+        let bus = Bus::new(frame);
+        let err = read(&bus).unwrap_err();
+        assert_eq!(err, SensorError::BitTimeout { bit: 12 });
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn every_wait_is_bounded() {
+        // A line stuck high mid-frame must not hang the read either.
+        let mut frame = dht_frame([55, 0, 23, 0]);
+        frame.truncate(3 + 20 * 2 + 1);
+        frame.push((true, 1_000_000));
+        let bus = Bus::new(frame);
+        assert_eq!(read(&bus), Err(SensorError::BitTimeout { bit: 20 }));
+    }
+
+    #[test]
+    fn releases_line_after_start_pulse() {
+        let bus = Bus::new(dht_frame([55, 0, 23, 0]));
+        read(&bus).unwrap();
+        let start = bus.host_low_us();
+        assert!(start >= 18_000, "start pulse too short: {start}us");
+    }
+
+    /// A trace as the RMT captures it: the tail of the host's start pulse, then
+    /// the sensor's reply.
+    fn trace(data: [u8; 4]) -> Vec<Pulse> {
+        let mut pulses = vec![Pulse {
+            high: false,
+            micros: 7,
+        }];
+        pulses.extend(dht_frame(data).into_iter().map(|(high, us)| Pulse {
+            high,
+            micros: us as u16,
+        }));
+        pulses
+    }
+
+    #[test]
+    fn decodes_clean_trace() {
+        let frame = decode_pulses(&trace([0x02, 0x8C, 0x01, 0x5F])).unwrap();
+        assert_eq!(frame, [0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+    }
+
+    #[test]
+    fn decodes_jittered_trace() {
+        let jitter = [12i16, -9, 7, -11, 3, 14, -6, 0];
+        let mut pulses = trace([55, 4, 23, 8]);
+        for (pulse, j) in pulses.iter_mut().zip(jitter.iter().cycle()) {
+            pulse.micros = (pulse.micros as i16 + j) as u16;
+        }
+        assert_eq!(decode_pulses(&pulses), Ok([55, 4, 23, 8, 90]));
+    }
+
+    #[test]
+    fn decodes_trace_without_start_pulse_tail() {
+        let pulses = trace([55, 0, 23, 0]);
+        assert_eq!(decode_pulses(&pulses[1..]), Ok([55, 0, 23, 0, 78]));
+    }
+
+    #[test]
+    fn truncated_trace_reports_missing_bit() {
+        let mut pulses = trace([55, 0, 23, 0]);
+        // Tail, release and preamble take four entries, each bit two more.
+        pulses.truncate(4 + 33 * 2 + 1);
+        assert_eq!(
+            decode_pulses(&pulses),
+            Err(SensorError::BitTimeout { bit: 33 })
+        );
+    }
+
+    #[test]
+    fn trace_without_preamble_is_no_response() {
+        let pulses = [
+            Pulse {
+                high: false,
+                micros: 900,
+            },
+            Pulse {
+                high: true,
+                micros: 2000,
+            },
+        ];
+        assert_eq!(decode_pulses(&pulses), Err(SensorError::NoResponse));
+        assert_eq!(decode_pulses(&[]), Err(SensorError::NoResponse));
+    }
+
+    #[test]
+    fn trace_checksum_is_verified() {
+        let mut pulses = trace([55, 0, 23, 0]);
+        let last_high = pulses.len() - 2;
+        pulses[last_high].micros = 70;
+        assert_eq!(decode_pulses(&pulses), Err(SensorError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn convert_signed_splits_sign_bit() {
+        assert_eq!(convert_signed(0x85), (true, 5));
+        assert_eq!(convert_signed(0x05), (false, 5));
+    }
+}
@@ -0,0 +1,225 @@
+//! Maxim DS18B20 digital thermometers on a shared 1-Wire bus.
+//!
+//! Several probes usually hang off the same pin, so conversions are started
+//! for all of them at once with SKIP ROM and each probe is then read out by
+//! its ROM code. Every scratchpad read is checked against its CRC-8.
+
+use crate::onewire::{self, OneWireBus, Rom};
+use crate::sensor::SensorError;
+
+/// Family code in the first ROM byte of every DS18B20.
+pub const FAMILY_CODE: u8 = 0x28;
+
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+const WRITE_SCRATCHPAD: u8 = 0x4E;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// 0.5 °C steps.
+    Bits9,
+    /// 0.25 °C steps.
+    Bits10,
+    /// 0.125 °C steps.
+    Bits11,
+    /// 0.0625 °C steps, the power-on default.
+    Bits12,
+}
+
+impl Resolution {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            9 => Some(Resolution::Bits9),
+            10 => Some(Resolution::Bits10),
+            11 => Some(Resolution::Bits11),
+            12 => Some(Resolution::Bits12),
+            _ => None,
+        }
+    }
+
+    /// Value of the configuration register selecting this resolution.
+    fn config(self) -> u8 {
+        match self {
+            Resolution::Bits9 => 0x1F,
+            Resolution::Bits10 => 0x3F,
+            Resolution::Bits11 => 0x5F,
+            Resolution::Bits12 => 0x7F,
+        }
+    }
+
+    /// Worst-case conversion time from the datasheet.
+    pub fn conversion_ms(self) -> u32 {
+        match self {
+            Resolution::Bits9 => 94,
+            Resolution::Bits10 => 188,
+            Resolution::Bits11 => 375,
+            Resolution::Bits12 => 750,
+        }
+    }
+
+    /// Mask clearing the temperature bits that are undefined at this
+    /// resolution.
+    fn mask(self) -> i16 {
+        match self {
+            Resolution::Bits9 => !0x7,
+            Resolution::Bits10 => !0x3,
+            Resolution::Bits11 => !0x1,
+            Resolution::Bits12 => !0x0,
+        }
+    }
+}
+
+/// Converts the temperature register (signed, 1/16 °C) into tenths of a
+/// degree, rounded to nearest.
+pub fn convert(raw: i16, resolution: Resolution) -> i16 {
+    let raw = (raw & resolution.mask()) as i32;
+    (raw * 10 + 8).div_euclid(16) as i16
+}
+
+/// Starts a temperature conversion on every device on the bus. Results are
+/// ready after [`Resolution::conversion_ms`] of the slowest-configured probe.
+pub fn start_conversion<B: OneWireBus>(bus: &mut B) -> Result<(), SensorError> {
+    bus.select(None)?;
+    bus.write_byte(CONVERT_T)
+}
+
+/// Reads and CRC-checks the 9-byte scratchpad of one probe.
+pub fn read_scratchpad<B: OneWireBus>(bus: &mut B, rom: &Rom) -> Result<[u8; 9], SensorError> {
+    bus.select(Some(rom))?;
+    bus.write_byte(READ_SCRATCHPAD)?;
+    let mut scratchpad = [0u8; 9];
+    for byte in scratchpad.iter_mut() {
+        *byte = bus.read_byte()?;
+    }
+    // A probe that dropped off the bus reads as all ones, which would
+    // otherwise only show up as a CRC failure.
+    if scratchpad.iter().all(|&b| b == 0xFF) {
+        return Err(SensorError::NoResponse);
+    }
+    if onewire::crc8(&scratchpad[..8]) != scratchpad[8] {
+        return Err(SensorError::ChecksumMismatch);
+    }
+    Ok(scratchpad)
+}
+
+/// Reads the result of the last conversion of one probe, in tenths of a
+/// degree Celsius.
+pub fn read_temperature<B: OneWireBus>(
+    bus: &mut B,
+    rom: &Rom,
+    resolution: Resolution,
+) -> Result<i16, SensorError> {
+    let scratchpad = read_scratchpad(bus, rom)?;
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    Ok(convert(raw, resolution))
+}
+
+/// Sets the resolution of one probe, keeping its alarm thresholds. The
+/// setting lives in the scratchpad only and is lost on power-down, so it is
+/// applied on every boot rather than copied to the probe's EEPROM.
+pub fn set_resolution<B: OneWireBus>(
+    bus: &mut B,
+    rom: &Rom,
+    resolution: Resolution,
+) -> Result<(), SensorError> {
+    let scratchpad = read_scratchpad(bus, rom)?;
+    if scratchpad[4] == resolution.config() {
+        return Ok(());
+    }
+    bus.select(Some(rom))?;
+    bus.write_byte(WRITE_SCRATCHPAD)?;
+    for byte in [scratchpad[2], scratchpad[3], resolution.config()] {
+        bus.write_byte(byte)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::OneWireSim;
+
+    #[test]
+    fn converts_datasheet_values() {
+        // Table 1 of the datasheet.
+        let table = [
+            (0x07D0u16, 1250),
+            (0x0550, 850),
+            (0x0191, 251),
+            (0x00A2, 101),
+            (0x0008, 5),
+            (0x0000, 0),
+            (0xFFF8, -5),
+            (0xFF5E, -101),
+            (0xFE6F, -251),
+            (0xFC90, -550),
+        ];
+        for (raw, tenths) in table {
+            assert_eq!(
+                convert(raw as i16, Resolution::Bits12),
+                tenths,
+                "{raw:#06x}"
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_undefined_bits_at_low_resolution() {
+        // 25.0625 °C with the LSB set, which is undefined at 9 bits.
+        assert_eq!(convert(0x0191, Resolution::Bits9), 250);
+        assert_eq!(convert(0x0191, Resolution::Bits12), 251);
+    }
+
+    #[test]
+    fn reads_each_probe_by_rom() {
+        let a = OneWireSim::rom(FAMILY_CODE, 1);
+        let b = OneWireSim::rom(FAMILY_CODE, 2);
+        let mut sim = OneWireSim::new(&[a, b]);
+        sim.set_raw(&a, 0x0191);
+        sim.set_raw(&b, -0x0191);
+        start_conversion(&mut sim).unwrap();
+        assert_eq!(read_temperature(&mut sim, &a, Resolution::Bits12), Ok(251));
+        assert_eq!(read_temperature(&mut sim, &b, Resolution::Bits12), Ok(-251));
+    }
+
+    #[test]
+    fn corrupt_scratchpad_is_rejected() {
+        let rom = OneWireSim::rom(FAMILY_CODE, 7);
+        let mut sim = OneWireSim::new(&[rom]);
+        start_conversion(&mut sim).unwrap();
+        sim.corrupt_scratchpad(&rom);
+        assert_eq!(
+            read_temperature(&mut sim, &rom, Resolution::Bits12),
+            Err(SensorError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn unknown_rom_reads_as_no_response() {
+        let rom = OneWireSim::rom(FAMILY_CODE, 7);
+        let mut sim = OneWireSim::new(&[rom]);
+        let other = OneWireSim::rom(FAMILY_CODE, 8);
+        assert_eq!(
+            read_temperature(&mut sim, &other, Resolution::Bits12),
+            Err(SensorError::NoResponse)
+        );
+    }
+
+    #[test]
+    fn sets_resolution_and_keeps_alarms() {
+        let rom = OneWireSim::rom(FAMILY_CODE, 3);
+        let mut sim = OneWireSim::new(&[rom]);
+        let before = sim.scratchpad(&rom);
+        set_resolution(&mut sim, &rom, Resolution::Bits10).unwrap();
+        let after = sim.scratchpad(&rom);
+        assert_eq!(after[4], 0x3F);
+        assert_eq!(after[2..4], before[2..4]);
+        assert_eq!(onewire::crc8(&after[..8]), after[8]);
+    }
+
+    #[test]
+    fn empty_bus_is_no_response() {
+        let mut sim = OneWireSim::new(&[]);
+        assert_eq!(start_conversion(&mut sim), Err(SensorError::NoResponse));
+    }
+}
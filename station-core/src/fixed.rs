@@ -0,0 +1,101 @@
+//! Formatting and parsing of the fixed-point values sensors report.
+
+use core::fmt;
+
+/// Displays a value stored in tenths with a single decimal place.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tenths(pub i32);
+
+impl fmt::Display for Tenths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{}", sign, abs / 10, abs % 10)
+    }
+}
+
+/// Displays `value / 10^places` with all `places` decimals.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Scaled {
+    pub value: i64,
+    pub places: u32,
+}
+
+impl fmt::Display for Scaled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.value < 0 { "-" } else { "" };
+        let abs = self.value.unsigned_abs();
+        let scale = 10u64.pow(self.places);
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = self.places as usize
+        )
+    }
+}
+
+/// Parses a decimal number into an integer scaled by `10^places`; further
+/// decimals are truncated.
+pub fn parse_decimal(s: &str, places: u32) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for byte in whole.bytes() {
+        value = value.checked_mul(10)?.checked_add(digit(byte)?)?;
+    }
+    let mut digits = fraction.bytes();
+    for _ in 0..places {
+        let next = match digits.next() {
+            Some(byte) => digit(byte)?,
+            None => 0,
+        };
+        value = value.checked_mul(10)?.checked_add(next)?;
+    }
+    if !digits.all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(if negative { -value } else { value })
+}
+
+fn digit(byte: u8) -> Option<i64> {
+    byte.is_ascii_digit().then(|| (byte - b'0') as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_one_decimal() {
+        assert_eq!(Tenths(231).to_string(), "23.1");
+        assert_eq!(Tenths(0).to_string(), "0.0");
+        assert_eq!(Tenths(-5).to_string(), "-0.5");
+        assert_eq!(Tenths(-101).to_string(), "-10.1");
+    }
+
+    #[test]
+    fn parses_decimals_to_fixed_point() {
+        assert_eq!(parse_decimal("-0.5", 1), Some(-5));
+        assert_eq!(parse_decimal("1.0234", 2), Some(102));
+        assert_eq!(parse_decimal("12", 3), Some(12_000));
+        assert_eq!(parse_decimal(".", 1), None);
+        assert_eq!(parse_decimal("1.2x", 1), None);
+    }
+
+    #[test]
+    fn formats_scaled_with_leading_zeros() {
+        let scaled = |value, places| Scaled { value, places }.to_string();
+        assert_eq!(scaled(481_173_000, 7), "48.1173000");
+        assert_eq!(scaled(-5_000_001, 7), "-0.5000001");
+        assert_eq!(scaled(1_001, 3), "1.001");
+    }
+}
@@ -0,0 +1,542 @@
+//! Append-only log of fixed-size records in a flash partition.
+//!
+//! The [`Backlog`](crate::backlog::Backlog) in RTC memory covers a few hours
+//! offline; a portable station can be away from WiFi for days. Samples that
+//! no longer fit there are appended to this log and drained oldest-first
+//! once the station is back online.
+//!
+//! The partition is used as a ring of sectors. Each sector starts with a
+//! header carrying a sequence number, so the oldest and newest sectors can be
+//! found again after a reset, followed by equal slots of
+//!
+//! ```text
+//! payload words | check word | consumed word
+//! ```
+//!
+//! A record is written in two steps: the payload, then the check word over
+//! it, which commits the record. A reset in between leaves a slot without a
+//! valid check, which is skipped. Once the server has a record, its consumed
+//! word is cleared. Every word is programmed once between erases, so nothing
+//! depends on the flash allowing bits to be cleared twice.
+//!
+//! A sector whose records have all been consumed is retired by clearing a
+//! word in its header; it is only erased when the ring comes around to it
+//! again. Sectors are therefore erased in turn, which spreads the wear evenly
+//! over the partition. When the ring is full, the oldest sector is erased to
+//! make room and its records are lost.
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::persist;
+
+/// Longest record the log accepts, in words.
+pub const MAX_RECORD_WORDS: usize = 32;
+
+const SECTOR_MAGIC: u32 = 0x4C4F_4753;
+const RECORD_MAGIC: u32 = 0x5245_4344;
+// Magic, sequence number, its complement, and the retired marker.
+const HEADER_WORDS: usize = 4;
+const HEADER_BYTES: u32 = HEADER_WORDS as u32 * 4;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Where a record sits in the log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    sector: u32,
+    slot: u32,
+}
+
+/// State of a slot found by reading it.
+enum Slot {
+    /// Never written: this and every later slot of the sector is free.
+    Free,
+    /// Written, but the write did not complete.
+    Torn,
+    Committed {
+        consumed: bool,
+    },
+}
+
+/// Log of records of `N` words in the partition at `offset`. It holds no
+/// reference to the flash, which is passed to each call, so the partition
+/// can share the flash with other users.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashLog<const N: usize> {
+    offset: u32,
+    sectors: u32,
+    sector_size: u32,
+    /// Sector records are appended to and its sequence number, `None` while
+    /// the partition holds no live sector.
+    head: Option<(u32, u32)>,
+    /// First free slot in the head sector.
+    next_slot: u32,
+    /// Oldest live sector.
+    tail: u32,
+}
+
+impl<const N: usize> FlashLog<N> {
+    const SLOT_WORDS: usize = N + 2;
+    const SLOT_BYTES: u32 = Self::SLOT_WORDS as u32 * 4;
+
+    /// Finds the log in the `len` bytes of flash at `offset`, which must be
+    /// sector aligned. An erased or foreign partition gives an empty log.
+    pub fn mount<F: NorFlash>(flash: &mut F, offset: u32, len: u32) -> Result<Self, F::Error> {
+        assert!(N > 0 && N <= MAX_RECORD_WORDS, "unsupported record size");
+        let sector_size = F::ERASE_SIZE as u32;
+        let mut log = Self {
+            offset,
+            sectors: len / sector_size,
+            sector_size,
+            head: None,
+            next_slot: 0,
+            tail: 0,
+        };
+        let mut oldest: Option<(u32, u32)> = None;
+        for sector in 0..log.sectors {
+            let Some(sequence) = log.live_sequence(flash, sector)? else {
+                continue;
+            };
+            if log.head.is_none_or(|(_, newest)| sequence > newest) {
+                log.head = Some((sector, sequence));
+            }
+            if oldest.is_none_or(|(_, first)| sequence < first) {
+                oldest = Some((sector, sequence));
+            }
+        }
+        if let (Some((head, _)), Some((tail, _))) = (log.head, oldest) {
+            log.tail = tail;
+            while log.next_slot < log.slots_per_sector() {
+                let position = Position {
+                    sector: head,
+                    slot: log.next_slot,
+                };
+                let mut record = [0; N];
+                if let Slot::Free = log.read_slot(flash, position, &mut record)? {
+                    break;
+                }
+                log.next_slot += 1;
+            }
+        }
+        Ok(log)
+    }
+
+    /// Number of records a sector holds.
+    pub fn slots_per_sector(&self) -> u32 {
+        (self.sector_size - HEADER_BYTES) / Self::SLOT_BYTES
+    }
+
+    /// Appends `record` and commits it. Returns the number of records that
+    /// had not been consumed yet and were lost because the oldest sector had
+    /// to be erased to make room.
+    pub fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        record: &[u32; N],
+    ) -> Result<usize, F::Error> {
+        let mut lost = 0;
+        if self.head.is_none() || self.next_slot >= self.slots_per_sector() {
+            lost = self.open_sector(flash)?;
+        }
+        let Some((sector, _)) = self.head else {
+            unreachable!("no sector after opening one");
+        };
+        let position = Position {
+            sector,
+            slot: self.next_slot,
+        };
+        // A failed write still uses up the slot.
+        self.next_slot += 1;
+
+        let mut words = [0; MAX_RECORD_WORDS + 1];
+        words[..N].copy_from_slice(record);
+        persist::seal(&mut words[..N + 1], RECORD_MAGIC);
+        let address = self.slot_address(position);
+        write_words(flash, address, &words[..N])?;
+        write_words(flash, address + N as u32 * 4, &words[N..N + 1])?;
+        Ok(lost)
+    }
+
+    /// Reads the oldest committed record that has not been consumed and comes
+    /// after `after`, or after the start of the log if `after` is `None`.
+    /// Returns its position, or `None` when there is no such record.
+    pub fn read<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        after: Option<Position>,
+        record: &mut [u32; N],
+    ) -> Result<Option<Position>, F::Error> {
+        let Some((head, _)) = self.head else {
+            return Ok(None);
+        };
+        let mut position = match after {
+            Some(after) => match self.next(after) {
+                Some(position) => position,
+                None => return Ok(None),
+            },
+            None => Position {
+                sector: self.tail,
+                slot: 0,
+            },
+        };
+        loop {
+            let at_end = position.sector == head && position.slot >= self.next_slot;
+            if at_end {
+                return Ok(None);
+            }
+            if self.live_sequence(flash, position.sector)?.is_some() {
+                match self.read_slot(flash, position, record)? {
+                    Slot::Committed { consumed: false } => return Ok(Some(position)),
+                    // The rest of the sector was never written
+                    Slot::Free if position.sector != head => {
+                        position = Position {
+                            sector: position.sector,
+                            slot: self.slots_per_sector() - 1,
+                        };
+                    }
+                    _ => {}
+                }
+            } else {
+                // A dead sector inside the ring, left by a reset while it
+                // was being opened
+                position = Position {
+                    sector: position.sector,
+                    slot: self.slots_per_sector() - 1,
+                };
+            }
+            match self.next(position) {
+                Some(next) => position = next,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Marks every record up to and including the one at `through` as
+    /// consumed, and retires the sectors that no longer hold anything else.
+    pub fn consume_through<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        through: Position,
+    ) -> Result<(), F::Error> {
+        let mut record = [0; N];
+        let mut after = None;
+        while let Some(position) = self.read(flash, after, &mut record)? {
+            if self.is_after(position, through) {
+                break;
+            }
+            let address = self.slot_address(position) + (N as u32 + 1) * 4;
+            write_words(flash, address, &[0])?;
+            after = Some(position);
+        }
+        while let Some((head, _)) = self.head {
+            if self.tail == head || self.pending_in(flash, self.tail)? > 0 {
+                break;
+            }
+            // Cleared rather than erased, so a reset cannot bring back
+            // half-erased records
+            let retired = self.sector_address(self.tail) + (HEADER_WORDS as u32 - 1) * 4;
+            write_words(flash, retired, &[0])?;
+            self.tail = (self.tail + 1) % self.sectors;
+        }
+        Ok(())
+    }
+
+    /// Erases the sector after the head, writes its header and makes it the
+    /// new head. Returns how many unconsumed records it held.
+    fn open_sector<F: NorFlash>(&mut self, flash: &mut F) -> Result<usize, F::Error> {
+        let (sector, sequence) = match self.head {
+            Some((head, sequence)) => ((head + 1) % self.sectors, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let mut lost = 0;
+        if self.head.is_some() && sector == self.tail {
+            // The ring is full
+            lost = self.pending_in(flash, sector)?;
+            self.tail = (sector + 1) % self.sectors;
+        }
+        let address = self.sector_address(sector);
+        flash.erase(address, address + self.sector_size)?;
+        write_words(flash, address, &[SECTOR_MAGIC, sequence, !sequence])?;
+        if self.head.is_none() {
+            self.tail = sector;
+        }
+        self.head = Some((sector, sequence));
+        self.next_slot = 0;
+        Ok(lost)
+    }
+
+    /// Sequence number of `sector` if it is part of the log.
+    fn live_sequence<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        sector: u32,
+    ) -> Result<Option<u32>, F::Error> {
+        let mut header = [0; HEADER_WORDS];
+        read_words(flash, self.sector_address(sector), &mut header)?;
+        let [magic, sequence, check, retired] = header;
+        let live = magic == SECTOR_MAGIC && sequence == !check && retired == ERASED;
+        Ok(live.then_some(sequence))
+    }
+
+    fn pending_in<F: NorFlash>(&self, flash: &mut F, sector: u32) -> Result<usize, F::Error> {
+        let mut record = [0; N];
+        let mut pending = 0;
+        for slot in 0..self.slots_per_sector() {
+            match self.read_slot(flash, Position { sector, slot }, &mut record)? {
+                Slot::Free => break,
+                Slot::Committed { consumed: false } => pending += 1,
+                _ => {}
+            }
+        }
+        Ok(pending)
+    }
+
+    fn read_slot<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        position: Position,
+        record: &mut [u32; N],
+    ) -> Result<Slot, F::Error> {
+        let mut words = [0; MAX_RECORD_WORDS + 2];
+        let words = &mut words[..Self::SLOT_WORDS];
+        read_words(flash, self.slot_address(position), words)?;
+        if words.iter().all(|&word| word == ERASED) {
+            return Ok(Slot::Free);
+        }
+        if !persist::is_sealed(&words[..N + 1], RECORD_MAGIC) {
+            return Ok(Slot::Torn);
+        }
+        record.copy_from_slice(&words[..N]);
+        // A partly cleared marker still means the server has the record.
+        Ok(Slot::Committed {
+            consumed: words[N + 1] != ERASED,
+        })
+    }
+
+    /// The slot after `position` in ring order, or `None` past the head.
+    fn next(&self, position: Position) -> Option<Position> {
+        let (head, _) = self.head?;
+        if position.slot + 1 < self.slots_per_sector() {
+            return Some(Position {
+                sector: position.sector,
+                slot: position.slot + 1,
+            });
+        }
+        if position.sector == head {
+            return None;
+        }
+        Some(Position {
+            sector: (position.sector + 1) % self.sectors,
+            slot: 0,
+        })
+    }
+
+    /// Whether `a` comes after `b` in ring order.
+    fn is_after(&self, a: Position, b: Position) -> bool {
+        let rank = |p: Position| ((p.sector + self.sectors - self.tail) % self.sectors, p.slot);
+        rank(a) > rank(b)
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.offset + sector * self.sector_size
+    }
+
+    fn slot_address(&self, position: Position) -> u32 {
+        self.sector_address(position.sector) + HEADER_BYTES + position.slot * Self::SLOT_BYTES
+    }
+}
+
+fn read_words<F: NorFlash>(flash: &mut F, address: u32, words: &mut [u32]) -> Result<(), F::Error> {
+    let mut bytes = [0u8; (MAX_RECORD_WORDS + 2) * 4];
+    let bytes = &mut bytes[..words.len() * 4];
+    flash.read(address, bytes)?;
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    Ok(())
+}
+
+fn write_words<F: NorFlash>(flash: &mut F, address: u32, words: &[u32]) -> Result<(), F::Error> {
+    let mut bytes = [0u8; (MAX_RECORD_WORDS + 2) * 4];
+    let bytes = &mut bytes[..words.len() * 4];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    flash.write(address, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Flash;
+
+    const SECTORS: u32 = 3;
+    const LEN: u32 = SECTORS * 4096;
+
+    type Log = FlashLog<4>;
+
+    fn record(idx: u32) -> [u32; 4] {
+        [idx, idx.wrapping_mul(0x9E37_79B9), !idx, 0x5A5A_0000 | idx]
+    }
+
+    fn mount(flash: &mut Flash) -> Log {
+        Log::mount(flash, 0, LEN).unwrap()
+    }
+
+    /// Records waiting to be consumed, oldest first, as their index.
+    fn pending(log: &Log, flash: &mut Flash) -> Vec<u32> {
+        let mut out = Vec::new();
+        let mut record = [0; 4];
+        let mut after = None;
+        while let Some(position) = log.read(flash, after, &mut record).unwrap() {
+            assert_eq!(record, self::record(record[0]), "corrupted record");
+            out.push(record[0]);
+            after = Some(position);
+        }
+        out
+    }
+
+    fn position_of(log: &Log, flash: &mut Flash, idx: u32) -> Position {
+        let mut record = [0; 4];
+        let mut after = None;
+        while let Some(position) = log.read(flash, after, &mut record).unwrap() {
+            if record[0] == idx {
+                return position;
+            }
+            after = Some(position);
+        }
+        panic!("record {} not found", idx);
+    }
+
+    #[test]
+    fn records_come_back_oldest_first_after_remount() {
+        let mut flash = Flash::new(SECTORS as usize);
+        let mut log = mount(&mut flash);
+        assert_eq!(pending(&log, &mut flash), []);
+        for idx in 0..10 {
+            assert_eq!(log.append(&mut flash, &record(idx)).unwrap(), 0);
+        }
+        let mut log = mount(&mut flash);
+        assert_eq!(pending(&log, &mut flash), (0..10).collect::<Vec<_>>());
+        log.append(&mut flash, &record(10)).unwrap();
+        assert_eq!(pending(&log, &mut flash).last(), Some(&10));
+    }
+
+    #[test]
+    fn consumed_records_stay_consumed() {
+        let mut flash = Flash::new(SECTORS as usize);
+        let mut log = mount(&mut flash);
+        for idx in 0..5 {
+            log.append(&mut flash, &record(idx)).unwrap();
+        }
+        let through = position_of(&log, &mut flash, 2);
+        log.consume_through(&mut flash, through).unwrap();
+        assert_eq!(pending(&log, &mut flash), [3, 4]);
+        assert_eq!(pending(&mount(&mut flash), &mut flash), [3, 4]);
+    }
+
+    #[test]
+    fn consumed_sectors_are_retired_and_reused_in_turn() {
+        let mut flash = Flash::new(SECTORS as usize);
+        let mut log = mount(&mut flash);
+        let per_sector = log.slots_per_sector();
+        let mut next = 0;
+        // Go round the ring twice, draining as we go.
+        for _ in 0..2 * SECTORS * per_sector {
+            log.append(&mut flash, &record(next)).unwrap();
+            next += 1;
+            if next % 50 == 0 {
+                let through = position_of(&log, &mut flash, next - 1);
+                log.consume_through(&mut flash, through).unwrap();
+                assert_eq!(pending(&log, &mut flash), []);
+            }
+        }
+        let log = mount(&mut flash);
+        let left = pending(&log, &mut flash);
+        assert_eq!(left, (next - next % 50..next).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_ring_drops_the_oldest_sector() {
+        let mut flash = Flash::new(SECTORS as usize);
+        let mut log = mount(&mut flash);
+        let per_sector = log.slots_per_sector();
+        let total = SECTORS * per_sector;
+        for idx in 0..total {
+            assert_eq!(log.append(&mut flash, &record(idx)).unwrap(), 0);
+        }
+        assert_eq!(
+            log.append(&mut flash, &record(total)).unwrap(),
+            per_sector as usize
+        );
+        let left = pending(&mount(&mut flash), &mut flash);
+        assert_eq!(left, (per_sector..=total).collect::<Vec<_>>());
+    }
+
+    /// Runs appends and consumes with the power cut after every possible
+    /// number of bytes, then checks that a remount finds every record that
+    /// was committed and not consumed, in order, and nothing else but
+    /// possibly the record being written at the time.
+    #[test]
+    fn survives_power_loss_at_any_point() {
+        let per_sector = Log::mount(&mut Flash::new(1), 0, 4096)
+            .unwrap()
+            .slots_per_sector();
+        // Enough to open a second sector and retire the first.
+        let appends = per_sector + 20;
+        let mut cut = 0;
+        loop {
+            let mut flash = Flash::new(SECTORS as usize);
+            let mut log = mount(&mut flash);
+            flash.cut_power_after(cut);
+            let mut committed = Vec::new();
+            let mut consumed_through = None;
+            let mut in_flight = None;
+            let mut finished = true;
+            for idx in 0..appends {
+                in_flight = Some(idx);
+                if log.append(&mut flash, &record(idx)).is_err() {
+                    finished = false;
+                    break;
+                }
+                committed.push(idx);
+                in_flight = None;
+                if idx == per_sector + 5 {
+                    let through = position_of(&log, &mut flash, per_sector + 2);
+                    if log.consume_through(&mut flash, through).is_err() {
+                        finished = false;
+                        consumed_through = Some(per_sector + 2);
+                        break;
+                    }
+                    consumed_through = Some(per_sector + 2);
+                    committed.retain(|&i| i > per_sector + 2);
+                }
+            }
+            flash.restore_power();
+
+            let mut log = mount(&mut flash);
+            let found = pending(&log, &mut flash);
+            let mut expected = committed.clone();
+            if let Some(idx) = in_flight
+                && found.last() == Some(&idx)
+            {
+                expected.push(idx);
+            }
+            if let Some(through) = consumed_through
+                && !finished
+            {
+                // Cut while consuming: some records may already be marked.
+                expected.retain(|&i| i > through || found.contains(&i));
+            }
+            assert_eq!(found, expected, "power cut after {} bytes", cut);
+
+            // The log keeps working after the reset.
+            log.append(&mut flash, &record(1_000)).unwrap();
+            assert_eq!(pending(&log, &mut flash).last(), Some(&1_000));
+
+            if finished {
+                break;
+            }
+            cut += 7;
+        }
+    }
+}
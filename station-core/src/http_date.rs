@@ -0,0 +1,188 @@
+//! Clock references from the `Date` header of HTTP responses (RFC 7231).
+//!
+//! Every response from the ingest server says when it was sent, to the
+//! second. That is much coarser than SNTP, but it comes with the upload
+//! anyway, so it sets the clock on networks where UDP port 123 is blocked.
+//! [`reference`] finds the header and turns it into a clock [`Reference`];
+//! [`parse`] reads the date itself.
+
+use crate::clock::{self, Reference};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The Unix time, in milliseconds, of an HTTP date in any of the three
+/// formats RFC 7231 asks recipients to accept:
+///
+/// - `Sun, 06 Nov 1994 08:49:37 GMT` (IMF-fixdate, what servers send)
+/// - `Sunday, 06-Nov-94 08:49:37 GMT` (obsolete RFC 850 format)
+/// - `Sun Nov  6 08:49:37 1994` (ANSI C's `asctime()`)
+///
+/// Two-digit years from 70 on are taken to be in the 1900s, earlier ones in
+/// the 2000s. The day of the week is not checked.
+pub fn parse(date: &str) -> Option<u64> {
+    let (weekday, rest) = date.trim().split_once(' ')?;
+    let mut fields = rest.split_ascii_whitespace();
+    let (day, month, year, time);
+    if weekday.ends_with(',') {
+        let first = fields.next()?;
+        if let Some((d, rest)) = first.split_once('-') {
+            let (m, y) = rest.split_once('-')?;
+            let short = number(y, 2, 2)?;
+            day = d;
+            month = m;
+            year = short + if short < 70 { 2000 } else { 1900 };
+        } else {
+            day = first;
+            month = fields.next()?;
+            year = number(fields.next()?, 4, 4)?;
+        }
+        time = fields.next()?;
+        if fields.next()? != "GMT" {
+            return None;
+        }
+    } else {
+        month = fields.next()?;
+        day = fields.next()?;
+        time = fields.next()?;
+        year = number(fields.next()?, 4, 4)?;
+    }
+    if fields.next().is_some() {
+        return None;
+    }
+
+    let month = MONTHS.iter().position(|&name| name == month)? as u8 + 1;
+    let day = number(day, 1, 2)? as u8;
+    let days = clock::days_from_civil(year as i64, month, day);
+    // Rejects days past the end of the month, which would roll over.
+    if clock::civil_from_days(days) != (year as i64, month, day) {
+        return None;
+    }
+    let mut clock_fields = time.split(':');
+    let mut next = |max: u64| number(clock_fields.next()?, 2, 2).filter(|&value| value <= max);
+    // A leap second is allowed, and counted as the next one.
+    let seconds = next(23)? * 3_600 + next(59)? * 60 + next(60)?;
+    if clock_fields.next().is_some() {
+        return None;
+    }
+    u64::try_from(days)
+        .ok()
+        .map(|days| (days * 86_400 + seconds) * 1_000)
+}
+
+/// Reads the `Date` header from `response`, a whole HTTP response or just
+/// its head. `sent_rtc_ms` is the RTC time before the request went out and
+/// `received_rtc_ms` the time the response was in. The server stamped the
+/// response somewhere in between, and the date drops the milliseconds, so
+/// the reference is the middle of what that allows.
+pub fn reference(response: &str, sent_rtc_ms: u64, received_rtc_ms: u64) -> Option<Reference> {
+    let date = parse(header(response, "Date")?)?;
+    let spread = 1_000 + received_rtc_ms.saturating_sub(sent_rtc_ms);
+    Some(Reference {
+        rtc_ms: received_rtc_ms,
+        unix_ms: date + spread / 2,
+        error_ms: spread / 2 + 1,
+    })
+}
+
+/// The value of the first header called `name`, ignoring case, in the head
+/// of `response`.
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let head = response.split("\r\n\r\n").next()?;
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (field, value) = line.split_once(':')?;
+        field.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// `text` as a decimal number of `min` to `max` digits, and nothing else.
+fn number(text: &str, min: usize, max: usize) -> Option<u64> {
+    if !(min..=max).contains(&text.len()) || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1994-11-06T08:49:37Z, the example in RFC 7231.
+    const EXAMPLE_MS: u64 = 784_111_777_000;
+
+    #[test]
+    fn parses_all_three_formats() {
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(EXAMPLE_MS));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(EXAMPLE_MS));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(EXAMPLE_MS));
+        assert_eq!(
+            parse("Sat, 01 Mar 2025 14:05:09 GMT"),
+            Some(1_740_837_909_000)
+        );
+        assert_eq!(
+            parse("Saturday, 01-Mar-25 14:05:09 GMT"),
+            Some(1_740_837_909_000)
+        );
+        assert_eq!(
+            parse("Thu, 29 Feb 2024 23:59:60 GMT"),
+            Some(1_709_251_200_000)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 08:49:37 GMT extra",
+            "Sun, 06 nov 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Thu, 29 Feb 2023 00:00:00 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:37:00 GMT",
+            "Sun, 06 Nov 94 08:49:37 GMT",
+            "Sun, +6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 94",
+        ] {
+            assert_eq!(parse(date), None, "{:?}", date);
+        }
+    }
+
+    #[test]
+    fn finds_the_header() {
+        let response = "HTTP/1.1 200 OK\r\n\
+                        Server: Werkzeug/3.0.1 Python/3.11.2\r\n\
+                        date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                        Content-Type: application/json\r\n\
+                        \r\n\
+                        {\"Date\":\"Mon, 07 Nov 1994 00:00:00 GMT\"}";
+        assert_eq!(
+            header(response, "Date"),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+        assert_eq!(header(response, "Content-Length"), None);
+        // A date only in the body is not a header.
+        assert_eq!(header("HTTP/1.0 200 OK\r\n\r\nDate: x", "Date"), None);
+    }
+
+    #[test]
+    fn spreads_the_error_over_the_round_trip() {
+        let response = "HTTP/1.0 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n";
+        // Sent at RTC 10 000 and answered 200 ms later: stamped some time in
+        // the 1.2 s after the second began.
+        assert_eq!(
+            reference(response, 10_000, 10_200),
+            Some(Reference {
+                rtc_ms: 10_200,
+                unix_ms: EXAMPLE_MS + 600,
+                error_ms: 601,
+            })
+        );
+        assert_eq!(reference("HTTP/1.0 200 OK\r\n\r\n", 0, 100), None);
+    }
+}
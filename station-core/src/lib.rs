@@ -0,0 +1,37 @@
+//! Hardware-independent pieces of the weather station firmware.
+//!
+//! Everything in here is written against `embedded-hal` traits (or plain data)
+//! rather than `esp-hal` types, so it builds for the ESP32 and can also be
+//! exercised with `cargo test` on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod backlog;
+pub mod battery;
+pub mod bme280;
+pub mod calibration;
+pub mod clock;
+pub mod derived;
+pub mod dht;
+pub mod ds18b20;
+pub mod fixed;
+pub mod flash_log;
+pub mod http_date;
+pub mod network;
+pub mod nmea;
+pub mod onewire;
+pub mod persist;
+pub mod plausibility;
+pub mod pms;
+pub mod rain;
+pub mod report;
+pub mod sampling;
+pub mod scd4x;
+pub mod schedule;
+pub mod sensor;
+pub mod sht;
+pub mod sntp;
+pub mod wind;
+
+#[cfg(test)]
+mod mock;
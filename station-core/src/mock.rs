@@ -0,0 +1,605 @@
+//! Test doubles for host tests.
+//!
+//! A [`Registers`] stands in for a register-mapped I2C device, a
+//! [`Commands`] for a command-driven one and a [`OneWireSim`] for a 1-Wire
+//! bus populated with DS18B20 probes. A [`Bus`] replays a recorded waveform
+//! on a virtual time base shared by the pin, delay and clock handles it hands
+//! out. The waveform starts when the host releases the line after pulling it
+//! low, and every pin access costs one microsecond so that polling loops
+//! always make progress.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::pin::pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+use embedded_hal::i2c::{self, ErrorKind, I2c, NoAcknowledgeSource, Operation};
+use embedded_storage::nor_flash::{
+    self, NorFlash, NorFlashErrorKind, ReadNorFlash, check_erase, check_read, check_write,
+};
+
+use crate::dht::Clock;
+use crate::onewire::{self, OneWireBus, Rom};
+use crate::sensor::SensorError;
+
+const POLL_COST_NS: u64 = 1_000;
+
+/// One waveform segment: line level and duration in microseconds.
+pub type Segment = (bool, u32);
+
+struct State {
+    now_ns: u64,
+    waveform: Vec<Segment>,
+    host_low_since: Option<u64>,
+    released_at: Option<u64>,
+    last_host_low_ns: u64,
+}
+
+impl State {
+    fn level(&self) -> bool {
+        if self.host_low_since.is_some() {
+            return false;
+        }
+        let Some(released_at) = self.released_at else {
+            return true;
+        };
+        let mut offset = self.now_ns - released_at;
+        for &(level, us) in &self.waveform {
+            let len = us as u64 * 1_000;
+            if offset < len {
+                return level;
+            }
+            offset -= len;
+        }
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Bus(Rc<RefCell<State>>);
+
+impl Bus {
+    pub fn new(waveform: Vec<Segment>) -> Self {
+        Self(Rc::new(RefCell::new(State {
+            now_ns: 0,
+            waveform,
+            host_low_since: None,
+            released_at: None,
+            last_host_low_ns: 0,
+        })))
+    }
+
+    pub fn pin(&self) -> Pin {
+        Pin(self.clone())
+    }
+
+    pub fn delay(&self) -> Delay {
+        Delay(self.clone())
+    }
+
+    pub fn clock(&self) -> MockClock {
+        MockClock(self.clone())
+    }
+
+    /// Length of the most recent host-driven low pulse.
+    pub fn host_low_us(&self) -> u64 {
+        self.0.borrow().last_host_low_ns / 1_000
+    }
+
+    fn advance(&self, ns: u64) {
+        self.0.borrow_mut().now_ns += ns;
+    }
+}
+
+pub struct Pin(Bus);
+
+impl ErrorType for Pin {
+    type Error = Infallible;
+}
+
+impl InputPin for Pin {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        self.0.advance(POLL_COST_NS);
+        Ok(self.0.0.borrow().level())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+impl OutputPin for Pin {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        let mut state = self.0.0.borrow_mut();
+        if state.host_low_since.is_none() {
+            state.host_low_since = Some(state.now_ns);
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        let mut state = self.0.0.borrow_mut();
+        if let Some(since) = state.host_low_since.take() {
+            state.last_host_low_ns = state.now_ns - since;
+            state.released_at = Some(state.now_ns);
+        }
+        Ok(())
+    }
+}
+
+pub struct Delay(Bus);
+
+impl DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.advance(ns as u64);
+    }
+}
+
+pub struct MockClock(Bus);
+
+impl Clock for MockClock {
+    fn now_micros(&self) -> u64 {
+        self.0.0.borrow().now_ns / 1_000
+    }
+}
+
+/// Builds the reply a healthy DHT sensor gives for `data`, checksum included.
+pub fn dht_frame(data: [u8; 4]) -> Vec<Segment> {
+    let checksum = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    let mut frame = vec![(true, 30), (false, 80), (true, 80)];
+    for byte in data.into_iter().chain([checksum]) {
+        for bit in (0..8).rev() {
+            let one = byte & (1 << bit) != 0;
+            frame.push((false, 50));
+            frame.push((true, if one { 70 } else { 26 }));
+        }
+    }
+    frame.push((false, 50));
+    frame
+}
+
+/// Runs a future to completion. Mock sensors never actually wait, so polling
+/// in a loop with a no-op waker is enough.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Delay that returns immediately, for drivers whose tests do not care about
+/// elapsed time.
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// An I2C device exposing 256 byte-wide registers with an auto-incrementing
+/// register pointer, as most sensors do. Clones share the register file so a
+/// test can inspect what the driver wrote.
+#[derive(Clone)]
+pub struct Registers {
+    address: u8,
+    regs: Rc<RefCell<[u8; 256]>>,
+}
+
+impl Registers {
+    pub fn new(address: u8) -> Self {
+        Self {
+            address,
+            regs: Rc::new(RefCell::new([0; 256])),
+        }
+    }
+
+    pub fn set(&self, register: u8, values: &[u8]) {
+        let start = register as usize;
+        self.regs.borrow_mut()[start..start + values.len()].copy_from_slice(values);
+    }
+
+    pub fn get(&self, register: u8) -> u8 {
+        self.regs.borrow()[register as usize]
+    }
+}
+
+impl i2c::ErrorType for Registers {
+    type Error = ErrorKind;
+}
+
+impl I2c for Registers {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), ErrorKind> {
+        if address != self.address {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        let mut regs = self.regs.borrow_mut();
+        let mut pointer = 0usize;
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => {
+                    let Some((&register, values)) = bytes.split_first() else {
+                        continue;
+                    };
+                    pointer = register as usize;
+                    for value in values {
+                        regs[pointer] = *value;
+                        pointer = (pointer + 1) % 256;
+                    }
+                }
+                Operation::Read(buf) => {
+                    for byte in buf.iter_mut() {
+                        *byte = regs[pointer];
+                        pointer = (pointer + 1) % 256;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An I2C device driven by commands: every write is recorded, and each read
+/// is answered with the next queued response. A read with nothing queued is
+/// not acknowledged, as a sensor that is still busy would do.
+#[derive(Clone)]
+pub struct Commands {
+    address: u8,
+    writes: Rc<RefCell<Vec<Vec<u8>>>>,
+    reads: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Commands {
+    pub fn new(address: u8) -> Self {
+        Self {
+            address,
+            writes: Rc::default(),
+            reads: Rc::default(),
+        }
+    }
+
+    pub fn queue_read(&self, data: &[u8]) {
+        self.reads.borrow_mut().push_back(data.to_vec());
+    }
+
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.writes.borrow().clone()
+    }
+
+    pub fn pending_reads(&self) -> usize {
+        self.reads.borrow().len()
+    }
+}
+
+impl i2c::ErrorType for Commands {
+    type Error = ErrorKind;
+}
+
+impl I2c for Commands {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), ErrorKind> {
+        if address != self.address {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => self.writes.borrow_mut().push(bytes.to_vec()),
+                Operation::Read(buf) => {
+                    let response = self
+                        .reads
+                        .borrow_mut()
+                        .pop_front()
+                        .ok_or(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))?;
+                    let len = buf.len().min(response.len());
+                    buf[..len].copy_from_slice(&response[..len]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    /// Shifting in a ROM command.
+    RomCommand,
+    /// Taking part in SEARCH ROM; `step` 0 sends the bit, 1 its complement
+    /// and 2 receives the master's choice.
+    Search { bit: u8, step: u8 },
+    /// Comparing MATCH ROM against its own code.
+    Match { bit: u8 },
+    /// Shifting in a function command.
+    Function,
+    /// Shifting out the scratchpad.
+    ReadScratchpad { bit: u8 },
+    /// Shifting TH, TL and the configuration register in.
+    WriteScratchpad { bit: u8 },
+    /// Deselected or done until the next reset.
+    Idle,
+}
+
+struct Probe {
+    rom: Rom,
+    scratchpad: [u8; 9],
+    /// Temperature register value latched by the next CONVERT T.
+    raw: i16,
+    phase: Phase,
+    command: u8,
+    command_bits: u8,
+}
+
+impl Probe {
+    /// The level this device drives during a read slot; released is high.
+    fn output(&self) -> bool {
+        match self.phase {
+            Phase::Search { bit, step: 0 } => self.rom_bit(bit),
+            Phase::Search { bit, step: 1 } => !self.rom_bit(bit),
+            Phase::ReadScratchpad { bit } if bit < 72 => {
+                self.scratchpad[bit as usize / 8] & (1 << (bit % 8)) != 0
+            }
+            _ => true,
+        }
+    }
+
+    fn rom_bit(&self, bit: u8) -> bool {
+        self.rom.0[bit as usize / 8] & (1 << (bit % 8)) != 0
+    }
+
+    fn after_read(&mut self) {
+        match &mut self.phase {
+            Phase::Search { step, .. } if *step < 2 => *step += 1,
+            Phase::ReadScratchpad { bit } => *bit = bit.saturating_add(1),
+            _ => {}
+        }
+    }
+
+    fn write(&mut self, value: bool) {
+        match self.phase {
+            Phase::RomCommand | Phase::Function => {
+                self.command |= (value as u8) << self.command_bits;
+                self.command_bits += 1;
+                if self.command_bits == 8 {
+                    let command = self.command;
+                    self.command = 0;
+                    self.command_bits = 0;
+                    self.phase = match (self.phase, command) {
+                        (Phase::RomCommand, onewire::SEARCH_ROM) => {
+                            Phase::Search { bit: 0, step: 0 }
+                        }
+                        (Phase::RomCommand, onewire::MATCH_ROM) => Phase::Match { bit: 0 },
+                        (Phase::RomCommand, onewire::SKIP_ROM) => Phase::Function,
+                        (Phase::Function, 0x44) => {
+                            self.scratchpad[..2].copy_from_slice(&self.raw.to_le_bytes());
+                            self.scratchpad[8] = onewire::crc8(&self.scratchpad[..8]);
+                            Phase::Idle
+                        }
+                        (Phase::Function, 0xBE) => Phase::ReadScratchpad { bit: 0 },
+                        (Phase::Function, 0x4E) => Phase::WriteScratchpad { bit: 0 },
+                        _ => Phase::Idle,
+                    };
+                }
+            }
+            Phase::Search { bit, step: 2 } => {
+                self.phase = if value != self.rom_bit(bit) || bit == 63 {
+                    Phase::Idle
+                } else {
+                    Phase::Search {
+                        bit: bit + 1,
+                        step: 0,
+                    }
+                };
+            }
+            Phase::Match { bit } => {
+                self.phase = if value != self.rom_bit(bit) {
+                    Phase::Idle
+                } else if bit == 63 {
+                    Phase::Function
+                } else {
+                    Phase::Match { bit: bit + 1 }
+                };
+            }
+            Phase::WriteScratchpad { bit } => {
+                let byte = 2 + bit as usize / 8;
+                let mask = 1 << (bit % 8);
+                if value {
+                    self.scratchpad[byte] |= mask;
+                } else {
+                    self.scratchpad[byte] &= !mask;
+                }
+                self.phase = if bit == 23 {
+                    self.scratchpad[4] |= 0x1F;
+                    self.scratchpad[8] = onewire::crc8(&self.scratchpad[..8]);
+                    Phase::Idle
+                } else {
+                    Phase::WriteScratchpad { bit: bit + 1 }
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A 1-Wire bus with DS18B20-like devices on it, simulated slot by slot:
+/// every device follows the master's writes and read slots see the wired AND
+/// of what they drive.
+pub struct OneWireSim {
+    probes: Vec<Probe>,
+}
+
+impl OneWireSim {
+    pub fn new(roms: &[Rom]) -> Self {
+        let probes = roms
+            .iter()
+            .map(|&rom| {
+                // Power-on scratchpad: 85 °C, TH/TL alarms, 12-bit resolution.
+                let mut scratchpad = [0x50, 0x05, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, 0];
+                scratchpad[8] = onewire::crc8(&scratchpad[..8]);
+                Probe {
+                    rom,
+                    scratchpad,
+                    raw: 0x0550,
+                    phase: Phase::Idle,
+                    command: 0,
+                    command_bits: 0,
+                }
+            })
+            .collect();
+        Self { probes }
+    }
+
+    /// Builds a ROM code with a valid CRC from a family code and serial.
+    pub fn rom(family: u8, serial: u64) -> Rom {
+        let mut bytes = [0; 8];
+        bytes[0] = family;
+        bytes[1..7].copy_from_slice(&serial.to_le_bytes()[..6]);
+        bytes[7] = onewire::crc8(&bytes[..7]);
+        Rom(bytes)
+    }
+
+    /// Sets the temperature register value the probe latches on CONVERT T.
+    pub fn set_raw(&mut self, rom: &Rom, raw: i16) {
+        self.probe(rom).raw = raw;
+    }
+
+    pub fn scratchpad(&mut self, rom: &Rom) -> [u8; 9] {
+        self.probe(rom).scratchpad
+    }
+
+    /// Flips a bit in the scratchpad as the wire would, leaving the CRC stale.
+    pub fn corrupt_scratchpad(&mut self, rom: &Rom) {
+        self.probe(rom).scratchpad[0] ^= 0x01;
+    }
+
+    fn probe(&mut self, rom: &Rom) -> &mut Probe {
+        self.probes.iter_mut().find(|p| p.rom == *rom).unwrap()
+    }
+}
+
+impl OneWireBus for OneWireSim {
+    fn reset(&mut self) -> Result<bool, SensorError> {
+        for probe in &mut self.probes {
+            probe.phase = Phase::RomCommand;
+            probe.command = 0;
+            probe.command_bits = 0;
+        }
+        Ok(!self.probes.is_empty())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), SensorError> {
+        for probe in &mut self.probes {
+            probe.write(bit);
+        }
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, SensorError> {
+        let level = self.probes.iter().all(Probe::output);
+        for probe in &mut self.probes {
+            probe.after_read();
+        }
+        Ok(level)
+    }
+}
+
+/// NOR flash with the ESP32's geometry: erasing sets a 4 KiB sector to 0xFF,
+/// and writes can only clear bits.
+///
+/// [`Flash::cut_power_after`] simulates a power loss in the middle of an
+/// operation: the byte being programmed or erased when the budget runs out
+/// is left half done, and every operation fails until power is restored.
+#[derive(Clone)]
+pub struct Flash {
+    pub data: Vec<u8>,
+    /// Bytes that can still be programmed or erased, if power is to be cut.
+    power_left: Option<usize>,
+}
+
+impl Flash {
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: vec![0xFF; sectors * Self::ERASE_SIZE],
+            power_left: None,
+        }
+    }
+
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.power_left = Some(bytes);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power_left = None;
+    }
+
+    /// Uses up power for one byte; `false` once the power is gone.
+    fn spend(&mut self) -> bool {
+        match &mut self.power_left {
+            None => true,
+            Some(0) => false,
+            Some(left) => {
+                *left -= 1;
+                true
+            }
+        }
+    }
+}
+
+impl nor_flash::ErrorType for Flash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+        check_read(self, offset, bytes.len())?;
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.data[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), NorFlashErrorKind> {
+        check_erase(self, from, to)?;
+        for idx in from as usize..to as usize {
+            if !self.spend() {
+                self.data[idx] |= 0x0F;
+                return Err(NorFlashErrorKind::Other);
+            }
+            self.data[idx] = 0xFF;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), NorFlashErrorKind> {
+        check_write(self, offset, bytes.len())?;
+        let start = offset as usize;
+        for (idx, byte) in bytes.iter().enumerate() {
+            if !self.spend() {
+                self.data[start + idx] &= byte | 0xF0;
+                return Err(NorFlashErrorKind::Other);
+            }
+            self.data[start + idx] &= byte;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! What it takes to get back on the network quickly after deep sleep.
+//!
+//! A full reconnect scans every channel for the access point and then waits
+//! for a DHCP lease, which together keep the radio on for seconds. The
+//! access point the station last joined, and the lease it was given, are
+//! kept in RTC memory; the next wake joins that access point directly on
+//! its channel and reuses the address until the lease runs out. Either is
+//! forgotten as soon as it stops working, so the next attempt starts over.
+
+use core::net::Ipv4Addr;
+
+use crate::persist;
+
+/// Number of words [`LinkCache`] occupies in RTC memory.
+pub const LINK_WORDS: usize = 12;
+
+const LINK_MAGIC: u32 = 0x4C49_4E4B;
+
+const HAS_ACCESS_POINT: u32 = 1 << 0;
+const HAS_LEASE: u32 = 1 << 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AccessPoint {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+/// An address handed out by DHCP.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: [Option<Ipv4Addr>; 2],
+    /// RTC time at which the address must no longer be used.
+    pub expires_ms: u64,
+}
+
+impl Lease {
+    pub fn is_valid(&self, now_ms: u64) -> bool {
+        now_ms < self.expires_ms
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LinkCache {
+    pub access_point: Option<AccessPoint>,
+    pub lease: Option<Lease>,
+}
+
+impl LinkCache {
+    /// The cached lease, if it can still be used at `now_ms`.
+    pub fn lease(&self, now_ms: u64) -> Option<&Lease> {
+        self.lease.as_ref().filter(|lease| lease.is_valid(now_ms))
+    }
+
+    pub fn to_words(&self) -> [u32; LINK_WORDS] {
+        let mut words = [0; LINK_WORDS];
+        if let Some(ap) = &self.access_point {
+            words[0] |= HAS_ACCESS_POINT;
+            let [b0, b1, b2, b3, b4, b5] = ap.bssid;
+            words[1] = u32::from_le_bytes([b0, b1, b2, b3]);
+            words[2] = u32::from_le_bytes([b4, b5, ap.channel, 0]);
+        }
+        if let Some(lease) = &self.lease {
+            words[0] |= HAS_LEASE;
+            words[3] = lease.address.to_bits();
+            words[4] = lease.prefix_len as u32;
+            // 0.0.0.0 is never a usable gateway or DNS server.
+            let optional = |address: Option<Ipv4Addr>| address.map_or(0, Ipv4Addr::to_bits);
+            words[5] = optional(lease.gateway);
+            words[6] = optional(lease.dns_servers[0]);
+            words[7] = optional(lease.dns_servers[1]);
+            words[8..10].copy_from_slice(&persist::split_u64(lease.expires_ms));
+        }
+        persist::seal(&mut words, LINK_MAGIC);
+        words
+    }
+
+    /// Restores a cache saved by [`LinkCache::to_words`]. Memory without a
+    /// valid check word gives an empty cache, and a full reconnect.
+    pub fn from_words(words: &[u32; LINK_WORDS]) -> Self {
+        if !persist::is_sealed(words, LINK_MAGIC) {
+            return Self::default();
+        }
+        let access_point = (words[0] & HAS_ACCESS_POINT != 0).then(|| {
+            let [b0, b1, b2, b3] = words[1].to_le_bytes();
+            let [b4, b5, channel, _] = words[2].to_le_bytes();
+            AccessPoint {
+                bssid: [b0, b1, b2, b3, b4, b5],
+                channel,
+            }
+        });
+        let optional = |word: u32| (word != 0).then(|| Ipv4Addr::from_bits(word));
+        let lease = (words[0] & HAS_LEASE != 0).then(|| Lease {
+            address: Ipv4Addr::from_bits(words[3]),
+            prefix_len: words[4] as u8,
+            gateway: optional(words[5]),
+            dns_servers: [optional(words[6]), optional(words[7])],
+            expires_ms: persist::join_u64(&words[8..10]),
+        });
+        Self {
+            access_point,
+            lease,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> LinkCache {
+        LinkCache {
+            access_point: Some(AccessPoint {
+                bssid: [0x24, 0x0A, 0xC4, 0x12, 0x34, 0x56],
+                channel: 11,
+            }),
+            lease: Some(Lease {
+                address: Ipv4Addr::new(192, 168, 1, 57),
+                prefix_len: 24,
+                gateway: Some(Ipv4Addr::new(192, 168, 1, 1)),
+                dns_servers: [Some(Ipv4Addr::new(192, 168, 1, 1)), None],
+                expires_ms: 7_200_000,
+            }),
+        }
+    }
+
+    #[test]
+    fn survives_rtc_memory() {
+        let cache = cache();
+        assert_eq!(LinkCache::from_words(&cache.to_words()), cache);
+        let partial = LinkCache {
+            lease: None,
+            ..cache
+        };
+        assert_eq!(LinkCache::from_words(&partial.to_words()), partial);
+        assert_eq!(
+            LinkCache::from_words(&LinkCache::default().to_words()),
+            LinkCache::default()
+        );
+    }
+
+    #[test]
+    fn torn_memory_forgets_everything() {
+        let mut words = cache().to_words();
+        words[3] ^= 1 << 8;
+        assert_eq!(LinkCache::from_words(&words), LinkCache::default());
+        assert_eq!(
+            LinkCache::from_words(&[0; LINK_WORDS]),
+            LinkCache::default()
+        );
+    }
+
+    #[test]
+    fn lease_is_reused_until_it_expires() {
+        let cache = cache();
+        assert!(cache.lease(0).is_some());
+        assert!(cache.lease(7_199_999).is_some());
+        assert!(cache.lease(7_200_000).is_none());
+        // The access point outlives the lease.
+        assert!(cache.access_point.is_some());
+    }
+}
@@ -0,0 +1,642 @@
+//! NMEA 0183 parsing for GPS modules.
+//!
+//! GPS modules print one sentence per line over UART, typically at 9600 baud.
+//! Two sentences carry everything a reading needs: GGA (position, altitude,
+//! fix quality) and RMC (position and date). [`LineReader`] collects lines
+//! from the byte stream, [`parse`] checks a line's checksum and decodes it,
+//! and [`Tracker`] combines the latest of both into a [`Fix`].
+//!
+//! Coordinates are kept as integers in units of 1e-7 degrees (about a
+//! centimetre), so no floating point is needed.
+
+use core::str;
+
+use crate::fixed::parse_decimal;
+
+/// Longest sentence the standard allows, `$` and `*hh` included.
+pub const MAX_SENTENCE_LEN: usize = 82;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NmeaError {
+    /// The line does not have the `$...*hh` shape, or a field is invalid.
+    Malformed,
+    ChecksumMismatch,
+    /// A well-formed sentence of a type that is not decoded.
+    Unsupported,
+}
+
+/// Time of day in UTC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UtcTime {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl UtcTime {
+    pub fn seconds_of_day(&self) -> u32 {
+        self.hour as u32 * 3600 + self.minute as u32 * 60 + self.second as u32
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    /// Days since 1970-01-01.
+    pub fn days_since_epoch(&self) -> i64 {
+        // Howard Hinnant's days_from_civil.
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+}
+
+/// GGA fix quality indicator.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FixQuality {
+    Invalid,
+    Gps,
+    Dgps,
+    Pps,
+    Rtk,
+    FloatRtk,
+    Estimated,
+    Manual,
+    Simulation,
+}
+
+impl FixQuality {
+    fn from_digit(digit: u8) -> Option<FixQuality> {
+        Some(match digit {
+            0 => FixQuality::Invalid,
+            1 => FixQuality::Gps,
+            2 => FixQuality::Dgps,
+            3 => FixQuality::Pps,
+            4 => FixQuality::Rtk,
+            5 => FixQuality::FloatRtk,
+            6 => FixQuality::Estimated,
+            7 => FixQuality::Manual,
+            8 => FixQuality::Simulation,
+            _ => return None,
+        })
+    }
+
+    /// Short machine-readable name, used in uploads.
+    pub fn code(&self) -> &'static str {
+        match self {
+            FixQuality::Invalid => "none",
+            FixQuality::Gps => "gps",
+            FixQuality::Dgps => "dgps",
+            FixQuality::Pps => "pps",
+            FixQuality::Rtk => "rtk",
+            FixQuality::FloatRtk => "float_rtk",
+            FixQuality::Estimated => "estimated",
+            FixQuality::Manual => "manual",
+            FixQuality::Simulation => "simulation",
+        }
+    }
+
+    /// Whether the receiver computed a real position (estimated, manual
+    /// and simulated ones do not count).
+    pub fn is_fix(&self) -> bool {
+        matches!(
+            self,
+            FixQuality::Gps
+                | FixQuality::Dgps
+                | FixQuality::Pps
+                | FixQuality::Rtk
+                | FixQuality::FloatRtk
+        )
+    }
+}
+
+/// Latitude and longitude in 1e-7 degrees, north and east positive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub latitude: i32,
+    pub longitude: i32,
+}
+
+/// Global positioning fix data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Gga {
+    pub time: Option<UtcTime>,
+    pub position: Option<Position>,
+    pub quality: FixQuality,
+    pub satellites: u8,
+    /// Altitude above mean sea level in decimetres.
+    pub altitude: Option<i32>,
+}
+
+/// Recommended minimum data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rmc {
+    pub time: Option<UtcTime>,
+    /// Whether the receiver considers the data valid (status `A`).
+    pub valid: bool,
+    pub position: Option<Position>,
+    pub date: Option<Date>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sentence {
+    Gga(Gga),
+    Rmc(Rmc),
+}
+
+/// Collects the bytes of one sentence at a time. Anything before a `$` is
+/// skipped, and lines longer than the standard allows are dropped.
+#[derive(Debug, Clone)]
+pub struct LineReader {
+    buf: [u8; MAX_SENTENCE_LEN],
+    len: usize,
+    /// Inside a sentence, or discarding up to the next `$`.
+    active: bool,
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineReader {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_SENTENCE_LEN],
+            len: 0,
+            active: false,
+        }
+    }
+
+    /// Adds a byte and returns the line it completed, without the line
+    /// ending.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        match byte {
+            b'$' => {
+                self.buf[0] = byte;
+                self.len = 1;
+                self.active = true;
+                None
+            }
+            b'\r' | b'\n' if self.active => {
+                self.active = false;
+                Some(&self.buf[..self.len])
+            }
+            _ if self.active => {
+                if self.len == MAX_SENTENCE_LEN {
+                    self.active = false;
+                } else {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Checks the checksum of a `$...*hh` line and returns the part between `$`
+/// and `*`.
+pub fn verify(line: &[u8]) -> Result<&str, NmeaError> {
+    let rest = line.strip_prefix(b"$").ok_or(NmeaError::Malformed)?;
+    let star = rest
+        .iter()
+        .rposition(|&b| b == b'*')
+        .ok_or(NmeaError::Malformed)?;
+    let (body, checksum) = (&rest[..star], &rest[star + 1..]);
+    let [high, low] = checksum else {
+        return Err(NmeaError::Malformed);
+    };
+    let expected = hex_digit(*high)? << 4 | hex_digit(*low)?;
+    if body.iter().fold(0u8, |sum, &b| sum ^ b) != expected {
+        return Err(NmeaError::ChecksumMismatch);
+    }
+    str::from_utf8(body).map_err(|_| NmeaError::Malformed)
+}
+
+fn hex_digit(byte: u8) -> Result<u8, NmeaError> {
+    (byte as char)
+        .to_digit(16)
+        .map(|d| d as u8)
+        .ok_or(NmeaError::Malformed)
+}
+
+/// Parses one line, with or without its line ending.
+pub fn parse(line: &[u8]) -> Result<Sentence, NmeaError> {
+    let line = line.trim_ascii_end();
+    let body = verify(line)?;
+    let mut fields = body.split(',');
+    let address = fields.next().ok_or(NmeaError::Malformed)?;
+    // Two-letter talker (GP, GN, GL, ...) followed by the sentence type.
+    if address.len() != 5 || !address.is_ascii() {
+        return Err(NmeaError::Malformed);
+    }
+    let mut field = || fields.next().ok_or(NmeaError::Malformed);
+    match &address[2..] {
+        "GGA" => {
+            let time = parse_time(field()?)?;
+            let position = parse_position(field()?, field()?, field()?, field()?)?;
+            let quality = match field()? {
+                "" => FixQuality::Invalid,
+                digit => digit
+                    .parse()
+                    .ok()
+                    .and_then(FixQuality::from_digit)
+                    .ok_or(NmeaError::Malformed)?,
+            };
+            let satellites = optional(field()?, |s| s.parse().ok())?.unwrap_or(0);
+            let _hdop = field()?;
+            let altitude = optional(field()?, |s| parse_decimal(s, 1))?;
+            let altitude = altitude
+                .map(|value| i32::try_from(value).map_err(|_| NmeaError::Malformed))
+                .transpose()?;
+            Ok(Sentence::Gga(Gga {
+                time,
+                position,
+                quality,
+                satellites,
+                altitude,
+            }))
+        }
+        "RMC" => {
+            let time = parse_time(field()?)?;
+            let valid = field()? == "A";
+            let position = parse_position(field()?, field()?, field()?, field()?)?;
+            let _speed = field()?;
+            let _course = field()?;
+            let date = parse_date(field()?)?;
+            Ok(Sentence::Rmc(Rmc {
+                time,
+                valid,
+                position,
+                date,
+            }))
+        }
+        _ => Err(NmeaError::Unsupported),
+    }
+}
+
+/// Applies `parse` to a field that may be empty.
+fn optional<T>(field: &str, parse: impl FnOnce(&str) -> Option<T>) -> Result<Option<T>, NmeaError> {
+    if field.is_empty() {
+        return Ok(None);
+    }
+    parse(field).map(Some).ok_or(NmeaError::Malformed)
+}
+
+/// `hhmmss` or `hhmmss.sss`.
+fn parse_time(field: &str) -> Result<Option<UtcTime>, NmeaError> {
+    optional(field, |s| {
+        let (hms, _) = s.split_once('.').unwrap_or((s, ""));
+        if hms.len() != 6 || !hms.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let millis = parse_decimal(s, 3)? % 1000;
+        let time = UtcTime {
+            hour: hms[0..2].parse().ok()?,
+            minute: hms[2..4].parse().ok()?,
+            second: hms[4..6].parse().ok()?,
+            millisecond: millis as u16,
+        };
+        // 60 allows for a leap second.
+        (time.hour < 24 && time.minute < 60 && time.second <= 60).then_some(time)
+    })
+}
+
+/// `ddmmyy`; two-digit years are taken to be in this century.
+fn parse_date(field: &str) -> Result<Option<Date>, NmeaError> {
+    optional(field, |s| {
+        if s.len() != 6 || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let date = Date {
+            day: s[0..2].parse().ok()?,
+            month: s[2..4].parse().ok()?,
+            year: 2000 + s[4..6].parse::<u16>().ok()?,
+        };
+        ((1..=12).contains(&date.month) && (1..=31).contains(&date.day)).then_some(date)
+    })
+}
+
+/// Degrees and minutes (`ddmm.mmmm` or `dddmm.mmmm`) with a hemisphere,
+/// in 1e-7 degrees.
+fn parse_coordinate(value: &str, hemisphere: &str, max_degrees: i64) -> Option<i32> {
+    // Minutes scaled by 1e7 keep sub-centimetre precision after dividing
+    // by 60.
+    let scaled = parse_decimal(value, 7)?;
+    if scaled < 0 {
+        return None;
+    }
+    let degrees = scaled / 1_000_000_000;
+    let minutes = scaled % 1_000_000_000;
+    if minutes >= 60 * 10_000_000 {
+        return None;
+    }
+    let total = degrees * 10_000_000 + (minutes + 30) / 60;
+    if total > max_degrees * 10_000_000 {
+        return None;
+    }
+    match hemisphere {
+        "N" | "E" => Some(total as i32),
+        "S" | "W" => Some(-total as i32),
+        _ => None,
+    }
+}
+
+fn parse_position(
+    latitude: &str,
+    north_south: &str,
+    longitude: &str,
+    east_west: &str,
+) -> Result<Option<Position>, NmeaError> {
+    if latitude.is_empty() && longitude.is_empty() {
+        return Ok(None);
+    }
+    let latitude = match north_south {
+        "N" | "S" => parse_coordinate(latitude, north_south, 90),
+        _ => None,
+    };
+    let longitude = match east_west {
+        "E" | "W" => parse_coordinate(longitude, east_west, 180),
+        _ => None,
+    };
+    match (latitude, longitude) {
+        (Some(latitude), Some(longitude)) => Ok(Some(Position {
+            latitude,
+            longitude,
+        })),
+        _ => Err(NmeaError::Malformed),
+    }
+}
+
+/// Where and when a reading was taken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fix {
+    pub position: Position,
+    /// Altitude above mean sea level in decimetres.
+    pub altitude: Option<i32>,
+    pub quality: FixQuality,
+    pub satellites: u8,
+    pub time: UtcTime,
+    /// Only known once an RMC sentence has been seen.
+    pub date: Option<Date>,
+}
+
+impl Fix {
+    /// Seconds since the Unix epoch, if the date is known.
+    pub fn unix_time(&self) -> Option<i64> {
+        let date = self.date?;
+        Some(date.days_since_epoch() * 86_400 + self.time.seconds_of_day() as i64)
+    }
+}
+
+/// Keeps the latest GGA fix and RMC date.
+#[derive(Debug, Clone, Default)]
+pub struct Tracker {
+    gga: Option<Gga>,
+    date: Option<Date>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, sentence: &Sentence) {
+        match sentence {
+            Sentence::Gga(gga) => self.gga = Some(*gga),
+            Sentence::Rmc(rmc) if rmc.valid => self.date = rmc.date.or(self.date),
+            Sentence::Rmc(_) => {}
+        }
+    }
+
+    /// The latest fix, once the receiver has reported a real position.
+    pub fn fix(&self) -> Option<Fix> {
+        let gga = self.gga?;
+        if !gga.quality.is_fix() {
+            return None;
+        }
+        Some(Fix {
+            position: gga.position?,
+            altitude: gga.altitude,
+            quality: gga.quality,
+            satellites: gga.satellites,
+            time: gga.time?,
+            date: self.date,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GGA: &[u8] = b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+    const RMC: &[u8] = b"$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n";
+
+    /// Wraps `body` in `$` and a correct checksum.
+    fn sentence(body: &str) -> Vec<u8> {
+        let checksum = body.bytes().fold(0u8, |sum, b| sum ^ b);
+        format!("${}*{:02X}\r\n", body, checksum).into_bytes()
+    }
+
+    #[test]
+    fn parses_gga() {
+        let Sentence::Gga(gga) = parse(GGA).unwrap() else {
+            panic!("not a GGA sentence");
+        };
+        assert_eq!(
+            gga.time,
+            Some(UtcTime {
+                hour: 12,
+                minute: 35,
+                second: 19,
+                millisecond: 0
+            })
+        );
+        // 48 deg 07.038 min, 11 deg 31 min.
+        assert_eq!(
+            gga.position,
+            Some(Position {
+                latitude: 481_173_000,
+                longitude: 115_166_667
+            })
+        );
+        assert_eq!(gga.quality, FixQuality::Gps);
+        assert_eq!(gga.satellites, 8);
+        assert_eq!(gga.altitude, Some(5454));
+    }
+
+    #[test]
+    fn parses_rmc() {
+        let Sentence::Rmc(rmc) = parse(RMC).unwrap() else {
+            panic!("not an RMC sentence");
+        };
+        assert!(rmc.valid);
+        assert_eq!(
+            rmc.date,
+            Some(Date {
+                year: 2094,
+                month: 3,
+                day: 23
+            })
+        );
+    }
+
+    #[test]
+    fn southern_and_western_hemispheres_are_negative() {
+        let line = sentence("GNGGA,001500.25,3351.5500,S,15112.6000,W,2,11,0.7,-12.3,M,,M,,");
+        let Sentence::Gga(gga) = parse(&line).unwrap() else {
+            panic!("not a GGA sentence");
+        };
+        let position = gga.position.unwrap();
+        assert_eq!(position.latitude, -338_591_667);
+        assert_eq!(position.longitude, -1_512_100_000);
+        assert_eq!(gga.altitude, Some(-123));
+        assert_eq!(gga.time.unwrap().millisecond, 250);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut line = GGA.to_vec();
+        line[10] = b'6';
+        assert_eq!(parse(&line), Err(NmeaError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(parse(b"GPGGA,123519*47"), Err(NmeaError::Malformed));
+        assert_eq!(parse(b"$GPGGA,123519"), Err(NmeaError::Malformed));
+        assert_eq!(parse(b"$GPGGA*4"), Err(NmeaError::Malformed));
+        assert_eq!(parse(&sentence("GPGGA,123519")), Err(NmeaError::Malformed));
+        assert_eq!(
+            parse(&sentence(
+                "GPGGA,123519,9107.038,N,01131.000,E,1,08,0.9,545.4,M,,M,,"
+            )),
+            Err(NmeaError::Malformed)
+        );
+        assert_eq!(
+            parse(&sentence(
+                "GPGGA,123519,4807.038,X,01131.000,E,1,08,0.9,545.4,M,,M,,"
+            )),
+            Err(NmeaError::Malformed)
+        );
+    }
+
+    #[test]
+    fn other_sentences_are_unsupported() {
+        assert_eq!(
+            parse(&sentence("GPGSV,1,1,00")),
+            Err(NmeaError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn empty_fields_before_a_fix() {
+        let line = sentence("GPGGA,,,,,,0,00,99.99,,,,,,");
+        let Sentence::Gga(gga) = parse(&line).unwrap() else {
+            panic!("not a GGA sentence");
+        };
+        assert_eq!((gga.time, gga.position, gga.altitude), (None, None, None));
+        assert_eq!(gga.quality, FixQuality::Invalid);
+        let mut tracker = Tracker::new();
+        tracker.update(&Sentence::Gga(gga));
+        assert_eq!(tracker.fix(), None);
+    }
+
+    #[test]
+    fn line_reader_skips_noise_and_overlong_lines() {
+        let mut reader = LineReader::new();
+        let mut stream = b"\x00\xFFgarbage\r\n$GPGGA,12".to_vec();
+        stream.extend_from_slice(&[b'9'; MAX_SENTENCE_LEN]);
+        stream.extend_from_slice(b"\r\n");
+        stream.extend_from_slice(GGA);
+        let mut lines = Vec::new();
+        for byte in stream {
+            if let Some(line) = reader.push(byte) {
+                lines.push(line.to_vec());
+            }
+        }
+        assert_eq!(lines, [GGA.trim_ascii_end().to_vec()]);
+    }
+
+    #[test]
+    fn tracker_combines_position_and_date() {
+        let mut tracker = Tracker::new();
+        tracker.update(&parse(RMC).unwrap());
+        tracker.update(&parse(GGA).unwrap());
+        let fix = tracker.fix().unwrap();
+        assert_eq!(fix.position.latitude, 481_173_000);
+        assert_eq!(fix.date.unwrap().year, 2094);
+    }
+
+    #[test]
+    fn unix_time_from_date_and_time() {
+        let line = sentence("GPRMC,083000,A,4807.038,N,01131.000,E,0.0,0.0,171026,,,A");
+        let mut tracker = Tracker::new();
+        tracker.update(&parse(&line).unwrap());
+        tracker.update(&parse(GGA).unwrap());
+        // GGA's 12:35:19 on 2026-10-17.
+        assert_eq!(tracker.fix().unwrap().unix_time(), Some(1_792_240_519));
+        let leap_day = Date {
+            year: 2024,
+            month: 2,
+            day: 29,
+        };
+        assert_eq!(leap_day.days_since_epoch(), 19_782);
+    }
+
+    #[test]
+    fn survives_mutated_input() {
+        // Cheap in-tree fuzzing with a fixed seed; `fuzz/` has the
+        // coverage-guided version. Every input must be rejected or parsed
+        // without panicking, and anything accepted must have a valid
+        // checksum.
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let seeds = [GGA, RMC];
+        for _ in 0..20_000 {
+            let mut line = seeds[(next() % 2) as usize].to_vec();
+            for _ in 0..(next() % 4 + 1) {
+                let pos = (next() % line.len() as u64) as usize;
+                match next() % 3 {
+                    0 => line[pos] = next() as u8,
+                    1 => {
+                        line.remove(pos);
+                    }
+                    _ => line.insert(pos, b",0.-9NSEW*$"[(next() % 11) as usize]),
+                }
+                if line.is_empty() {
+                    break;
+                }
+            }
+            if parse(&line).is_ok() {
+                assert!(verify(line.trim_ascii_end()).is_ok());
+            }
+            let mut reader = LineReader::new();
+            for &byte in &line {
+                if let Some(found) = reader.push(byte) {
+                    let _ = parse(found);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Dallas/Maxim 1-Wire bus master.
+//!
+//! [`OneWire`] bit-bangs the standard-speed time slots on an open-drain pin,
+//! the same way the DHT driver drives its data line. Everything above the
+//! slot level (bytes, ROM commands, the ROM search) is written against the
+//! [`OneWireBus`] trait so it can be exercised on the host against simulated
+//! devices.
+
+use core::fmt;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use crate::sensor::SensorError;
+
+pub const SEARCH_ROM: u8 = 0xF0;
+pub const MATCH_ROM: u8 = 0x55;
+pub const SKIP_ROM: u8 = 0xCC;
+
+/// A device's 64-bit ROM code: family code, 48-bit serial number and CRC, in
+/// the order they come off the wire.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// The ROM as one integer, with the family code in the low byte.
+    pub fn id(&self) -> u64 {
+        u64::from_le_bytes(self.0)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        crc8(&self.0[..7]) == self.0[7]
+    }
+}
+
+impl fmt::Display for Rom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Dallas/Maxim CRC-8 (polynomial x^8 + x^5 + x^4 + 1, LSB first), used for
+/// ROM codes and scratchpads.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+/// Slot-level access to a 1-Wire bus.
+pub trait OneWireBus {
+    /// Sends a reset pulse and returns whether any device answered with a
+    /// presence pulse.
+    fn reset(&mut self) -> Result<bool, SensorError>;
+    fn write_bit(&mut self, bit: bool) -> Result<(), SensorError>;
+    fn read_bit(&mut self) -> Result<bool, SensorError>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), SensorError> {
+        for idx in 0..8 {
+            self.write_bit(byte & (1 << idx) != 0)?;
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<u8, SensorError> {
+        let mut byte = 0;
+        for idx in 0..8 {
+            if self.read_bit()? {
+                byte |= 1 << idx;
+            }
+        }
+        Ok(byte)
+    }
+
+    /// Resets the bus and addresses one device, or every device when `rom` is
+    /// `None`.
+    fn select(&mut self, rom: Option<&Rom>) -> Result<(), SensorError> {
+        if !self.reset()? {
+            return Err(SensorError::NoResponse);
+        }
+        match rom {
+            Some(rom) => {
+                self.write_byte(MATCH_ROM)?;
+                for byte in rom.0 {
+                    self.write_byte(byte)?;
+                }
+                Ok(())
+            }
+            None => self.write_byte(SKIP_ROM),
+        }
+    }
+}
+
+// Standard-speed slot timings from the Maxim 1-Wire application notes, in
+// microseconds.
+const RESET_LOW_US: u32 = 480;
+const PRESENCE_SAMPLE_US: u32 = 70;
+const RESET_RECOVERY_US: u32 = 410;
+const WRITE_ONE_LOW_US: u32 = 6;
+const WRITE_ONE_RELEASE_US: u32 = 64;
+const WRITE_ZERO_LOW_US: u32 = 60;
+const WRITE_ZERO_RELEASE_US: u32 = 10;
+const READ_LOW_US: u32 = 6;
+const READ_SAMPLE_US: u32 = 9;
+const READ_RELEASE_US: u32 = 55;
+
+/// Bit-banged bus master on an open-drain pin with its input buffer enabled
+/// and an external pull-up (4.7k for short runs).
+///
+/// The slots only tolerate a few microseconds of jitter; callers on a busy
+/// system should keep interrupts off for the duration of each slot.
+pub struct OneWire<P, D> {
+    pub pin: P,
+    pub delay: D,
+}
+
+impl<P: InputPin + OutputPin, D: DelayNs> OneWire<P, D> {
+    pub fn new(pin: P, delay: D) -> Self {
+        Self { pin, delay }
+    }
+
+    fn set_low(&mut self) -> Result<(), SensorError> {
+        self.pin.set_low().map_err(|_| SensorError::PinError)
+    }
+
+    fn release(&mut self) -> Result<(), SensorError> {
+        self.pin.set_high().map_err(|_| SensorError::PinError)
+    }
+
+    fn is_high(&mut self) -> Result<bool, SensorError> {
+        self.pin.is_high().map_err(|_| SensorError::PinError)
+    }
+}
+
+impl<P: InputPin + OutputPin, D: DelayNs> OneWireBus for OneWire<P, D> {
+    fn reset(&mut self) -> Result<bool, SensorError> {
+        if !self.is_high()? {
+            return Err(SensorError::StuckLow);
+        }
+        self.set_low()?;
+        self.delay.delay_us(RESET_LOW_US);
+        self.release()?;
+        self.delay.delay_us(PRESENCE_SAMPLE_US);
+        let present = !self.is_high()?;
+        self.delay.delay_us(RESET_RECOVERY_US);
+        Ok(present)
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), SensorError> {
+        let (low, release) = if bit {
+            (WRITE_ONE_LOW_US, WRITE_ONE_RELEASE_US)
+        } else {
+            (WRITE_ZERO_LOW_US, WRITE_ZERO_RELEASE_US)
+        };
+        self.set_low()?;
+        self.delay.delay_us(low);
+        self.release()?;
+        self.delay.delay_us(release);
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, SensorError> {
+        self.set_low()?;
+        self.delay.delay_us(READ_LOW_US);
+        self.release()?;
+        self.delay.delay_us(READ_SAMPLE_US);
+        let bit = self.is_high()?;
+        self.delay.delay_us(READ_RELEASE_US);
+        Ok(bit)
+    }
+}
+
+/// Enumerates the devices on a bus with the SEARCH ROM algorithm from Maxim
+/// application note 187, one ROM per call to [`RomSearch::next`].
+#[derive(Debug, Default)]
+pub struct RomSearch {
+    rom: [u8; 8],
+    last_discrepancy: u8,
+    done: bool,
+}
+
+impl RomSearch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Finds the next device, or `None` once every device has been returned.
+    pub fn next<B: OneWireBus>(&mut self, bus: &mut B) -> Result<Option<Rom>, SensorError> {
+        if self.done || !bus.reset()? {
+            self.done = true;
+            return Ok(None);
+        }
+        bus.write_byte(SEARCH_ROM)?;
+
+        let mut last_zero = 0;
+        for bit_number in 1..=64u8 {
+            let idx = (bit_number - 1) as usize;
+            let mask = 1 << (idx % 8);
+            let id_bit = bus.read_bit()?;
+            let complement = bus.read_bit()?;
+            let direction = match (id_bit, complement) {
+                // Nobody answered: devices left the bus mid-search.
+                (true, true) => {
+                    self.done = true;
+                    return Err(SensorError::NoResponse);
+                }
+                // All remaining devices agree on this bit.
+                (bit, complement) if bit != complement => bit,
+                // Discrepancy: retrace the previous path below the last
+                // branch point, take the one branch there, zeros after it.
+                _ => {
+                    let direction = if bit_number < self.last_discrepancy {
+                        self.rom[idx / 8] & mask != 0
+                    } else {
+                        bit_number == self.last_discrepancy
+                    };
+                    if !direction {
+                        last_zero = bit_number;
+                    }
+                    direction
+                }
+            };
+            if direction {
+                self.rom[idx / 8] |= mask;
+            } else {
+                self.rom[idx / 8] &= !mask;
+            }
+            bus.write_bit(direction)?;
+        }
+
+        self.last_discrepancy = last_zero;
+        self.done = last_zero == 0;
+        let rom = Rom(self.rom);
+        if !rom.is_valid() {
+            self.done = true;
+            return Err(SensorError::ChecksumMismatch);
+        }
+        Ok(Some(rom))
+    }
+}
+
+/// Collects up to `N` ROMs from the bus; devices beyond that are ignored.
+pub fn search_all<B: OneWireBus, const N: usize>(
+    bus: &mut B,
+) -> Result<heapless::Vec<Rom, N>, SensorError> {
+    let mut roms = heapless::Vec::new();
+    let mut search = RomSearch::new();
+    while let Some(rom) = search.next(bus)? {
+        if roms.push(rom).is_err() {
+            break;
+        }
+    }
+    Ok(roms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Bus, OneWireSim};
+
+    #[test]
+    fn crc_matches_application_note_example() {
+        // Maxim AN27: ROM 02 1C B8 01 00 00 00 has CRC A2.
+        assert_eq!(crc8(&[0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00]), 0xA2);
+    }
+
+    #[test]
+    fn formats_rom_in_wire_order() {
+        let rom = Rom([0x28, 0xFF, 0x4C, 0x1A, 0x00, 0x16, 0x03, 0x9E]);
+        assert_eq!(rom.to_string(), "28FF4C1A0016039E");
+        assert_eq!(rom.id() & 0xFF, 0x28);
+    }
+
+    #[test]
+    fn detects_presence_pulse() {
+        let bus = Bus::new(vec![(true, 30), (false, 120)]);
+        let mut wire = OneWire::new(bus.pin(), bus.delay());
+        assert_eq!(wire.reset(), Ok(true));
+        assert!(bus.host_low_us() >= 480);
+    }
+
+    #[test]
+    fn empty_bus_has_no_presence() {
+        let bus = Bus::new(Vec::new());
+        let mut wire = OneWire::new(bus.pin(), bus.delay());
+        assert_eq!(wire.reset(), Ok(false));
+        assert_eq!(RomSearch::new().next(&mut wire), Ok(None));
+    }
+
+    #[test]
+    fn search_finds_every_device() {
+        let roms = [
+            OneWireSim::rom(0x28, 0x0000_0000_0001),
+            OneWireSim::rom(0x28, 0x0000_0000_0002),
+            OneWireSim::rom(0x28, 0x8000_0000_0003),
+            OneWireSim::rom(0x10, 0x0000_1234_5678),
+        ];
+        let mut sim = OneWireSim::new(&roms);
+        let found = search_all::<_, 8>(&mut sim).unwrap();
+        assert_eq!(found.len(), roms.len());
+        for rom in roms {
+            assert!(found.contains(&rom), "{rom} not found");
+        }
+    }
+
+    #[test]
+    fn search_stops_at_capacity() {
+        let roms = [
+            OneWireSim::rom(0x28, 1),
+            OneWireSim::rom(0x28, 2),
+            OneWireSim::rom(0x28, 3),
+        ];
+        let mut sim = OneWireSim::new(&roms);
+        assert_eq!(search_all::<_, 2>(&mut sim).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn search_rejects_corrupt_rom() {
+        let mut rom = OneWireSim::rom(0x28, 42);
+        rom.0[7] ^= 0xFF;
+        let mut sim = OneWireSim::new(&[rom]);
+        assert_eq!(
+            RomSearch::new().next(&mut sim),
+            Err(SensorError::ChecksumMismatch)
+        );
+    }
+}
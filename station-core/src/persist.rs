@@ -0,0 +1,53 @@
+//! Sealing state kept in RTC memory across deep sleep, or in flash.
+//!
+//! RTC memory is only cleared on a power-on reset, and a reset that lands in
+//! the middle of a write leaves a mix of old and new words behind; flash has
+//! the same problem during an erase and rewrite. State is therefore stored as
+//! plain words with a trailing check word, and anything that fails the check
+//! is treated as absent.
+
+/// Writes the check word for `words[..len - 1]` into the last word. `magic`
+/// tells different kinds of state apart.
+pub fn seal(words: &mut [u32], magic: u32) {
+    let (check, body) = words.split_last_mut().expect("no room for check word");
+    *check = check_word(body, magic);
+}
+
+/// Whether `words` were written by [`seal`] with the same `magic`.
+pub fn is_sealed(words: &[u32], magic: u32) -> bool {
+    match words.split_last() {
+        Some((check, body)) => *check == check_word(body, magic),
+        None => false,
+    }
+}
+
+/// Splits a `u64` into two words, low word first.
+pub fn split_u64(value: u64) -> [u32; 2] {
+    [value as u32, (value >> 32) as u32]
+}
+
+pub fn join_u64(words: &[u32]) -> u64 {
+    words[0] as u64 | (words[1] as u64) << 32
+}
+
+fn check_word(words: &[u32], magic: u32) -> u32 {
+    words
+        .iter()
+        .fold(magic, |acc, word| acc.rotate_left(5) ^ word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_torn_and_foreign_state() {
+        let mut words = [1, 2, 3, 0];
+        seal(&mut words, 0x1234);
+        assert!(is_sealed(&words, 0x1234));
+        assert!(!is_sealed(&words, 0x4321));
+        words[1] = 7;
+        assert!(!is_sealed(&words, 0x1234));
+        assert!(!is_sealed(&[0; 4], 0x1234));
+    }
+}
@@ -0,0 +1,439 @@
+//! Plausibility checks on sensor values before they are uploaded.
+//!
+//! A sensor can pass its checksum and still report nonsense: 0% humidity
+//! from a flaky DHT11, a 30 °C jump between two wakes, or the same value for
+//! hours after it has locked up. Each quantity in a [`Measurement`] is graded
+//! [`Quality::Good`], [`Quality::Suspect`] or [`Quality::Bad`]:
+//!
+//! - [`check_range`] marks values outside the sensor's rated range as bad. It
+//!   runs per sensor in [`poll_all`](crate::sensor::poll_all), so a bad value
+//!   from one sensor gives way to a good one from the next.
+//! - [`History::assess`] compares the merged result with the previous wake,
+//!   marking implausibly fast changes and stuck values as suspect. The
+//!   history is small enough to live in RTC memory across deep sleep.
+
+use crate::persist;
+use crate::sensor::Measurement;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Quality {
+    Good,
+    /// Within the sensor's range, but inconsistent with earlier readings.
+    Suspect,
+    /// Outside what the sensor can measure; the value should not be used.
+    Bad,
+}
+
+impl Quality {
+    /// Short machine-readable name, used in uploads.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Suspect => "suspect",
+            Quality::Bad => "bad",
+        }
+    }
+}
+
+/// Grade of each quantity in a [`Measurement`]; `None` where the quantity
+/// was not measured.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Assessment {
+    pub temperature: Option<Quality>,
+    pub humidity: Option<Quality>,
+    pub pressure: Option<Quality>,
+}
+
+impl Assessment {
+    pub fn is_empty(&self) -> bool {
+        self.temperature.is_none() && self.humidity.is_none() && self.pressure.is_none()
+    }
+}
+
+/// Inclusive range of values, in the unit the quantity is stored in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl Range {
+    pub const fn new(min: i32, max: i32) -> Self {
+        Self { min, max }
+    }
+
+    pub fn contains(&self, value: i32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+/// Rated measurement range of a sensor: temperature in tenths of a degree,
+/// humidity in tenths of a percent, pressure in pascals.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    pub temperature: Range,
+    pub humidity: Range,
+    pub pressure: Range,
+}
+
+impl Limits {
+    /// Anything a weather station could plausibly see on the ground.
+    pub const PHYSICAL: Limits = Limits {
+        temperature: Range::new(-800, 600),
+        humidity: Range::new(0, 1000),
+        pressure: Range::new(30_000, 110_000),
+    };
+    pub const DHT11: Limits = Limits {
+        temperature: Range::new(-200, 600),
+        humidity: Range::new(50, 950),
+        ..Limits::PHYSICAL
+    };
+    pub const DHT22: Limits = Limits {
+        temperature: Range::new(-400, 800),
+        humidity: Range::new(1, 1000),
+        ..Limits::PHYSICAL
+    };
+    pub const BME280: Limits = Limits {
+        temperature: Range::new(-400, 850),
+        humidity: Range::new(1, 1000),
+        pressure: Range::new(30_000, 110_000),
+    };
+    pub const SHT: Limits = Limits {
+        temperature: Range::new(-400, 1250),
+        humidity: Range::new(1, 1000),
+        ..Limits::PHYSICAL
+    };
+    pub const SCD4X: Limits = Limits {
+        temperature: Range::new(-100, 600),
+        humidity: Range::new(0, 1000),
+        ..Limits::PHYSICAL
+    };
+}
+
+/// Grades every quantity present in `measurement` as good or bad against
+/// `limits`.
+pub fn check_range(measurement: &mut Measurement, limits: &Limits) {
+    let grade = |value: Option<i32>, range: &Range| {
+        value.map(|v| {
+            if range.contains(v) {
+                Quality::Good
+            } else {
+                Quality::Bad
+            }
+        })
+    };
+    measurement.quality = Assessment {
+        temperature: grade(measurement.temperature.map(i32::from), &limits.temperature),
+        humidity: grade(measurement.humidity.map(i32::from), &limits.humidity),
+        pressure: grade(measurement.pressure.map(|p| p as i32), &limits.pressure),
+    };
+}
+
+/// How fast a quantity may change and how long it may stay exactly the same.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChangeLimit {
+    /// Largest plausible change per minute. Changes over shorter intervals
+    /// are allowed a full minute's worth, since sensors have their own noise
+    /// and settling.
+    pub max_per_minute: i32,
+    /// Number of consecutive identical readings after which the sensor is
+    /// considered stuck; 0 disables the check.
+    pub stuck_cycles: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChangeRules {
+    pub temperature: ChangeLimit,
+    pub humidity: ChangeLimit,
+    pub pressure: ChangeLimit,
+}
+
+impl ChangeRules {
+    pub const DEFAULT: ChangeRules = ChangeRules {
+        // 2 °C per minute covers the sun coming out on a screened sensor.
+        temperature: ChangeLimit {
+            max_per_minute: 20,
+            stuck_cycles: 60,
+        },
+        humidity: ChangeLimit {
+            max_per_minute: 100,
+            stuck_cycles: 60,
+        },
+        // Even a passing squall line moves pressure by only a few hPa.
+        pressure: ChangeLimit {
+            max_per_minute: 100,
+            stuck_cycles: 60,
+        },
+    };
+}
+
+/// Last accepted value of one quantity.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct Track {
+    known: bool,
+    last: i32,
+    at_ms: u64,
+    repeats: u16,
+}
+
+impl Track {
+    fn assess(&mut self, value: i32, now_ms: u64, limit: &ChangeLimit) -> Quality {
+        if !self.known || now_ms < self.at_ms {
+            *self = Track {
+                known: true,
+                last: value,
+                at_ms: now_ms,
+                repeats: 0,
+            };
+            return Quality::Good;
+        }
+        let minutes_ms = (now_ms - self.at_ms).max(60_000);
+        let allowed = limit.max_per_minute as i64 * minutes_ms as i64 / 60_000;
+        let jumped = (value as i64 - self.last as i64).abs() > allowed;
+        self.repeats = if value == self.last {
+            self.repeats.saturating_add(1)
+        } else {
+            0
+        };
+        let stuck = limit.stuck_cycles > 0 && self.repeats >= limit.stuck_cycles;
+        self.last = value;
+        self.at_ms = now_ms;
+        if jumped || stuck {
+            Quality::Suspect
+        } else {
+            Quality::Good
+        }
+    }
+
+    fn to_words(self) -> [u32; 4] {
+        let [at_low, at_high] = persist::split_u64(self.at_ms);
+        [
+            self.last as u32,
+            at_low,
+            at_high,
+            (self.known as u32) << 31 | self.repeats as u32,
+        ]
+    }
+
+    fn from_words(words: &[u32]) -> Self {
+        Self {
+            last: words[0] as i32,
+            at_ms: persist::join_u64(&words[1..3]),
+            known: words[3] >> 31 != 0,
+            repeats: words[3] as u16,
+        }
+    }
+}
+
+/// Number of words [`History`] occupies in RTC memory.
+pub const HISTORY_WORDS: usize = 13;
+
+const HISTORY_MAGIC: u32 = 0x504C_4155;
+
+/// Values from earlier wakes, kept in RTC memory so rate-of-change and
+/// stuck-value checks work across deep sleep.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    temperature: Track,
+    humidity: Track,
+    pressure: Track,
+}
+
+impl History {
+    /// Checks the merged measurement against the previous wake, downgrading
+    /// good values to suspect where they changed too fast or have not changed
+    /// for too long. Bad values are left out of the history. `now_ms` must be
+    /// a clock that keeps running through deep sleep.
+    pub fn assess(&mut self, measurement: &mut Measurement, now_ms: u64, rules: &ChangeRules) {
+        fn apply(
+            track: &mut Track,
+            value: Option<i32>,
+            quality: &mut Option<Quality>,
+            now_ms: u64,
+            limit: &ChangeLimit,
+        ) {
+            let Some(value) = value else { return };
+            let current = quality.unwrap_or(Quality::Good);
+            if current == Quality::Bad {
+                return;
+            }
+            *quality = Some(current.max(track.assess(value, now_ms, limit)));
+        }
+
+        let quality = &mut measurement.quality;
+        apply(
+            &mut self.temperature,
+            measurement.temperature.map(i32::from),
+            &mut quality.temperature,
+            now_ms,
+            &rules.temperature,
+        );
+        apply(
+            &mut self.humidity,
+            measurement.humidity.map(i32::from),
+            &mut quality.humidity,
+            now_ms,
+            &rules.humidity,
+        );
+        apply(
+            &mut self.pressure,
+            measurement.pressure.map(|p| p as i32),
+            &mut quality.pressure,
+            now_ms,
+            &rules.pressure,
+        );
+    }
+
+    /// Serializes the history with a check word, for storage in RTC memory.
+    pub fn to_words(&self) -> [u32; HISTORY_WORDS] {
+        let mut words = [0; HISTORY_WORDS];
+        for (chunk, track) in
+            words
+                .chunks_exact_mut(4)
+                .zip([self.temperature, self.humidity, self.pressure])
+        {
+            chunk.copy_from_slice(&track.to_words());
+        }
+        persist::seal(&mut words, HISTORY_MAGIC);
+        words
+    }
+
+    /// Restores a history saved by [`History::to_words`]. Memory that does
+    /// not carry a valid check word (first boot, power loss, a reset during
+    /// a write) yields an empty history.
+    pub fn from_words(words: &[u32; HISTORY_WORDS]) -> Self {
+        if !persist::is_sealed(words, HISTORY_MAGIC) {
+            return Self::default();
+        }
+        Self {
+            temperature: Track::from_words(&words[0..4]),
+            humidity: Track::from_words(&words[4..8]),
+            pressure: Track::from_words(&words[8..12]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000;
+
+    fn measurement(temperature: i16, humidity: u16) -> Measurement {
+        let mut m = Measurement {
+            temperature: Some(temperature),
+            humidity: Some(humidity),
+            ..Default::default()
+        };
+        check_range(&mut m, &Limits::DHT22);
+        m
+    }
+
+    #[test]
+    fn out_of_range_values_are_bad() {
+        let mut m = Measurement {
+            temperature: Some(215),
+            humidity: Some(0),
+            ..Default::default()
+        };
+        check_range(&mut m, &Limits::DHT11);
+        assert_eq!(m.quality.temperature, Some(Quality::Good));
+        assert_eq!(m.quality.humidity, Some(Quality::Bad));
+        assert_eq!(m.quality.pressure, None);
+    }
+
+    #[test]
+    fn first_reading_is_good() {
+        let mut history = History::default();
+        let mut m = measurement(215, 500);
+        history.assess(&mut m, 0, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.temperature, Some(Quality::Good));
+        assert_eq!(m.quality.humidity, Some(Quality::Good));
+    }
+
+    #[test]
+    fn sudden_jump_is_suspect() {
+        let mut history = History::default();
+        history.assess(&mut measurement(215, 500), 0, &ChangeRules::DEFAULT);
+        let mut m = measurement(515, 510);
+        history.assess(&mut m, 5 * MINUTE, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.temperature, Some(Quality::Suspect));
+        assert_eq!(m.quality.humidity, Some(Quality::Good));
+    }
+
+    #[test]
+    fn allowed_change_scales_with_elapsed_time() {
+        let mut history = History::default();
+        history.assess(&mut measurement(100, 500), 0, &ChangeRules::DEFAULT);
+        // 15 °C in 5 minutes is over the limit...
+        let mut m = measurement(250, 500);
+        history.assess(&mut m, 5 * MINUTE, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.temperature, Some(Quality::Suspect));
+        // ...but a further 15 °C over the next half hour is not.
+        let mut m = measurement(400, 500);
+        history.assess(&mut m, 35 * MINUTE, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.temperature, Some(Quality::Good));
+    }
+
+    #[test]
+    fn short_intervals_get_a_minute_of_allowance() {
+        let mut history = History::default();
+        history.assess(&mut measurement(200, 500), 0, &ChangeRules::DEFAULT);
+        let mut m = measurement(215, 500);
+        history.assess(&mut m, 5_000, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.temperature, Some(Quality::Good));
+    }
+
+    #[test]
+    fn stuck_value_becomes_suspect() {
+        let rules = ChangeRules {
+            humidity: ChangeLimit {
+                max_per_minute: 100,
+                stuck_cycles: 3,
+            },
+            ..ChangeRules::DEFAULT
+        };
+        let mut history = History::default();
+        let mut grades = Vec::new();
+        for cycle in 0..5 {
+            let mut m = measurement(200 + cycle as i16, 730);
+            history.assess(&mut m, cycle * MINUTE, &rules);
+            grades.push(m.quality.humidity.unwrap());
+        }
+        use Quality::*;
+        assert_eq!(grades, [Good, Good, Good, Suspect, Suspect]);
+        let mut m = measurement(205, 731);
+        history.assess(&mut m, 5 * MINUTE, &rules);
+        assert_eq!(m.quality.humidity, Some(Good));
+    }
+
+    #[test]
+    fn bad_values_stay_bad_and_do_not_enter_history() {
+        let mut history = History::default();
+        history.assess(&mut measurement(215, 500), 0, &ChangeRules::DEFAULT);
+        let mut m = measurement(215, 0);
+        history.assess(&mut m, MINUTE, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.humidity, Some(Quality::Bad));
+        let mut m = measurement(215, 505);
+        history.assess(&mut m, 2 * MINUTE, &ChangeRules::DEFAULT);
+        assert_eq!(m.quality.humidity, Some(Quality::Good));
+    }
+
+    #[test]
+    fn history_round_trips_through_words() {
+        let mut history = History::default();
+        let mut m = measurement(-123, 456);
+        m.pressure = Some(101_325);
+        history.assess(&mut m, 5_000_000_000, &ChangeRules::DEFAULT);
+        let restored = History::from_words(&history.to_words());
+        assert_eq!(restored, history);
+    }
+
+    #[test]
+    fn uninitialized_memory_gives_empty_history() {
+        assert_eq!(History::from_words(&[0; HISTORY_WORDS]), History::default());
+        let mut words = History::default().to_words();
+        words[0] ^= 1;
+        assert_eq!(History::from_words(&words), History::default());
+    }
+}
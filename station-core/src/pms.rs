@@ -0,0 +1,379 @@
+//! Plantower PMS5003/PMS7003 particulate matter sensors over UART.
+//!
+//! The sensor streams 32-byte frames at 9600 baud. It is switched to passive
+//! mode, where a frame is only sent on request, and put to sleep between
+//! wakes so its fan does not run down the battery. Frames are picked out of
+//! the byte stream by [`FrameParser`], which recovers from line noise and
+//! partial frames by searching for the next header.
+
+use crate::sensor::SensorError;
+
+/// Length of a data frame, header and checksum included.
+pub const FRAME_LEN: usize = 32;
+
+/// Time the fan needs after waking before readings are stable.
+pub const WAKE_UP_MS: u32 = 30_000;
+
+const HEADER: [u8; 2] = [0x42, 0x4D];
+// Length field of a data frame: 13 data words and the checksum.
+const DATA_LENGTH: u16 = 28;
+
+const CMD_MODE: u8 = 0xE1;
+const CMD_READ: u8 = 0xE2;
+const CMD_SLEEP: u8 = 0xE4;
+
+/// Builds a 7-byte command frame: header, command, two data bytes and the
+/// sum of the preceding bytes.
+pub const fn command(command: u8, data: u16) -> [u8; 7] {
+    let [data_h, data_l] = data.to_be_bytes();
+    let sum = HEADER[0] as u16 + HEADER[1] as u16 + command as u16 + data_h as u16 + data_l as u16;
+    let [sum_h, sum_l] = sum.to_be_bytes();
+    [HEADER[0], HEADER[1], command, data_h, data_l, sum_h, sum_l]
+}
+
+/// Only send a frame when asked with [`READ_PASSIVE`].
+pub const PASSIVE_MODE: [u8; 7] = command(CMD_MODE, 0);
+/// Requests one frame in passive mode.
+pub const READ_PASSIVE: [u8; 7] = command(CMD_READ, 0);
+/// Stops the fan and laser.
+pub const SLEEP: [u8; 7] = command(CMD_SLEEP, 0);
+/// Starts the fan again; readings are unreliable for [`WAKE_UP_MS`].
+pub const WAKE: [u8; 7] = command(CMD_SLEEP, 1);
+
+/// Mass concentrations in micrograms per cubic metre.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Particulates {
+    pub pm1_0: u16,
+    pub pm2_5: u16,
+    pub pm10: u16,
+}
+
+impl Particulates {
+    /// US EPA Air Quality Index, the worse of the PM2.5 and PM10 indices.
+    pub fn aqi(&self) -> u16 {
+        aqi_pm2_5(self.pm2_5 as u32 * 10).max(aqi_pm10(self.pm10 as u32))
+    }
+}
+
+/// One decoded data frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Concentrations with the factory calibration against standard
+    /// particles ("CF=1").
+    pub standard: Particulates,
+    /// Concentrations under atmospheric conditions; the ones to report.
+    pub atmospheric: Particulates,
+    /// Particles larger than 0.3, 0.5, 1.0, 2.5, 5 and 10 µm in 0.1 L of air.
+    pub counts: [u16; 6],
+}
+
+/// Checks the header, length and checksum of a complete frame and decodes
+/// it.
+pub fn decode_frame(bytes: &[u8; FRAME_LEN]) -> Result<Frame, SensorError> {
+    if bytes[..2] != HEADER || u16::from_be_bytes([bytes[2], bytes[3]]) != DATA_LENGTH {
+        return Err(SensorError::UnknownDevice);
+    }
+    let sum = bytes[..FRAME_LEN - 2]
+        .iter()
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    if sum != u16::from_be_bytes([bytes[30], bytes[31]]) {
+        return Err(SensorError::ChecksumMismatch);
+    }
+    let word = |idx: usize| u16::from_be_bytes([bytes[4 + idx * 2], bytes[5 + idx * 2]]);
+    let mut counts = [0u16; 6];
+    for (idx, count) in counts.iter_mut().enumerate() {
+        *count = word(6 + idx);
+    }
+    Ok(Frame {
+        standard: Particulates {
+            pm1_0: word(0),
+            pm2_5: word(1),
+            pm10: word(2),
+        },
+        atmospheric: Particulates {
+            pm1_0: word(3),
+            pm2_5: word(4),
+            pm10: word(5),
+        },
+        counts,
+    })
+}
+
+/// Collects data frames from a byte stream one byte at a time.
+///
+/// Bytes that cannot start a frame are skipped. When a frame turns out to be
+/// invalid, the search for the next header resumes right after the rejected
+/// frame's header, so a frame that began inside a truncated one is not lost.
+#[derive(Debug, Clone)]
+pub struct FrameParser {
+    buf: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl Default for FrameParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameParser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Adds a byte. Returns the frame it completed, or
+    /// [`SensorError::ChecksumMismatch`] if a complete frame was corrupted.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, SensorError>> {
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if !self.prefix_valid() {
+            self.resync();
+            return None;
+        }
+        if self.len < FRAME_LEN {
+            return None;
+        }
+        let result = decode_frame(&self.buf);
+        match result {
+            Ok(_) => self.len = 0,
+            Err(_) => self.resync(),
+        }
+        Some(result)
+    }
+
+    /// Whether the bytes collected so far can still be the start of a data
+    /// frame.
+    fn prefix_valid(&self) -> bool {
+        let buf = &self.buf[..self.len];
+        buf.iter()
+            .zip(HEADER)
+            .all(|(&byte, expected)| byte == expected)
+            && (buf.len() < 4 || u16::from_be_bytes([buf[2], buf[3]]) == DATA_LENGTH)
+    }
+
+    /// Drops the first byte and moves the next possible frame start to the
+    /// front of the buffer.
+    fn resync(&mut self) {
+        loop {
+            let Some(offset) = self.buf[1..self.len].iter().position(|&b| b == HEADER[0]) else {
+                self.len = 0;
+                return;
+            };
+            let start = offset + 1;
+            self.buf.copy_within(start..self.len, 0);
+            self.len -= start;
+            if self.prefix_valid() {
+                return;
+            }
+        }
+    }
+}
+
+/// Linear interpolation within one AQI category: concentrations `lo..=hi`
+/// map to indices `index_lo..=index_hi`.
+struct Breakpoint {
+    lo: u32,
+    hi: u32,
+    index_lo: u32,
+    index_hi: u32,
+}
+
+const fn bp(lo: u32, hi: u32, index_lo: u32, index_hi: u32) -> Breakpoint {
+    Breakpoint {
+        lo,
+        hi,
+        index_lo,
+        index_hi,
+    }
+}
+
+// PM2.5 in tenths of µg/m³, as revised by the EPA in 2024.
+const PM2_5_BREAKPOINTS: [Breakpoint; 6] = [
+    bp(0, 90, 0, 50),
+    bp(91, 354, 51, 100),
+    bp(355, 554, 101, 150),
+    bp(555, 1254, 151, 200),
+    bp(1255, 2254, 201, 300),
+    bp(2255, 3254, 301, 500),
+];
+
+// PM10 in µg/m³.
+const PM10_BREAKPOINTS: [Breakpoint; 6] = [
+    bp(0, 54, 0, 50),
+    bp(55, 154, 51, 100),
+    bp(155, 254, 101, 150),
+    bp(255, 354, 151, 200),
+    bp(355, 424, 201, 300),
+    bp(425, 604, 301, 500),
+];
+
+fn index(concentration: u32, breakpoints: &[Breakpoint]) -> u16 {
+    let Some(bp) = breakpoints.iter().find(|bp| concentration <= bp.hi) else {
+        return 500;
+    };
+    let span = bp.hi - bp.lo;
+    let offset = (bp.index_hi - bp.index_lo) * (concentration - bp.lo);
+    (bp.index_lo + (offset + span / 2) / span) as u16
+}
+
+/// AQI for a PM2.5 concentration in tenths of µg/m³. Values beyond the top
+/// of the scale are reported as 500.
+pub fn aqi_pm2_5(tenths: u32) -> u16 {
+    index(tenths, &PM2_5_BREAKPOINTS)
+}
+
+/// AQI for a PM10 concentration in µg/m³.
+pub fn aqi_pm10(concentration: u32) -> u16 {
+    index(concentration, &PM10_BREAKPOINTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(words: [u16; 13]) -> [u8; FRAME_LEN] {
+        let mut bytes = [0u8; FRAME_LEN];
+        bytes[..2].copy_from_slice(&HEADER);
+        bytes[2..4].copy_from_slice(&DATA_LENGTH.to_be_bytes());
+        for (idx, word) in words.iter().enumerate() {
+            bytes[4 + idx * 2..6 + idx * 2].copy_from_slice(&word.to_be_bytes());
+        }
+        let sum = bytes[..30].iter().map(|&b| b as u16).sum::<u16>();
+        bytes[30..].copy_from_slice(&sum.to_be_bytes());
+        bytes
+    }
+
+    const WORDS: [u16; 13] = [5, 8, 9, 4, 7, 9, 1_230, 380, 62, 6, 2, 1, 0];
+
+    fn feed(parser: &mut FrameParser, bytes: &[u8]) -> Vec<Result<Frame, SensorError>> {
+        bytes.iter().filter_map(|&b| parser.push(b)).collect()
+    }
+
+    #[test]
+    fn commands_match_datasheet() {
+        assert_eq!(PASSIVE_MODE, [0x42, 0x4D, 0xE1, 0x00, 0x00, 0x01, 0x70]);
+        assert_eq!(READ_PASSIVE, [0x42, 0x4D, 0xE2, 0x00, 0x00, 0x01, 0x71]);
+        assert_eq!(SLEEP, [0x42, 0x4D, 0xE4, 0x00, 0x00, 0x01, 0x73]);
+        assert_eq!(WAKE, [0x42, 0x4D, 0xE4, 0x00, 0x01, 0x01, 0x74]);
+    }
+
+    #[test]
+    fn decodes_frame() {
+        let decoded = decode_frame(&frame(WORDS)).unwrap();
+        assert_eq!(
+            decoded.standard,
+            Particulates {
+                pm1_0: 5,
+                pm2_5: 8,
+                pm10: 9
+            }
+        );
+        assert_eq!(
+            decoded.atmospheric,
+            Particulates {
+                pm1_0: 4,
+                pm2_5: 7,
+                pm10: 9
+            }
+        );
+        assert_eq!(decoded.counts, [1_230, 380, 62, 6, 2, 1]);
+    }
+
+    #[test]
+    fn parses_consecutive_frames() {
+        let mut parser = FrameParser::new();
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&frame(WORDS));
+        stream.extend_from_slice(&frame([0, 0, 0, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0]));
+        let frames = feed(&mut parser, &stream);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].unwrap().atmospheric.pm10, 3);
+    }
+
+    #[test]
+    fn skips_garbage_before_frame() {
+        let mut parser = FrameParser::new();
+        let mut stream = vec![0x00, 0x42, 0x42, 0x13, 0x4D, 0x42, 0x4D, 0x00, 0x04, 0xFF];
+        stream.extend_from_slice(&frame(WORDS));
+        let frames = feed(&mut parser, &stream);
+        assert_eq!(frames, [decode_frame(&frame(WORDS))]);
+    }
+
+    #[test]
+    fn ignores_command_acknowledgement() {
+        let mut parser = FrameParser::new();
+        let mut stream = vec![0x42, 0x4D, 0x00, 0x04, 0xE1, 0x00, 0x01, 0x74];
+        stream.extend_from_slice(&frame(WORDS));
+        assert_eq!(feed(&mut parser, &stream).len(), 1);
+    }
+
+    #[test]
+    fn reports_corrupted_frame_and_recovers() {
+        let mut parser = FrameParser::new();
+        let mut corrupted = frame(WORDS);
+        corrupted[12] ^= 0x01;
+        let mut stream = corrupted.to_vec();
+        stream.extend_from_slice(&frame(WORDS));
+        let frames = feed(&mut parser, &stream);
+        assert_eq!(frames[0], Err(SensorError::ChecksumMismatch));
+        assert_eq!(frames[1], decode_frame(&frame(WORDS)));
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn resyncs_onto_frame_inside_truncated_one() {
+        // A frame cut off after 20 bytes (e.g. the sensor was reset) runs
+        // into the next one, which has to be found inside the rejected bytes
+        let mut parser = FrameParser::new();
+        let mut stream = frame(WORDS)[..20].to_vec();
+        stream.extend_from_slice(&frame(WORDS));
+        let frames = feed(&mut parser, &stream);
+        assert_eq!(frames.last(), Some(&decode_frame(&frame(WORDS))));
+        assert!(frames[..frames.len() - 1].iter().all(Result::is_err));
+    }
+
+    #[test]
+    fn rejects_wrong_length_field() {
+        let mut bytes = frame(WORDS);
+        bytes[3] = 20;
+        assert_eq!(decode_frame(&bytes), Err(SensorError::UnknownDevice));
+    }
+
+    #[test]
+    fn pm2_5_index_follows_2024_breakpoints() {
+        assert_eq!(aqi_pm2_5(0), 0);
+        assert_eq!(aqi_pm2_5(90), 50);
+        assert_eq!(aqi_pm2_5(91), 51);
+        assert_eq!(aqi_pm2_5(120), 56);
+        assert_eq!(aqi_pm2_5(350), 99);
+        assert_eq!(aqi_pm2_5(555), 151);
+        assert_eq!(aqi_pm2_5(3254), 500);
+        assert_eq!(aqi_pm2_5(9_990), 500);
+    }
+
+    #[test]
+    fn pm10_index() {
+        assert_eq!(aqi_pm10(54), 50);
+        assert_eq!(aqi_pm10(100), 73);
+        assert_eq!(aqi_pm10(604), 500);
+    }
+
+    #[test]
+    fn aqi_is_worst_of_both() {
+        let clean = Particulates {
+            pm1_0: 3,
+            pm2_5: 5,
+            pm10: 8,
+        };
+        assert_eq!(clean.aqi(), 28);
+        let dusty = Particulates {
+            pm1_0: 3,
+            pm2_5: 5,
+            pm10: 100,
+        };
+        assert_eq!(dusty.aqi(), 73);
+    }
+}
@@ -0,0 +1,182 @@
+//! Tipping-bucket rain gauge.
+//!
+//! Each tip of the bucket closes a reed switch, which wakes the station from
+//! deep sleep. The tip is counted in RTC memory and the station goes straight
+//! back to sleep; the count is only turned into rainfall and uploaded on the
+//! next scheduled wake.
+
+use crate::persist;
+
+/// Rain per tip of the common 0.011 inch bucket (Misol WH-SP-RG, SparkFun
+/// SEN-15901), in micrometres.
+pub const MICROMETRES_PER_TIP: u32 = 279;
+
+/// Tips closer together than this are contact bounce or the wake-up of one
+/// tip being seen twice; a bucket cannot empty that fast.
+pub const DEBOUNCE_MS: u64 = 500;
+
+/// With no tip for this long, it has stopped raining as far as the rate is
+/// concerned.
+pub const RATE_TIMEOUT_MS: u64 = 15 * 60 * 1000;
+
+/// Number of words [`RainGauge`] occupies in RTC memory.
+pub const GAUGE_WORDS: usize = 7;
+
+const GAUGE_MAGIC: u32 = 0x5241_494E;
+
+/// Rain since the last acknowledged upload.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rainfall {
+    pub tips: u32,
+    /// Accumulated rain in hundredths of a millimetre.
+    pub amount: u32,
+    /// Current rain rate in hundredths of a millimetre per hour.
+    pub rate: u32,
+}
+
+/// Tip counter kept in RTC memory.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RainGauge {
+    /// Tips not yet uploaded.
+    pending: u32,
+    last_tip_ms: u64,
+    previous_tip_ms: u64,
+    /// Tips seen so far, saturating at 2; the rate needs two.
+    seen: u32,
+}
+
+impl RainGauge {
+    /// Counts a tip at `now_ms` unless it falls inside the debounce window
+    /// of the previous one. Returns whether it was counted.
+    pub fn record_tip(&mut self, now_ms: u64) -> bool {
+        if self.seen > 0 && now_ms.saturating_sub(self.last_tip_ms) < DEBOUNCE_MS {
+            return false;
+        }
+        self.pending = self.pending.saturating_add(1);
+        self.previous_tip_ms = self.last_tip_ms;
+        self.last_tip_ms = now_ms;
+        self.seen = (self.seen + 1).min(2);
+        true
+    }
+
+    /// Rain since the last acknowledged report, for a bucket of
+    /// `micrometres_per_tip`. The rate follows the interval between the last
+    /// two tips and decays once the time since the last tip exceeds it.
+    pub fn report(&self, now_ms: u64, micrometres_per_tip: u32) -> Rainfall {
+        let since_last = now_ms.saturating_sub(self.last_tip_ms);
+        let rate = if self.seen < 2 || since_last > RATE_TIMEOUT_MS {
+            0
+        } else {
+            let interval = (self.last_tip_ms - self.previous_tip_ms)
+                .max(since_last)
+                .max(1);
+            // um per tip over ms per tip, scaled to hundredths of mm per hour.
+            (micrometres_per_tip as u64 * 3_600_000 / 10 / interval) as u32
+        };
+        Rainfall {
+            tips: self.pending,
+            amount: (self.pending as u64 * micrometres_per_tip as u64 / 10) as u32,
+            rate,
+        }
+    }
+
+    /// Removes the tips of an uploaded report. Tips that arrived after the
+    /// report was taken stay pending.
+    pub fn acknowledge(&mut self, report: &Rainfall) {
+        self.pending = self.pending.saturating_sub(report.tips);
+    }
+
+    pub fn to_words(&self) -> [u32; GAUGE_WORDS] {
+        let [last_low, last_high] = persist::split_u64(self.last_tip_ms);
+        let [previous_low, previous_high] = persist::split_u64(self.previous_tip_ms);
+        let mut words = [
+            self.pending,
+            last_low,
+            last_high,
+            previous_low,
+            previous_high,
+            self.seen,
+            0,
+        ];
+        persist::seal(&mut words, GAUGE_MAGIC);
+        words
+    }
+
+    /// Restores a gauge saved by [`RainGauge::to_words`], starting from zero
+    /// if the memory does not hold one.
+    pub fn from_words(words: &[u32; GAUGE_WORDS]) -> Self {
+        if !persist::is_sealed(words, GAUGE_MAGIC) {
+            return Self::default();
+        }
+        Self {
+            pending: words[0],
+            last_tip_ms: persist::join_u64(&words[1..3]),
+            previous_tip_ms: persist::join_u64(&words[3..5]),
+            seen: words[5].min(2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000;
+
+    #[test]
+    fn bounces_are_not_counted() {
+        let mut gauge = RainGauge::default();
+        assert!(gauge.record_tip(10_000));
+        assert!(!gauge.record_tip(10_050));
+        assert!(!gauge.record_tip(10_499));
+        assert!(gauge.record_tip(10_500));
+        assert_eq!(gauge.report(11_000, MICROMETRES_PER_TIP).tips, 2);
+    }
+
+    #[test]
+    fn converts_tips_to_millimetres() {
+        let mut gauge = RainGauge::default();
+        for tip in 0..10 {
+            gauge.record_tip(tip * MINUTE);
+        }
+        let report = gauge.report(9 * MINUTE, MICROMETRES_PER_TIP);
+        // 10 tips of 0.279 mm.
+        assert_eq!(report.amount, 279);
+    }
+
+    #[test]
+    fn rate_follows_tip_interval() {
+        let mut gauge = RainGauge::default();
+        gauge.record_tip(0);
+        assert_eq!(gauge.report(MINUTE, MICROMETRES_PER_TIP).rate, 0);
+        gauge.record_tip(MINUTE);
+        // One 0.279 mm tip per minute is 16.74 mm/h.
+        assert_eq!(gauge.report(MINUTE, MICROMETRES_PER_TIP).rate, 1674);
+        // Four minutes later without a tip it can be at most a quarter of that.
+        assert_eq!(gauge.report(5 * MINUTE, MICROMETRES_PER_TIP).rate, 418);
+        assert_eq!(gauge.report(20 * MINUTE, MICROMETRES_PER_TIP).rate, 0);
+    }
+
+    #[test]
+    fn acknowledge_keeps_later_tips() {
+        let mut gauge = RainGauge::default();
+        gauge.record_tip(0);
+        gauge.record_tip(MINUTE);
+        let report = gauge.report(MINUTE, MICROMETRES_PER_TIP);
+        gauge.record_tip(2 * MINUTE);
+        gauge.acknowledge(&report);
+        assert_eq!(gauge.report(2 * MINUTE, MICROMETRES_PER_TIP).tips, 1);
+    }
+
+    #[test]
+    fn survives_a_round_trip_through_rtc_words() {
+        let mut gauge = RainGauge::default();
+        gauge.record_tip(5_000_000_000);
+        gauge.record_tip(5_000_060_000);
+        assert_eq!(RainGauge::from_words(&gauge.to_words()), gauge);
+        assert_eq!(
+            RainGauge::from_words(&[0; GAUGE_WORDS]),
+            RainGauge::default()
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Deciding whether a reading is worth turning the radio on for.
+//!
+//! Bringing up WiFi costs far more energy than reading the sensors, and most
+//! wakes find the weather much as it was. [`LastReport`] remembers the values
+//! the server last accepted, in RTC memory across deep sleep, and
+//! [`LastReport::due`] only asks for an upload when a quantity has moved by
+//! at least its [`Threshold::delta`] or has not been reported for its
+//! [`Threshold::heartbeat_ms`], so the server can still tell a quiet station
+//! from a dead one.
+
+use crate::persist;
+use crate::plausibility::Quality;
+use crate::sensor::Measurement;
+
+/// Number of words [`LastReport`] occupies in RTC memory.
+pub const REPORT_WORDS: usize = 11;
+
+const REPORT_MAGIC: u32 = 0x5245_5054;
+
+const HOUR_MS: u64 = 3_600_000;
+
+/// Flag next to the known-value bits: an upload has been accepted.
+const SENT: u32 = 1 << 31;
+
+/// The quantities compared with the last report.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Pressure,
+    Co2,
+    Pm2_5,
+    WindSpeed,
+    Battery,
+    /// Rain since the last accepted upload; the gauge keeps the count, so
+    /// nothing is remembered here.
+    Rain,
+}
+
+/// Quantities whose last reported value is remembered, in storage order.
+const REMEMBERED: [Quantity; 7] = [
+    Quantity::Temperature,
+    Quantity::Humidity,
+    Quantity::Pressure,
+    Quantity::Co2,
+    Quantity::Pm2_5,
+    Quantity::WindSpeed,
+    Quantity::Battery,
+];
+
+/// When one quantity calls for an upload.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Threshold {
+    /// Smallest change worth reporting, in the quantity's unit in
+    /// [`Measurement`]. Zero reports every wake.
+    pub delta: u32,
+    /// Longest time without an upload while the quantity is measured.
+    pub heartbeat_ms: u64,
+}
+
+impl Threshold {
+    /// Never calls for an upload.
+    pub const IGNORE: Threshold = Threshold {
+        delta: u32::MAX,
+        heartbeat_ms: u64::MAX,
+    };
+}
+
+/// A [`Threshold`] per quantity.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReportRules {
+    /// Tenths of a degree.
+    pub temperature: Threshold,
+    /// Tenths of a percent.
+    pub humidity: Threshold,
+    /// Pascals.
+    pub pressure: Threshold,
+    /// Parts per million.
+    pub co2: Threshold,
+    /// Micrograms per cubic metre.
+    pub pm2_5: Threshold,
+    /// Average speed in tenths of a metre per second.
+    pub wind_speed: Threshold,
+    /// Estimated charge in percent.
+    pub battery: Threshold,
+    /// Hundredths of a millimetre.
+    pub rain: Threshold,
+}
+
+impl ReportRules {
+    /// About the resolution of a good sensor, and an upload at least once
+    /// an hour; the battery drains slowly enough to check on every six.
+    pub const DEFAULT: ReportRules = ReportRules {
+        temperature: Threshold {
+            delta: 3,
+            heartbeat_ms: HOUR_MS,
+        },
+        humidity: Threshold {
+            delta: 20,
+            heartbeat_ms: HOUR_MS,
+        },
+        pressure: Threshold {
+            delta: 50,
+            heartbeat_ms: HOUR_MS,
+        },
+        co2: Threshold {
+            delta: 100,
+            heartbeat_ms: HOUR_MS,
+        },
+        pm2_5: Threshold {
+            delta: 5,
+            heartbeat_ms: HOUR_MS,
+        },
+        wind_speed: Threshold {
+            delta: 10,
+            heartbeat_ms: HOUR_MS,
+        },
+        battery: Threshold {
+            delta: 5,
+            heartbeat_ms: 6 * HOUR_MS,
+        },
+        // Any rain at all.
+        rain: Threshold {
+            delta: 1,
+            heartbeat_ms: HOUR_MS,
+        },
+    };
+
+    pub fn threshold(&self, quantity: Quantity) -> Threshold {
+        match quantity {
+            Quantity::Temperature => self.temperature,
+            Quantity::Humidity => self.humidity,
+            Quantity::Pressure => self.pressure,
+            Quantity::Co2 => self.co2,
+            Quantity::Pm2_5 => self.pm2_5,
+            Quantity::WindSpeed => self.wind_speed,
+            Quantity::Battery => self.battery,
+            Quantity::Rain => self.rain,
+        }
+    }
+}
+
+/// Why an upload is due.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Nothing has been reported since the station was powered up.
+    First,
+    /// The quantity moved by its delta, appeared or disappeared.
+    Changed(Quantity),
+    /// The quantity has not been reported for its heartbeat interval.
+    Heartbeat(Quantity),
+}
+
+/// A quantity as read this wake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Observed {
+    /// Graded bad; neither compared nor remembered.
+    Untrusted,
+    Missing,
+    Value(i32),
+}
+
+fn observe(measurement: &Measurement, quantity: Quantity) -> Observed {
+    let quality = match quantity {
+        Quantity::Temperature => measurement.quality.temperature,
+        Quantity::Humidity => measurement.quality.humidity,
+        Quantity::Pressure => measurement.quality.pressure,
+        _ => None,
+    };
+    if quality == Some(Quality::Bad) {
+        return Observed::Untrusted;
+    }
+    let value = match quantity {
+        Quantity::Temperature => measurement.temperature.map(i32::from),
+        Quantity::Humidity => measurement.humidity.map(i32::from),
+        Quantity::Pressure => measurement.pressure.map(|p| p as i32),
+        Quantity::Co2 => measurement.co2.map(i32::from),
+        Quantity::Pm2_5 => measurement.particulates.map(|pm| pm.pm2_5.into()),
+        Quantity::WindSpeed => measurement.wind.map(|w| w.speed.into()),
+        Quantity::Battery => measurement.battery.map(|b| b.percent.into()),
+        Quantity::Rain => measurement.rain.map(|r| r.amount as i32),
+    };
+    value.map_or(Observed::Missing, Observed::Value)
+}
+
+/// The values in the last upload the server accepted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LastReport {
+    values: [Option<i32>; REMEMBERED.len()],
+    /// RTC time of the upload; `None` before the first.
+    at_ms: Option<u64>,
+}
+
+impl LastReport {
+    /// Whether `measurement`, taken at `now_ms` on the RTC timer, should be
+    /// uploaded. Changes are looked for before heartbeats, so the trigger
+    /// names the most telling reason.
+    pub fn due(
+        &self,
+        measurement: &Measurement,
+        now_ms: u64,
+        rules: &ReportRules,
+    ) -> Option<Trigger> {
+        let Some(at_ms) = self.at_ms else {
+            return Some(Trigger::First);
+        };
+        let elapsed_ms = now_ms.saturating_sub(at_ms);
+
+        let rain = observe(measurement, Quantity::Rain);
+        if let Observed::Value(amount) = rain
+            && amount as u32 >= rules.rain.delta
+        {
+            return Some(Trigger::Changed(Quantity::Rain));
+        }
+        for (quantity, before) in REMEMBERED.into_iter().zip(self.values) {
+            let changed = match (before, observe(measurement, quantity)) {
+                (_, Observed::Untrusted) => false,
+                (Some(before), Observed::Value(now)) => {
+                    now.abs_diff(before) >= rules.threshold(quantity).delta
+                }
+                (None, Observed::Missing) => false,
+                _ => true,
+            };
+            if changed {
+                return Some(Trigger::Changed(quantity));
+            }
+        }
+
+        let measured = REMEMBERED
+            .into_iter()
+            .zip(self.values)
+            .filter(|(quantity, before)| {
+                before.is_some() || matches!(observe(measurement, *quantity), Observed::Value(_))
+            })
+            .map(|(quantity, _)| quantity)
+            .chain((rain != Observed::Missing).then_some(Quantity::Rain));
+        measured
+            .filter(|&quantity| elapsed_ms >= rules.threshold(quantity).heartbeat_ms)
+            .min_by_key(|&quantity| rules.threshold(quantity).heartbeat_ms)
+            .map(Trigger::Heartbeat)
+    }
+
+    /// Remembers `measurement` as accepted by the server at `now_ms`. Values
+    /// graded bad leave the previous one in place.
+    pub fn sent(&mut self, measurement: &Measurement, now_ms: u64) {
+        for (quantity, value) in REMEMBERED.into_iter().zip(&mut self.values) {
+            match observe(measurement, quantity) {
+                Observed::Untrusted => {}
+                Observed::Missing => *value = None,
+                Observed::Value(now) => *value = Some(now),
+            }
+        }
+        self.at_ms = Some(now_ms);
+    }
+
+    pub fn to_words(&self) -> [u32; REPORT_WORDS] {
+        let mut words = [0; REPORT_WORDS];
+        let mut known = 0;
+        for (i, value) in self.values.iter().enumerate() {
+            if let Some(value) = value {
+                words[i] = *value as u32;
+                known |= 1 << i;
+            }
+        }
+        if self.at_ms.is_some() {
+            known |= SENT;
+        }
+        words[7] = known;
+        words[8..10].copy_from_slice(&persist::split_u64(self.at_ms.unwrap_or(0)));
+        persist::seal(&mut words, REPORT_MAGIC);
+        words
+    }
+
+    /// Restores a report saved by [`LastReport::to_words`]. Memory without
+    /// a valid check word means nothing has been reported yet.
+    pub fn from_words(words: &[u32; REPORT_WORDS]) -> Self {
+        if !persist::is_sealed(words, REPORT_MAGIC) {
+            return Self::default();
+        }
+        let mut values = [None; REMEMBERED.len()];
+        for (i, value) in values.iter_mut().enumerate() {
+            if words[7] & 1 << i != 0 {
+                *value = Some(words[i] as i32);
+            }
+        }
+        Self {
+            values,
+            at_ms: (words[7] & SENT != 0).then(|| persist::join_u64(&words[8..10])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::Battery;
+    use crate::rain::Rainfall;
+
+    const MINUTE: u64 = 60_000;
+
+    fn reading(temperature: i16, humidity: u16) -> Measurement {
+        Measurement {
+            temperature: Some(temperature),
+            humidity: Some(humidity),
+            ..Default::default()
+        }
+    }
+
+    fn reported(measurement: &Measurement) -> LastReport {
+        let mut last = LastReport::default();
+        last.sent(measurement, 0);
+        last
+    }
+
+    #[test]
+    fn first_reading_is_always_due() {
+        let last = LastReport::default();
+        assert_eq!(
+            last.due(&reading(200, 500), 0, &ReportRules::DEFAULT),
+            Some(Trigger::First)
+        );
+    }
+
+    #[test]
+    fn small_changes_are_skipped() {
+        let last = reported(&reading(200, 500));
+        let rules = ReportRules::DEFAULT;
+        assert_eq!(last.due(&reading(202, 515), 5 * MINUTE, &rules), None);
+        assert_eq!(
+            last.due(&reading(197, 500), 5 * MINUTE, &rules),
+            Some(Trigger::Changed(Quantity::Temperature))
+        );
+        assert_eq!(
+            last.due(&reading(200, 480), 5 * MINUTE, &rules),
+            Some(Trigger::Changed(Quantity::Humidity))
+        );
+    }
+
+    #[test]
+    fn deltas_are_per_quantity() {
+        let last = reported(&reading(200, 500));
+        let rules = ReportRules {
+            temperature: Threshold {
+                delta: 10,
+                ..ReportRules::DEFAULT.temperature
+            },
+            humidity: Threshold::IGNORE,
+            ..ReportRules::DEFAULT
+        };
+        assert_eq!(last.due(&reading(209, 900), 5 * MINUTE, &rules), None);
+        assert_eq!(
+            last.due(&reading(210, 500), 5 * MINUTE, &rules),
+            Some(Trigger::Changed(Quantity::Temperature))
+        );
+    }
+
+    #[test]
+    fn gradual_drift_is_measured_from_the_last_report() {
+        let mut last = reported(&reading(200, 500));
+        let rules = ReportRules::DEFAULT;
+        // Skipped readings are not remembered, so small steps add up.
+        for (wake, temperature) in [(1, 201), (2, 202)] {
+            assert_eq!(
+                last.due(&reading(temperature, 500), wake * 5 * MINUTE, &rules),
+                None
+            );
+        }
+        assert!(last.due(&reading(203, 500), 15 * MINUTE, &rules).is_some());
+        last.sent(&reading(203, 500), 15 * MINUTE);
+        assert_eq!(last.due(&reading(204, 500), 20 * MINUTE, &rules), None);
+    }
+
+    #[test]
+    fn heartbeat_is_per_quantity() {
+        let mut measurement = reading(200, 500);
+        measurement.battery = Some(Battery {
+            millivolts: 3_900,
+            percent: 70,
+        });
+        let last = reported(&measurement);
+        let rules = ReportRules {
+            temperature: Threshold {
+                heartbeat_ms: 30 * MINUTE,
+                ..ReportRules::DEFAULT.temperature
+            },
+            humidity: Threshold::IGNORE,
+            ..ReportRules::DEFAULT
+        };
+        assert_eq!(last.due(&measurement, 29 * MINUTE, &rules), None);
+        assert_eq!(
+            last.due(&measurement, 30 * MINUTE, &rules),
+            Some(Trigger::Heartbeat(Quantity::Temperature))
+        );
+        // Only quantities the station measures keep it awake.
+        let battery_only = Measurement {
+            battery: measurement.battery,
+            ..Default::default()
+        };
+        let last = reported(&battery_only);
+        assert_eq!(last.due(&battery_only, 5 * 60 * MINUTE, &rules), None);
+        assert_eq!(
+            last.due(&battery_only, 6 * 60 * MINUTE, &rules),
+            Some(Trigger::Heartbeat(Quantity::Battery))
+        );
+    }
+
+    #[test]
+    fn appearing_and_vanishing_values_are_changes() {
+        let last = reported(&reading(200, 500));
+        let mut with_pressure = reading(200, 500);
+        with_pressure.pressure = Some(101_325);
+        assert_eq!(
+            last.due(&with_pressure, MINUTE, &ReportRules::DEFAULT),
+            Some(Trigger::Changed(Quantity::Pressure))
+        );
+        let no_humidity = Measurement {
+            temperature: Some(200),
+            ..Default::default()
+        };
+        assert_eq!(
+            last.due(&no_humidity, MINUTE, &ReportRules::DEFAULT),
+            Some(Trigger::Changed(Quantity::Humidity))
+        );
+    }
+
+    #[test]
+    fn bad_values_neither_trigger_nor_stick() {
+        let mut last = reported(&reading(200, 500));
+        let mut spike = reading(850, 500);
+        spike.quality.temperature = Some(Quality::Bad);
+        assert_eq!(last.due(&spike, MINUTE, &ReportRules::DEFAULT), None);
+        last.sent(&spike, MINUTE);
+        assert_eq!(
+            last.due(&reading(201, 500), 2 * MINUTE, &ReportRules::DEFAULT),
+            None
+        );
+    }
+
+    #[test]
+    fn rain_is_always_reported() {
+        let last = reported(&reading(200, 500));
+        let mut wet = reading(200, 500);
+        wet.rain = Some(Rainfall {
+            tips: 0,
+            amount: 0,
+            rate: 0,
+        });
+        assert_eq!(last.due(&wet, MINUTE, &ReportRules::DEFAULT), None);
+        wet.rain = Some(Rainfall {
+            tips: 1,
+            amount: 28,
+            rate: 0,
+        });
+        assert_eq!(
+            last.due(&wet, MINUTE, &ReportRules::DEFAULT),
+            Some(Trigger::Changed(Quantity::Rain))
+        );
+    }
+
+    #[test]
+    fn survives_rtc_memory() {
+        let mut measurement = reading(-45, 910);
+        measurement.co2 = Some(612);
+        let mut last = LastReport::default();
+        last.sent(&measurement, 123_456_789_012);
+        assert_eq!(LastReport::from_words(&last.to_words()), last);
+        // Nothing reported yet, and a torn write, read back as never sent.
+        assert_eq!(
+            LastReport::from_words(&LastReport::default().to_words()),
+            LastReport::default()
+        );
+        let mut words = last.to_words();
+        words[8] ^= 1;
+        assert_eq!(LastReport::from_words(&words), LastReport::default());
+    }
+}
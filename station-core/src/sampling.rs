@@ -0,0 +1,234 @@
+//! Multi-sample reading policy for the DHT sensors.
+//!
+//! A single DHT read fails often enough (checksum errors, a missed edge) that
+//! uploading whatever the first attempt returned is not good enough. The
+//! [`Sampler`] decides when to read again and reduces the successful samples to
+//! their median, while the caller owns the sensor and the timer.
+
+use crate::dht::{Model, Reading};
+use crate::sensor::SensorError;
+
+/// Upper bound on [`SamplingPolicy::samples`], sizing the sampler's buffer.
+pub const MAX_SAMPLES: usize = 9;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SamplingPolicy {
+    /// Time the sensor needs after power-on before its first reading.
+    pub warm_up_ms: u32,
+    /// Minimum spacing between two reads; the sensor returns stale or corrupt
+    /// data when polled faster.
+    pub min_interval_ms: u32,
+    /// Number of good samples to take the median of.
+    pub samples: u8,
+    /// Failed reads tolerated before giving up.
+    pub retries: u8,
+}
+
+impl SamplingPolicy {
+    /// Datasheet timings for `model`, three samples and three retries.
+    pub fn for_model(model: Model) -> Self {
+        let settle_ms = match model {
+            Model::Dht11 => 1_000,
+            Model::Dht22 => 2_000,
+        };
+        Self {
+            warm_up_ms: settle_ms,
+            min_interval_ms: settle_ms,
+            samples: 3,
+            retries: 3,
+        }
+    }
+
+    /// How much longer to wait before the first read, given the time since the
+    /// sensor was powered.
+    pub fn warm_up_remaining_ms(&self, powered_for_ms: u64) -> u32 {
+        (self.warm_up_ms as u64).saturating_sub(powered_for_ms) as u32
+    }
+}
+
+/// What the caller should do after recording a read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Read again after waiting this many milliseconds.
+    ReadAfter(u32),
+    Done,
+}
+
+pub struct Sampler {
+    policy: SamplingPolicy,
+    readings: [Reading; MAX_SAMPLES],
+    len: usize,
+    failures: u8,
+    last_error: Option<SensorError>,
+}
+
+impl Sampler {
+    pub fn new(policy: SamplingPolicy) -> Self {
+        Self {
+            policy: SamplingPolicy {
+                samples: policy.samples.clamp(1, MAX_SAMPLES as u8),
+                ..policy
+            },
+            readings: [Reading {
+                humidity: 0,
+                temperature: 0,
+                pressure: None,
+            }; MAX_SAMPLES],
+            len: 0,
+            failures: 0,
+            last_error: None,
+        }
+    }
+
+    /// Records the outcome of one read and says whether another is needed.
+    ///
+    /// Checksum mismatches and timeouts use up the retry budget; a pin error
+    /// means the hardware is misconfigured, so sampling stops right away.
+    pub fn record(&mut self, result: Result<Reading, SensorError>) -> Step {
+        match result {
+            Ok(reading) => {
+                self.readings[self.len] = reading;
+                self.len += 1;
+            }
+            Err(error) => {
+                self.last_error = Some(error);
+                let retryable = error == SensorError::ChecksumMismatch || error.is_timeout();
+                if !retryable || self.failures >= self.policy.retries {
+                    return Step::Done;
+                }
+                self.failures += 1;
+            }
+        }
+        if self.len >= self.policy.samples as usize {
+            Step::Done
+        } else {
+            Step::ReadAfter(self.policy.min_interval_ms)
+        }
+    }
+
+    /// The median of the good samples, or the last error if there were none.
+    pub fn finish(&self) -> Result<Reading, SensorError> {
+        if self.len == 0 {
+            return Err(self.last_error.unwrap_or(SensorError::NoResponse));
+        }
+        let readings = &self.readings[..self.len];
+        let mut temperatures = [0i32; MAX_SAMPLES];
+        let mut humidities = [0i32; MAX_SAMPLES];
+        let mut pressures = [0i32; MAX_SAMPLES];
+        let mut with_pressure = 0;
+        for (idx, reading) in readings.iter().enumerate() {
+            temperatures[idx] = reading.temperature as i32;
+            humidities[idx] = reading.humidity as i32;
+            if let Some(pressure) = reading.pressure {
+                pressures[with_pressure] = pressure as i32;
+                with_pressure += 1;
+            }
+        }
+        Ok(Reading {
+            temperature: median(&mut temperatures[..self.len]) as i16,
+            humidity: median(&mut humidities[..self.len]) as u16,
+            pressure: (with_pressure > 0).then(|| median(&mut pressures[..with_pressure]) as u32),
+        })
+    }
+}
+
+/// Median of a non-empty slice; even lengths average the two middle values.
+fn median(values: &mut [i32]) -> i32 {
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(temperature: i16, humidity: u16) -> Result<Reading, SensorError> {
+        Ok(Reading {
+            humidity,
+            temperature,
+            pressure: None,
+        })
+    }
+
+    fn policy(samples: u8, retries: u8) -> SamplingPolicy {
+        SamplingPolicy {
+            warm_up_ms: 1_000,
+            min_interval_ms: 1_000,
+            samples,
+            retries,
+        }
+    }
+
+    #[test]
+    fn takes_median_of_samples() {
+        let mut sampler = Sampler::new(policy(3, 0));
+        assert_eq!(sampler.record(reading(215, 400)), Step::ReadAfter(1_000));
+        assert_eq!(sampler.record(reading(900, 410)), Step::ReadAfter(1_000));
+        assert_eq!(sampler.record(reading(212, 990)), Step::Done);
+        assert_eq!(sampler.finish(), reading(215, 410));
+    }
+
+    #[test]
+    fn even_sample_count_averages_middle_pair() {
+        let mut sampler = Sampler::new(policy(2, 0));
+        sampler.record(reading(-20, 500));
+        sampler.record(reading(-10, 520));
+        assert_eq!(sampler.finish(), reading(-15, 510));
+    }
+
+    #[test]
+    fn retries_checksum_and_timeouts_within_budget() {
+        let mut sampler = Sampler::new(policy(1, 2));
+        let step = sampler.record(Err(SensorError::ChecksumMismatch));
+        assert_eq!(step, Step::ReadAfter(1_000));
+        let step = sampler.record(Err(SensorError::BitTimeout { bit: 4 }));
+        assert_eq!(step, Step::ReadAfter(1_000));
+        assert_eq!(sampler.record(reading(230, 550)), Step::Done);
+        assert_eq!(sampler.finish(), reading(230, 550));
+    }
+
+    #[test]
+    fn reports_last_error_when_budget_runs_out() {
+        let mut sampler = Sampler::new(policy(3, 1));
+        sampler.record(Err(SensorError::ChecksumMismatch));
+        assert_eq!(sampler.record(Err(SensorError::NoResponse)), Step::Done);
+        assert_eq!(sampler.finish(), Err(SensorError::NoResponse));
+    }
+
+    #[test]
+    fn keeps_partial_samples_when_budget_runs_out() {
+        let mut sampler = Sampler::new(policy(3, 0));
+        sampler.record(reading(230, 550));
+        assert_eq!(sampler.record(Err(SensorError::StuckHigh)), Step::Done);
+        assert_eq!(sampler.finish(), reading(230, 550));
+    }
+
+    #[test]
+    fn pin_error_stops_immediately() {
+        let mut sampler = Sampler::new(policy(3, 5));
+        assert_eq!(sampler.record(Err(SensorError::PinError)), Step::Done);
+        assert_eq!(sampler.finish(), Err(SensorError::PinError));
+    }
+
+    #[test]
+    fn warm_up_counts_from_power_on() {
+        let policy = SamplingPolicy::for_model(Model::Dht22);
+        assert_eq!(policy.warm_up_remaining_ms(0), 2_000);
+        assert_eq!(policy.warm_up_remaining_ms(1_500), 500);
+        assert_eq!(policy.warm_up_remaining_ms(60_000), 0);
+    }
+
+    #[test]
+    fn sample_count_is_clamped_to_buffer() {
+        let mut sampler = Sampler::new(policy(200, 0));
+        for _ in 0..MAX_SAMPLES - 1 {
+            assert!(matches!(sampler.record(reading(1, 1)), Step::ReadAfter(_)));
+        }
+        assert_eq!(sampler.record(reading(1, 1)), Step::Done);
+    }
+}
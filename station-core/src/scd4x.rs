@@ -0,0 +1,327 @@
+//! Sensirion SCD40/SCD41 photoacoustic CO2 sensors over I2C.
+//!
+//! The SCD41 takes one measurement on request and idles in between, which
+//! suits a station that deep sleeps between wakes. The SCD40 only measures
+//! periodically, so it is started, read once and stopped again. Commands are
+//! 16-bit words; arguments and responses are words followed by the same
+//! CRC-8 as the SHT sensors.
+//!
+//! CO2 readings depend on air density, so the sensor is told the ambient
+//! pressure before each measurement when another sensor provides it.
+//! Automatic self-calibration assumes the sensor sees fresh air (about 400
+//! ppm) at least once a week; indoors or in a greenhouse it has to be turned
+//! off, and the setting is stored in the sensor's EEPROM.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::sensor::{Measurement, SensorError};
+use crate::sht::{self, crc8, decode_words};
+
+/// Fixed address of the SCD4x family.
+pub const ADDRESS: u8 = 0x62;
+
+const MEASURE_SINGLE_SHOT: u16 = 0x219D;
+const START_PERIODIC: u16 = 0x21B1;
+const STOP_PERIODIC: u16 = 0x3F86;
+const READ_MEASUREMENT: u16 = 0xEC05;
+const SET_AMBIENT_PRESSURE: u16 = 0xE000;
+const SET_ASC_ENABLED: u16 = 0x2416;
+const GET_ASC_ENABLED: u16 = 0x2313;
+const PERSIST_SETTINGS: u16 = 0x3615;
+
+const MEASURE_MS: u32 = 5_000;
+const STOP_MS: u32 = 500;
+const PERSIST_MS: u32 = 800;
+// Execution time of get and set commands.
+const COMMAND_MS: u32 = 1;
+
+/// Pressures the sensor accepts for compensation, in pascals.
+const PRESSURE_RANGE: (u32, u32) = (70_000, 120_000);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    Scd40,
+    Scd41,
+}
+
+/// Encodes a command without argument.
+pub fn command(command: u16) -> [u8; 2] {
+    command.to_be_bytes()
+}
+
+/// Encodes a command followed by one argument word and its CRC.
+pub fn command_with_arg(command: u16, arg: u16) -> [u8; 5] {
+    let [c0, c1] = command.to_be_bytes();
+    let [a0, a1] = arg.to_be_bytes();
+    [c0, c1, a0, a1, crc8(&[a0, a1])]
+}
+
+/// CO2 concentration with the temperature and humidity the sensor measured
+/// alongside it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Co2Reading {
+    /// CO2 in parts per million.
+    pub co2: u16,
+    /// Temperature in tenths of a degree Celsius.
+    pub temperature: i16,
+    /// Relative humidity in tenths of a percent.
+    pub humidity: u16,
+}
+
+/// Decodes the three CRC-protected words returned by `read_measurement`.
+pub fn decode_measurement(data: &[u8]) -> Result<Co2Reading, SensorError> {
+    let [co2, temperature, humidity] = decode_words::<3>(data)?;
+    // A CO2 value of 0 means the measurement was not ready.
+    if co2 == 0 {
+        return Err(SensorError::NoResponse);
+    }
+    // Same transfer functions as the SHT3x.
+    let reading = sht::convert(sht::Variant::Sht3x, temperature, humidity);
+    Ok(Co2Reading {
+        co2,
+        temperature: reading.temperature,
+        humidity: reading.humidity,
+    })
+}
+
+impl From<Co2Reading> for Measurement {
+    fn from(reading: Co2Reading) -> Self {
+        Self {
+            temperature: Some(reading.temperature),
+            humidity: Some(reading.humidity),
+            co2: Some(reading.co2),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Scd4x<I> {
+    i2c: I,
+    variant: Variant,
+}
+
+impl<I: I2c> Scd4x<I> {
+    pub fn new(i2c: I, variant: Variant) -> Self {
+        Self { i2c, variant }
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// Sets the pressure, in pascals, the next measurements are compensated
+    /// for. Values outside the sensor's accepted range are clamped to it.
+    pub fn set_ambient_pressure<D: DelayNs>(
+        &mut self,
+        pascals: u32,
+        delay: &mut D,
+    ) -> Result<(), SensorError> {
+        let pascals = pascals.clamp(PRESSURE_RANGE.0, PRESSURE_RANGE.1);
+        let hpa = (pascals + 50) / 100;
+        self.write(&command_with_arg(SET_AMBIENT_PRESSURE, hpa as u16))?;
+        delay.delay_ms(COMMAND_MS);
+        Ok(())
+    }
+
+    /// Whether automatic self-calibration is currently enabled.
+    pub fn automatic_self_calibration<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<bool, SensorError> {
+        self.write(&command(GET_ASC_ENABLED))?;
+        delay.delay_ms(COMMAND_MS);
+        let [enabled] = self.read_words::<1>()?;
+        Ok(enabled != 0)
+    }
+
+    /// Turns automatic self-calibration on or off and stores the setting in
+    /// EEPROM so it survives power cycles. The EEPROM only endures a few
+    /// thousand writes, so nothing is written if the setting already matches.
+    /// Returns whether it had to be changed.
+    pub fn set_automatic_self_calibration<D: DelayNs>(
+        &mut self,
+        enabled: bool,
+        delay: &mut D,
+    ) -> Result<bool, SensorError> {
+        if self.automatic_self_calibration(delay)? == enabled {
+            return Ok(false);
+        }
+        self.write(&command_with_arg(SET_ASC_ENABLED, enabled as u16))?;
+        delay.delay_ms(COMMAND_MS);
+        self.write(&command(PERSIST_SETTINGS))?;
+        delay.delay_ms(PERSIST_MS);
+        Ok(true)
+    }
+
+    /// Takes one measurement, which takes about five seconds.
+    pub fn measure<D: DelayNs>(&mut self, delay: &mut D) -> Result<Co2Reading, SensorError> {
+        match self.variant {
+            Variant::Scd41 => {
+                self.write(&command(MEASURE_SINGLE_SHOT))?;
+                delay.delay_ms(MEASURE_MS);
+                self.read_measurement(delay)
+            }
+            Variant::Scd40 => {
+                self.write(&command(START_PERIODIC))?;
+                delay.delay_ms(MEASURE_MS);
+                let result = self.read_measurement(delay);
+                // Stop even after a failed read so the sensor accepts
+                // settings again
+                self.write(&command(STOP_PERIODIC))?;
+                delay.delay_ms(STOP_MS);
+                result
+            }
+        }
+    }
+
+    fn read_measurement<D: DelayNs>(&mut self, delay: &mut D) -> Result<Co2Reading, SensorError> {
+        self.write(&command(READ_MEASUREMENT))?;
+        delay.delay_ms(COMMAND_MS);
+        let mut data = [0u8; 9];
+        self.i2c
+            .read(ADDRESS, &mut data)
+            .map_err(|_| SensorError::BusError)?;
+        decode_measurement(&data)
+    }
+
+    fn read_words<const N: usize>(&mut self) -> Result<[u16; N], SensorError> {
+        let mut data = [0u8; 9];
+        let data = &mut data[..N * 3];
+        self.i2c
+            .read(ADDRESS, data)
+            .map_err(|_| SensorError::BusError)?;
+        decode_words::<N>(data)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SensorError> {
+        self.i2c
+            .write(ADDRESS, bytes)
+            .map_err(|_| SensorError::BusError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Commands, NoDelay};
+
+    fn words(values: &[u16]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for word in values {
+            let bytes = word.to_be_bytes();
+            out.extend_from_slice(&bytes);
+            out.push(crc8(&bytes));
+        }
+        out
+    }
+
+    #[test]
+    fn encodes_commands_as_in_datasheet() {
+        assert_eq!(command(MEASURE_SINGLE_SHOT), [0x21, 0x9D]);
+        // Ambient pressure of 987 hPa.
+        assert_eq!(
+            command_with_arg(SET_AMBIENT_PRESSURE, 0x03DB),
+            [0xE0, 0x00, 0x03, 0xDB, 0x42]
+        );
+        assert_eq!(
+            command_with_arg(SET_ASC_ENABLED, 1),
+            [0x24, 0x16, 0x00, 0x01, 0xB0]
+        );
+    }
+
+    #[test]
+    fn decodes_measurement() {
+        // 500 ppm, 0x6667 is 25.0 C, 0x5EB9 is 37.0 %RH.
+        let reading = decode_measurement(&words(&[0x01F4, 0x6667, 0x5EB9])).unwrap();
+        assert_eq!(
+            reading,
+            Co2Reading {
+                co2: 500,
+                temperature: 250,
+                humidity: 370
+            }
+        );
+    }
+
+    #[test]
+    fn corrupted_measurement_is_a_checksum_mismatch() {
+        let mut data = words(&[0x01F4, 0x6667, 0x5EB9]);
+        data[1] ^= 0x10;
+        assert_eq!(
+            decode_measurement(&data),
+            Err(SensorError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn zero_co2_means_not_ready() {
+        assert_eq!(
+            decode_measurement(&words(&[0, 0x6667, 0x5EB9])),
+            Err(SensorError::NoResponse)
+        );
+    }
+
+    #[test]
+    fn scd41_measures_single_shot() {
+        let device = Commands::new(ADDRESS);
+        device.queue_read(&words(&[0x0320, 0x6667, 0x5EB9]));
+        let mut scd = Scd4x::new(device.clone(), Variant::Scd41);
+        assert_eq!(scd.measure(&mut NoDelay).unwrap().co2, 800);
+        assert_eq!(device.writes(), vec![vec![0x21, 0x9D], vec![0xEC, 0x05]]);
+    }
+
+    #[test]
+    fn scd40_is_stopped_after_failed_read() {
+        let device = Commands::new(ADDRESS);
+        let mut scd = Scd4x::new(device.clone(), Variant::Scd40);
+        assert_eq!(scd.measure(&mut NoDelay), Err(SensorError::BusError));
+        assert_eq!(
+            device.writes(),
+            vec![vec![0x21, 0xB1], vec![0xEC, 0x05], vec![0x3F, 0x86]]
+        );
+    }
+
+    #[test]
+    fn ambient_pressure_is_sent_in_hectopascals() {
+        let device = Commands::new(ADDRESS);
+        let mut scd = Scd4x::new(device.clone(), Variant::Scd41);
+        scd.set_ambient_pressure(98_730, &mut NoDelay).unwrap();
+        scd.set_ambient_pressure(50_000, &mut NoDelay).unwrap();
+        assert_eq!(
+            device.writes(),
+            vec![
+                command_with_arg(SET_AMBIENT_PRESSURE, 987).to_vec(),
+                command_with_arg(SET_AMBIENT_PRESSURE, 700).to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn self_calibration_is_only_persisted_when_changed() {
+        let device = Commands::new(ADDRESS);
+        device.queue_read(&words(&[1]));
+        let mut scd = Scd4x::new(device.clone(), Variant::Scd41);
+        assert!(
+            !scd.set_automatic_self_calibration(true, &mut NoDelay)
+                .unwrap()
+        );
+        assert_eq!(device.writes(), vec![vec![0x23, 0x13]]);
+
+        let device = Commands::new(ADDRESS);
+        device.queue_read(&words(&[1]));
+        let mut scd = Scd4x::new(device.clone(), Variant::Scd41);
+        assert!(
+            scd.set_automatic_self_calibration(false, &mut NoDelay)
+                .unwrap()
+        );
+        assert_eq!(
+            device.writes(),
+            vec![
+                vec![0x23, 0x13],
+                command_with_arg(SET_ASC_ENABLED, 0).to_vec(),
+                vec![0x36, 0x15],
+            ]
+        );
+    }
+}
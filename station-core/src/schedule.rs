@@ -0,0 +1,537 @@
+//! How long to deep sleep before the next reading.
+//!
+//! The interval starts from the configured one and adapts to conditions:
+//!
+//! - an empty battery stretches it to the maximum, a low one doubles it;
+//! - a value beyond an alert threshold shortens it to the minimum;
+//! - fast changes shorten it, so a front or a storm is resolved in detail;
+//! - readings that stay stable wake after wake stretch it step by step.
+//!
+//! [`next_interval`] makes the decision from a summary of the conditions and
+//! has no state of its own. [`Trend`] compares each reading with the one
+//! before to tell fast changes from stable weather, and is small enough to
+//! live in RTC memory across deep sleep.
+
+use crate::persist;
+use crate::plausibility::Quality;
+use crate::sensor::Measurement;
+
+/// Number of words [`Trend`] occupies in RTC memory.
+pub const TREND_WORDS: usize = 8;
+
+const TREND_MAGIC: u32 = 0x5452_4E44;
+
+const HOUR_MS: u64 = 3_600_000;
+
+/// Rates of change per hour: temperature in tenths of a degree, humidity in
+/// tenths of a percent, pressure in pascals.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rates {
+    pub temperature: u32,
+    pub humidity: u32,
+    pub pressure: u32,
+}
+
+/// Values that call for closer watching. `None` disables a threshold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Alerts {
+    /// Temperature at or above this, in tenths of a degree.
+    pub temperature_above: Option<i16>,
+    /// Temperature at or below this, in tenths of a degree.
+    pub temperature_below: Option<i16>,
+    /// Gust in tenths of a metre per second.
+    pub gust_above: Option<u16>,
+    /// Rain rate in hundredths of a millimetre per hour.
+    pub rain_rate_above: Option<u32>,
+    /// PM2.5 in micrograms per cubic metre.
+    pub pm2_5_above: Option<u16>,
+    /// CO2 in parts per million.
+    pub co2_above: Option<u16>,
+}
+
+impl Alerts {
+    pub const NONE: Alerts = Alerts {
+        temperature_above: None,
+        temperature_below: None,
+        gust_above: None,
+        rain_rate_above: None,
+        pm2_5_above: None,
+        co2_above: None,
+    };
+
+    /// Thresholds that matter wherever the station stands; temperature
+    /// depends too much on the climate to have a default.
+    pub const DEFAULT: Alerts = Alerts {
+        // Gale force, Beaufort 8.
+        gust_above: Some(172),
+        // Heavy rain.
+        rain_rate_above: Some(1_000),
+        // Where the US AQI turns "unhealthy".
+        pm2_5_above: Some(56),
+        co2_above: Some(2_000),
+        ..Alerts::NONE
+    };
+
+    /// Whether any value in `measurement` is beyond its threshold. Values
+    /// graded bad are not trusted to raise an alert.
+    pub fn triggered(&self, measurement: &Measurement) -> bool {
+        fn beyond<T: PartialOrd>(value: Option<T>, threshold: Option<T>, above: bool) -> bool {
+            match (value, threshold) {
+                (Some(value), Some(threshold)) if above => value >= threshold,
+                (Some(value), Some(threshold)) => value <= threshold,
+                _ => false,
+            }
+        }
+        let temperature = measurement
+            .temperature
+            .filter(|_| measurement.quality.temperature != Some(Quality::Bad));
+        beyond(temperature, self.temperature_above, true)
+            || beyond(temperature, self.temperature_below, false)
+            || beyond(measurement.wind.map(|w| w.gust), self.gust_above, true)
+            || beyond(measurement.rain.map(|r| r.rate), self.rain_rate_above, true)
+            || beyond(
+                measurement.particulates.map(|pm| pm.pm2_5),
+                self.pm2_5_above,
+                true,
+            )
+            || beyond(measurement.co2, self.co2_above, true)
+    }
+}
+
+/// How the interval adapts around the configured one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Interval when nothing calls for a change.
+    pub interval_ms: u64,
+    pub min_interval_ms: u64,
+    pub max_interval_ms: u64,
+    /// Battery charge at or below which the interval is doubled.
+    pub low_battery_percent: u8,
+    /// Battery charge at or below which the interval is the maximum.
+    pub critical_battery_percent: u8,
+    /// Changes at least this fast, in any quantity, shorten the interval.
+    pub fast: Rates,
+    /// Changes no faster than this, in every quantity, count as stable.
+    pub stable: Rates,
+    /// Stable wakes in a row before the interval doubles; it doubles again
+    /// after as many more, up to [`Policy::max_interval_ms`].
+    pub stable_wakes: u16,
+    pub alerts: Alerts,
+}
+
+impl Policy {
+    /// Adapts between a fifth and twelve times `interval_ms`; with five
+    /// minutes, between one minute and an hour.
+    pub const fn new(interval_ms: u64) -> Self {
+        Self {
+            interval_ms,
+            min_interval_ms: interval_ms / 5,
+            max_interval_ms: interval_ms * 12,
+            low_battery_percent: 20,
+            critical_battery_percent: 5,
+            // A front passing, or the pressure drop ahead of a storm.
+            fast: Rates {
+                temperature: 30,
+                humidity: 150,
+                pressure: 150,
+            },
+            stable: Rates {
+                temperature: 5,
+                humidity: 20,
+                pressure: 20,
+            },
+            stable_wakes: 6,
+            alerts: Alerts::DEFAULT,
+        }
+    }
+}
+
+/// How the latest reading compares with the one before.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Some quantity changes at least as fast as [`Policy::fast`].
+    Fast,
+    /// Neither fast nor stable, or nothing to compare with.
+    Steady,
+    /// Every quantity has stayed within [`Policy::stable`] for this many
+    /// wakes in a row.
+    Stable { wakes: u16 },
+}
+
+/// Everything the interval depends on at the end of a wake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Conditions {
+    pub battery_percent: Option<u8>,
+    pub change: Change,
+    /// A value is beyond its [`Alerts`] threshold.
+    pub alert: bool,
+}
+
+/// What decided the interval, for logging.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reason {
+    Configured,
+    CriticalBattery,
+    Alert,
+    FastChange,
+    Stable,
+    LowBattery,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub interval_ms: u64,
+    pub reason: Reason,
+}
+
+/// Picks the time until the next wake. An empty battery takes precedence
+/// over everything, since a station that browns out reports nothing at all;
+/// an alert comes next. Otherwise fast changes quarter the interval, stable
+/// readings stretch it, and a low battery doubles the result.
+pub fn next_interval(policy: &Policy, conditions: &Conditions) -> Decision {
+    let decision = |interval_ms: u64, reason| Decision {
+        interval_ms: interval_ms.clamp(policy.min_interval_ms, policy.max_interval_ms),
+        reason,
+    };
+    let battery = conditions.battery_percent;
+    if battery.is_some_and(|percent| percent <= policy.critical_battery_percent) {
+        return decision(policy.max_interval_ms, Reason::CriticalBattery);
+    }
+    if conditions.alert {
+        return decision(policy.min_interval_ms, Reason::Alert);
+    }
+    let (mut interval_ms, mut reason) = match conditions.change {
+        Change::Fast => (policy.interval_ms / 4, Reason::FastChange),
+        Change::Stable { wakes } if policy.stable_wakes > 0 && wakes >= policy.stable_wakes => {
+            let doublings = (wakes / policy.stable_wakes).min(16) as u32;
+            (
+                policy.interval_ms.saturating_mul(1 << doublings),
+                Reason::Stable,
+            )
+        }
+        _ => (policy.interval_ms, Reason::Configured),
+    };
+    if battery.is_some_and(|percent| percent <= policy.low_battery_percent) {
+        interval_ms = interval_ms.saturating_mul(2);
+        reason = Reason::LowBattery;
+    }
+    decision(interval_ms, reason)
+}
+
+/// The previous reading, to judge how fast values are changing.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Trend {
+    temperature: Option<i32>,
+    humidity: Option<i32>,
+    pressure: Option<i32>,
+    at_ms: u64,
+    stable_wakes: u16,
+}
+
+impl Trend {
+    /// Compares `measurement`, taken at `now_ms` on the RTC timer, with the
+    /// previous one and remembers it for the next wake. Values graded bad
+    /// are left out of the comparison.
+    pub fn update(&mut self, measurement: &Measurement, now_ms: u64, policy: &Policy) -> Change {
+        let usable = |value: Option<i32>, quality: Option<Quality>| {
+            value.filter(|_| quality != Some(Quality::Bad))
+        };
+        let quality = measurement.quality;
+        let current = Trend {
+            temperature: usable(measurement.temperature.map(i32::from), quality.temperature),
+            humidity: usable(measurement.humidity.map(i32::from), quality.humidity),
+            pressure: usable(measurement.pressure.map(|p| p as i32), quality.pressure),
+            at_ms: now_ms,
+            stable_wakes: 0,
+        };
+
+        let elapsed_ms = now_ms.saturating_sub(self.at_ms);
+        let rates = [
+            (
+                self.temperature,
+                current.temperature,
+                policy.fast.temperature,
+                policy.stable.temperature,
+            ),
+            (
+                self.humidity,
+                current.humidity,
+                policy.fast.humidity,
+                policy.stable.humidity,
+            ),
+            (
+                self.pressure,
+                current.pressure,
+                policy.fast.pressure,
+                policy.stable.pressure,
+            ),
+        ];
+        let mut compared = false;
+        let mut fast = false;
+        let mut stable = true;
+        for (before, now, fast_rate, stable_rate) in rates {
+            let (Some(before), Some(now)) = (before, now) else {
+                continue;
+            };
+            if elapsed_ms == 0 {
+                break;
+            }
+            compared = true;
+            let per_hour = now.abs_diff(before) as u64 * HOUR_MS / elapsed_ms;
+            fast |= per_hour >= fast_rate as u64;
+            stable &= per_hour <= stable_rate as u64;
+        }
+
+        let change = if !compared {
+            Change::Steady
+        } else if fast {
+            Change::Fast
+        } else if stable {
+            Change::Stable {
+                wakes: self.stable_wakes.saturating_add(1),
+            }
+        } else {
+            Change::Steady
+        };
+        *self = Trend {
+            stable_wakes: match change {
+                Change::Stable { wakes } => wakes,
+                _ => 0,
+            },
+            ..current
+        };
+        change
+    }
+
+    pub fn to_words(&self) -> [u32; TREND_WORDS] {
+        let [at_low, at_high] = persist::split_u64(self.at_ms);
+        let known = self.temperature.is_some() as u32
+            | (self.humidity.is_some() as u32) << 1
+            | (self.pressure.is_some() as u32) << 2;
+        let mut words = [
+            self.temperature.unwrap_or(0) as u32,
+            self.humidity.unwrap_or(0) as u32,
+            self.pressure.unwrap_or(0) as u32,
+            at_low,
+            at_high,
+            known,
+            self.stable_wakes as u32,
+            0,
+        ];
+        persist::seal(&mut words, TREND_MAGIC);
+        words
+    }
+
+    /// Restores a trend saved by [`Trend::to_words`], starting afresh if the
+    /// memory does not hold one.
+    pub fn from_words(words: &[u32; TREND_WORDS]) -> Self {
+        if !persist::is_sealed(words, TREND_MAGIC) {
+            return Self::default();
+        }
+        let known = |bit: u32| words[5] & 1 << bit != 0;
+        Self {
+            temperature: known(0).then_some(words[0] as i32),
+            humidity: known(1).then_some(words[1] as i32),
+            pressure: known(2).then_some(words[2] as i32),
+            at_ms: persist::join_u64(&words[3..5]),
+            stable_wakes: words[6] as u16,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pms::Particulates;
+    use crate::wind::Wind;
+
+    const MINUTE: u64 = 60_000;
+    const POLICY: Policy = Policy::new(5 * MINUTE);
+
+    fn conditions(battery_percent: u8, change: Change, alert: bool) -> Conditions {
+        Conditions {
+            battery_percent: Some(battery_percent),
+            change,
+            alert,
+        }
+    }
+
+    fn reading(temperature: i16, pressure: u32) -> Measurement {
+        Measurement {
+            temperature: Some(temperature),
+            pressure: Some(pressure),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_configured_interval_by_default() {
+        let decision = next_interval(&POLICY, &conditions(80, Change::Steady, false));
+        assert_eq!(
+            decision,
+            Decision {
+                interval_ms: 5 * MINUTE,
+                reason: Reason::Configured
+            }
+        );
+        // Without a battery reading nothing changes either.
+        let unknown = Conditions {
+            battery_percent: None,
+            change: Change::Steady,
+            alert: false,
+        };
+        assert_eq!(next_interval(&POLICY, &unknown).interval_ms, 5 * MINUTE);
+    }
+
+    #[test]
+    fn low_battery_doubles_and_empty_battery_maximizes() {
+        let low = next_interval(&POLICY, &conditions(20, Change::Steady, false));
+        assert_eq!(
+            (low.interval_ms, low.reason),
+            (10 * MINUTE, Reason::LowBattery)
+        );
+        let empty = next_interval(&POLICY, &conditions(5, Change::Fast, true));
+        assert_eq!(
+            (empty.interval_ms, empty.reason),
+            (60 * MINUTE, Reason::CriticalBattery)
+        );
+    }
+
+    #[test]
+    fn alert_shortens_to_minimum() {
+        let decision = next_interval(&POLICY, &conditions(15, Change::Stable { wakes: 50 }, true));
+        assert_eq!(
+            (decision.interval_ms, decision.reason),
+            (MINUTE, Reason::Alert)
+        );
+    }
+
+    #[test]
+    fn fast_change_shortens() {
+        let decision = next_interval(&POLICY, &conditions(80, Change::Fast, false));
+        assert_eq!(
+            (decision.interval_ms, decision.reason),
+            (75_000, Reason::FastChange)
+        );
+        // Never below the minimum, though.
+        let cautious = Policy {
+            min_interval_ms: 2 * MINUTE,
+            ..POLICY
+        };
+        assert_eq!(
+            next_interval(&cautious, &conditions(80, Change::Fast, false)).interval_ms,
+            2 * MINUTE
+        );
+    }
+
+    #[test]
+    fn stability_stretches_step_by_step() {
+        let interval = |wakes| {
+            next_interval(&POLICY, &conditions(80, Change::Stable { wakes }, false)).interval_ms
+        };
+        assert_eq!(interval(5), 5 * MINUTE);
+        assert_eq!(interval(6), 10 * MINUTE);
+        assert_eq!(interval(12), 20 * MINUTE);
+        assert_eq!(interval(18), 40 * MINUTE);
+        assert_eq!(interval(24), 60 * MINUTE);
+        assert_eq!(interval(u16::MAX), 60 * MINUTE);
+        // Low battery on top, still within the maximum.
+        let tired = next_interval(&POLICY, &conditions(10, Change::Stable { wakes: 6 }, false));
+        assert_eq!(
+            (tired.interval_ms, tired.reason),
+            (20 * MINUTE, Reason::LowBattery)
+        );
+    }
+
+    #[test]
+    fn alerts_fire_at_thresholds() {
+        let alerts = Alerts {
+            temperature_above: Some(350),
+            temperature_below: Some(0),
+            ..Alerts::DEFAULT
+        };
+        assert!(!alerts.triggered(&reading(200, 101_325)));
+        assert!(alerts.triggered(&reading(350, 101_325)));
+        assert!(alerts.triggered(&reading(-5, 101_325)));
+        let mut bad = reading(900, 101_325);
+        bad.quality.temperature = Some(Quality::Bad);
+        assert!(!alerts.triggered(&bad));
+
+        let gale = Measurement {
+            wind: Some(Wind {
+                speed: 90,
+                gust: 180,
+                direction: None,
+            }),
+            ..Default::default()
+        };
+        assert!(alerts.triggered(&gale));
+        let smoke = Measurement {
+            particulates: Some(Particulates {
+                pm1_0: 40,
+                pm2_5: 60,
+                pm10: 80,
+            }),
+            ..Default::default()
+        };
+        assert!(alerts.triggered(&smoke));
+        assert!(!Alerts::NONE.triggered(&smoke));
+    }
+
+    #[test]
+    fn trend_detects_fast_change() {
+        let mut trend = Trend::default();
+        assert_eq!(
+            trend.update(&reading(200, 101_300), 0, &POLICY),
+            Change::Steady
+        );
+        // 2 hPa in ten minutes is 12 hPa per hour.
+        assert_eq!(
+            trend.update(&reading(200, 101_100), 10 * MINUTE, &POLICY),
+            Change::Fast
+        );
+        // 0.6 °C in ten minutes is 3.6 °C per hour.
+        assert_eq!(
+            trend.update(&reading(206, 101_100), 20 * MINUTE, &POLICY),
+            Change::Fast
+        );
+    }
+
+    #[test]
+    fn trend_counts_stable_wakes() {
+        let mut trend = Trend::default();
+        trend.update(&reading(200, 101_300), 0, &POLICY);
+        for wake in 1..=3 {
+            assert_eq!(
+                trend.update(&reading(200, 101_300), wake * 5 * MINUTE, &POLICY),
+                Change::Stable { wakes: wake as u16 }
+            );
+        }
+        // 1 °C per hour is neither fast nor stable, and starts the count over.
+        assert_eq!(
+            trend.update(&reading(205, 101_300), 20 * MINUTE + 10 * MINUTE, &POLICY),
+            Change::Steady
+        );
+        assert_eq!(
+            trend.update(&reading(205, 101_300), 35 * MINUTE, &POLICY),
+            Change::Stable { wakes: 1 }
+        );
+    }
+
+    #[test]
+    fn trend_ignores_bad_values_and_survives_rtc_memory() {
+        let mut trend = Trend::default();
+        trend.update(&reading(200, 101_300), 0, &POLICY);
+        let mut spike = reading(850, 101_300);
+        spike.quality.temperature = Some(Quality::Bad);
+        assert_eq!(
+            trend.update(&spike, 5 * MINUTE, &POLICY),
+            Change::Stable { wakes: 1 }
+        );
+        let restored = Trend::from_words(&trend.to_words());
+        assert_eq!(restored, trend);
+        let mut words = trend.to_words();
+        words[0] ^= 1;
+        assert_eq!(Trend::from_words(&words), Trend::default());
+    }
+}
@@ -0,0 +1,922 @@
+//! Sensor-independent measurement types and polling.
+//!
+//! Every sensor driver exposes itself through [`EnvironmentalSensor`], filling
+//! in the quantities it can measure. The firmware keeps the sensors fitted to a
+//! station in a list and [`poll_all`] merges their results into one
+//! [`Measurement`] for upload, so stations can mix sensors without changes to
+//! the upload path.
+
+use core::fmt;
+
+use crate::battery::Battery;
+use crate::calibration::CalibrationTable;
+use crate::clock::Timestamp;
+use crate::derived::Derived;
+use crate::dht::Reading;
+use crate::fixed::{Scaled, Tenths};
+use crate::nmea::Fix;
+use crate::onewire::Rom;
+use crate::plausibility::{self, Assessment, Limits, Quality};
+use crate::pms::Particulates;
+use crate::rain::Rainfall;
+use crate::wind::Wind;
+
+/// Number of failed sensors a [`Measurement`] can report.
+pub const MAX_FAULTS: usize = 8;
+
+/// Number of 1-Wire temperature probes a [`Measurement`] can report.
+pub const MAX_PROBES: usize = 8;
+
+/// Longest [`EnvironmentalSensor::name`] that [`MAX_JSON_LEN`] makes room
+/// for in the fault list.
+pub const MAX_SENSOR_NAME: usize = 8;
+
+/// Longest JSON [`Measurement::write_json`] produces: every optional field
+/// present at its widest value, all probes and faults, and the longest
+/// status. Firmware buffers for the upload are sized from this.
+pub const MAX_JSON_LEN: usize = r#"{"status":"sensor_failed","error":"checksum_mismatch""#.len()
+    + r#","time":"584556019-04-03T14:25:51.615Z","unix_ms":18446744073709551615"#.len()
+    + r#","temp":-3276.8,"hum":6553.5,"pres":42949672.95,"co2":65535"#.len()
+    + r#","dew_point":-3276.8,"heat_index":-3276.8,"humidex":-3276.8,"wet_bulb":-3276.8"#.len()
+    + r#","abs_hum":6553.5"#.len()
+    + r#","probes":{}"#.len()
+    + MAX_PROBES * r#""0123456789ABCDEF":-3276.8,"#.len()
+    + r#","quality":{"temp":"suspect","hum":"suspect","pres":"suspect"}"#.len()
+    + r#","rain":42949672.95,"rain_rate":42949672.95"#.len()
+    + r#","wind":6553.5,"gust":6553.5,"wind_dir":337.5,"wind_card":"NNE""#.len()
+    + r#","pm1":65535,"pm25":65535,"pm10":65535,"aqi":65535"#.len()
+    + r#","lat":-214.7483648,"lon":-214.7483648,"alt":-214748364.8"#.len()
+    + r#","fix":"simulation","sats":255,"gps_time":"65535-255-255T255:255:255Z""#.len()
+    + r#","batt_v":65.535,"batt_pct":255"#.len()
+    + r#","faults":[]"#.len()
+    + MAX_FAULTS * (r#"":checksum_mismatch","#.len() + MAX_SENSOR_NAME)
+    + r#"}"#.len();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SensorError {
+    ChecksumMismatch,
+    /// The line never went low after the start signal: nothing is connected,
+    /// or the sensor is unpowered.
+    NoResponse,
+    /// The line was held low for longer than any part of the protocol allows.
+    StuckLow,
+    /// The line was held high for longer than any part of the protocol allows.
+    StuckHigh,
+    /// An edge inside the 40 data bits arrived late; `bit` counts from the
+    /// most significant bit of the first byte.
+    BitTimeout {
+        bit: u8,
+    },
+    PinError,
+    /// A transfer on a shared bus (I2C, UART) failed or was not acknowledged.
+    BusError,
+    /// Something answered at the sensor's address, but its identification
+    /// did not match any supported part.
+    UnknownDevice,
+}
+
+impl SensorError {
+    /// Short machine-readable name, used in uploads.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SensorError::ChecksumMismatch => "checksum_mismatch",
+            SensorError::NoResponse => "no_response",
+            SensorError::StuckLow => "stuck_low",
+            SensorError::StuckHigh => "stuck_high",
+            SensorError::BitTimeout { .. } => "bit_timeout",
+            SensorError::PinError => "pin_error",
+            SensorError::BusError => "bus_error",
+            SensorError::UnknownDevice => "unknown_device",
+        }
+    }
+
+    /// Whether the error came from a missing or late edge on the data line,
+    /// as opposed to a corrupted frame or a pin fault.
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            SensorError::NoResponse
+                | SensorError::StuckLow
+                | SensorError::StuckHigh
+                | SensorError::BitTimeout { .. }
+        )
+    }
+}
+
+/// A sensor that could not be read this cycle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub sensor: &'static str,
+    pub error: SensorError,
+}
+
+/// Temperature of one probe on a 1-Wire bus, in tenths of a degree Celsius.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProbeReading {
+    pub rom: Rom,
+    pub temperature: i16,
+}
+
+/// Everything a station reports for one wake cycle. Each quantity is optional
+/// since no single sensor measures all of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Measurement {
+    /// Temperature in tenths of a degree Celsius.
+    pub temperature: Option<i16>,
+    /// Relative humidity in tenths of a percent.
+    pub humidity: Option<u16>,
+    /// Barometric pressure in pascals.
+    pub pressure: Option<u32>,
+    /// CO2 concentration in parts per million.
+    pub co2: Option<u16>,
+    /// External probes, reported individually rather than merged into
+    /// `temperature` since they usually sit somewhere else (soil, water).
+    pub probes: heapless::Vec<ProbeReading, MAX_PROBES>,
+    /// How trustworthy each of the quantities above is, see
+    /// [`plausibility`].
+    pub quality: Assessment,
+    /// The station's own supply, not counted as a sensor value.
+    pub battery: Option<Battery>,
+    /// Rain counted since the last acknowledged upload.
+    pub rain: Option<Rainfall>,
+    pub wind: Option<Wind>,
+    pub particulates: Option<Particulates>,
+    /// Where the reading was taken, for portable stations with a GPS.
+    pub location: Option<Fix>,
+    /// When the reading was taken, as Unix time in milliseconds, once the
+    /// station's [`WallClock`](crate::clock::WallClock) is set.
+    pub timestamp: Option<u64>,
+    pub faults: heapless::Vec<Fault, MAX_FAULTS>,
+}
+
+impl Measurement {
+    /// Whether no sensor delivered any value. The battery and the clock
+    /// are the station's own and do not count.
+    pub fn is_empty(&self) -> bool {
+        self.temperature.is_none()
+            && self.humidity.is_none()
+            && self.pressure.is_none()
+            && self.co2.is_none()
+            && self.probes.is_empty()
+            && self.rain.is_none()
+            && self.wind.is_none()
+            && self.particulates.is_none()
+            && self.location.is_none()
+    }
+
+    /// Fills in the quantities still missing from `self` with those of
+    /// `other`; values already present win, so earlier sensors take priority,
+    /// unless they were graded [`Quality::Bad`] and `other` has a better one.
+    pub fn merge(&mut self, other: Measurement) {
+        fn pick<T>(
+            value: &mut Option<T>,
+            quality: &mut Option<Quality>,
+            other: Option<T>,
+            other_quality: Option<Quality>,
+        ) {
+            let replace = value.is_none()
+                || (*quality == Some(Quality::Bad) && other_quality != Some(Quality::Bad));
+            if replace && other.is_some() {
+                *value = other;
+                *quality = other_quality;
+            }
+        }
+
+        let quality = &mut self.quality;
+        pick(
+            &mut self.temperature,
+            &mut quality.temperature,
+            other.temperature,
+            other.quality.temperature,
+        );
+        pick(
+            &mut self.humidity,
+            &mut quality.humidity,
+            other.humidity,
+            other.quality.humidity,
+        );
+        pick(
+            &mut self.pressure,
+            &mut quality.pressure,
+            other.pressure,
+            other.quality.pressure,
+        );
+        self.co2 = self.co2.or(other.co2);
+        self.battery = self.battery.or(other.battery);
+        self.rain = self.rain.or(other.rain);
+        self.wind = self.wind.or(other.wind);
+        self.particulates = self.particulates.or(other.particulates);
+        self.location = self.location.or(other.location);
+        self.timestamp = self.timestamp.or(other.timestamp);
+        for probe in other.probes {
+            let _ = self.probes.push(probe);
+        }
+        for fault in other.faults {
+            let _ = self.faults.push(fault);
+        }
+    }
+
+    /// Dew point, heat index and the like, if temperature and humidity were
+    /// both measured and neither was graded bad.
+    pub fn derived(&self) -> Option<Derived> {
+        if self.quality.temperature == Some(Quality::Bad)
+            || self.quality.humidity == Some(Quality::Bad)
+        {
+            return None;
+        }
+        let reading = Reading {
+            temperature: self.temperature?,
+            humidity: self.humidity?,
+            pressure: self.pressure,
+        };
+        Derived::from_reading(&reading)
+    }
+
+    /// Serializes the measurement as the JSON object the server ingests.
+    ///
+    /// A cycle where no sensor produced anything is reported with a
+    /// `sensor_failed` status rather than placeholder values. When both
+    /// temperature and humidity are known, the quantities in [`Derived`] are
+    /// added as well.
+    pub fn write_json<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        if self.is_empty() {
+            let error = self.faults.first().map_or("no_sensors", |f| f.error.code());
+            write!(w, "{{\"status\":\"sensor_failed\",\"error\":\"{}\"", error)?;
+        } else {
+            w.write_str("{\"status\":\"ok\"")?;
+        }
+        if let Some(timestamp) = self.timestamp {
+            write!(
+                w,
+                ",\"time\":\"{}\",\"unix_ms\":{}",
+                Timestamp(timestamp),
+                timestamp
+            )?;
+        }
+        if let Some(temperature) = self.temperature {
+            write!(w, ",\"temp\":{}", Tenths(temperature as i32))?;
+        }
+        if let Some(humidity) = self.humidity {
+            write!(w, ",\"hum\":{}", Tenths(humidity as i32))?;
+        }
+        if let Some(pressure) = self.pressure {
+            // Pascals to hectopascals with two decimals.
+            write!(w, ",\"pres\":{}.{:02}", pressure / 100, pressure % 100)?;
+        }
+        if let Some(co2) = self.co2 {
+            write!(w, ",\"co2\":{}", co2)?;
+        }
+        if let Some(derived) = self.derived() {
+            write!(w, ",\"dew_point\":{}", Tenths(derived.dew_point as i32))?;
+            write!(w, ",\"heat_index\":{}", Tenths(derived.heat_index as i32))?;
+            write!(w, ",\"humidex\":{}", Tenths(derived.humidex as i32))?;
+            write!(w, ",\"wet_bulb\":{}", Tenths(derived.wet_bulb as i32))?;
+            write!(
+                w,
+                ",\"abs_hum\":{}",
+                Tenths(derived.absolute_humidity as i32)
+            )?;
+        }
+        if !self.probes.is_empty() {
+            w.write_str(",\"probes\":{")?;
+            for (idx, probe) in self.probes.iter().enumerate() {
+                if idx > 0 {
+                    w.write_str(",")?;
+                }
+                write!(w, "\"{}\":{}", probe.rom, Tenths(probe.temperature as i32))?;
+            }
+            w.write_str("}")?;
+        }
+        if !self.quality.is_empty() {
+            w.write_str(",\"quality\":{")?;
+            let mut first = true;
+            for (key, quality) in [
+                ("temp", self.quality.temperature),
+                ("hum", self.quality.humidity),
+                ("pres", self.quality.pressure),
+            ] {
+                let Some(quality) = quality else { continue };
+                if !first {
+                    w.write_str(",")?;
+                }
+                first = false;
+                write!(w, "\"{}\":\"{}\"", key, quality.code())?;
+            }
+            w.write_str("}")?;
+        }
+        if let Some(rain) = self.rain {
+            write!(
+                w,
+                ",\"rain\":{}.{:02},\"rain_rate\":{}.{:02}",
+                rain.amount / 100,
+                rain.amount % 100,
+                rain.rate / 100,
+                rain.rate % 100
+            )?;
+        }
+        if let Some(wind) = self.wind {
+            write!(
+                w,
+                ",\"wind\":{},\"gust\":{}",
+                Tenths(wind.speed as i32),
+                Tenths(wind.gust as i32)
+            )?;
+            if let Some(direction) = wind.direction {
+                write!(
+                    w,
+                    ",\"wind_dir\":{},\"wind_card\":\"{}\"",
+                    Tenths(direction.decidegrees() as i32),
+                    direction.cardinal()
+                )?;
+            }
+        }
+        if let Some(pm) = self.particulates {
+            write!(
+                w,
+                ",\"pm1\":{},\"pm25\":{},\"pm10\":{},\"aqi\":{}",
+                pm.pm1_0,
+                pm.pm2_5,
+                pm.pm10,
+                pm.aqi()
+            )?;
+        }
+        if let Some(fix) = self.location {
+            write!(
+                w,
+                ",\"lat\":{},\"lon\":{}",
+                Scaled {
+                    value: fix.position.latitude as i64,
+                    places: 7
+                },
+                Scaled {
+                    value: fix.position.longitude as i64,
+                    places: 7
+                }
+            )?;
+            if let Some(altitude) = fix.altitude {
+                write!(w, ",\"alt\":{}", Tenths(altitude))?;
+            }
+            write!(
+                w,
+                ",\"fix\":\"{}\",\"sats\":{}",
+                fix.quality.code(),
+                fix.satellites
+            )?;
+            if let Some(date) = fix.date {
+                write!(
+                    w,
+                    ",\"gps_time\":\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z\"",
+                    date.year,
+                    date.month,
+                    date.day,
+                    fix.time.hour,
+                    fix.time.minute,
+                    fix.time.second
+                )?;
+            }
+        }
+        if let Some(battery) = self.battery {
+            write!(
+                w,
+                ",\"batt_v\":{}.{:03},\"batt_pct\":{}",
+                battery.millivolts / 1000,
+                battery.millivolts % 1000,
+                battery.percent
+            )?;
+        }
+        if !self.faults.is_empty() {
+            w.write_str(",\"faults\":[")?;
+            for (idx, fault) in self.faults.iter().enumerate() {
+                if idx > 0 {
+                    w.write_str(",")?;
+                }
+                write!(w, "\"{}:{}\"", fault.sensor, fault.error.code())?;
+            }
+            w.write_str("]")?;
+        }
+        w.write_str("}")
+    }
+}
+
+impl From<Reading> for Measurement {
+    fn from(reading: Reading) -> Self {
+        Self {
+            temperature: Some(reading.temperature),
+            humidity: Some(reading.humidity),
+            pressure: reading.pressure,
+            ..Default::default()
+        }
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait EnvironmentalSensor {
+    /// Short identifier used when reporting faults, at most
+    /// [`MAX_SENSOR_NAME`] bytes.
+    fn name(&self) -> &'static str;
+
+    /// Rated range of the sensor; values outside it are graded bad.
+    fn limits(&self) -> Limits {
+        Limits::PHYSICAL
+    }
+
+    /// Called with what the sensors polled earlier measured, right before
+    /// [`measure`](EnvironmentalSensor::measure), for sensors whose readings
+    /// need compensating (a CO2 sensor needs the ambient pressure).
+    fn prepare(&mut self, _earlier: &Measurement) {}
+
+    /// Takes a measurement, leaving quantities the sensor does not provide as
+    /// `None`.
+    async fn measure(&mut self) -> Result<Measurement, SensorError>;
+}
+
+/// Polls every sensor in order, corrects each result with `calibration`,
+/// checks it against the sensor's [`limits`](EnvironmentalSensor::limits)
+/// and merges them. Each sensor is
+/// [prepared](EnvironmentalSensor::prepare) with the results so far. Sensors that fail
+/// are listed in [`Measurement::faults`] and do not stop the others from
+/// being read.
+pub async fn poll_all<S: EnvironmentalSensor>(
+    sensors: &mut [S],
+    calibration: &CalibrationTable,
+) -> Measurement {
+    let mut merged = Measurement::default();
+    for sensor in sensors.iter_mut() {
+        sensor.prepare(&merged);
+        match sensor.measure().await {
+            Ok(mut measurement) => {
+                calibration.apply(sensor.name(), &mut measurement);
+                plausibility::check_range(&mut measurement, &sensor.limits());
+                merged.merge(measurement);
+            }
+            Err(error) => {
+                let _ = merged.faults.push(Fault {
+                    sensor: sensor.name(),
+                    error,
+                });
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::Command;
+    use crate::mock::block_on;
+    use crate::nmea::{Date, FixQuality, Position, UtcTime};
+    use crate::wind::Direction;
+
+    struct Fixed(&'static str, Result<Measurement, SensorError>);
+
+    impl EnvironmentalSensor for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn measure(&mut self) -> Result<Measurement, SensorError> {
+            self.1.clone()
+        }
+    }
+
+    fn json(measurement: &Measurement) -> String {
+        let mut out = String::new();
+        measurement.write_json(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn merges_in_priority_order() {
+        let mut sensors = [
+            Fixed(
+                "dht",
+                Ok(Measurement {
+                    temperature: Some(231),
+                    humidity: Some(550),
+                    ..Default::default()
+                }),
+            ),
+            Fixed(
+                "baro",
+                Ok(Measurement {
+                    temperature: Some(225),
+                    pressure: Some(101_325),
+                    ..Default::default()
+                }),
+            ),
+        ];
+        let merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        assert_eq!(merged.temperature, Some(231));
+        assert_eq!(merged.humidity, Some(550));
+        assert_eq!(merged.pressure, Some(101_325));
+        assert!(merged.faults.is_empty());
+    }
+
+    #[test]
+    fn calibration_applies_to_the_named_sensor_only() {
+        let reading = || {
+            Ok(Measurement {
+                temperature: Some(225),
+                pressure: Some(101_325),
+                ..Default::default()
+            })
+        };
+        let mut sensors = [Fixed("baro", reading()), Fixed("dht", reading())];
+        let mut calibration = CalibrationTable::new();
+        let command = Command::parse("dht temp -1.5").unwrap();
+        calibration.execute(&command).unwrap();
+
+        // The first sensor wins, so swap them to see the corrected value
+        let merged = block_on(poll_all(&mut sensors, &calibration));
+        assert_eq!(merged.temperature, Some(225));
+        sensors.reverse();
+        let merged = block_on(poll_all(&mut sensors, &calibration));
+        assert_eq!(merged.temperature, Some(210));
+    }
+
+    #[test]
+    fn failed_sensor_does_not_hide_others() {
+        let mut sensors = [
+            Fixed("dht", Err(SensorError::NoResponse)),
+            Fixed(
+                "baro",
+                Ok(Measurement {
+                    pressure: Some(98_760),
+                    ..Default::default()
+                }),
+            ),
+        ];
+        let merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        assert_eq!(
+            json(&merged),
+            r#"{"status":"ok","pres":987.60,"quality":{"pres":"good"},"faults":["dht:no_response"]}"#
+        );
+    }
+
+    #[test]
+    fn out_of_range_value_gives_way_to_next_sensor() {
+        struct Ranged(Fixed, Limits);
+
+        impl EnvironmentalSensor for Ranged {
+            fn name(&self) -> &'static str {
+                self.0.name()
+            }
+
+            fn limits(&self) -> Limits {
+                self.1
+            }
+
+            async fn measure(&mut self) -> Result<Measurement, SensorError> {
+                self.0.measure().await
+            }
+        }
+
+        let reading = |temperature, humidity| {
+            Ok(Measurement {
+                temperature: Some(temperature),
+                humidity: Some(humidity),
+                ..Default::default()
+            })
+        };
+        let mut sensors = [
+            Ranged(Fixed("dht", reading(215, 0)), Limits::DHT11),
+            Ranged(Fixed("bme280", reading(209, 480)), Limits::BME280),
+        ];
+        let merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        assert_eq!(
+            (merged.temperature, merged.humidity),
+            (Some(215), Some(480))
+        );
+        assert_eq!(merged.quality.humidity, Some(Quality::Good));
+    }
+
+    #[test]
+    fn bad_values_are_flagged_without_derived_quantities() {
+        let mut measurement = Measurement {
+            temperature: Some(215),
+            humidity: Some(0),
+            ..Default::default()
+        };
+        plausibility::check_range(&mut measurement, &Limits::DHT11);
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","temp":21.5,"hum":0.0,"quality":{"temp":"good","hum":"bad"}}"#
+        );
+    }
+
+    #[test]
+    fn reports_rain_in_millimetres() {
+        let measurement = Measurement {
+            pressure: Some(101_325),
+            rain: Some(Rainfall {
+                tips: 3,
+                amount: 83,
+                rate: 1674,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","pres":1013.25,"rain":0.83,"rain_rate":16.74}"#
+        );
+    }
+
+    #[test]
+    fn reports_wind_with_direction() {
+        let measurement = Measurement {
+            pressure: Some(101_325),
+            wind: Some(Wind {
+                speed: 34,
+                gust: 67,
+                direction: Some(Direction(1)),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","pres":1013.25,"wind":3.4,"gust":6.7,"wind_dir":22.5,"wind_card":"NNE"}"#
+        );
+    }
+
+    #[test]
+    fn reports_particulates_with_aqi() {
+        let measurement = Measurement {
+            pressure: Some(101_325),
+            particulates: Some(Particulates {
+                pm1_0: 8,
+                pm2_5: 12,
+                pm10: 20,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","pres":1013.25,"pm1":8,"pm25":12,"pm10":20,"aqi":56}"#
+        );
+    }
+
+    #[test]
+    fn reports_timestamp_once_the_clock_is_set() {
+        let measurement = Measurement {
+            temperature: Some(215),
+            timestamp: Some(1_740_787_200_250),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","time":"2025-03-01T00:00:00.250Z","unix_ms":1740787200250,"temp":21.5}"#
+        );
+    }
+
+    #[test]
+    fn later_sensors_are_prepared_with_earlier_results() {
+        // Reports `pressure` and echoes the pressure it was prepared with as
+        // its CO2 value
+        struct Compensated {
+            pressure: Option<u32>,
+            prepared: Option<u32>,
+        }
+
+        impl EnvironmentalSensor for Compensated {
+            fn name(&self) -> &'static str {
+                "compensated"
+            }
+
+            fn prepare(&mut self, earlier: &Measurement) {
+                self.prepared = earlier.pressure;
+            }
+
+            async fn measure(&mut self) -> Result<Measurement, SensorError> {
+                Ok(Measurement {
+                    pressure: self.pressure,
+                    co2: self.prepared.map(|pressure| (pressure / 100) as u16),
+                    ..Default::default()
+                })
+            }
+        }
+
+        let mut sensors = [
+            Compensated {
+                pressure: Some(98_700),
+                prepared: None,
+            },
+            Compensated {
+                pressure: None,
+                prepared: None,
+            },
+        ];
+        let mut merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        merged.quality = Assessment::default();
+        assert_eq!(sensors[0].prepared, None);
+        assert_eq!(json(&merged), r#"{"status":"ok","pres":987.00,"co2":987}"#);
+    }
+
+    #[test]
+    fn reports_location_with_gps_time() {
+        let measurement = Measurement {
+            pressure: Some(101_325),
+            location: Some(Fix {
+                position: Position {
+                    latitude: 481_173_000,
+                    longitude: -1_512_100_000,
+                },
+                altitude: Some(5_454),
+                quality: FixQuality::Dgps,
+                satellites: 8,
+                time: UtcTime {
+                    hour: 12,
+                    minute: 35,
+                    second: 19,
+                    millisecond: 0,
+                },
+                date: Some(Date {
+                    year: 2026,
+                    month: 10,
+                    day: 17,
+                }),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","pres":1013.25,"lat":48.1173000,"lon":-151.2100000,"alt":545.4,"fix":"dgps","sats":8,"gps_time":"2026-10-17T12:35:19Z"}"#
+        );
+    }
+
+    #[test]
+    fn widest_measurement_fits_max_json_len() {
+        let mut probes = heapless::Vec::new();
+        while probes
+            .push(ProbeReading {
+                rom: Rom([0xFF; 8]),
+                temperature: i16::MIN,
+            })
+            .is_ok()
+        {}
+        let mut faults = heapless::Vec::new();
+        while faults
+            .push(Fault {
+                sensor: "ds18b20",
+                error: SensorError::ChecksumMismatch,
+            })
+            .is_ok()
+        {}
+        let measurement = Measurement {
+            temperature: Some(-400),
+            humidity: Some(1_000),
+            pressure: Some(u32::MAX),
+            co2: Some(u16::MAX),
+            probes,
+            quality: Assessment {
+                temperature: Some(Quality::Suspect),
+                humidity: Some(Quality::Suspect),
+                pressure: Some(Quality::Suspect),
+            },
+            battery: Some(Battery {
+                millivolts: u16::MAX,
+                percent: u8::MAX,
+            }),
+            rain: Some(Rainfall {
+                tips: u32::MAX,
+                amount: u32::MAX,
+                rate: u32::MAX,
+            }),
+            wind: Some(Wind {
+                speed: u16::MAX,
+                gust: u16::MAX,
+                direction: Some(Direction(15)),
+            }),
+            particulates: Some(Particulates {
+                pm1_0: u16::MAX,
+                pm2_5: u16::MAX,
+                pm10: u16::MAX,
+            }),
+            location: Some(Fix {
+                position: Position {
+                    latitude: i32::MIN,
+                    longitude: i32::MIN,
+                },
+                altitude: Some(i32::MIN),
+                quality: FixQuality::Simulation,
+                satellites: u8::MAX,
+                time: UtcTime {
+                    hour: 23,
+                    minute: 59,
+                    second: 59,
+                    millisecond: 999,
+                },
+                date: Some(Date {
+                    year: 9999,
+                    month: 12,
+                    day: 31,
+                }),
+            }),
+            timestamp: Some(u64::MAX),
+            faults,
+        };
+        assert!(measurement.derived().is_some());
+        let out = json(&measurement);
+        assert!(
+            out.len() <= MAX_JSON_LEN,
+            "{} > {}",
+            out.len(),
+            MAX_JSON_LEN
+        );
+    }
+
+    #[test]
+    fn reports_battery_even_when_sensors_fail() {
+        let mut sensors = [Fixed("dht", Err(SensorError::NoResponse))];
+        let mut merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        merged.battery = Some(Battery::from_millivolts(3_905));
+        assert_eq!(
+            json(&merged),
+            r#"{"status":"sensor_failed","error":"no_response","batt_v":3.905,"batt_pct":64,"faults":["dht:no_response"]}"#
+        );
+    }
+
+    #[test]
+    fn wind_alone_is_a_reading() {
+        let mut sensors = [Fixed("dht", Err(SensorError::NoResponse))];
+        let mut merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        merged.wind = Some(Wind {
+            speed: 42,
+            gust: 80,
+            direction: None,
+        });
+        assert!(!merged.is_empty());
+        assert_eq!(
+            json(&merged),
+            r#"{"status":"ok","wind":4.2,"gust":8.0,"faults":["dht:no_response"]}"#
+        );
+    }
+
+    #[test]
+    fn all_sensors_failing_is_reported_explicitly() {
+        let mut sensors = [Fixed("dht", Err(SensorError::ChecksumMismatch))];
+        let merged = block_on(poll_all(&mut sensors, &CalibrationTable::new()));
+        assert_eq!(
+            json(&merged),
+            r#"{"status":"sensor_failed","error":"checksum_mismatch","faults":["dht:checksum_mismatch"]}"#
+        );
+    }
+
+    #[test]
+    fn empty_registry_is_a_failure() {
+        let merged = block_on(poll_all::<Fixed>(&mut [], &CalibrationTable::new()));
+        assert_eq!(
+            json(&merged),
+            r#"{"status":"sensor_failed","error":"no_sensors"}"#
+        );
+    }
+
+    #[test]
+    fn reports_probes_by_rom() {
+        let mut probes = heapless::Vec::new();
+        for (rom, temperature) in [
+            (Rom([0x28, 0xFF, 0x4C, 0x1A, 0x00, 0x16, 0x03, 0x9E]), 125),
+            (Rom([0x28, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]), -31),
+        ] {
+            probes.push(ProbeReading { rom, temperature }).unwrap();
+        }
+        let mut merged = Measurement {
+            humidity: Some(400),
+            ..Default::default()
+        };
+        merged.merge(Measurement {
+            probes,
+            ..Default::default()
+        });
+        assert_eq!(
+            json(&merged),
+            r#"{"status":"ok","hum":40.0,"probes":{"28FF4C1A0016039E":12.5,"2801020304050607":-3.1}}"#
+        );
+    }
+
+    #[test]
+    fn includes_derived_quantities() {
+        let measurement = Measurement {
+            temperature: Some(250),
+            humidity: Some(600),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","temp":25.0,"hum":60.0,"dew_point":16.7,"heat_index":25.1,"humidex":30.1,"wet_bulb":19.5,"abs_hum":13.8}"#
+        );
+    }
+
+    #[test]
+    fn formats_negative_temperature() {
+        let measurement = Measurement {
+            temperature: Some(-5),
+            humidity: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            json(&measurement),
+            r#"{"status":"ok","temp":-0.5,"hum":100.0,"dew_point":-0.5,"heat_index":-1.9,"humidex":-2.8,"wet_bulb":-0.6,"abs_hum":4.7}"#
+        );
+    }
+}
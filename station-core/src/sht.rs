@@ -0,0 +1,255 @@
+//! Sensirion SHT3x (SHT30/31/35) and SHT4x (SHT40/41/45) humidity sensors
+//! over I2C.
+//!
+//! Both families are read with a single-shot, high-repeatability measurement
+//! and return two 16-bit words, each followed by a CRC-8 that is checked
+//! before the word is used. They can also run an on-chip heater to drive off
+//! condensation, which otherwise pins the humidity reading near 100%.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::dht::Reading;
+use crate::sensor::SensorError;
+
+/// Default address of both families (SHT3x with ADDR low, SHT4x-A).
+pub const DEFAULT_ADDRESS: u8 = 0x44;
+
+/// Relative humidity, in tenths of a percent, at or above which the sensor is
+/// assumed to be wet and worth heating.
+pub const CONDENSATION_HUMIDITY: u16 = 950;
+
+// SHT3x: single shot, high repeatability, no clock stretching.
+const SHT3X_MEASURE: [u8; 2] = [0x24, 0x00];
+const SHT3X_HEATER_ON: [u8; 2] = [0x30, 0x6D];
+const SHT3X_HEATER_OFF: [u8; 2] = [0x30, 0x66];
+const SHT3X_MEASURE_MS: u32 = 16;
+const SHT3X_HEAT_MS: u32 = 1_000;
+// SHT4x: high precision measurement, and 200mW heating for 1s followed by a
+// measurement.
+const SHT4X_MEASURE: [u8; 1] = [0xFD];
+const SHT4X_HEAT: [u8; 1] = [0x39];
+const SHT4X_MEASURE_MS: u32 = 10;
+const SHT4X_HEAT_MS: u32 = 1_100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    Sht3x,
+    Sht4x,
+}
+
+/// CRC-8 used by Sensirion sensors: polynomial 0x31, initial value 0xFF.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Checks the CRC of each `[msb, lsb, crc]` triple in `data` and returns the
+/// words. A mismatch is reported as [`SensorError::ChecksumMismatch`].
+pub fn decode_words<const N: usize>(data: &[u8]) -> Result<[u16; N], SensorError> {
+    if data.len() != N * 3 {
+        return Err(SensorError::BusError);
+    }
+    let mut words = [0u16; N];
+    for (word, chunk) in words.iter_mut().zip(data.chunks_exact(3)) {
+        if crc8(&chunk[..2]) != chunk[2] {
+            return Err(SensorError::ChecksumMismatch);
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(words)
+}
+
+/// Converts raw words into a [`Reading`] using the family's transfer function.
+pub fn convert(variant: Variant, raw_temperature: u16, raw_humidity: u16) -> Reading {
+    // T = -45 + 175 * raw / (2^16 - 1), in tenths.
+    let temperature = -450 + scale(raw_temperature, 1750);
+    let humidity = match variant {
+        // RH = 100 * raw / (2^16 - 1)
+        Variant::Sht3x => scale(raw_humidity, 1000),
+        // RH = -6 + 125 * raw / (2^16 - 1), clipped to the physical range.
+        Variant::Sht4x => (-60 + scale(raw_humidity, 1250)).clamp(0, 1000),
+    };
+    Reading {
+        temperature: temperature as i16,
+        humidity: humidity as u16,
+        pressure: None,
+    }
+}
+
+/// `raw * span / 65535`, rounded to nearest.
+fn scale(raw: u16, span: i32) -> i32 {
+    ((raw as i32 * span) + 32_767) / 65_535
+}
+
+pub struct Sht<I> {
+    i2c: I,
+    address: u8,
+    variant: Variant,
+}
+
+impl<I: I2c> Sht<I> {
+    pub fn new(i2c: I, address: u8, variant: Variant) -> Self {
+        Self {
+            i2c,
+            address,
+            variant,
+        }
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// Runs one single-shot high-repeatability measurement.
+    pub fn measure<D: DelayNs>(&mut self, delay: &mut D) -> Result<Reading, SensorError> {
+        match self.variant {
+            Variant::Sht3x => self.write(&SHT3X_MEASURE)?,
+            Variant::Sht4x => self.write(&SHT4X_MEASURE)?,
+        }
+        delay.delay_ms(match self.variant {
+            Variant::Sht3x => SHT3X_MEASURE_MS,
+            Variant::Sht4x => SHT4X_MEASURE_MS,
+        });
+        self.read_reading()
+    }
+
+    /// Heats the sensor for about a second to evaporate condensation. Readings
+    /// stay high until the die has cooled again, so only call this once the
+    /// current measurement has been taken.
+    pub fn heat<D: DelayNs>(&mut self, delay: &mut D) -> Result<(), SensorError> {
+        match self.variant {
+            Variant::Sht3x => {
+                self.write(&SHT3X_HEATER_ON)?;
+                delay.delay_ms(SHT3X_HEAT_MS);
+                self.write(&SHT3X_HEATER_OFF)
+            }
+            Variant::Sht4x => {
+                // The heater switches itself off and leaves a measurement
+                // behind, which is read out to free the sensor but discarded.
+                self.write(&SHT4X_HEAT)?;
+                delay.delay_ms(SHT4X_HEAT_MS);
+                self.read_reading().map(|_| ())
+            }
+        }
+    }
+
+    fn read_reading(&mut self) -> Result<Reading, SensorError> {
+        let mut data = [0u8; 6];
+        self.i2c
+            .read(self.address, &mut data)
+            .map_err(|_| SensorError::BusError)?;
+        let [temperature, humidity] = decode_words::<2>(&data)?;
+        Ok(convert(self.variant, temperature, humidity))
+    }
+
+    fn write(&mut self, command: &[u8]) -> Result<(), SensorError> {
+        self.i2c
+            .write(self.address, command)
+            .map_err(|_| SensorError::BusError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Commands, NoDelay};
+
+    fn frame(temperature: u16, humidity: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        for word in [temperature, humidity] {
+            let bytes = word.to_be_bytes();
+            out.extend_from_slice(&bytes);
+            out.push(crc8(&bytes));
+        }
+        out
+    }
+
+    #[test]
+    fn crc_matches_datasheet_example() {
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+        assert_eq!(crc8(&[0x00, 0x00]), 0x81);
+    }
+
+    #[test]
+    fn bad_crc_is_a_checksum_mismatch() {
+        let mut data = frame(0x6666, 0x8000);
+        data[5] ^= 0x01;
+        assert_eq!(decode_words::<2>(&data), Err(SensorError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn converts_sht3x_range_ends() {
+        let low = convert(Variant::Sht3x, 0, 0);
+        assert_eq!((low.temperature, low.humidity), (-450, 0));
+        let high = convert(Variant::Sht3x, 0xFFFF, 0xFFFF);
+        assert_eq!((high.temperature, high.humidity), (1300, 1000));
+    }
+
+    #[test]
+    fn sht4x_humidity_is_clipped() {
+        assert_eq!(convert(Variant::Sht4x, 0x6666, 0).humidity, 0);
+        assert_eq!(convert(Variant::Sht4x, 0x6666, 0xFFFF).humidity, 1000);
+        // Half scale: -6 + 62.5 = 56.5 %RH.
+        assert_eq!(convert(Variant::Sht4x, 0x6666, 0x8000).humidity, 565);
+    }
+
+    #[test]
+    fn measures_sht3x_single_shot() {
+        let device = Commands::new(DEFAULT_ADDRESS);
+        // 0x6666 is 25.0 C, 0x8000 is 50.0 %RH.
+        device.queue_read(&frame(0x6666, 0x8000));
+        let mut sht = Sht::new(device.clone(), DEFAULT_ADDRESS, Variant::Sht3x);
+        let reading = sht.measure(&mut NoDelay).unwrap();
+        assert_eq!((reading.temperature, reading.humidity), (250, 500));
+        assert_eq!(device.writes(), vec![vec![0x24, 0x00]]);
+    }
+
+    #[test]
+    fn measurement_crc_failure_propagates() {
+        let device = Commands::new(DEFAULT_ADDRESS);
+        let mut data = frame(0x6666, 0x8000);
+        data[2] ^= 0xFF;
+        device.queue_read(&data);
+        let mut sht = Sht::new(device, DEFAULT_ADDRESS, Variant::Sht4x);
+        assert_eq!(
+            sht.measure(&mut NoDelay),
+            Err(SensorError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn sht3x_heater_is_switched_off_again() {
+        let device = Commands::new(DEFAULT_ADDRESS);
+        let mut sht = Sht::new(device.clone(), DEFAULT_ADDRESS, Variant::Sht3x);
+        sht.heat(&mut NoDelay).unwrap();
+        assert_eq!(device.writes(), vec![vec![0x30, 0x6D], vec![0x30, 0x66]]);
+    }
+
+    #[test]
+    fn sht4x_heater_reads_out_its_measurement() {
+        let device = Commands::new(DEFAULT_ADDRESS);
+        device.queue_read(&frame(0x9000, 0x1000));
+        let mut sht = Sht::new(device.clone(), DEFAULT_ADDRESS, Variant::Sht4x);
+        sht.heat(&mut NoDelay).unwrap();
+        assert_eq!(device.writes(), vec![vec![0x39]]);
+        assert_eq!(device.pending_reads(), 0);
+    }
+
+    #[test]
+    fn missing_sensor_is_a_bus_error() {
+        let device = Commands::new(0x45);
+        let mut sht = Sht::new(device, DEFAULT_ADDRESS, Variant::Sht4x);
+        assert_eq!(sht.measure(&mut NoDelay), Err(SensorError::BusError));
+    }
+}
@@ -0,0 +1,275 @@
+//! Simple Network Time Protocol client messages (RFC 4330).
+//!
+//! One request, one reply: [`request`] builds the datagram and [`parse`]
+//! checks the server's answer and turns its timestamps into a clock
+//! [`Reference`]. Sending and timing the exchange is left to the caller.
+//! Where UDP port 123 is blocked, every attempt costs a DNS lookup and the
+//! whole reply timeout with the radio on, so [`Backoff`] spaces them out
+//! after failures.
+
+use crate::clock::Reference;
+use crate::persist;
+
+pub const PORT: u16 = 123;
+
+/// Number of words [`Backoff`] occupies in RTC memory.
+pub const BACKOFF_WORDS: usize = 4;
+
+const BACKOFF_MAGIC: u32 = 0x4E54_5042;
+
+/// Wait after the first failed attempt; it doubles with each further one.
+const FIRST_BACKOFF_MS: u64 = 30 * 60_000;
+const MAX_BACKOFF_MS: u64 = 24 * 60 * 60_000;
+
+/// Length of a request and of the replies this client reads.
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970).
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SntpError {
+    /// Shorter than an NTP header.
+    Short,
+    /// Not a server reply to this client's request: the wrong mode, or the
+    /// origin timestamp does not echo the request.
+    Unexpected,
+    /// A kiss-o'-death (stratum 0): the server asks not to be queried, and
+    /// carries no time.
+    Refused,
+    /// The server's own clock is not synchronized.
+    Unsynchronized,
+}
+
+/// Builds a request. `nonce` goes out as the transmit timestamp, which the
+/// server echoes back; a random one keeps off-path replies out.
+pub fn request(nonce: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    packet[0] = VERSION << 3 | MODE_CLIENT;
+    packet[40..48].copy_from_slice(&nonce.to_be_bytes());
+    packet
+}
+
+/// Checks `reply` to the request sent with `nonce`. `sent_rtc_ms` and
+/// `received_rtc_ms` are the RTC times the request left and the reply
+/// arrived; the server's processing time is taken out of the round trip,
+/// and the reference splits the rest evenly between the two directions.
+pub fn parse(
+    reply: &[u8],
+    nonce: u64,
+    sent_rtc_ms: u64,
+    received_rtc_ms: u64,
+) -> Result<Reference, SntpError> {
+    if reply.len() < PACKET_LEN {
+        return Err(SntpError::Short);
+    }
+    let timestamp = |at: usize| {
+        u64::from_be_bytes([
+            reply[at],
+            reply[at + 1],
+            reply[at + 2],
+            reply[at + 3],
+            reply[at + 4],
+            reply[at + 5],
+            reply[at + 6],
+            reply[at + 7],
+        ])
+    };
+    let leap = reply[0] >> 6;
+    let mode = reply[0] & 0x07;
+    let stratum = reply[1];
+    if mode != MODE_SERVER || timestamp(24) != nonce {
+        return Err(SntpError::Unexpected);
+    }
+    if stratum == 0 {
+        return Err(SntpError::Refused);
+    }
+    if leap == LEAP_UNSYNCHRONIZED || stratum > 15 {
+        return Err(SntpError::Unsynchronized);
+    }
+
+    let received = unix_ms(timestamp(32));
+    let transmitted = unix_ms(timestamp(40));
+    let round_trip = received_rtc_ms.saturating_sub(sent_rtc_ms);
+    let in_flight = round_trip.saturating_sub(transmitted.saturating_sub(received));
+    Ok(Reference {
+        rtc_ms: received_rtc_ms,
+        unix_ms: transmitted + in_flight / 2,
+        error_ms: in_flight / 2 + 1,
+    })
+}
+
+/// When SNTP may be tried again after failed attempts.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Backoff {
+    /// Failed attempts in a row.
+    failures: u32,
+    /// RTC time before which no attempt is made.
+    retry_ms: u64,
+}
+
+impl Backoff {
+    pub fn may_try(&self, now_ms: u64) -> bool {
+        now_ms >= self.retry_ms
+    }
+
+    /// Records a failed attempt at `now_ms`: the next one waits 30 minutes,
+    /// doubling with each failure in a row up to a day.
+    pub fn failed(&mut self, now_ms: u64) {
+        let wait = FIRST_BACKOFF_MS
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_BACKOFF_MS);
+        self.failures = self.failures.saturating_add(1);
+        self.retry_ms = now_ms + wait;
+    }
+
+    pub fn succeeded(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn to_words(&self) -> [u32; BACKOFF_WORDS] {
+        let mut words = [0; BACKOFF_WORDS];
+        words[0] = self.failures;
+        words[1..3].copy_from_slice(&persist::split_u64(self.retry_ms));
+        persist::seal(&mut words, BACKOFF_MAGIC);
+        words
+    }
+
+    /// Restores a back-off saved by [`Backoff::to_words`]. Memory without a
+    /// valid check word allows an attempt straight away.
+    pub fn from_words(words: &[u32; BACKOFF_WORDS]) -> Self {
+        if !persist::is_sealed(words, BACKOFF_MAGIC) {
+            return Self::default();
+        }
+        Self {
+            failures: words[0],
+            retry_ms: persist::join_u64(&words[1..3]),
+        }
+    }
+}
+
+/// Converts an NTP timestamp (seconds since 1900 and a binary fraction) to
+/// Unix milliseconds. The seconds wrap in 2036; with the top bit clear they
+/// are taken to be past the wrap.
+fn unix_ms(timestamp: u64) -> u64 {
+    let mut seconds = timestamp >> 32;
+    if seconds & 0x8000_0000 == 0 {
+        seconds += 1 << 32;
+    }
+    let fraction = timestamp & 0xFFFF_FFFF;
+    (seconds - UNIX_EPOCH) * 1_000 + ((fraction * 1_000) >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: u64 = 0x1234_5678_9ABC_DEF0;
+    // 2025-03-01T00:00:00Z in NTP seconds.
+    const MARCH_2025: u64 = 1_740_787_200 + UNIX_EPOCH;
+    const MARCH_2025_MS: u64 = 1_740_787_200_000;
+
+    fn reply(leap: u8, stratum: u8, receive: u64, transmit: u64) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0] = leap << 6 | VERSION << 3 | MODE_SERVER;
+        packet[1] = stratum;
+        packet[24..32].copy_from_slice(&NONCE.to_be_bytes());
+        packet[32..40].copy_from_slice(&receive.to_be_bytes());
+        packet[40..48].copy_from_slice(&transmit.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn builds_request() {
+        let packet = request(NONCE);
+        assert_eq!(packet[0], 0x23);
+        assert!(packet[1..40].iter().all(|&b| b == 0));
+        assert_eq!(packet[40..48], NONCE.to_be_bytes());
+    }
+
+    #[test]
+    fn splits_round_trip() {
+        // Sent at RTC 10 000, answered at 11 100; the server held it for a
+        // second, so each way took 50 ms.
+        let receive = MARCH_2025 << 32;
+        let transmit = (MARCH_2025 + 1) << 32;
+        let reference = parse(&reply(0, 2, receive, transmit), NONCE, 10_000, 11_100).unwrap();
+        assert_eq!(
+            reference,
+            Reference {
+                rtc_ms: 11_100,
+                unix_ms: MARCH_2025_MS + 1_000 + 50,
+                error_ms: 51,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_replies() {
+        let time = MARCH_2025 << 32;
+        assert_eq!(
+            parse(&reply(0, 2, time, time)[..47], NONCE, 0, 10),
+            Err(SntpError::Short)
+        );
+        assert_eq!(
+            parse(&reply(0, 2, time, time), NONCE + 1, 0, 10),
+            Err(SntpError::Unexpected)
+        );
+        let mut client = reply(0, 2, time, time);
+        client[0] = VERSION << 3 | MODE_CLIENT;
+        assert_eq!(parse(&client, NONCE, 0, 10), Err(SntpError::Unexpected));
+        assert_eq!(
+            parse(&reply(0, 0, time, time), NONCE, 0, 10),
+            Err(SntpError::Refused)
+        );
+        assert_eq!(
+            parse(&reply(3, 2, time, time), NONCE, 0, 10),
+            Err(SntpError::Unsynchronized)
+        );
+    }
+
+    #[test]
+    fn backs_off_after_failures() {
+        const MINUTE: u64 = 60_000;
+        let mut backoff = Backoff::default();
+        assert!(backoff.may_try(0));
+        backoff.failed(1_000);
+        assert!(!backoff.may_try(1_000 + 29 * MINUTE));
+        assert!(backoff.may_try(1_000 + 30 * MINUTE));
+        backoff.failed(31 * MINUTE);
+        assert!(!backoff.may_try(90 * MINUTE));
+        assert!(backoff.may_try(91 * MINUTE));
+        for _ in 0..40 {
+            backoff.failed(0);
+        }
+        assert!(backoff.may_try(24 * 60 * MINUTE));
+        backoff.succeeded();
+        assert!(backoff.may_try(0));
+    }
+
+    #[test]
+    fn backoff_survives_rtc_memory() {
+        let mut backoff = Backoff::default();
+        backoff.failed(5_000);
+        assert_eq!(Backoff::from_words(&backoff.to_words()), backoff);
+        let mut words = backoff.to_words();
+        words[1] ^= 1;
+        assert_eq!(Backoff::from_words(&words), Backoff::default());
+    }
+
+    #[test]
+    fn handles_the_2036_wrap() {
+        assert_eq!(unix_ms(UNIX_EPOCH << 32), 0);
+        // 2036-02-07T06:28:16Z is NTP second 2^32, which wraps to zero.
+        assert_eq!(unix_ms(0), 2_085_978_496_000);
+        assert_eq!(
+            unix_ms(0x7FFF_FFFF << 32 | 0x8000_0000),
+            2_085_978_496_000 + 2_147_483_647_500
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Cup anemometer and resistor-ladder wind vane.
+//!
+//! The anemometer closes a reed switch once per revolution (or a fixed
+//! number of times); pulses are counted over short intervals and turned into
+//! an average speed and a gust. The vane switches one of sixteen resistors
+//! into a divider, which is read with the ADC and matched against a table.
+
+/// Pulse rate to speed conversion of an anemometer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Anemometer {
+    /// Wind speed in millimetres per second for one pulse per second.
+    pub mm_per_s_per_hz: u32,
+}
+
+impl Anemometer {
+    /// The common 2.4 km/h per Hz cup anemometer (SparkFun SEN-15901, Misol
+    /// WH-SP-WS01).
+    pub const SPARKFUN: Anemometer = Anemometer {
+        mm_per_s_per_hz: 667,
+    };
+
+    /// Speed in tenths of a metre per second for `pulses` counted over
+    /// `interval_ms`.
+    pub fn speed(&self, pulses: u32, interval_ms: u32) -> u16 {
+        if interval_ms == 0 {
+            return 0;
+        }
+        // pulses / s * mm/s per Hz, in tenths of m/s (100 mm/s).
+        let numerator = pulses as u64 * self.mm_per_s_per_hz as u64 * 1000;
+        let denominator = interval_ms as u64 * 100;
+        ((numerator + denominator / 2) / denominator).min(u16::MAX as u64) as u16
+    }
+}
+
+/// Sixteen compass points, clockwise from north.
+const CARDINALS: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW",
+    "NNW",
+];
+
+/// One of the sixteen directions a vane can report; 0 is north.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Direction(pub u8);
+
+impl Direction {
+    /// Direction the wind comes from, in tenths of a degree.
+    pub fn decidegrees(&self) -> u16 {
+        (self.0 % 16) as u16 * 225
+    }
+
+    pub fn cardinal(&self) -> &'static str {
+        CARDINALS[(self.0 % 16) as usize]
+    }
+}
+
+/// Resistance of the vane in ohms for each of the sixteen directions,
+/// starting at north.
+pub type VaneTable = [u32; 16];
+
+/// The SparkFun/Argent Data Systems vane (SEN-15901).
+pub const SPARKFUN_VANE: VaneTable = [
+    33_000, 6_570, 8_200, 891, 1_000, 688, 2_200, 1_410, 3_900, 3_140, 16_000, 14_120, 120_000,
+    42_120, 64_900, 21_880,
+];
+
+/// Matches further off than this (as a ratio, in percent) are treated as an
+/// open or shorted vane rather than a direction.
+const VANE_TOLERANCE_PERCENT: u64 = 150;
+
+/// Resistance of the vane from the voltage across it, with the vane between
+/// the pin and ground and `pullup_ohms` from the pin to `supply_mv`. Returns
+/// `None` for an open circuit.
+pub fn vane_resistance(pin_mv: u16, supply_mv: u16, pullup_ohms: u32) -> Option<u32> {
+    if pin_mv >= supply_mv {
+        return None;
+    }
+    let ohms = pullup_ohms as u64 * pin_mv as u64 / (supply_mv - pin_mv) as u64;
+    Some(ohms.min(u32::MAX as u64) as u32)
+}
+
+/// Direction whose table resistance is closest to `ohms`, comparing ratios
+/// so the many small and few large ladder values are matched equally well.
+pub fn direction(ohms: u32, table: &VaneTable) -> Option<Direction> {
+    let ohms = ohms.max(1) as u64;
+    // Ratio of the larger to the smaller resistance, as a fraction.
+    let ratio = |expected: u32| {
+        let expected = expected.max(1) as u64;
+        (ohms.max(expected), ohms.min(expected))
+    };
+    let (idx, (big, small)) = table
+        .iter()
+        .map(|&expected| ratio(expected))
+        .enumerate()
+        .min_by(|(_, (a_big, a_small)), (_, (b_big, b_small))| {
+            (a_big * b_small).cmp(&(b_big * a_small))
+        })?;
+    if big * 100 > small * VANE_TOLERANCE_PERCENT {
+        return None;
+    }
+    Some(Direction(idx as u8))
+}
+
+/// Wind over one sampling window.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Wind {
+    /// Average speed in tenths of a metre per second.
+    pub speed: u16,
+    /// Highest speed over `gust_intervals` consecutive intervals, in tenths
+    /// of a metre per second.
+    pub gust: u16,
+    /// `None` when the vane could not be read.
+    pub direction: Option<Direction>,
+}
+
+impl Wind {
+    /// Summarizes pulse counts taken over consecutive intervals of
+    /// `interval_ms`. The WMO defines a gust as the highest 3 second
+    /// average, so with 1 second intervals `gust_intervals` is 3.
+    pub fn from_counts(
+        counts: &[u16],
+        interval_ms: u32,
+        gust_intervals: usize,
+        anemometer: &Anemometer,
+    ) -> Self {
+        let total: u32 = counts.iter().map(|&c| c as u32).sum();
+        let speed = anemometer.speed(total, interval_ms * counts.len() as u32);
+        let window = gust_intervals.clamp(1, counts.len().max(1));
+        let gust = counts
+            .windows(window)
+            .map(|w| {
+                let pulses = w.iter().map(|&c| c as u32).sum();
+                anemometer.speed(pulses, interval_ms * window as u32)
+            })
+            .max()
+            .unwrap_or(speed);
+        Self {
+            speed,
+            gust: gust.max(speed),
+            direction: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_pulse_per_second_is_2_4_kmh() {
+        // 2.4 km/h = 0.667 m/s.
+        assert_eq!(Anemometer::SPARKFUN.speed(10, 10_000), 7);
+        assert_eq!(Anemometer::SPARKFUN.speed(150, 10_000), 100);
+        assert_eq!(Anemometer::SPARKFUN.speed(0, 10_000), 0);
+        assert_eq!(Anemometer::SPARKFUN.speed(5, 0), 0);
+    }
+
+    #[test]
+    fn gust_is_best_three_second_average() {
+        let counts = [3, 3, 3, 9, 12, 9, 3, 3, 3, 3];
+        let wind = Wind::from_counts(&counts, 1_000, 3, &Anemometer::SPARKFUN);
+        // 51 pulses in 10 s = 5.1 Hz = 3.4 m/s.
+        assert_eq!(wind.speed, 34);
+        // 30 pulses in 3 s = 10 Hz = 6.7 m/s.
+        assert_eq!(wind.gust, 67);
+    }
+
+    #[test]
+    fn short_window_uses_all_intervals_for_gust() {
+        let wind = Wind::from_counts(&[4, 6], 1_000, 3, &Anemometer::SPARKFUN);
+        assert_eq!(wind.gust, wind.speed);
+        let calm = Wind::from_counts(&[], 1_000, 3, &Anemometer::SPARKFUN);
+        assert_eq!((calm.speed, calm.gust), (0, 0));
+    }
+
+    #[test]
+    fn computes_vane_resistance_from_divider() {
+        // 10k pull-up to 3.3 V: 33k reads 2.53 V, 1k reads 0.3 V.
+        assert_eq!(vane_resistance(2_531, 3_300, 10_000), Some(32_912));
+        assert_eq!(vane_resistance(300, 3_300, 10_000), Some(1_000));
+        assert_eq!(vane_resistance(3_300, 3_300, 10_000), None);
+    }
+
+    #[test]
+    fn maps_every_table_entry_to_its_direction() {
+        for (idx, &ohms) in SPARKFUN_VANE.iter().enumerate() {
+            assert_eq!(direction(ohms, &SPARKFUN_VANE), Some(Direction(idx as u8)));
+            // A few percent of ADC error still lands on the same point.
+            assert_eq!(
+                direction(ohms * 104 / 100, &SPARKFUN_VANE),
+                Some(Direction(idx as u8))
+            );
+            assert_eq!(
+                direction(ohms * 96 / 100, &SPARKFUN_VANE),
+                Some(Direction(idx as u8))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_open_or_shorted_vane() {
+        assert_eq!(direction(1_000_000, &SPARKFUN_VANE), None);
+        assert_eq!(direction(0, &SPARKFUN_VANE), None);
+    }
+
+    #[test]
+    fn names_directions() {
+        assert_eq!(Direction(0).cardinal(), "N");
+        assert_eq!(Direction(10).cardinal(), "SW");
+        assert_eq!(Direction(10).decidegrees(), 2250);
+        assert_eq!(Direction(15).decidegrees(), 3375);
+    }
+}